@@ -0,0 +1,176 @@
+use crate::{ColumnValue, DisplayLimits, QueryReport, RowField, wrap_column_value};
+
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+const NUMERIC_OID: u32 = 1700;
+
+/// Describe every difference between two reports, column metadata first,
+/// then row counts, then individual cells
+pub(crate) fn compare_reports(
+    left: &QueryReport,
+    right: &QueryReport,
+    float_tolerance: f64,
+    limits: &DisplayLimits,
+) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if left.fields.len() != right.fields.len() {
+        differences.push(format!(
+            "column count: {} vs {}",
+            left.fields.len(),
+            right.fields.len()
+        ));
+    }
+    for (idx, (a, b)) in left.fields.iter().zip(&right.fields).enumerate() {
+        if a.name != b.name || a.type_oid != b.type_oid || a.format != b.format {
+            differences.push(format!(
+                "column {}: name='{}' oid={} format={} vs name='{}' oid={} format={}",
+                idx,
+                a.name,
+                a.type_oid,
+                a.format_label(),
+                b.name,
+                b.type_oid,
+                b.format_label()
+            ));
+        }
+    }
+
+    if left.rows.len() != right.rows.len() {
+        differences.push(format!(
+            "row count: {} vs {}",
+            left.rows.len(),
+            right.rows.len()
+        ));
+    }
+    for (row_idx, (a_row, b_row)) in left.rows.iter().zip(&right.rows).enumerate() {
+        for (col_idx, (a, b)) in a_row.iter().zip(b_row).enumerate() {
+            let field = left.fields.get(col_idx);
+            if !values_match(field, a, b, float_tolerance) {
+                differences.push(format!(
+                    "row {} col {} ({}): {} vs {}",
+                    row_idx,
+                    col_idx,
+                    field.map(|f| f.name.as_str()).unwrap_or("?col"),
+                    wrap_column_value(field, a, limits),
+                    wrap_column_value(field, b, limits)
+                ));
+            }
+        }
+    }
+
+    differences
+}
+
+fn values_match(
+    field: Option<&RowField>,
+    left: &ColumnValue,
+    right: &ColumnValue,
+    float_tolerance: f64,
+) -> bool {
+    match (left, right) {
+        (ColumnValue::Null, ColumnValue::Null) => true,
+        (ColumnValue::Bytes(a), ColumnValue::Bytes(b)) if a == b => true,
+        (ColumnValue::Bytes(a), ColumnValue::Bytes(b)) => {
+            let Some(field) = field else {
+                return false;
+            };
+            match (decode_float(field, a), decode_float(field, b)) {
+                (Some(a), Some(b)) => (a - b).abs() <= float_tolerance,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Read a float4/float8/numeric value as f64, in either wire format
+fn decode_float(field: &RowField, bytes: &[u8]) -> Option<f64> {
+    match (field.type_oid, field.format) {
+        (FLOAT4_OID | FLOAT8_OID | NUMERIC_OID, 0) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+        (FLOAT4_OID, 1) => Some(f32::from_be_bytes(bytes.try_into().ok()?) as f64),
+        (FLOAT8_OID, 1) => Some(f64::from_be_bytes(bytes.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, type_oid: u32, format: i16) -> RowField {
+        RowField {
+            name: name.to_string(),
+            type_oid,
+            format,
+            ..RowField::default()
+        }
+    }
+
+    fn report(fields: Vec<RowField>, rows: Vec<Vec<ColumnValue>>) -> QueryReport {
+        QueryReport {
+            fields,
+            rows,
+            ..QueryReport::default()
+        }
+    }
+
+    fn bytes(value: &[u8]) -> ColumnValue {
+        ColumnValue::Bytes(value.to_vec())
+    }
+
+    #[test]
+    fn identical_reports_have_no_differences() {
+        let fields = vec![field("id", 23, 0), field("name", 25, 0)];
+        let rows = vec![vec![bytes(b"1"), ColumnValue::Null]];
+        let left = report(fields.clone(), rows.clone());
+        let right = report(fields, rows);
+        assert!(compare_reports(&left, &right, 0.0, &DisplayLimits::default()).is_empty());
+    }
+
+    #[test]
+    fn metadata_row_count_and_cells_are_compared() {
+        let left = report(
+            vec![field("id", 23, 0)],
+            vec![vec![bytes(b"1")], vec![bytes(b"2")]],
+        );
+        let right = report(vec![field("id", 20, 0)], vec![vec![bytes(b"1")]]);
+        assert_eq!(
+            compare_reports(&left, &right, 0.0, &DisplayLimits::default()),
+            [
+                "column 0: name='id' oid=23 format=text vs name='id' oid=20 format=text",
+                "row count: 2 vs 1",
+            ]
+        );
+
+        let right = report(
+            vec![field("id", 23, 0)],
+            vec![vec![bytes(b"1")], vec![ColumnValue::Null]],
+        );
+        assert_eq!(
+            compare_reports(&left, &right, 0.0, &DisplayLimits::default()),
+            ["row 1 col 0 (id): text:'2' vs <NULL>"]
+        );
+    }
+
+    #[test]
+    fn float_columns_use_the_tolerance() {
+        let fields = vec![field("text_val", 701, 0), field("bin_val", 701, 1)];
+        let left = report(
+            fields.clone(),
+            vec![vec![
+                bytes(b"0.30000000000000004"),
+                bytes(&0.1f64.to_be_bytes()),
+            ]],
+        );
+        let right = report(
+            fields,
+            vec![vec![bytes(b"0.3"), bytes(&0.1000001f64.to_be_bytes())]],
+        );
+        assert_eq!(
+            compare_reports(&left, &right, 0.0, &DisplayLimits::default()).len(),
+            2
+        );
+        assert!(compare_reports(&left, &right, 1e-6, &DisplayLimits::default()).is_empty());
+    }
+}
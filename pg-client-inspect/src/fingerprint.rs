@@ -0,0 +1,187 @@
+use crate::{
+    Args, ColumnValue, Connection, ExitCode, message_tag, parse_data_row, parse_fields,
+    summarize_backend_error,
+};
+use anyhow::{Context, Result, anyhow};
+use bytes::BytesMut;
+use postgres_protocol::message::backend::Message;
+use postgres_protocol::message::frontend;
+use std::io::Write;
+
+/// Label and query of each fingerprint entry; multi-row results are joined
+const PROBES: &[(&str, &str)] = &[
+    ("version", "SELECT version()"),
+    (
+        "server_version_num",
+        "SELECT current_setting('server_version_num')",
+    ),
+    ("extension_count", "SELECT count(*) FROM pg_extension"),
+    (
+        "installed_extensions",
+        "SELECT name FROM pg_available_extensions WHERE installed_version IS NOT NULL ORDER BY name",
+    ),
+    ("max_connections", "SHOW max_connections"),
+    ("shared_buffers", "SHOW shared_buffers"),
+];
+
+/// Values of every probe, in `PROBES` order; a failed probe holds its error
+struct Fingerprint {
+    entries: Vec<(&'static str, String)>,
+}
+
+impl Fingerprint {
+    fn collect(connection: &mut Connection) -> Result<Self> {
+        let mut entries = Vec::with_capacity(PROBES.len());
+        for (label, sql) in PROBES {
+            let value = match text_rows(connection, sql)? {
+                Ok(rows) => rows
+                    .into_iter()
+                    .filter_map(|row| row.into_iter().next())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Err(error) => format!("error: {error}"),
+            };
+            entries.push((*label, value));
+        }
+        Ok(Self { entries })
+    }
+
+    fn print(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let width = self.entries.iter().map(|(label, _)| label.len()).max();
+        for (label, value) in &self.entries {
+            writeln!(
+                out,
+                "  {:width$}  {}",
+                label,
+                value,
+                width = width.unwrap_or(0)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// One line per entry whose value differs between the two servers
+    fn differences(&self, other: &Fingerprint) -> Vec<String> {
+        self.entries
+            .iter()
+            .zip(&other.entries)
+            .filter(|((_, a), (_, b))| a != b)
+            .map(|((label, a), (_, b))| format!("{label}: {a} vs {b}"))
+            .collect()
+    }
+}
+
+/// Fingerprint the server, and with --compare-host a second one, then
+/// report where the two differ
+pub(crate) fn run_fingerprint(
+    connection: &mut Connection,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let fingerprint = Fingerprint::collect(connection)?;
+    let Some(compare_host) = &args.compare_host else {
+        writeln!(out, "fingerprint of {}:{}:", args.host, args.port)?;
+        return fingerprint.print(out).context("failed to write the report");
+    };
+
+    let compare_port = args.compare_port.unwrap_or(args.port);
+    let mut other = Connection::connect_to(compare_host, compare_port, None, args)?;
+    other.startup(args)?;
+    let other_fingerprint = Fingerprint::collect(&mut other)?;
+    other.terminate()?;
+
+    writeln!(out, "== {}:{} ==", args.host, args.port)?;
+    fingerprint.print(out)?;
+    writeln!(out, "== {}:{} ==", compare_host, compare_port)?;
+    other_fingerprint.print(out)?;
+
+    let differences = fingerprint.differences(&other_fingerprint);
+    if differences.is_empty() {
+        writeln!(out, "fingerprints match")?;
+        return Ok(());
+    }
+    writeln!(out, "differences:")?;
+    for difference in &differences {
+        writeln!(out, "  {difference}")?;
+    }
+    Err(
+        anyhow!("fingerprints differ in {} place(s)", differences.len())
+            .context(ExitCode::AssertionFailed),
+    )
+}
+
+/// Run `sql` with the simple query protocol and return every row as text
+/// (NULL as `NULL`), or the server's error
+pub(crate) fn text_rows(
+    connection: &mut Connection,
+    sql: &str,
+) -> Result<Result<Vec<Vec<String>>, String>> {
+    eprintln!("query: {}", sql);
+    let mut buf = BytesMut::new();
+    frontend::query(sql, &mut buf).context("failed to encode Query")?;
+    connection.send(&buf, "Query")?;
+
+    let mut fields = Vec::new();
+    let mut rows = Vec::new();
+    let mut error = None;
+    loop {
+        match connection.read_message()? {
+            Message::RowDescription(desc) => fields = parse_fields(&desc)?,
+            Message::DataRow(data_row) => {
+                let row = parse_data_row(&fields, &data_row)?
+                    .into_iter()
+                    .map(|value| match value {
+                        ColumnValue::Bytes(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                        ColumnValue::Null => "NULL".to_string(),
+                    })
+                    .collect();
+                rows.push(row);
+            }
+            Message::CommandComplete(_) | Message::NoticeResponse(_) => {}
+            Message::ErrorResponse(err) => error = Some(summarize_backend_error(err)?),
+            Message::ReadyForQuery(_) => break,
+            other => eprintln!("unexpected message: {:?}", message_tag(&other)),
+        }
+    }
+    Ok(error.map_or(Ok(rows), Err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(entries: &[(&'static str, &str)]) -> Fingerprint {
+        Fingerprint {
+            entries: entries
+                .iter()
+                .map(|(label, value)| (*label, value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn differing_entries_are_listed() {
+        let primary = fingerprint(&[
+            ("server_version_num", "150004"),
+            ("installed_extensions", "pg_stat_statements, plpgsql"),
+            ("max_connections", "100"),
+        ]);
+        let replica = fingerprint(&[
+            ("server_version_num", "150004"),
+            ("installed_extensions", "plpgsql"),
+            ("max_connections", "100"),
+        ]);
+        assert_eq!(
+            primary.differences(&replica),
+            vec!["installed_extensions: pg_stat_statements, plpgsql vs plpgsql"]
+        );
+        assert!(primary.differences(&primary).is_empty());
+
+        let mut out = Vec::new();
+        replica.print(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "  server_version_num    150004\n  installed_extensions  plpgsql\n  max_connections       100\n"
+        );
+    }
+}
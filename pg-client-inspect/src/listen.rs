@@ -0,0 +1,191 @@
+use crate::{
+    Args, Connection, ExitCode, Notice, Notification, decode_error_fields, format_backend_error,
+    message_tag, quote_identifier,
+};
+use anyhow::{Context, Result, anyhow};
+use bytes::BytesMut;
+use postgres_protocol::message::backend::Message;
+use postgres_protocol::message::frontend;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Run LISTEN on `channel`, then print each NotificationResponse as it
+/// arrives until the process is interrupted or --listen-timeout runs out
+pub(crate) fn run_listen(
+    connection: &mut Connection,
+    channel: &str,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let sql = format!("LISTEN {}", quote_identifier(channel));
+    eprintln!("query: {sql}");
+    let mut buf = BytesMut::new();
+    frontend::query(&sql, &mut buf).context("failed to encode Query")?;
+    connection.send(&buf, "Query")?;
+    let mut error = None;
+    loop {
+        match connection.read_message()? {
+            Message::CommandComplete(_) => {}
+            Message::ErrorResponse(err) => error = Some(format_backend_error(err)?),
+            Message::ReadyForQuery(_) => break,
+            other => eprintln!("unexpected message: {:?}", message_tag(&other)),
+        }
+    }
+    if let Some(error) = error {
+        return Err(anyhow!("LISTEN failed: {error}").context(ExitCode::QueryFailed));
+    }
+
+    let started = Instant::now();
+    let deadline = args
+        .listen_timeout
+        .map(|seconds| started + Duration::from_secs(seconds));
+    writeln!(out, "listening on channel {channel}")?;
+    out.flush()?;
+    let mut received = 0;
+    loop {
+        // Block until the next message, or at most until the deadline
+        let wait = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        connection
+            .stream
+            .set_read_timeout(wait)
+            .context("unable to set read timeout")?;
+        let message = match connection.read_message() {
+            Ok(message) => message,
+            Err(err) if deadline.is_some() && ExitCode::of(&err) == ExitCode::Timeout => break,
+            Err(err) => return Err(err),
+        };
+        match message {
+            Message::NotificationResponse(body) => {
+                let notification = Notification::from_body(&body);
+                writeln!(
+                    out,
+                    "notification: channel='{}' pid={} payload='{}'",
+                    notification.channel, notification.process_id, notification.payload
+                )?;
+                out.flush()?;
+                received += 1;
+            }
+            Message::NoticeResponse(notice) => {
+                let notice = Notice {
+                    fields: decode_error_fields(notice.fields())?,
+                };
+                eprintln!("notice: {}", notice);
+            }
+            Message::ParameterStatus(status) => eprintln!(
+                "parameter: {} = {}",
+                status.name().unwrap_or("<invalid utf8>"),
+                status.value().unwrap_or("<invalid utf8>")
+            ),
+            // Only sent when the server ends the session, e.g. on shutdown
+            Message::ErrorResponse(err) => {
+                return Err(anyhow!(format_backend_error(err)?).context(format!(
+                    "server ended the session after {received} notification(s)"
+                )));
+            }
+            other => eprintln!("unexpected message: {:?}", message_tag(&other)),
+        }
+    }
+    writeln!(
+        out,
+        "listen timeout reached after {:.1}s, {received} notification(s) received",
+        started.elapsed().as_secs_f64()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stream;
+    use clap::Parser;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![tag];
+        message.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn notification(pid: i32, channel: &str, payload: &str) -> Vec<u8> {
+        let mut body = pid.to_be_bytes().to_vec();
+        body.extend_from_slice(format!("{channel}\0{payload}\0").as_bytes());
+        message(b'A', &body)
+    }
+
+    #[test]
+    fn notifications_are_printed_until_the_session_ends() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let args = Args::parse_from([
+            "pg-client-inspect",
+            "--user=u",
+            "--database=d",
+            "--listen=jobs",
+        ]);
+        let mut connection = Connection::from_stream(Stream::Unix(client), &args);
+
+        let mut replies = message(b'C', b"LISTEN\0");
+        replies.extend(message(b'Z', b"I"));
+        replies.extend(notification(4242, "jobs", "job 1 done"));
+        replies.extend(message(b'S', b"TimeZone\0UTC\0"));
+        replies.extend(notification(4243, "jobs", ""));
+        replies.extend(message(
+            b'E',
+            b"SFATAL\0C57P01\0Mterminating connection due to administrator command\0\0",
+        ));
+        server.write_all(&replies).unwrap();
+
+        let mut out = Vec::new();
+        let err = run_listen(&mut connection, "jobs", &args, &mut out).unwrap_err();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "listening on channel jobs\n\
+             notification: channel='jobs' pid=4242 payload='job 1 done'\n\
+             notification: channel='jobs' pid=4243 payload=''\n"
+        );
+        assert!(
+            format!("{err:#}").starts_with("server ended the session after 2 notification(s)"),
+            "{err:#}"
+        );
+
+        let mut sent = [0u8; 19];
+        server.read_exact(&mut sent).unwrap();
+        assert_eq!(&sent, b"Q\0\0\0\x12LISTEN \"jobs\"\0");
+    }
+
+    #[test]
+    fn the_listen_timeout_ends_the_wait() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let args = Args::parse_from([
+            "pg-client-inspect",
+            "--user=u",
+            "--database=d",
+            "--listen=Mixed Case",
+            "--listen-timeout=1",
+        ]);
+        let mut connection = Connection::from_stream(Stream::Unix(client), &args);
+        let mut replies = message(b'C', b"LISTEN\0");
+        replies.extend(message(b'Z', b"I"));
+        replies.extend(notification(7, "Mixed Case", "hello"));
+        server.write_all(&replies).unwrap();
+
+        let mut out = Vec::new();
+        run_listen(&mut connection, "Mixed Case", &args, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("notification: channel='Mixed Case' pid=7 payload='hello'\n"),
+            "{out}"
+        );
+        assert!(out.ends_with("1 notification(s) received\n"), "{out}");
+    }
+}
@@ -1,96 +1,776 @@
 use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine as _;
 use bytes::BytesMut;
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, ValueEnum};
 use fallible_iterator::FallibleIterator;
 use postgres_protocol::IsNull;
 use postgres_protocol::message::backend::{self, DataRowBody, Message, RowDescriptionBody};
 use postgres_protocol::message::frontend::{self, BindError};
 use std::fmt::Write as _;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Read, Write};
 use std::net::TcpStream;
-use std::time::Duration;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+mod compare;
+mod copy;
+mod fingerprint;
+mod listen;
+mod pipeline;
+mod replay;
+mod schema;
+mod sqlstate;
+
+/// Directories searched for PostgreSQL socket files when none is given
+const SOCKET_DIRS: &[&str] = &["/var/run/postgresql", "/tmp", "/run/postgresql"];
 
 #[derive(Parser, Debug)]
-#[command(author, version, about = "Inspect raw PostgreSQL protocol responses")]
+#[command(
+    author,
+    version,
+    about = "Inspect raw PostgreSQL protocol responses",
+    after_help = EXIT_CODES_HELP
+)]
 struct Args {
     #[arg(long, default_value = "127.0.0.1")]
     host: String,
     #[arg(long, default_value_t = 5432)]
     port: u16,
+    /// Connect over a UNIX socket file instead of TCP
+    #[arg(long)]
+    socket_path: Option<PathBuf>,
     #[arg(long)]
     user: String,
     #[arg(long)]
     database: String,
-    #[arg(long)]
-    query: String,
+    /// Query to run; repeat to run several in order on one connection, inside BEGIN/COMMIT
+    #[arg(long, required_unless_present_any = ["pipeline_queries", "ping", "replay_capture", "fingerprint", "listen"])]
+    query: Vec<String>,
     #[arg(long)]
     password: Option<String>,
     #[arg(long, default_value_t = true, action = ArgAction::Set)]
     binary_result: bool,
     #[arg(long, default_value_t = 10)]
     timeout_seconds: u64,
+    /// Prepared statement name used for Parse/Bind (empty string = unnamed)
+    #[arg(long, default_value = "stmt1")]
+    statement_name: String,
+    /// Portal name used for Bind/Describe/Execute (empty string = unnamed)
+    #[arg(long, default_value = "portal1")]
+    portal_name: String,
+    /// Maximum number of bytes shown for binary values (default: no limit)
+    #[arg(long, value_name = "N")]
+    max_display_bytes: Option<usize>,
+    /// Maximum number of characters shown for text values (default: no limit)
+    #[arg(long, value_name = "N")]
+    max_display_text: Option<usize>,
+    /// Ignore --max-display-bytes and --max-display-text
+    #[arg(long)]
+    full_values: bool,
+    /// Number of bytes between spaces in hex output (0 = no spaces)
+    #[arg(long, default_value_t = 0)]
+    hex_group_size: usize,
+    /// How values that are not ASCII text are shown
+    #[arg(long, value_enum, default_value_t = BinaryDisplayFormat::Hex)]
+    binary_display_format: BinaryDisplayFormat,
+    /// Rows per Execute (or FETCH with --cursor-name), at most 2147483647; rows are printed as they arrive and not kept (0 = all at once)
+    #[arg(long, value_name = "N", default_value_t = 0, value_parser = clap::value_parser!(u32).range(..=i32::MAX as i64))]
+    fetch_size: u32,
+    /// Iterate the query through DECLARE <name> CURSOR / FETCH instead of the extended protocol
+    #[arg(long)]
+    cursor_name: Option<String>,
+    /// Print every message sent and received, in order, after the run
+    #[arg(long)]
+    transcript: bool,
+    /// After the first query, read more queries from stdin and run them on the same connection
+    #[arg(long, alias = "interactive")]
+    keep_connection: bool,
+    /// Run the query (or --fingerprint) on a second server too and report any differences
+    #[arg(long)]
+    compare_host: Option<String>,
+    /// Port of the comparison server (defaults to --port)
+    #[arg(long)]
+    compare_port: Option<u16>,
+    /// Largest difference still considered equal for float columns
+    #[arg(long, default_value_t = 0.0)]
+    float_tolerance: f64,
+    /// Wrap each query in EXPLAIN (FORMAT TEXT) to print its plan
+    #[arg(long, conflicts_with = "cursor_name")]
+    explain: bool,
+    /// Wrap each query in EXPLAIN (ANALYZE, FORMAT TEXT); this runs the query
+    #[arg(long, conflicts_with_all = ["explain", "cursor_name"])]
+    explain_analyze: bool,
+    /// Send every statement of a file (separated by ';') in one pipelined write instead of --query
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["compare_host", "cursor_name", "keep_connection"])]
+    pipeline_queries: Option<PathBuf>,
+    /// With --pipeline-queries, run the statements again one round trip at a time and compare timings
+    #[arg(long, requires = "pipeline_queries")]
+    pipeline_compare: bool,
+    /// Write the result report to a file; protocol trace lines go to stderr
+    #[arg(long, value_name = "PATH")]
+    output_file: Option<PathBuf>,
+    /// Check the query with Parse/Describe and estimate its rows with EXPLAIN instead of running it
+    #[arg(long, conflicts_with_all = ["cursor_name", "explain", "explain_analyze", "pipeline_queries", "compare_host"])]
+    dry_run: bool,
+    /// Only connect, authenticate and wait for ReadyForQuery, then report the server parameters and handshake time
+    #[arg(long, conflicts_with_all = ["query", "pipeline_queries", "compare_host", "keep_connection", "dry_run"])]
+    ping: bool,
+    /// Leave client_encoding out of the startup packet
+    #[arg(long)]
+    no_client_encoding: bool,
+    /// Leave application_name out of the startup packet
+    #[arg(long)]
+    no_application_name: bool,
+    /// Send only user and database in the startup packet
+    #[arg(long)]
+    minimal_startup: bool,
+    /// Hex-dump the encoded startup message to stderr and decode its parameters back from the bytes
+    #[arg(long)]
+    debug_startup: bool,
+    /// Request this protocol version in the startup packet instead of 3.0, e.g. 3.2
+    #[arg(long, value_name = "MAJOR.MINOR", value_parser = parse_protocol_version)]
+    protocol_version: Option<i32>,
+    /// Run repeated --query values without wrapping them in BEGIN/COMMIT
+    #[arg(long)]
+    no_transaction: bool,
+    /// Replay the client messages of the first session in a PCAP-NG file written by the proxy's --export-pgwire-capture
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["query", "pipeline_queries", "compare_host", "keep_connection", "dry_run", "ping"])]
+    replay_capture: Option<PathBuf>,
+    /// With --replay-capture, authenticate with --user/--database/--password instead of the captured startup and password messages
+    #[arg(long, requires = "replay_capture")]
+    skip_auth_replay: bool,
+    /// Query version, extensions and key settings to fingerprint the server; with --compare-host, diff two servers
+    #[arg(long, conflicts_with_all = ["query", "pipeline_queries", "keep_connection", "dry_run", "ping", "replay_capture"])]
+    fingerprint: bool,
+    /// Send DISCARD ALL right after startup so the query runs in a clean session
+    #[arg(long, conflicts_with_all = ["ping", "replay_capture"])]
+    reset: bool,
+    /// Print only the column count, row count and command tag of each query; rows are decoded but not printed
+    #[arg(long, conflicts_with_all = ["cursor_name", "compare_host", "pipeline_queries", "dry_run", "ping", "replay_capture", "fingerprint"])]
+    count_only: bool,
+    /// Look up the table column behind each result field in pg_attribute and report its name, NOT NULL and unique keys
+    #[arg(long, conflicts_with_all = ["cursor_name", "dry_run", "pipeline_queries", "ping", "replay_capture", "fingerprint"])]
+    resolve_schema: bool,
+    /// Run LISTEN on a channel and print each notification as it arrives, until interrupted
+    #[arg(long, value_name = "CHANNEL", conflicts_with_all = ["query", "pipeline_queries", "compare_host", "keep_connection", "dry_run", "ping", "replay_capture", "fingerprint"])]
+    listen: Option<String>,
+    /// With --listen, stop waiting for notifications after this many seconds
+    #[arg(long, value_name = "SECONDS", requires = "listen")]
+    listen_timeout: Option<u64>,
+    /// Type OIDs of the columns of a binary COPY TO STDOUT, in order, e.g. 23,25,1700; rows are decoded and printed like a result set
+    #[arg(long, value_name = "OID", value_delimiter = ',')]
+    copy_types: Vec<u32>,
+}
+
+/// Parameters of the startup packet; poolers and non-PostgreSQL servers
+/// sometimes reject the optional ones
+fn startup_parameters(args: &Args) -> Vec<(&str, &str)> {
+    let mut parameters = vec![
+        ("user", args.user.as_str()),
+        ("database", args.database.as_str()),
+    ];
+    if !args.minimal_startup && !args.no_client_encoding {
+        parameters.push(("client_encoding", "UTF8"));
+    }
+    if !args.minimal_startup && !args.no_application_name {
+        parameters.push(("application_name", "postgres-protocol-inspector"));
+    }
+    parameters
+}
+
+/// The version field of a startup packet for `major.minor`
+fn parse_protocol_version(value: &str) -> Result<i32, String> {
+    let (major, minor) = value
+        .split_once('.')
+        .ok_or_else(|| "expected MAJOR.MINOR, e.g. 3.2".to_string())?;
+    let major: u16 = major
+        .parse()
+        .map_err(|_| format!("invalid major version: {major}"))?;
+    let minor: u16 = minor
+        .parse()
+        .map_err(|_| format!("invalid minor version: {minor}"))?;
+    if major > i16::MAX as u16 {
+        return Err(format!("major version out of range: {major}"));
+    }
+    Ok(((major as i32) << 16) | minor as i32)
+}
+
+fn format_protocol_version(version: i32) -> String {
+    format!("{}.{}", version >> 16, version & 0xffff)
+}
+
+/// `--debug-startup`: the encoded startup message as offset, hex and ASCII,
+/// then the version and parameters read back from the bytes
+fn print_startup_debug(buf: &[u8], parameters: &[(&str, &str)]) {
+    eprintln!("startup message ({} bytes):", buf.len());
+    for line in hex_dump_lines(buf) {
+        eprintln!("  {line}");
+    }
+    match decode_startup_message(buf) {
+        Ok((version, decoded)) => {
+            eprintln!(
+                "decoded startup: protocol {}, {} parameters",
+                format_protocol_version(version),
+                decoded.len()
+            );
+            for (name, value) in &decoded {
+                eprintln!("  {name} = {value}");
+            }
+            let expected: Vec<(String, String)> = parameters
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            if decoded != expected {
+                eprintln!("warning: decoded parameters differ from the ones encoded: {expected:?}");
+            }
+        }
+        Err(err) => eprintln!("warning: startup message does not decode: {err}"),
+    }
+}
+
+/// Lines of `offset: hex bytes  ASCII`, 16 bytes each, as in the proxy's hex dumps
+fn hex_dump_lines(data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if (0x20..=0x7e).contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:04x}: {:<48}  {}", i * 16, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+/// The protocol version and parameters of an encoded startup message,
+/// checking the length prefix and the terminating zero byte
+fn decode_startup_message(buf: &[u8]) -> Result<(i32, Vec<(String, String)>), String> {
+    let header = buf
+        .get(..8)
+        .ok_or_else(|| format!("{} bytes is shorter than the header", buf.len()))?;
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if length != buf.len() {
+        return Err(format!(
+            "length prefix {length} does not match the {} bytes encoded",
+            buf.len()
+        ));
+    }
+    let version = i32::from_be_bytes(header[4..8].try_into().unwrap());
+    let Some((&0, body)) = buf[8..].split_last() else {
+        return Err("parameters are not terminated by a zero byte".to_string());
+    };
+    let mut parameters = Vec::new();
+    if body.is_empty() {
+        return Ok((version, parameters));
+    }
+    let Some((&0, strings)) = body.split_last() else {
+        return Err("the last value is not terminated by a zero byte".to_string());
+    };
+    let strings: Vec<&[u8]> = strings.split(|&b| b == 0).collect();
+    if !strings.len().is_multiple_of(2) {
+        return Err("a parameter name has no value".to_string());
+    }
+    for pair in strings.chunks(2) {
+        parameters.push((
+            String::from_utf8_lossy(pair[0]).into_owned(),
+            String::from_utf8_lossy(pair[1]).into_owned(),
+        ));
+    }
+    Ok((version, parameters))
+}
+
+/// Newest version the server supports and the protocol options it did not
+/// recognize, from the body of a NegotiateProtocolVersion message. The
+/// protocol documents a bare minor version, but servers send the whole
+/// version number; both are accepted.
+fn parse_negotiate_protocol_version(body: &[u8]) -> Result<(i32, Vec<String>)> {
+    let read_i32 = |at: usize| -> Result<i32> {
+        let bytes = body
+            .get(at..at + 4)
+            .context("NegotiateProtocolVersion message is truncated")?;
+        Ok(i32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let newest = match read_i32(0)? {
+        minor if minor >> 16 == 0 => (3 << 16) | minor,
+        version => version,
+    };
+    let count = read_i32(4)?;
+    let mut options = Vec::new();
+    let mut rest = &body[8..];
+    for _ in 0..count {
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .context("NegotiateProtocolVersion option is not terminated")?;
+        options.push(String::from_utf8_lossy(&rest[..end]).into_owned());
+        rest = &rest[end + 1..];
+    }
+    Ok((newest, options))
+}
+
+/// Rows per FETCH when --cursor-name is used without --fetch-size
+const DEFAULT_CURSOR_FETCH_SIZE: u32 = 100;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  connection failed, or any other error such as an unreadable input file
+  2  authentication failed or the server refused the startup
+  3  the server answered a query with an ErrorResponse
+  4  an assertion failed: --compare-host or --fingerprint found differences, or pipelined responses arrived out of order
+  5  timed out waiting for the server (--timeout-seconds)
+  6  invalid command-line arguments, before any connection is made";
+
+/// Process exit status, so scripts and CI can tell failures apart. Errors
+/// are tagged with it as a context where the failure is classified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExitCode {
+    Success = 0,
+    ConnectionFailed = 1,
+    AuthFailed = 2,
+    QueryFailed = 3,
+    AssertionFailed = 4,
+    Timeout = 5,
+    UsageError = 6,
+}
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExitCode::Success => "success",
+            ExitCode::ConnectionFailed => "connection failed",
+            ExitCode::AuthFailed => "authentication failed",
+            ExitCode::QueryFailed => "query failed",
+            ExitCode::AssertionFailed => "assertion failed",
+            ExitCode::Timeout => "timed out",
+            ExitCode::UsageError => "invalid arguments",
+        })
+    }
+}
+
+impl ExitCode {
+    /// The code an error was tagged with; untagged errors count as
+    /// connection failures
+    fn of(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<ExitCode>()
+            .copied()
+            .unwrap_or(ExitCode::ConnectionFailed)
+    }
+
+    /// A socket read or write failed; with --timeout-seconds set on the
+    /// socket, running out of time shows up as WouldBlock or TimedOut
+    fn of_socket_error(err: &std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ExitCode::Timeout,
+            _ => ExitCode::ConnectionFailed,
+        }
+    }
+
+    /// Arguments clap rejected; --help and --version come back from clap
+    /// as errors too, but succeed
+    fn of_usage_error(err: &clap::Error) -> Self {
+        if err.use_stderr() {
+            ExitCode::UsageError
+        } else {
+            ExitCode::Success
+        }
+    }
 }
 
 fn main() {
-    if let Err(err) = run() {
-        eprintln!("error: {err:#}");
-        std::process::exit(1);
+    // clap would exit with 2 itself, the code of an authentication failure
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(err) => {
+            let _ = err.print();
+            std::process::exit(ExitCode::of_usage_error(&err) as i32);
+        }
+    };
+    let code = match run(&args) {
+        Ok(()) => ExitCode::Success,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::of(&err)
+        }
+    };
+    std::process::exit(code as i32);
+}
+
+fn run(args: &Args) -> Result<()> {
+    let mut connection = Connection::connect(args)?;
+    let limits = DisplayLimits::from_args(args);
+    let mut out: Box<dyn Write> = match &args.output_file {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("failed to create output file {}", path.display())
+            })?))
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let outcome = run_session(&mut connection, args, &limits, &mut out);
+    out.flush().context("failed to write the report")?;
+    // The transcript is most useful when something went wrong, so it is
+    // printed before the error is reported
+    if let Some(transcript) = &connection.transcript {
+        print_transcript(transcript);
+    }
+    outcome
+}
+
+fn run_session(
+    connection: &mut Connection,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if let Some(path) = &args.replay_capture {
+        replay::run_replay(connection, path, args, limits, out)?;
+        return connection.terminate();
+    }
+    connection.startup(args)?;
+    if args.reset {
+        reset_session(connection, limits, out)?;
+    }
+    if args.ping {
+        write_ping_report(connection, out).context("failed to write the report")?;
+        return connection.terminate();
+    }
+    if args.fingerprint {
+        fingerprint::run_fingerprint(connection, args, out)?;
+        return connection.terminate();
+    }
+    if let Some(channel) = &args.listen {
+        listen::run_listen(connection, channel, args, out)?;
+        return connection.terminate();
+    }
+    if let Some(path) = &args.pipeline_queries {
+        pipeline::run_pipeline(connection, path, args, limits, out)?;
+        return connection.terminate();
+    }
+    if let Some(compare_host) = &args.compare_host {
+        run_comparison(connection, compare_host, args, limits, out)?;
+        return connection.terminate();
+    }
+    match args.query.as_slice() {
+        [query] => run_query(connection, query, args, limits, out)?,
+        queries => run_script(connection, queries, args, limits, out)?,
+    }
+    if args.keep_connection {
+        run_stdin_queries(connection, args, limits, out)?;
     }
+    connection.terminate()
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
-    let mut connection = Connection::connect(&args)?;
-    connection.startup(&args)?;
-    let report = connection.run_extended_query(&args)?;
-    report.print();
-    connection.terminate()?;
+/// Drop prepared statements, temporary tables and settings left over from
+/// earlier use of the session, the way connection poolers do
+fn reset_session(
+    connection: &mut Connection,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    match connection.simple_query("DISCARD ALL", limits, false, out) {
+        Ok(_) => {
+            writeln!(out, "reset: DISCARD ALL succeeded")?;
+            Ok(())
+        }
+        Err(err) => {
+            writeln!(out, "reset: DISCARD ALL failed: {err:#}")?;
+            Err(err.context("failed to reset the session"))
+        }
+    }
+}
+
+/// Handshake latency, measured from before the socket was opened, and the
+/// parameters the server reported during startup
+fn write_ping_report(connection: &Connection, out: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "ping: ok, handshake took {:?}",
+        connection.opened_at.elapsed()
+    )?;
+    writeln!(out, "server parameters:")?;
+    for (name, value) in &connection.server_parameters {
+        writeln!(out, "  {name} = {value}")?;
+    }
+    Ok(())
+}
+
+fn run_query(
+    connection: &mut Connection,
+    query: &str,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if args.dry_run {
+        let summary = connection.dry_run(query, args)?;
+        return writeln!(out, "{summary}").context("failed to write the report");
+    }
+    let query = &explain_query(query, args);
+    if let Some(cursor_name) = &args.cursor_name {
+        connection.run_cursor_query(cursor_name, query, args, limits, out)
+    } else {
+        let report = connection.run_extended_query(
+            query,
+            &args.statement_name,
+            &args.portal_name,
+            args,
+            limits,
+            out,
+        )?;
+        report
+            .print(limits, out)
+            .context("failed to write the report")
+    }
+}
+
+/// Run several queries in order on one connection, inside a transaction
+/// unless --no-transaction; the first failure rolls the transaction back
+fn run_script(
+    connection: &mut Connection,
+    queries: &[String],
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if args.cursor_name.is_some() || args.dry_run {
+        bail!("a repeated --query cannot be combined with --cursor-name or --dry-run");
+    }
+    let transaction = !args.no_transaction;
+    if transaction {
+        connection.simple_query("BEGIN", limits, false, out)?;
+    }
+    for (idx, query) in queries.iter().enumerate() {
+        writeln!(out, "== query {}: {} ==", idx + 1, query)?;
+        let statement = numbered_name(&args.statement_name, idx);
+        let portal = numbered_name(&args.portal_name, idx);
+        let query = explain_query(query, args);
+        match connection.run_extended_query(&query, &statement, &portal, args, limits, out) {
+            Ok(report) => report.print(limits, out)?,
+            Err(err) => {
+                writeln!(out, "error: {err:#}")?;
+                if transaction {
+                    connection.simple_query("ROLLBACK", limits, false, out)?;
+                    writeln!(out, "transaction rolled back")?;
+                }
+                // The error was reported above; only its exit code is kept
+                return Err(anyhow!("query {} of {} failed", idx + 1, queries.len())
+                    .context(ExitCode::of(&err)));
+            }
+        }
+    }
+    if transaction {
+        connection.simple_query("COMMIT", limits, false, out)?;
+        writeln!(out, "transaction committed")?;
+    }
     Ok(())
 }
 
+/// Statement or portal name for query `idx` of a script: a trailing number
+/// is counted up (stmt1, stmt2, ...) and the unnamed one stays unnamed
+fn numbered_name(base: &str, idx: usize) -> String {
+    if base.is_empty() {
+        return String::new();
+    }
+    let prefix = base.trim_end_matches(|c: char| c.is_ascii_digit());
+    let start = base[prefix.len()..].parse::<usize>().unwrap_or(1);
+    format!("{prefix}{}", start + idx)
+}
+
+/// Run the query against the main and the comparison server and fail when
+/// their results differ
+fn run_comparison(
+    connection: &mut Connection,
+    compare_host: &str,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if args.cursor_name.is_some() || args.fetch_size > 0 {
+        bail!(
+            "--compare-host needs all rows and cannot be combined with --cursor-name or --fetch-size"
+        );
+    }
+    let [query] = args.query.as_slice() else {
+        bail!("--compare-host runs a single --query");
+    };
+    let compare_port = args.compare_port.unwrap_or(args.port);
+    let query = explain_query(query, args);
+    let (statement, portal) = (args.statement_name.as_str(), args.portal_name.as_str());
+
+    let report = connection.run_extended_query(&query, statement, portal, args, limits, out)?;
+    let mut other = Connection::connect_to(compare_host, compare_port, None, args)?;
+    other.startup(args)?;
+    let other_report = other.run_extended_query(&query, statement, portal, args, limits, out)?;
+    other.terminate()?;
+
+    writeln!(out, "== {}:{} ==", args.host, args.port)?;
+    report.print(limits, out)?;
+    writeln!(out, "== {}:{} ==", compare_host, compare_port)?;
+    other_report.print(limits, out)?;
+
+    let differences =
+        compare::compare_reports(&report, &other_report, args.float_tolerance, limits);
+    if differences.is_empty() {
+        writeln!(out, "responses match")?;
+        return Ok(());
+    }
+    writeln!(out, "differences:")?;
+    for difference in &differences {
+        writeln!(out, "  {difference}")?;
+    }
+    Err(
+        anyhow!("responses differ in {} place(s)", differences.len())
+            .context(ExitCode::AssertionFailed),
+    )
+}
+
+/// Run each stdin line as a query until EOF or `\q`; errors are reported
+/// and the session carries on
+fn run_stdin_queries(
+    connection: &mut Connection,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout()
+            .flush()
+            .context("failed to flush stdout")?;
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .context("failed to read stdin")?
+            == 0
+        {
+            return Ok(());
+        }
+        let line = line.trim();
+        match line {
+            "" => continue,
+            "\\q" | "\\quit" => return Ok(()),
+            command if command.starts_with('\\') => writeln!(out, "unknown command: {command}")?,
+            query => {
+                if let Err(err) = run_query(connection, query, args, limits, out) {
+                    writeln!(out, "error: {err:#}")?;
+                }
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
 struct Connection {
-    stream: TcpStream,
+    stream: Stream,
     read_buffer: BytesMut,
+    /// Messages exchanged so far, when --transcript is set
+    transcript: Option<Vec<TranscriptEntry>>,
+    /// When connecting started, for --ping
+    opened_at: Instant,
+    /// ParameterStatus values reported during startup
+    server_parameters: Vec<(String, String)>,
+}
+
+/// One message of the protocol conversation
+struct TranscriptEntry {
+    sent: bool,
+    label: String,
+    bytes: usize,
 }
 
 impl Connection {
     fn connect(args: &Args) -> Result<Self> {
-        let addr = format!("{}:{}", args.host, args.port);
-        let stream = TcpStream::connect(addr).context("failed to connect to server")?;
-        stream
-            .set_read_timeout(Some(Duration::from_secs(args.timeout_seconds)))
-            .context("unable to set read timeout")?;
-        stream
-            .set_write_timeout(Some(Duration::from_secs(args.timeout_seconds)))
-            .context("unable to set write timeout")?;
-        stream
-            .set_nodelay(true)
-            .context("unable to configure TCP_NODELAY")?;
+        Self::connect_to(&args.host, args.port, args.socket_path.as_deref(), args)
+    }
+
+    fn connect_to(host: &str, port: u16, socket_path: Option<&Path>, args: &Args) -> Result<Self> {
+        let opened_at = Instant::now();
+        let timeout = Some(Duration::from_secs(args.timeout_seconds));
+        let stream =
+            open_stream(host, port, socket_path, timeout).context(ExitCode::ConnectionFailed)?;
         Ok(Self {
+            opened_at,
+            ..Self::from_stream(stream, args)
+        })
+    }
+
+    fn from_stream(stream: Stream, args: &Args) -> Self {
+        Self {
             stream,
             read_buffer: BytesMut::with_capacity(4096),
-        })
+            transcript: args.transcript.then(Vec::new),
+            opened_at: Instant::now(),
+            server_parameters: Vec::new(),
+        }
     }
 
     fn startup(&mut self, args: &Args) -> Result<()> {
-        let parameters = vec![
-            ("user".to_string(), args.user.clone()),
-            ("database".to_string(), args.database.clone()),
-            ("client_encoding".to_string(), "UTF8".to_string()),
-            (
-                "application_name".to_string(),
-                "postgres-protocol-inspector".to_string(),
-            ),
-        ];
+        let parameters = startup_parameters(args);
         let mut buf = BytesMut::new();
-        frontend::startup_message(
-            parameters.iter().map(|(k, v)| (k.as_str(), v.as_str())),
-            &mut buf,
-        )
-        .context("failed to encode startup message")?;
-        self.stream
-            .write_all(&buf)
-            .context("failed to send startup message")?;
+        frontend::startup_message(parameters.iter().copied(), &mut buf)
+            .context("failed to encode startup message")?;
+        if let Some(version) = args.protocol_version {
+            // The version follows the length; the encoder always writes 3.0
+            buf[4..8].copy_from_slice(&version.to_be_bytes());
+            eprintln!(
+                "requesting protocol version {}",
+                format_protocol_version(version)
+            );
+        }
+        if args.debug_startup {
+            print_startup_debug(&buf, &parameters);
+        }
+        self.send(&buf, "startup message")?;
         self.consume_auth_responses(args)
     }
 
@@ -102,13 +782,16 @@ impl Connection {
                     let password = args
                         .password
                         .as_ref()
-                        .context("server requested cleartext password but none provided")?;
+                        .context("server requested cleartext password but none provided")
+                        .context(ExitCode::AuthFailed)?;
                     self.send_password(password)?;
                 }
                 Message::AuthenticationMd5Password(body) => {
-                    let password = args.password.as_ref().context(
-                        "server requested md5 password authentication but none provided",
-                    )?;
+                    let password = args
+                        .password
+                        .as_ref()
+                        .context("server requested md5 password authentication but none provided")
+                        .context(ExitCode::AuthFailed)?;
                     let response = md5_password_response(&args.user, password, body.salt());
                     self.send_password(&response)?;
                 }
@@ -118,33 +801,42 @@ impl Connection {
                     while let Some(name) = iter.next().context("failed to read SASL mechanism")? {
                         mechanisms.push(name.to_string());
                     }
-                    bail!("SASL authentication is not supported: {:?}", mechanisms);
+                    return Err(
+                        anyhow!("SASL authentication is not supported: {:?}", mechanisms)
+                            .context(ExitCode::AuthFailed),
+                    );
                 }
                 Message::AuthenticationSaslContinue(_) => {
-                    bail!("SASL continuation not supported by inspector");
+                    return Err(anyhow!("SASL continuation not supported by inspector")
+                        .context(ExitCode::AuthFailed));
                 }
                 Message::AuthenticationSaslFinal(_) => {
-                    bail!("SASL final message not supported by inspector");
+                    return Err(anyhow!("SASL final message not supported by inspector")
+                        .context(ExitCode::AuthFailed));
                 }
                 Message::ParameterStatus(status) => {
                     let name = status.name().unwrap_or("<invalid utf8>");
                     let value = status.value().unwrap_or("<invalid utf8>");
-                    println!("parameter: {} = {}", name, value);
+                    eprintln!("parameter: {} = {}", name, value);
+                    self.server_parameters
+                        .push((name.to_string(), value.to_string()));
                 }
                 Message::BackendKeyData(data) => {
-                    println!(
+                    eprintln!(
                         "backend key data: pid={} secret={}",
                         data.process_id(),
                         data.secret_key()
                     );
                 }
                 Message::ReadyForQuery(state) => {
-                    println!("ready for query (transaction state {})", state.status());
+                    eprintln!("ready for query (transaction state {})", state.status());
                     break;
                 }
-                Message::ErrorResponse(err) => bail!(format_backend_error(err)?),
+                Message::ErrorResponse(err) => {
+                    return Err(anyhow!(format_backend_error(err)?).context(ExitCode::AuthFailed));
+                }
                 other => {
-                    println!("startup message ignored: {:?}", message_tag(&other));
+                    eprintln!("startup message ignored: {:?}", message_tag(&other));
                 }
             }
         }
@@ -155,71 +847,170 @@ impl Connection {
         let mut buf = BytesMut::new();
         frontend::password_message(password.as_bytes(), &mut buf)
             .context("failed to encode password message")?;
-        self.stream
-            .write_all(&buf)
-            .context("failed to send password message")
+        self.send(&buf, "password message")
     }
 
-    fn run_extended_query(&mut self, args: &Args) -> Result<QueryReport> {
+    fn run_extended_query(
+        &mut self,
+        query: &str,
+        statement: &str,
+        portal: &str,
+        args: &Args,
+        limits: &DisplayLimits,
+        out: &mut dyn Write,
+    ) -> Result<QueryReport> {
         let mut buf = BytesMut::new();
+        if args.keep_connection {
+            // The statement from the previous query would make Parse fail;
+            // closing a statement that does not exist is not an error
+            frontend::close(b'S', statement, &mut buf).context("failed to encode Close")?;
+        }
         frontend::parse(
-            "stmt1",
-            &args.query,
+            statement,
+            query,
             std::iter::empty::<postgres_protocol::Oid>(),
             &mut buf,
         )
         .context("failed to encode Parse message")?;
-        frontend::bind(
-            "portal1",
-            "stmt1",
-            std::iter::empty::<i16>(),
-            std::iter::empty::<&[u8]>(),
-            |_value: &[u8], _buf| -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
-                unreachable!("no parameters expected")
-            },
-            if args.binary_result { vec![1] } else { vec![0] },
-            &mut buf,
-        )
-        .map_err(|error| match error {
-            BindError::Conversion(e) => anyhow!("failed to encode Bind message: {e}"),
-            BindError::Serialization(e) => anyhow!("failed to encode Bind message: {e}"),
-        })?;
-        frontend::describe(b'P', "portal1", &mut buf).context("failed to encode Describe")?;
-        frontend::execute("portal1", 0, &mut buf).context("failed to encode Execute")?;
-        frontend::sync(&mut buf);
-        self.stream
-            .write_all(&buf)
-            .context("failed to send extended query messages")?;
+        encode_bind(portal, statement, args.binary_result, &mut buf)?;
+        frontend::describe(b'P', portal, &mut buf).context("failed to encode Describe")?;
+        // With a row limit the portal has to survive between Executes, so
+        // Flush is used instead of Sync until the last batch arrives
+        let fetch_size = i32::try_from(args.fetch_size).context("--fetch-size is too large")?;
+        frontend::execute(portal, fetch_size, &mut buf).context("failed to encode Execute")?;
+        if fetch_size > 0 {
+            frontend::flush(&mut buf);
+        } else {
+            frontend::sync(&mut buf);
+        }
+        self.send(&buf, "extended query messages")?;
 
-        let mut report = QueryReport::default();
+        let mut report = QueryReport {
+            statement_name: statement.to_string(),
+            portal_name: portal.to_string(),
+            streamed: fetch_size > 0,
+            count_only: args.count_only,
+            ..QueryReport::default()
+        };
+        let mut error = None;
+        let mut copy = None;
         loop {
             match self.read_message()? {
+                Message::CloseComplete => eprintln!("close response: CloseComplete"),
                 Message::ParseComplete => {
-                    println!("parse response: ParseComplete");
+                    eprintln!("parse response: ParseComplete");
                     report.parse_complete = true;
                 }
                 Message::BindComplete => {
-                    println!("bind response: BindComplete");
+                    eprintln!("bind response: BindComplete");
                     report.bind_complete = true;
                 }
                 Message::RowDescription(desc) => {
                     let fields = parse_fields(&desc)?;
-                    println!("row description arrived:");
+                    eprintln!("row description arrived:");
                     debug_print_fields(&fields);
+                    if let Some(warning) = format_mismatch(&fields, args.binary_result) {
+                        eprintln!("{warning}");
+                    }
                     report.fields = fields;
                 }
                 Message::DataRow(data_row) => {
+                    // Decoded even with --count-only, so a malformed row
+                    // still fails the query
                     let parsed_row = parse_data_row(&report.fields, &data_row)?;
-                    println!("data row received:");
-                    debug_print_row(&report.fields, &parsed_row);
-                    report.rows.push(parsed_row);
+                    report.row_count += 1;
+                    if report.count_only {
+                        continue;
+                    }
+                    // Streamed rows are not kept for the report, so they
+                    // are part of the result as they arrive
+                    if report.streamed {
+                        writeln!(out, "data row received:")?;
+                        write_row(&report.fields, &parsed_row, limits, out)?;
+                    } else {
+                        eprintln!("data row received:");
+                        debug_print_row(&report.fields, &parsed_row, limits);
+                        report.rows.push(parsed_row);
+                    }
+                }
+                Message::CopyOutResponse(body) => {
+                    let columns = body
+                        .column_formats()
+                        .count()
+                        .context("failed to read CopyOutResponse")?;
+                    let binary = body.format() == 1;
+                    eprintln!(
+                        "copy out response: format={} columns={}",
+                        if binary { "binary" } else { "text" },
+                        columns
+                    );
+                    if binary {
+                        let types = args.copy_types.as_slice();
+                        if !types.is_empty() && types.len() != columns {
+                            eprintln!(
+                                "warning: --copy-types lists {} type(s) but the COPY has {} column(s)",
+                                types.len(),
+                                columns
+                            );
+                        }
+                        report.fields = copy::copy_fields(types, columns);
+                        copy = Some(copy::BinaryCopyDecoder::default());
+                    }
+                }
+                Message::CopyData(body) => {
+                    let Some(decoder) = &mut copy else {
+                        eprintln!("copy data: {}", format_value(body.data(), limits));
+                        report.row_count += 1;
+                        continue;
+                    };
+                    for row in decoder.push(body.data()).context(ExitCode::QueryFailed)? {
+                        report.row_count += 1;
+                        if report.count_only {
+                            continue;
+                        }
+                        if report.streamed {
+                            writeln!(out, "copy row received:")?;
+                            write_row(&report.fields, &row, limits, out)?;
+                        } else {
+                            eprintln!("copy row received:");
+                            debug_print_row(&report.fields, &row, limits);
+                            report.rows.push(row);
+                        }
+                    }
+                }
+                Message::CopyDone => {
+                    eprintln!("copy done after {} row(s)", report.row_count);
+                    if let Some(decoder) = copy.take() {
+                        decoder.finish().context(ExitCode::QueryFailed)?;
+                    }
+                }
+                Message::PortalSuspended => {
+                    eprintln!(
+                        "portal suspended after {} row(s), fetching {} more",
+                        report.row_count, fetch_size
+                    );
+                    let mut buf = BytesMut::new();
+                    frontend::execute(portal, fetch_size, &mut buf)
+                        .context("failed to encode Execute")?;
+                    frontend::flush(&mut buf);
+                    self.send(&buf, "Execute")?;
                 }
                 Message::CommandComplete(body) => {
                     let tag = body.tag().unwrap_or("<invalid utf8>").to_string();
                     report.command_tag = Some(tag);
+                    if fetch_size > 0 {
+                        self.send_sync()?;
+                    }
                 }
                 Message::ReadyForQuery(_) => break,
-                Message::EmptyQueryResponse => println!("empty query response"),
+                Message::EmptyQueryResponse => {
+                    eprintln!("empty query response");
+                    // Ends the Execute like CommandComplete, so the Sync
+                    // withheld for the next batch is due
+                    if fetch_size > 0 {
+                        self.send_sync()?;
+                    }
+                }
                 Message::ParameterDescription(pd) => {
                     let mut iter = pd.parameters();
                     let mut types = Vec::new();
@@ -229,50 +1020,344 @@ impl Connection {
                     {
                         types.push(oid);
                     }
-                    println!("parameter types: {:?}", types);
+                    eprintln!("parameter types: {:?}", types);
+                }
+                Message::NoData => eprintln!("no data response"),
+                // Read on to ReadyForQuery so the connection stays usable
+                Message::ErrorResponse(err) => {
+                    error = Some(format_backend_error(err)?);
+                    if fetch_size > 0 {
+                        self.send_sync()?;
+                    }
                 }
-                Message::NoData => println!("no data response"),
-                Message::ErrorResponse(err) => bail!(format_backend_error(err)?),
                 Message::NoticeResponse(notice) => {
-                    println!("notice: {}", format_error_fields(notice.fields())?);
+                    let notice = Notice {
+                        fields: decode_error_fields(notice.fields())?,
+                    };
+                    eprintln!("notice: {}", notice);
+                    report.notices.push(notice);
                 }
-                Message::NotificationResponse(notification) => {
-                    let channel = notification.channel().unwrap_or("<invalid utf8>");
-                    let payload = notification.message().unwrap_or("<invalid utf8>");
-                    println!("notification: channel={} payload={}", channel, payload);
+                Message::NotificationResponse(body) => {
+                    let notification = Notification::from_body(&body);
+                    eprintln!(
+                        "notification: channel={} payload={}",
+                        notification.channel, notification.payload
+                    );
+                    report.notifications.push(notification);
                 }
                 other => {
-                    println!("unexpected message: {:?}", message_tag(&other));
+                    eprintln!("unexpected message: {:?}", message_tag(&other));
                 }
             }
         }
 
+        if let Some(error) = error {
+            return Err(anyhow!(error).context(ExitCode::QueryFailed));
+        }
+        if args.resolve_schema {
+            schema::resolve_schema(self, &mut report.fields)?;
+        }
         Ok(report)
     }
 
-    fn terminate(mut self) -> Result<()> {
+    /// Parse and describe the statement without binding or executing it,
+    /// then ask EXPLAIN for the planner's row estimate. Bind is skipped
+    /// because it would need values for the statement's parameters.
+    fn dry_run(&mut self, query: &str, args: &Args) -> Result<String> {
+        let statement = args.statement_name.as_str();
+        let mut buf = BytesMut::new();
+        if args.keep_connection {
+            frontend::close(b'S', statement, &mut buf).context("failed to encode Close")?;
+        }
+        frontend::parse(
+            statement,
+            query,
+            std::iter::empty::<postgres_protocol::Oid>(),
+            &mut buf,
+        )
+        .context("failed to encode Parse message")?;
+        frontend::describe(b'S', statement, &mut buf).context("failed to encode Describe")?;
+        frontend::sync(&mut buf);
+        self.send(&buf, "dry-run messages")?;
+
+        let mut parameter_types = Vec::new();
+        let mut fields = Vec::new();
+        let mut error = None;
+        loop {
+            match self.read_message()? {
+                Message::CloseComplete => eprintln!("close response: CloseComplete"),
+                Message::ParseComplete => eprintln!("parse response: ParseComplete"),
+                Message::ParameterDescription(pd) => {
+                    let mut iter = pd.parameters();
+                    while let Some(oid) = iter
+                        .next()
+                        .context("failed to read parameter description")?
+                    {
+                        parameter_types.push(oid);
+                    }
+                    eprintln!("parameter types: {:?}", parameter_types);
+                }
+                Message::RowDescription(desc) => {
+                    fields = parse_fields(&desc)?;
+                    eprintln!("row description arrived:");
+                    debug_print_fields(&fields);
+                }
+                Message::NoData => eprintln!("no data response"),
+                Message::ErrorResponse(err) => error = Some(format_backend_error(err)?),
+                Message::ReadyForQuery(_) => break,
+                other => {
+                    eprintln!("unexpected message: {:?}", message_tag(&other));
+                }
+            }
+        }
+        if let Some(error) = error {
+            return Err(anyhow!("query is not valid: {error}").context(ExitCode::QueryFailed));
+        }
+
+        let estimate = self.estimate_rows(query, !parameter_types.is_empty())?;
+        Ok(dry_run_summary(&parameter_types, &fields, estimate))
+    }
+
+    /// The row estimate of the top plan node, or None when the statement
+    /// cannot be explained (utility statements such as DDL)
+    fn estimate_rows(&mut self, query: &str, has_parameters: bool) -> Result<Option<u64>> {
+        // GENERIC_PLAN (PostgreSQL 16+) plans statements with $n placeholders
+        let sql = if has_parameters {
+            format!("EXPLAIN (GENERIC_PLAN) {query}")
+        } else {
+            format!("EXPLAIN {query}")
+        };
+        eprintln!("query: {}", sql);
+        let mut buf = BytesMut::new();
+        frontend::query(&sql, &mut buf).context("failed to encode Query")?;
+        self.send(&buf, "Query")?;
+
+        let mut estimate = None;
+        loop {
+            match self.read_message()? {
+                Message::DataRow(row) if estimate.is_none() => {
+                    let line = row
+                        .ranges()
+                        .next()
+                        .context("failed to read EXPLAIN row")?
+                        .flatten()
+                        .map(|range| String::from_utf8_lossy(&row.buffer()[range]).into_owned());
+                    estimate = line.as_deref().and_then(plan_row_estimate);
+                }
+                Message::ErrorResponse(err) => {
+                    eprintln!("explain failed: {}", format_backend_error(err)?);
+                }
+                Message::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Stream the query through a server-side cursor, one FETCH at a time
+    fn run_cursor_query(
+        &mut self,
+        cursor_name: &str,
+        query: &str,
+        args: &Args,
+        limits: &DisplayLimits,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let fetch_size = match args.fetch_size {
+            0 => DEFAULT_CURSOR_FETCH_SIZE,
+            size => size,
+        };
+        let cursor = quote_identifier(cursor_name);
+
+        // Cursors without WITH HOLD only live inside a transaction block
+        self.simple_query("BEGIN", limits, false, out)?;
+        let fetched = self.fetch_cursor(&cursor, query, fetch_size, limits, out);
+        if fetched.is_err() {
+            // Leave the session idle for whatever runs next on it; when
+            // the connection itself failed, so does this
+            let _ = self.simple_query("ROLLBACK", limits, false, out);
+        }
+        let (total, batches) = fetched?;
+        self.simple_query("COMMIT", limits, false, out)?;
+        writeln!(
+            out,
+            "cursor {}: {} row(s) in {} fetch(es)",
+            cursor, total, batches
+        )
+        .context("failed to write the report")
+    }
+
+    /// Declare the cursor, fetch it to the end and close it; returns the
+    /// rows and FETCHes it took
+    fn fetch_cursor(
+        &mut self,
+        cursor: &str,
+        query: &str,
+        fetch_size: u32,
+        limits: &DisplayLimits,
+        out: &mut dyn Write,
+    ) -> Result<(usize, usize)> {
+        self.simple_query(&declare_cursor(cursor, query), limits, false, out)?;
+        let mut total = 0;
+        let mut batches = 0;
+        loop {
+            let fetch = format!("FETCH FORWARD {} FROM {}", fetch_size, cursor);
+            let rows = self.simple_query(&fetch, limits, batches == 0, out)?;
+            total += rows;
+            batches += 1;
+            if rows < fetch_size as usize {
+                break;
+            }
+        }
+        self.simple_query(&format!("CLOSE {}", cursor), limits, false, out)?;
+        Ok((total, batches))
+    }
+
+    /// Run a simple Query, printing its rows, and return how many arrived
+    fn simple_query(
+        &mut self,
+        sql: &str,
+        limits: &DisplayLimits,
+        show_fields: bool,
+        out: &mut dyn Write,
+    ) -> Result<usize> {
+        eprintln!("query: {}", sql);
+        let mut buf = BytesMut::new();
+        frontend::query(sql, &mut buf).context("failed to encode Query")?;
+        self.send(&buf, "Query")?;
+
+        let mut fields = Vec::new();
+        let mut rows = 0;
+        let mut error = None;
+        loop {
+            match self.read_message()? {
+                Message::RowDescription(desc) => {
+                    fields = parse_fields(&desc)?;
+                    if show_fields {
+                        writeln!(out, "row description arrived:")?;
+                        write_fields(&fields, out)?;
+                    }
+                }
+                Message::DataRow(data_row) => {
+                    let parsed_row = parse_data_row(&fields, &data_row)?;
+                    writeln!(out, "data row received:")?;
+                    write_row(&fields, &parsed_row, limits, out)?;
+                    rows += 1;
+                }
+                Message::CommandComplete(body) => {
+                    let tag = body.tag().unwrap_or("<invalid utf8>");
+                    writeln!(out, "command tag: {tag}")?;
+                }
+                Message::NoticeResponse(notice) => {
+                    writeln!(out, "notice: {}", format_error_fields(notice.fields())?)?;
+                }
+                // Keep reading until ReadyForQuery so the error is reported
+                // once the server is done with the query
+                Message::ErrorResponse(err) => error = Some(format_backend_error(err)?),
+                Message::ReadyForQuery(_) => break,
+                other => {
+                    eprintln!("unexpected message: {:?}", message_tag(&other));
+                }
+            }
+        }
+        match error {
+            Some(error) => Err(anyhow!(error).context(ExitCode::QueryFailed)),
+            None => Ok(rows),
+        }
+    }
+
+    fn send_sync(&mut self) -> Result<()> {
+        let mut buf = BytesMut::new();
+        frontend::sync(&mut buf);
+        self.send(&buf, "Sync")
+    }
+
+    fn send(&mut self, buf: &[u8], what: &str) -> Result<()> {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.extend(
+                describe_frontend_messages(buf)
+                    .into_iter()
+                    .map(|(label, bytes)| TranscriptEntry {
+                        sent: true,
+                        label,
+                        bytes,
+                    }),
+            );
+        }
+        self.stream.write_all(buf).map_err(|err| {
+            let code = ExitCode::of_socket_error(&err);
+            anyhow::Error::new(err)
+                .context(format!("failed to send {what}"))
+                .context(code)
+        })
+    }
+
+    fn terminate(&mut self) -> Result<()> {
         let mut buf = BytesMut::new();
         frontend::terminate(&mut buf);
-        self.stream
-            .write_all(&buf)
-            .context("failed to send Terminate message")
+        self.send(&buf, "Terminate message")
+    }
+
+    /// Report and consume a buffered NegotiateProtocolVersion, which
+    /// postgres-protocol cannot parse. The server sends it before
+    /// authentication when the startup packet asked for a newer minor
+    /// version or for options it does not know.
+    fn take_negotiate_protocol_version(&mut self) -> Result<()> {
+        if self.read_buffer.first() != Some(&b'v') || self.read_buffer.len() < 5 {
+            return Ok(());
+        }
+        let length = i32::from_be_bytes(self.read_buffer[1..5].try_into().unwrap()) as usize;
+        if self.read_buffer.len() < length + 1 {
+            return Ok(());
+        }
+        let message = self.read_buffer.split_to(length + 1);
+        if let Some(transcript) = &mut self.transcript {
+            transcript.push(TranscriptEntry {
+                sent: false,
+                label: "NegotiateProtocolVersion".to_string(),
+                bytes: message.len(),
+            });
+        }
+        let (newest, options) = parse_negotiate_protocol_version(&message[5..])?;
+        eprintln!(
+            "negotiate protocol version: server supports up to {}; unrecognized options: {}",
+            format_protocol_version(newest),
+            if options.is_empty() {
+                "none".to_string()
+            } else {
+                options.join(", ")
+            }
+        );
+        Ok(())
     }
 
     fn read_message(&mut self) -> Result<Message> {
         loop {
+            self.take_negotiate_protocol_version()?;
+            let buffered = self.read_buffer.len();
             if let Some(message) = backend::Message::parse(&mut self.read_buffer)
                 .context("failed to parse backend message")?
             {
+                if let Some(transcript) = &mut self.transcript {
+                    transcript.push(TranscriptEntry {
+                        sent: false,
+                        label: message_tag(&message).to_string(),
+                        bytes: buffered - self.read_buffer.len(),
+                    });
+                }
                 return Ok(message);
             }
 
             let mut temp = [0u8; 4096];
-            let read = self
-                .stream
-                .read(&mut temp)
-                .context("failed to read from socket")?;
+            let read = self.stream.read(&mut temp).map_err(|err| {
+                let code = ExitCode::of_socket_error(&err);
+                anyhow::Error::new(err)
+                    .context("failed to read from socket")
+                    .context(code)
+            })?;
             if read == 0 {
-                bail!("server closed the connection unexpectedly");
+                return Err(anyhow!("server closed the connection unexpectedly")
+                    .context(ExitCode::ConnectionFailed));
             }
             self.read_buffer.extend_from_slice(&temp[..read]);
         }
@@ -281,57 +1366,304 @@ impl Connection {
 
 #[derive(Default)]
 struct QueryReport {
+    statement_name: String,
+    portal_name: String,
     parse_complete: bool,
     bind_complete: bool,
     fields: Vec<RowField>,
     rows: Vec<Vec<ColumnValue>>,
+    row_count: usize,
+    /// Rows were printed as they arrived and not kept (--fetch-size)
+    streamed: bool,
+    /// Rows were only counted, and only the counts are reported (--count-only)
+    count_only: bool,
     command_tag: Option<String>,
+    notices: Vec<Notice>,
+    notifications: Vec<Notification>,
+    /// ErrorResponse of a replayed request
+    error: Option<String>,
+}
+
+/// A NoticeResponse, kept as its (field type, value) pairs
+struct Notice {
+    fields: Vec<(char, String)>,
+}
+
+impl Notice {
+    fn field(&self, code: char) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == code)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl std::fmt::Display for Notice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+struct Notification {
+    process_id: i32,
+    channel: String,
+    payload: String,
+}
+
+impl Notification {
+    fn from_body(body: &backend::NotificationResponseBody) -> Self {
+        Self {
+            process_id: body.process_id(),
+            channel: body.channel().unwrap_or("<invalid utf8>").to_string(),
+            payload: body.message().unwrap_or("<invalid utf8>").to_string(),
+        }
+    }
 }
 
 impl QueryReport {
-    fn print(&self) {
-        println!("parse complete: {}", self.parse_complete);
-        println!("bind complete: {}", self.bind_complete);
+    fn print(&self, limits: &DisplayLimits, out: &mut dyn Write) -> std::io::Result<()> {
+        if self.count_only {
+            return self.print_counts(out);
+        }
+        writeln!(
+            out,
+            "statement name: {}",
+            display_name(&self.statement_name)
+        )?;
+        writeln!(out, "portal name: {}", display_name(&self.portal_name))?;
+        writeln!(out, "parse complete: {}", self.parse_complete)?;
+        writeln!(out, "bind complete: {}", self.bind_complete)?;
         if self.fields.is_empty() {
-            println!("no row description returned");
+            writeln!(out, "no row description returned")?;
         } else {
-            println!("row description ({} column(s)):", self.fields.len());
+            writeln!(out, "row description ({} column(s)):", self.fields.len())?;
             for (idx, field) in self.fields.iter().enumerate() {
-                println!(
+                write!(
+                    out,
                     "  {}: name='{}' oid={} format={}",
                     idx,
                     field.name,
                     field.type_oid,
                     field.format_label()
-                );
+                )?;
+                match &field.source {
+                    Some(source) => writeln!(out, " source={source}")?,
+                    None => writeln!(out)?,
+                }
             }
         }
         for (row_idx, row) in self.rows.iter().enumerate() {
-            println!("row {row_idx}:");
+            writeln!(out, "row {row_idx}:")?;
             for (col_idx, value) in row.iter().enumerate() {
                 let field = self.fields.get(col_idx);
                 let column_name = field.map(|f| f.name.as_str()).unwrap_or("?col");
                 let format_label = field.map(|f| f.format_label()).unwrap_or("unknown");
-                println!(
+                writeln!(
+                    out,
                     "  {} ({} / {}): {}",
                     col_idx,
                     column_name,
                     format_label,
-                    wrap_column_value(value)
-                );
+                    wrap_column_value(field, value, limits)
+                )?;
             }
         }
+        if self.streamed {
+            writeln!(out, "rows streamed: {} (not kept)", self.row_count)?;
+        }
         if let Some(tag) = &self.command_tag {
-            println!("command tag: {tag}");
+            writeln!(out, "command tag: {tag}")?;
+        }
+        for (idx, notice) in self.notices.iter().enumerate() {
+            writeln!(
+                out,
+                "notice {}: {}: {}",
+                idx,
+                notice.field('S').unwrap_or("NOTICE"),
+                notice.field('M').unwrap_or("")
+            )?;
+        }
+        for (idx, notification) in self.notifications.iter().enumerate() {
+            writeln!(
+                out,
+                "notification {}: channel='{}' payload='{}' pid={}",
+                idx, notification.channel, notification.payload, notification.process_id
+            )?;
         }
+        if let Some(error) = &self.error {
+            writeln!(out, "error: {error}")?;
+        }
+        Ok(())
+    }
+
+    fn print_counts(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "columns: {}", self.fields.len())?;
+        writeln!(out, "rows: {}", self.row_count)?;
+        writeln!(
+            out,
+            "command tag: {}",
+            self.command_tag.as_deref().unwrap_or("(none)")
+        )
+    }
+}
+
+/// Encode a parameterless Bind requesting all columns in one format
+fn encode_bind(
+    portal: &str,
+    statement: &str,
+    binary_result: bool,
+    buf: &mut BytesMut,
+) -> Result<()> {
+    frontend::bind(
+        portal,
+        statement,
+        std::iter::empty::<i16>(),
+        std::iter::empty::<&[u8]>(),
+        |_value: &[u8], _buf| -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+            unreachable!("no parameters expected")
+        },
+        if binary_result { vec![1] } else { vec![0] },
+        buf,
+    )
+    .map_err(|error| match error {
+        BindError::Conversion(e) => anyhow!("failed to encode Bind message: {e}"),
+        BindError::Serialization(e) => anyhow!("failed to encode Bind message: {e}"),
+    })
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn declare_cursor(cursor: &str, query: &str) -> String {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    format!("DECLARE {} NO SCROLL CURSOR FOR {}", cursor, query)
+}
+
+/// Wrap the query in EXPLAIN when --explain or --explain-analyze is set,
+/// unless it already is one
+fn explain_query(query: &str, args: &Args) -> String {
+    let options = if args.explain_analyze {
+        "ANALYZE, FORMAT TEXT"
+    } else if args.explain {
+        "FORMAT TEXT"
+    } else {
+        return query.to_string();
+    };
+    let trimmed = query.trim_start();
+    let already_explain = trimmed
+        .get(..7)
+        .is_some_and(|keyword| keyword.eq_ignore_ascii_case("explain"))
+        && !trimmed[7..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    if already_explain {
+        query.to_string()
+    } else {
+        format!("EXPLAIN ({options}) {query}")
+    }
+}
+
+fn display_name(name: &str) -> String {
+    if name.is_empty() {
+        "(unnamed)".to_string()
+    } else {
+        format!("'{name}'")
+    }
+}
+
+/// The `rows=N` estimate of an EXPLAIN plan line
+fn plan_row_estimate(line: &str) -> Option<u64> {
+    let start = line.find(" rows=")? + " rows=".len();
+    let digits: String = line[start..]
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+/// One-line verdict of --dry-run
+fn dry_run_summary(parameter_types: &[u32], fields: &[RowField], estimate: Option<u64>) -> String {
+    let mut summary = String::from("Query is valid.");
+    if !parameter_types.is_empty() {
+        let parameters = parameter_types
+            .iter()
+            .enumerate()
+            .map(|(idx, oid)| format!("${} {}", idx + 1, type_name(*oid)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        summary.push_str(&format!(
+            " Takes {} parameter(s): ({}).",
+            parameter_types.len(),
+            parameters
+        ));
+    }
+    if fields.is_empty() {
+        summary.push_str(" Returns no rows.");
+    } else {
+        let columns = fields
+            .iter()
+            .map(|field| format!("{} {}", field.name, type_name(field.type_oid)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        summary.push_str(&format!(
+            " Returns {} columns: ({}).",
+            fields.len(),
+            columns
+        ));
+    }
+    if let Some(estimate) = estimate {
+        summary.push_str(&format!(" Estimated rows: {estimate}."));
     }
+    summary
+}
+
+/// Name of the common built-in types, or the OID for anything else
+fn type_name(oid: u32) -> String {
+    let name = match oid {
+        16 => "bool",
+        17 => "bytea",
+        18 => "char",
+        19 => "name",
+        20 => "int8",
+        21 => "int2",
+        23 => "int4",
+        25 => "text",
+        26 => "oid",
+        114 => "json",
+        142 => "xml",
+        700 => "float4",
+        701 => "float8",
+        1042 => "bpchar",
+        1043 => "varchar",
+        1082 => "date",
+        1083 => "time",
+        1114 => "timestamp",
+        1184 => "timestamptz",
+        1186 => "interval",
+        1266 => "timetz",
+        1700 => "numeric",
+        2950 => "uuid",
+        3802 => "jsonb",
+        _ => return format!("oid {oid}"),
+    };
+    name.to_string()
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct RowField {
     name: String,
+    /// OID of the table the column comes from, 0 for computed columns
+    table_oid: u32,
+    /// Attribute number of the column in that table
+    column_id: i16,
     type_oid: u32,
     format: i16,
+    /// Table column and constraints found by --resolve-schema
+    source: Option<String>,
 }
 
 impl RowField {
@@ -345,56 +1677,266 @@ impl RowField {
 }
 
 fn debug_print_fields(fields: &[RowField]) {
+    write_fields(fields, &mut std::io::stderr()).expect("failed to write to stderr");
+}
+
+fn write_fields(fields: &[RowField], out: &mut dyn Write) -> std::io::Result<()> {
     if fields.is_empty() {
-        println!("  (no columns)");
-        return;
+        return writeln!(out, "  (no columns)");
     }
     for (idx, field) in fields.iter().enumerate() {
-        println!(
+        writeln!(
+            out,
             "  col {idx}: name='{}' oid={} format={}",
             field.name,
             field.type_oid,
             field.format_label()
-        );
+        )?;
+    }
+    Ok(())
+}
+
+/// Warning naming the columns whose described format is not the one the
+/// Bind asked for, e.g. when a proxy or pooler rewrites result formats
+fn format_mismatch(fields: &[RowField], binary_result: bool) -> Option<String> {
+    let requested = if binary_result { 1 } else { 0 };
+    let mismatched: Vec<String> = fields
+        .iter()
+        .filter(|field| field.format != requested)
+        .map(|field| format!("'{}' ({})", field.name, field.format_label()))
+        .collect();
+    if mismatched.is_empty() {
+        return None;
     }
+    Some(format!(
+        "warning: requested {} results but {} of {} column(s) came back in another format: {}",
+        if binary_result { "binary" } else { "text" },
+        mismatched.len(),
+        fields.len(),
+        mismatched.join(", ")
+    ))
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 enum ColumnValue {
     Null,
     Bytes(Vec<u8>),
 }
 
-fn debug_print_row(fields: &[RowField], values: &[ColumnValue]) {
+fn debug_print_row(fields: &[RowField], values: &[ColumnValue], limits: &DisplayLimits) {
+    write_row(fields, values, limits, &mut std::io::stderr()).expect("failed to write to stderr");
+}
+
+fn write_row(
+    fields: &[RowField],
+    values: &[ColumnValue],
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
     for (idx, value) in values.iter().enumerate() {
         let field = fields.get(idx);
         let name = field.map(|f| f.name.as_str()).unwrap_or("<unnamed>");
         let format = field.map(|f| f.format_label()).unwrap_or("unknown");
-        println!(
+        writeln!(
+            out,
             "    col {idx} ({name} / {format}): {}",
-            wrap_column_value(value)
-        );
+            wrap_column_value(field, value, limits)
+        )?;
+    }
+    Ok(())
+}
+
+/// Encoding of values that are not ASCII text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum BinaryDisplayFormat {
+    /// 0x2a00ff
+    #[default]
+    Hex,
+    /// KgD/
+    Base64,
+    /// 42 0 255
+    Decimal,
+    /// \052\000\377
+    Octal,
+}
+
+/// Truncation and grouping settings applied when rendering column values
+#[derive(Clone, Copy, Debug)]
+struct DisplayLimits {
+    max_bytes: Option<usize>,
+    max_text: Option<usize>,
+    hex_group_size: usize,
+    binary_format: BinaryDisplayFormat,
+}
+
+impl DisplayLimits {
+    fn from_args(args: &Args) -> Self {
+        let limit = |max: Option<usize>| max.filter(|_| !args.full_values);
+        Self {
+            max_bytes: limit(args.max_display_bytes),
+            max_text: limit(args.max_display_text),
+            hex_group_size: args.hex_group_size,
+            binary_format: args.binary_display_format,
+        }
+    }
+}
+
+impl Default for DisplayLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            max_text: None,
+            hex_group_size: 0,
+            binary_format: BinaryDisplayFormat::Hex,
+        }
     }
 }
 
-fn wrap_column_value(value: &ColumnValue) -> String {
+fn wrap_column_value(
+    field: Option<&RowField>,
+    value: &ColumnValue,
+    limits: &DisplayLimits,
+) -> String {
     match value {
         ColumnValue::Null => "<NULL>".to_string(),
-        ColumnValue::Bytes(bytes) => format_value(bytes),
+        ColumnValue::Bytes(bytes) => field
+            .and_then(|field| format_small_type(field, bytes))
+            .unwrap_or_else(|| format_value(bytes, limits)),
+    }
+}
+
+const BOOL_OID: u32 = 16;
+const CHAR_OID: u32 = 18;
+const NAME_OID: u32 = 19;
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const OID_OID: u32 = 26;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+
+/// Typed rendering of the `char`, `name` and `oid` columns, and of binary
+/// `bool`, integer and float values, which would otherwise go through the
+/// ASCII-or-binary guess. None for any other type, or a value that does not
+/// decode as its type.
+fn format_small_type(field: &RowField, bytes: &[u8]) -> Option<String> {
+    match (field.type_oid, field.format) {
+        (BOOL_OID, 1) => match bytes {
+            [0] => Some("bool:false".to_string()),
+            [1] => Some("bool:true".to_string()),
+            _ => None,
+        },
+        (INT2_OID, 1) => Some(format!(
+            "int2:{}",
+            i16::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        (INT4_OID, 1) => Some(format!(
+            "int4:{}",
+            i32::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        (INT8_OID, 1) => Some(format!(
+            "int8:{}",
+            i64::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        (FLOAT4_OID, 1) => Some(format!(
+            "float4:{}",
+            f32::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        (FLOAT8_OID, 1) => Some(format!(
+            "float8:{}",
+            f64::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        (OID_OID, 0) => {
+            let oid: u32 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+            Some(format!("oid:{oid}"))
+        }
+        (OID_OID, 1) => Some(format!(
+            "oid:{}",
+            u32::from_be_bytes(bytes.try_into().ok()?)
+        )),
+        // Both formats send the identifier as text; fixed-size name
+        // buffers can leave NUL padding behind it
+        (NAME_OID, 0 | 1) => {
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            Some(format!(
+                "name:'{}'",
+                std::str::from_utf8(&bytes[..end]).ok()?
+            ))
+        }
+        (CHAR_OID, 0 | 1) => Some(format_char(char_code(bytes, field.format == 0)?)),
+        _ => None,
+    }
+}
+
+/// Byte held by a `char` value. The text format sends NUL as an empty
+/// string and bytes with the high bit set as a `\ooo` octal escape.
+fn char_code(bytes: &[u8], text: bool) -> Option<u8> {
+    match bytes {
+        [] if text => Some(0),
+        [byte] => Some(*byte),
+        [b'\\', digits @ ..] if text && digits.len() == 3 => {
+            u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok()
+        }
+        _ => None,
+    }
+}
+
+fn format_char(code: u8) -> String {
+    if code.is_ascii_graphic() || code == b' ' {
+        format!("char:'{}'", code as char)
+    } else {
+        format!("char:code {code}")
     }
 }
 
-fn format_value(bytes: &[u8]) -> String {
+fn format_value(bytes: &[u8], limits: &DisplayLimits) -> String {
     match std::str::from_utf8(bytes) {
-        Ok(text) if text.is_ascii() => format!("text:'{}'", text),
-        _ => format!("hex:{}", hex_string(bytes)),
+        Ok(text) if text.is_ascii() => match limits.max_text {
+            Some(max) if text.len() > max => {
+                format!("text:'{}...' ({} bytes)", &text[..max], text.len())
+            }
+            _ => format!("text:'{}'", text),
+        },
+        _ => match limits.max_bytes {
+            Some(max) if bytes.len() > max => format!(
+                "{}... ({} bytes)",
+                binary_string(&bytes[..max], limits),
+                bytes.len()
+            ),
+            _ => binary_string(bytes, limits),
+        },
+    }
+}
+
+/// Bytes in the --binary-display-format encoding, labelled with its name
+fn binary_string(bytes: &[u8], limits: &DisplayLimits) -> String {
+    match limits.binary_format {
+        BinaryDisplayFormat::Hex => format!("hex:{}", hex_string(bytes, limits.hex_group_size)),
+        BinaryDisplayFormat::Base64 => format!(
+            "base64:{}",
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        ),
+        BinaryDisplayFormat::Decimal => {
+            let values: Vec<String> = bytes.iter().map(u8::to_string).collect();
+            format!("decimal:{}", values.join(" "))
+        }
+        BinaryDisplayFormat::Octal => {
+            let mut out = String::from("octal:");
+            for byte in bytes {
+                let _ = write!(out, "\\{:03o}", byte);
+            }
+            out
+        }
     }
 }
 
-fn hex_string(bytes: &[u8]) -> String {
-    let mut out = String::with_capacity(bytes.len() * 2 + 2);
+fn hex_string(bytes: &[u8], group_size: usize) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3 + 2);
     out.push_str("0x");
-    for byte in bytes {
+    for (idx, byte) in bytes.iter().enumerate() {
+        if group_size > 0 && idx > 0 && idx % group_size == 0 {
+            out.push(' ');
+        }
         let _ = write!(out, "{:02x}", byte);
     }
     out
@@ -409,8 +1951,11 @@ fn parse_fields(description: &RowDescriptionBody) -> Result<Vec<RowField>> {
     {
         fields.push(RowField {
             name: field.name().to_string(),
+            table_oid: field.table_oid(),
+            column_id: field.column_id(),
             type_oid: field.type_oid(),
             format: field.format(),
+            source: None,
         });
     }
     Ok(fields)
@@ -429,7 +1974,7 @@ fn parse_data_row(fields: &[RowField], row: &DataRowBody) -> Result<Vec<ColumnVa
         }
     }
     if fields.len() != values.len() {
-        println!(
+        eprintln!(
             "warning: row has {} values but description has {} columns",
             values.len(),
             fields.len()
@@ -438,6 +1983,85 @@ fn parse_data_row(fields: &[RowField], row: &DataRowBody) -> Result<Vec<ColumnVa
     Ok(values)
 }
 
+/// Open the TCP or Unix socket connection to the server; without a socket path,
+/// a failed connection to the default host falls back to a local socket
+fn open_stream(
+    host: &str,
+    port: u16,
+    socket_path: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<Stream> {
+    let stream = if let Some(path) = socket_path {
+        Stream::Unix(connect_unix(path, timeout)?)
+    } else if host.starts_with('/') {
+        let path = Path::new(host).join(socket_file_name(port));
+        let path = if path.exists() {
+            path
+        } else {
+            let detected = find_pg_socket(port)
+                .ok_or_else(|| anyhow!("no PostgreSQL socket found at {}", path.display()))?;
+            eprintln!("auto-detected socket: {}", detected.display());
+            detected
+        };
+        Stream::Unix(connect_unix(&path, timeout)?)
+    } else {
+        match connect_tcp(host, port, timeout) {
+            Ok(stream) => Stream::Tcp(stream),
+            Err(err) if host == "127.0.0.1" => {
+                let Some(path) = find_pg_socket(port) else {
+                    return Err(err);
+                };
+                eprintln!("auto-detected socket: {}", path.display());
+                Stream::Unix(connect_unix(&path, timeout)?)
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    Ok(stream)
+}
+
+fn connect_tcp(host: &str, port: u16, timeout: Option<Duration>) -> Result<TcpStream> {
+    let addr = format!("{}:{}", host, port);
+    let stream = TcpStream::connect(addr).context("failed to connect to server")?;
+    stream
+        .set_read_timeout(timeout)
+        .context("unable to set read timeout")?;
+    stream
+        .set_write_timeout(timeout)
+        .context("unable to set write timeout")?;
+    stream
+        .set_nodelay(true)
+        .context("unable to configure TCP_NODELAY")?;
+    Ok(stream)
+}
+
+fn connect_unix(path: &Path, timeout: Option<Duration>) -> Result<UnixStream> {
+    let stream = UnixStream::connect(path)
+        .with_context(|| format!("failed to connect to socket {}", path.display()))?;
+    stream
+        .set_read_timeout(timeout)
+        .context("unable to set read timeout")?;
+    stream
+        .set_write_timeout(timeout)
+        .context("unable to set write timeout")?;
+    Ok(stream)
+}
+
+fn socket_file_name(port: u16) -> String {
+    format!(".s.PGSQL.{}", port)
+}
+
+/// Look for the server socket for `port` in the usual socket directories
+fn find_pg_socket(port: u16) -> Option<PathBuf> {
+    find_pg_socket_in(SOCKET_DIRS.iter().map(Path::new), port)
+}
+
+fn find_pg_socket_in<'a>(dirs: impl IntoIterator<Item = &'a Path>, port: u16) -> Option<PathBuf> {
+    dirs.into_iter()
+        .map(|dir| dir.join(socket_file_name(port)))
+        .find(|path| path.exists())
+}
+
 fn md5_password_response(user: &str, password: &str, salt: [u8; 4]) -> String {
     let mut inner = Vec::with_capacity(password.len() + user.len());
     inner.extend_from_slice(password.as_bytes());
@@ -450,18 +2074,121 @@ fn md5_password_response(user: &str, password: &str, salt: [u8; 4]) -> String {
     format!("md5{:x}", md5::compute(outer))
 }
 
+/// The summary line, then every field on an indented line below it
 fn format_backend_error(body: backend::ErrorResponseBody) -> Result<String> {
-    Ok(format_error_fields(body.fields())?)
+    let fields = decode_error_fields(body.fields())?;
+    Ok(format!(
+        "{}\n  {}",
+        summarize_error_fields(&fields),
+        describe_error_fields(&fields)
+    ))
+}
+
+/// Just the summary line, where the error has to fit on one line
+pub(crate) fn summarize_backend_error(body: backend::ErrorResponseBody) -> Result<String> {
+    Ok(summarize_error_fields(&decode_error_fields(body.fields())?))
+}
+
+/// `ERROR [22012]: division by zero`, labeled with the non-localized
+/// severity (FATAL, PANIC, ...) when the server sends one
+fn summarize_error_fields(fields: &[(char, String)]) -> String {
+    let field = |code: char| {
+        fields
+            .iter()
+            .find(|(field, _)| *field == code)
+            .map(|(_, value)| value.as_str())
+    };
+    let severity = field('V').or(field('S')).unwrap_or("ERROR");
+    let message = field('M').unwrap_or("(no message)");
+    match field('C') {
+        Some(code) => format!("{severity} [{code}]: {message}"),
+        None => format!("{severity}: {message}"),
+    }
 }
 
 fn format_error_fields(fields: backend::ErrorFields<'_>) -> Result<String> {
+    Ok(describe_error_fields(&decode_error_fields(fields)?))
+}
+
+/// `field=value` pairs, with the condition name after the SQLSTATE code
+fn describe_error_fields(fields: &[(char, String)]) -> String {
+    let parts: Vec<String> = fields
+        .iter()
+        .map(|(field, value)| match sqlstate::sqlstate_name(value) {
+            name if *field == 'C' && !name.is_empty() => format!("C={} ({})", value, name),
+            _ => format!("{}={}", field, value),
+        })
+        .collect();
+    parts.join(" ")
+}
+
+fn decode_error_fields(fields: backend::ErrorFields<'_>) -> Result<Vec<(char, String)>> {
     let mut iter = fields;
-    let mut parts = Vec::new();
+    let mut decoded = Vec::new();
     while let Some(field) = iter.next().context("failed to read error field")? {
         let value = std::str::from_utf8(field.value_bytes()).unwrap_or("<non-utf8>");
-        parts.push(format!("{}={}", field.type_() as char, value));
+        decoded.push((field.type_() as char, value.to_string()));
+    }
+    Ok(decoded)
+}
+
+fn print_transcript(transcript: &[TranscriptEntry]) {
+    eprintln!("transcript:");
+    for entry in transcript {
+        let arrow = if entry.sent { "→" } else { "←" };
+        eprintln!("  {} {} ({} bytes)", arrow, entry.label, entry.bytes);
+    }
+}
+
+/// Split an encoded frontend buffer into (label, size) pairs, one per message
+fn describe_frontend_messages(buf: &[u8]) -> Vec<(String, usize)> {
+    // The startup message is the only one without a type byte; its length
+    // prefix always starts with a zero byte, which is never a valid type
+    if buf.first() == Some(&0) {
+        return vec![("StartupMessage".to_string(), buf.len())];
+    }
+
+    let mut messages = Vec::new();
+    let mut rest = buf;
+    while rest.len() >= 5 {
+        let length = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        let size = (length + 1).min(rest.len());
+        let body = &rest[5..size];
+        let label = match rest[0] {
+            b'P' => format!("Parse({})", leading_name(body)),
+            b'B' => format!("Bind({})", leading_name(body)),
+            b'E' => format!("Execute({})", leading_name(body)),
+            b'D' | b'C' => {
+                let kind = if rest[0] == b'D' { "Describe" } else { "Close" };
+                let target = if body.first() == Some(&b'S') {
+                    "statement"
+                } else {
+                    "portal"
+                };
+                let name = leading_name(body.get(1..).unwrap_or_default());
+                format!("{}({} {})", kind, target, name)
+            }
+            b'S' => "Sync".to_string(),
+            b'H' => "Flush".to_string(),
+            b'Q' => "Query".to_string(),
+            b'p' => "PasswordMessage".to_string(),
+            b'X' => "Terminate".to_string(),
+            other => format!("'{}'", other as char),
+        };
+        messages.push((label, size));
+        rest = &rest[size..];
+    }
+    messages
+}
+
+/// The NUL-terminated statement or portal name a message body starts with
+fn leading_name(body: &[u8]) -> String {
+    let name = body.split(|b| *b == 0).next().unwrap_or_default();
+    if name.is_empty() {
+        "unnamed".to_string()
+    } else {
+        String::from_utf8_lossy(name).into_owned()
     }
-    Ok(parts.join(" "))
 }
 
 fn message_tag(message: &Message) -> &'static str {
@@ -471,6 +2198,7 @@ fn message_tag(message: &Message) -> &'static str {
         Message::AuthenticationOk => "AuthenticationOk",
         Message::BackendKeyData(_) => "BackendKeyData",
         Message::BindComplete => "BindComplete",
+        Message::CloseComplete => "CloseComplete",
         Message::CommandComplete(_) => "CommandComplete",
         Message::DataRow(_) => "DataRow",
         Message::EmptyQueryResponse => "EmptyQueryResponse",
@@ -481,6 +2209,7 @@ fn message_tag(message: &Message) -> &'static str {
         Message::ParameterDescription(_) => "ParameterDescription",
         Message::ParameterStatus(_) => "ParameterStatus",
         Message::ParseComplete => "ParseComplete",
+        Message::PortalSuspended => "PortalSuspended",
         Message::ReadyForQuery(_) => "ReadyForQuery",
         Message::RowDescription(_) => "RowDescription",
         other => {
@@ -495,21 +2224,592 @@ mod tests {
     use super::*;
     use hex::decode;
 
+    #[test]
+    fn test_report_is_written_to_any_writer() {
+        let report = QueryReport {
+            statement_name: String::new(),
+            portal_name: "p".to_string(),
+            command_tag: Some("SELECT 0".to_string()),
+            ..QueryReport::default()
+        };
+        let mut out = Vec::new();
+        report.print(&DisplayLimits::default(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("statement name: (unnamed)\nportal name: 'p'\n"));
+        assert!(text.ends_with("command tag: SELECT 0\n"));
+    }
+
+    #[test]
+    fn test_count_only_report_has_just_the_counts() {
+        let report = QueryReport {
+            fields: vec![RowField {
+                name: "n".to_string(),
+                type_oid: 23,
+                format: 1,
+                ..RowField::default()
+            }],
+            rows: Vec::new(),
+            row_count: 1000,
+            count_only: true,
+            command_tag: Some("SELECT 1000".to_string()),
+            ..QueryReport::default()
+        };
+        let mut out = Vec::new();
+        report.print(&DisplayLimits::default(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "columns: 1\nrows: 1000\ncommand tag: SELECT 1000\n"
+        );
+    }
+
+    #[test]
+    fn test_startup_parameters_can_be_left_out() {
+        let parse = |extra: &[&str]| {
+            let mut argv = vec!["pg-client-inspect", "--user=u", "--database=d", "--query=q"];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+        let names = |args: &Args| {
+            startup_parameters(args)
+                .into_iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&parse(&[])),
+            ["user", "database", "client_encoding", "application_name"]
+        );
+        assert_eq!(
+            names(&parse(&["--no-client-encoding"])),
+            ["user", "database", "application_name"]
+        );
+        assert_eq!(
+            names(&parse(&["--no-application-name"])),
+            ["user", "database", "client_encoding"]
+        );
+        assert_eq!(names(&parse(&["--minimal-startup"])), ["user", "database"]);
+    }
+
+    #[test]
+    fn test_fetch_size_fits_an_execute_row_limit() {
+        let parse = |size: &str| {
+            Args::try_parse_from([
+                "pg-client-inspect",
+                "--user=u",
+                "--database=d",
+                "--query=q",
+                "--fetch-size",
+                size,
+            ])
+        };
+        assert_eq!(parse("2147483647").unwrap().fetch_size, i32::MAX as u32);
+        assert!(parse("2147483648").is_err());
+    }
+
+    #[test]
+    fn test_repeated_queries_get_numbered_names() {
+        let args = Args::parse_from([
+            "pg-client-inspect",
+            "--user=u",
+            "--database=d",
+            "--query=insert into t values (1)",
+            "--query=select * from t",
+        ]);
+        assert_eq!(args.query.len(), 2);
+        assert_eq!(numbered_name(&args.statement_name, 0), "stmt1");
+        assert_eq!(numbered_name(&args.statement_name, 1), "stmt2");
+        assert_eq!(numbered_name("s9", 1), "s10");
+        assert_eq!(numbered_name("portal", 0), "portal1");
+        assert_eq!(numbered_name("", 3), "");
+    }
+
+    #[test]
+    fn test_dry_run_summary() {
+        let fields = [
+            RowField {
+                name: "col1".to_string(),
+                type_oid: 23,
+                format: 1,
+                ..RowField::default()
+            },
+            RowField {
+                name: "col2".to_string(),
+                type_oid: 25,
+                format: 1,
+                ..RowField::default()
+            },
+        ];
+        assert_eq!(
+            dry_run_summary(&[], &fields, Some(42)),
+            "Query is valid. Returns 2 columns: (col1 int4, col2 text). Estimated rows: 42."
+        );
+        assert_eq!(
+            dry_run_summary(&[20, 99999], &[], None),
+            "Query is valid. Takes 2 parameter(s): ($1 int8, $2 oid 99999). Returns no rows."
+        );
+        assert_eq!(
+            plan_row_estimate("Seq Scan on t  (cost=0.00..35.50 rows=2550 width=4)"),
+            Some(2550)
+        );
+        assert_eq!(plan_row_estimate("Result"), None);
+    }
+
+    #[test]
+    fn test_format_mismatch_names_columns() {
+        let field = |name: &str, format| RowField {
+            name: name.to_string(),
+            type_oid: 23,
+            format,
+            ..RowField::default()
+        };
+        let fields = [field("id", 1), field("name", 0), field("note", 0)];
+        assert_eq!(
+            format_mismatch(&fields, true).as_deref(),
+            Some(
+                "warning: requested binary results but 2 of 3 column(s) came back in \
+                 another format: 'name' (text), 'note' (text)"
+            )
+        );
+        assert_eq!(
+            format_mismatch(&fields, false).as_deref(),
+            Some(
+                "warning: requested text results but 1 of 3 column(s) came back in \
+                 another format: 'id' (binary)"
+            )
+        );
+        assert_eq!(format_mismatch(&fields[1..], false), None);
+    }
+
+    #[test]
+    fn test_explain_query_wraps_once() {
+        let args = Args::parse_from([
+            "pg-client-inspect",
+            "--user=u",
+            "--database=d",
+            "--query=select 1",
+            "--explain-analyze",
+        ]);
+        assert_eq!(
+            explain_query("select 1", &args),
+            "EXPLAIN (ANALYZE, FORMAT TEXT) select 1"
+        );
+        assert_eq!(
+            explain_query("  explain select 1", &args),
+            "  explain select 1"
+        );
+        assert_eq!(
+            explain_query("explained_view", &args),
+            "EXPLAIN (ANALYZE, FORMAT TEXT) explained_view"
+        );
+    }
+
+    #[test]
+    fn test_find_pg_socket_in_searches_directories_in_order() {
+        let root = std::env::temp_dir().join(format!("pg-inspect-sock-{}", std::process::id()));
+        let first = root.join("first");
+        let second = root.join("second");
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        std::fs::write(second.join(".s.PGSQL.6543"), b"").unwrap();
+
+        let dirs = [first.as_path(), second.as_path()];
+        assert_eq!(
+            find_pg_socket_in(dirs, 6543),
+            Some(second.join(".s.PGSQL.6543"))
+        );
+        assert_eq!(find_pg_socket_in(dirs, 6544), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_declare_cursor_quotes_name_and_strips_semicolon() {
+        let cursor = quote_identifier("my\"cur");
+        assert_eq!(cursor, "\"my\"\"cur\"");
+        assert_eq!(
+            declare_cursor(&cursor, "  select * from big_table;  "),
+            "DECLARE \"my\"\"cur\" NO SCROLL CURSOR FOR select * from big_table"
+        );
+    }
+
+    #[test]
+    fn test_describe_frontend_messages_labels_each_message() {
+        let mut buf = BytesMut::new();
+        frontend::parse("stmt1", "select 1", std::iter::empty(), &mut buf).unwrap();
+        frontend::describe(b'S', "", &mut buf).unwrap();
+        frontend::execute("portal1", 0, &mut buf).unwrap();
+        frontend::sync(&mut buf);
+
+        let messages = describe_frontend_messages(&buf);
+        let labels: Vec<&str> = messages.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(
+            labels,
+            [
+                "Parse(stmt1)",
+                "Describe(statement unnamed)",
+                "Execute(portal1)",
+                "Sync"
+            ]
+        );
+        assert_eq!(
+            messages.iter().map(|(_, size)| size).sum::<usize>(),
+            buf.len()
+        );
+        assert_eq!(messages[3].1, 5);
+    }
+
+    #[test]
+    fn test_describe_frontend_messages_recognizes_startup() {
+        let mut buf = BytesMut::new();
+        frontend::startup_message([("user", "postgres")], &mut buf).unwrap();
+        assert_eq!(
+            describe_frontend_messages(&buf),
+            vec![("StartupMessage".to_string(), buf.len())]
+        );
+    }
+
+    #[test]
+    fn test_error_summary_leads_with_severity_and_sqlstate() {
+        let fields = [
+            ('S', "FATAL".to_string()),
+            ('V', "FATAL".to_string()),
+            ('C', "28P01".to_string()),
+            (
+                'M',
+                "password authentication failed for user \"app\"".to_string(),
+            ),
+        ];
+        assert_eq!(
+            summarize_error_fields(&fields),
+            "FATAL [28P01]: password authentication failed for user \"app\""
+        );
+        // The localized severity is only used when V is missing
+        assert_eq!(
+            summarize_error_fields(&[('S', "PANIK".to_string()), ('V', "PANIC".to_string())]),
+            "PANIC: (no message)"
+        );
+        assert_eq!(
+            summarize_error_fields(&[('S', "ERREUR".to_string()), ('M', "x".to_string())]),
+            "ERREUR: x"
+        );
+    }
+
+    #[test]
+    fn test_error_fields_name_the_sqlstate() {
+        let fields = [
+            ('S', "ERROR".to_string()),
+            ('C', "42P01".to_string()),
+            ('M', "relation \"missing\" does not exist".to_string()),
+        ];
+        assert_eq!(
+            describe_error_fields(&fields),
+            "S=ERROR C=42P01 (undefined_table) M=relation \"missing\" does not exist"
+        );
+        // Codes outside every known class are shown as they are
+        assert_eq!(
+            describe_error_fields(&[('C', "ZZ001".to_string())]),
+            "C=ZZ001"
+        );
+    }
+
+    #[test]
+    fn test_decode_error_fields_from_notice() {
+        let body = b"SNOTICE\0C00000\0Mhello from plpgsql\0\0";
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(b"N");
+        frame.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        frame.extend_from_slice(body);
+
+        let Some(Message::NoticeResponse(notice)) = Message::parse(&mut frame).unwrap() else {
+            panic!("expected NoticeResponse");
+        };
+        let notice = Notice {
+            fields: decode_error_fields(notice.fields()).unwrap(),
+        };
+        assert_eq!(notice.field('S'), Some("NOTICE"));
+        assert_eq!(notice.field('M'), Some("hello from plpgsql"));
+        assert_eq!(notice.field('D'), None);
+        assert_eq!(notice.to_string(), "S=NOTICE C=00000 M=hello from plpgsql");
+    }
+
+    #[test]
+    fn test_protocol_version_is_encoded_as_major_and_minor() {
+        assert_eq!(parse_protocol_version("3.0"), Ok(196608));
+        assert_eq!(parse_protocol_version("3.2"), Ok(196610));
+        assert_eq!(format_protocol_version(196610), "3.2");
+        for invalid in ["3", "3.x", "3.65536", "40000.0"] {
+            assert!(parse_protocol_version(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_negotiate_protocol_version_is_decoded() {
+        // PostgreSQL 15 answering a request for 3.2
+        assert_eq!(
+            parse_negotiate_protocol_version(b"\0\x03\0\0\0\0\0\0").unwrap(),
+            (196608, Vec::new())
+        );
+        let body = b"\0\0\0\x01\0\0\0\x01_pq_.compression\0";
+        assert_eq!(
+            parse_negotiate_protocol_version(body).unwrap(),
+            (196609, vec!["_pq_.compression".to_string()])
+        );
+        assert!(parse_negotiate_protocol_version(b"\0\0\0\0\0\0\0\x01_pq_").is_err());
+    }
+
+    #[test]
+    fn test_decode_startup_message() {
+        let mut buf = BytesMut::new();
+        frontend::startup_message([("user", "app"), ("database", "db")], &mut buf).unwrap();
+        let (version, parameters) = decode_startup_message(&buf).unwrap();
+        assert_eq!(version, 196608);
+        assert_eq!(
+            parameters,
+            vec![
+                ("user".to_string(), "app".to_string()),
+                ("database".to_string(), "db".to_string())
+            ]
+        );
+
+        let mut short = buf.to_vec();
+        short.pop();
+        assert!(
+            decode_startup_message(&short)
+                .unwrap_err()
+                .contains("length prefix")
+        );
+        let mut unterminated = buf[..buf.len() - 1].to_vec();
+        unterminated[3] -= 1;
+        unterminated.pop();
+        unterminated.push(b'x');
+        assert!(decode_startup_message(&unterminated).is_err());
+        assert!(decode_startup_message(&[0, 0, 0, 8]).is_err());
+    }
+
+    #[test]
+    fn test_hex_dump_lines() {
+        let lines = hex_dump_lines(b"\0\0\0\x08user\0app\0\0\x01");
+        assert_eq!(
+            lines,
+            vec![
+                "0000: 00 00 00 08 75 73 65 72 00 61 70 70 00 00 01      ....user.app..."
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            hex_dump_lines(&[b'a'; 17])[1],
+            format!("0010: {:<48}  a", "61")
+        );
+    }
+
     #[test]
     fn test_hex_string() {
         let input = [0xde, 0xad, 0xbe, 0xef];
-        assert_eq!(hex_string(&input), "0xdeadbeef");
+        assert_eq!(hex_string(&input, 0), "0xdeadbeef");
+    }
+
+    #[test]
+    fn test_hex_string_grouped() {
+        let input = [0xde, 0xad, 0xbe, 0xef, 0x01];
+        assert_eq!(hex_string(&input, 2), "0xdead beef 01");
     }
 
     #[test]
     fn test_format_value_with_ascii() {
-        assert_eq!(format_value(b"hello"), "text:'hello'");
+        assert_eq!(
+            format_value(b"hello", &DisplayLimits::default()),
+            "text:'hello'"
+        );
     }
 
     #[test]
     fn test_format_value_with_binary() {
         let bytes = decode("000102ff").unwrap();
-        assert_eq!(format_value(&bytes), "hex:0x000102ff");
+        assert_eq!(
+            format_value(&bytes, &DisplayLimits::default()),
+            "hex:0x000102ff"
+        );
+    }
+
+    #[test]
+    fn test_format_value_truncates_to_limits() {
+        let limits = DisplayLimits {
+            max_bytes: Some(2),
+            max_text: Some(3),
+            hex_group_size: 0,
+            binary_format: BinaryDisplayFormat::Hex,
+        };
+        assert_eq!(format_value(b"hello", &limits), "text:'hel...' (5 bytes)");
+        let bytes = decode("000102ff").unwrap();
+        assert_eq!(format_value(&bytes, &limits), "hex:0x0001... (4 bytes)");
+    }
+
+    #[test]
+    fn test_format_value_binary_display_formats() {
+        let bytes = [42, 0, 255];
+        let limits = |binary_format| DisplayLimits {
+            binary_format,
+            ..DisplayLimits::default()
+        };
+        assert_eq!(
+            format_value(&bytes, &limits(BinaryDisplayFormat::Base64)),
+            "base64:KgD/"
+        );
+        assert_eq!(
+            format_value(&bytes, &limits(BinaryDisplayFormat::Decimal)),
+            "decimal:42 0 255"
+        );
+        assert_eq!(
+            format_value(&bytes, &limits(BinaryDisplayFormat::Octal)),
+            "octal:\\052\\000\\377"
+        );
+        let truncated = DisplayLimits {
+            max_bytes: Some(2),
+            ..limits(BinaryDisplayFormat::Decimal)
+        };
+        assert_eq!(
+            format_value(&bytes, &truncated),
+            "decimal:42 0... (3 bytes)"
+        );
+    }
+
+    #[test]
+    fn test_format_value_full_values() {
+        let limits = DisplayLimits {
+            max_bytes: None,
+            max_text: None,
+            hex_group_size: 0,
+            binary_format: BinaryDisplayFormat::Hex,
+        };
+        let text = "x".repeat(500);
+        assert_eq!(
+            format_value(text.as_bytes(), &limits),
+            format!("text:'{text}'")
+        );
+    }
+
+    fn typed_field(type_oid: u32, format: i16) -> RowField {
+        RowField {
+            name: "col".to_string(),
+            type_oid,
+            format,
+            ..RowField::default()
+        }
+    }
+
+    fn typed_value(field: &RowField, bytes: &[u8]) -> String {
+        wrap_column_value(
+            Some(field),
+            &ColumnValue::Bytes(bytes.to_vec()),
+            &DisplayLimits::default(),
+        )
+    }
+
+    #[test]
+    fn test_oid_values_are_unsigned_integers() {
+        assert_eq!(
+            typed_value(&typed_field(OID_OID, 0), b"4294967295"),
+            "oid:4294967295"
+        );
+        assert_eq!(
+            typed_value(&typed_field(OID_OID, 1), &[0, 0, 4, 235]),
+            "oid:1259"
+        );
+        // Values that do not decode fall back to the generic rendering
+        assert_eq!(typed_value(&typed_field(OID_OID, 0), b"-1"), "text:'-1'");
+    }
+
+    #[test]
+    fn test_name_values_are_trimmed_identifiers() {
+        assert_eq!(
+            typed_value(&typed_field(NAME_OID, 0), b"pg_class"),
+            "name:'pg_class'"
+        );
+        assert_eq!(
+            typed_value(&typed_field(NAME_OID, 1), b"pg_class\0\0\0"),
+            "name:'pg_class'"
+        );
+    }
+
+    #[test]
+    fn test_binary_numbers_and_booleans_are_decoded() {
+        assert_eq!(
+            typed_value(&typed_field(INT4_OID, 1), &42i32.to_be_bytes()),
+            "int4:42"
+        );
+        assert_eq!(
+            typed_value(&typed_field(INT2_OID, 1), &(-7i16).to_be_bytes()),
+            "int2:-7"
+        );
+        assert_eq!(
+            typed_value(&typed_field(INT8_OID, 1), &i64::MAX.to_be_bytes()),
+            "int8:9223372036854775807"
+        );
+        assert_eq!(
+            typed_value(&typed_field(FLOAT8_OID, 1), &1.5f64.to_be_bytes()),
+            "float8:1.5"
+        );
+        assert_eq!(
+            typed_value(&typed_field(FLOAT4_OID, 1), &(-0.25f32).to_be_bytes()),
+            "float4:-0.25"
+        );
+        assert_eq!(typed_value(&typed_field(BOOL_OID, 1), &[1]), "bool:true");
+        // Text values and values of the wrong size keep the generic rendering
+        assert_eq!(typed_value(&typed_field(INT4_OID, 0), b"42"), "text:'42'");
+        assert_eq!(
+            typed_value(&typed_field(INT4_OID, 1), &[0, 42]),
+            format_value(&[0, 42], &DisplayLimits::default())
+        );
+    }
+
+    #[test]
+    fn test_char_values_show_character_or_code() {
+        let text = typed_field(CHAR_OID, 0);
+        assert_eq!(typed_value(&text, b"r"), "char:'r'");
+        assert_eq!(typed_value(&text, b""), "char:code 0");
+        assert_eq!(typed_value(&text, b"\\201"), "char:code 129");
+        assert_eq!(typed_value(&text, &[1]), "char:code 1");
+        let binary = typed_field(CHAR_OID, 1);
+        assert_eq!(typed_value(&binary, &[0]), "char:code 0");
+        assert_eq!(typed_value(&binary, b" "), "char:' '");
+        // Other types keep the ASCII-or-binary rendering
+        assert_eq!(typed_value(&typed_field(25, 0), &[1]), "text:'\u{1}'");
+    }
+
+    #[test]
+    fn test_exit_code_follows_the_tag() {
+        let refused = anyhow!("password authentication failed").context(ExitCode::AuthFailed);
+        assert_eq!(ExitCode::of(&refused), ExitCode::AuthFailed);
+        // Context added on top of the tag does not hide it
+        assert_eq!(
+            ExitCode::of(&refused.context("startup failed")),
+            ExitCode::AuthFailed
+        );
+        let would_block = std::io::Error::from(std::io::ErrorKind::WouldBlock);
+        assert_eq!(ExitCode::of_socket_error(&would_block), ExitCode::Timeout);
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            ExitCode::of_socket_error(&reset),
+            ExitCode::ConnectionFailed
+        );
+        assert_eq!(
+            ExitCode::of(&anyhow!("failed to read pipeline.sql")),
+            ExitCode::ConnectionFailed
+        );
+
+        let usage =
+            |argv: &[&str]| ExitCode::of_usage_error(&Args::try_parse_from(argv).unwrap_err());
+        assert_eq!(
+            usage(&["pg-client-inspect", "--no-such-flag"]),
+            ExitCode::UsageError
+        );
+        assert_eq!(usage(&["pg-client-inspect", "--help"]), ExitCode::Success);
+    }
+
+    #[test]
+    fn test_display_name_marks_unnamed() {
+        assert_eq!(display_name(""), "(unnamed)");
+        assert_eq!(display_name("s0"), "'s0'");
     }
 
     #[test]
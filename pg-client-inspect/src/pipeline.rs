@@ -0,0 +1,261 @@
+use crate::{
+    Args, ColumnValue, Connection, DisplayLimits, ExitCode, RowField, encode_bind,
+    format_backend_error, format_mismatch, message_tag, parse_data_row, parse_fields, write_row,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::BytesMut;
+use postgres_protocol::message::backend::Message;
+use postgres_protocol::message::frontend;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Outcome of one statement of the pipeline
+#[derive(Default)]
+struct StatementResult {
+    command_tag: Option<String>,
+    fields: Vec<RowField>,
+    rows: Vec<Vec<ColumnValue>>,
+    error: Option<String>,
+    /// Responses that arrived earlier than the protocol allows, or that
+    /// belong to a different statement
+    out_of_order: Vec<String>,
+}
+
+/// Where a statement's responses are in Parse → Bind → Describe → rows →
+/// completion order
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Stage {
+    Start,
+    Parsed,
+    Bound,
+    Described,
+    Rows,
+    Complete,
+}
+
+/// Send every statement of `path` as Parse/Bind/Describe/Execute/Sync in a
+/// single write, then read all responses and check their order
+pub(crate) fn run_pipeline(
+    connection: &mut Connection,
+    path: &Path,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let sql = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let statements = split_statements(&sql);
+    if statements.is_empty() {
+        bail!("{} contains no statements", path.display());
+    }
+
+    let mut buf = BytesMut::new();
+    for statement in &statements {
+        encode_statement(statement, args.binary_result, &mut buf)?;
+    }
+    writeln!(
+        out,
+        "pipeline: sending {} statement(s) in one write ({} bytes)",
+        statements.len(),
+        buf.len()
+    )?;
+    let started = Instant::now();
+    connection.send(&buf, "pipelined messages")?;
+    let results = read_results(connection, &statements)?;
+    let pipelined = started.elapsed();
+
+    let mut failed = 0;
+    let mut out_of_order = 0;
+    for (idx, (statement, result)) in statements.iter().zip(&results).enumerate() {
+        let outcome = match (&result.error, &result.command_tag) {
+            (Some(error), _) => format!("error: {error}"),
+            (None, Some(tag)) => format!("{tag} ({} row(s))", result.rows.len()),
+            (None, None) => "empty query".to_string(),
+        };
+        writeln!(out, "statement {}: {} -> {}", idx + 1, statement, outcome)?;
+        if let Some(warning) = format_mismatch(&result.fields, args.binary_result) {
+            writeln!(out, "  {warning}")?;
+        }
+        for row in &result.rows {
+            writeln!(out, "  data row:")?;
+            write_row(&result.fields, row, limits, out)?;
+        }
+        for problem in &result.out_of_order {
+            writeln!(out, "  out of order: {problem}")?;
+        }
+        failed += usize::from(result.error.is_some());
+        out_of_order += result.out_of_order.len();
+    }
+    if out_of_order == 0 {
+        writeln!(out, "all responses arrived in order")?;
+    }
+    writeln!(out, "pipeline took {:?}", pipelined)?;
+
+    if args.pipeline_compare {
+        let sequential = run_sequential(connection, &statements, args)?;
+        let verdict = if sequential >= pipelined {
+            format!("pipelining saved {:?}", sequential - pipelined)
+        } else {
+            format!("pipelining was slower by {:?}", pipelined - sequential)
+        };
+        writeln!(
+            out,
+            "sequential took {:?} ({} round trips), {}",
+            sequential,
+            statements.len(),
+            verdict
+        )?;
+    }
+
+    if out_of_order > 0 {
+        return Err(anyhow!("{} response(s) arrived out of order", out_of_order)
+            .context(ExitCode::AssertionFailed));
+    }
+    if failed > 0 {
+        return Err(
+            anyhow!("{} of {} statement(s) failed", failed, statements.len())
+                .context(ExitCode::QueryFailed),
+        );
+    }
+    Ok(())
+}
+
+/// Run the statements again, waiting for each ReadyForQuery before sending
+/// the next one
+fn run_sequential(
+    connection: &mut Connection,
+    statements: &[String],
+    args: &Args,
+) -> Result<Duration> {
+    let started = Instant::now();
+    for statement in statements {
+        let mut buf = BytesMut::new();
+        encode_statement(statement, args.binary_result, &mut buf)?;
+        connection.send(&buf, "extended query messages")?;
+        read_results(connection, std::slice::from_ref(statement))?;
+    }
+    Ok(started.elapsed())
+}
+
+fn encode_statement(statement: &str, binary_result: bool, buf: &mut BytesMut) -> Result<()> {
+    frontend::parse(
+        "",
+        statement,
+        std::iter::empty::<postgres_protocol::Oid>(),
+        buf,
+    )
+    .context("failed to encode Parse message")?;
+    encode_bind("", "", binary_result, buf)?;
+    frontend::describe(b'P', "", buf).context("failed to encode Describe")?;
+    frontend::execute("", 0, buf).context("failed to encode Execute")?;
+    frontend::sync(buf);
+    Ok(())
+}
+
+/// Read responses until one ReadyForQuery per statement has arrived; rows
+/// are kept and printed later so printing does not count towards timings
+fn read_results(
+    connection: &mut Connection,
+    statements: &[String],
+) -> Result<Vec<StatementResult>> {
+    let mut results = Vec::with_capacity(statements.len());
+    for statement in statements {
+        let mut result = StatementResult::default();
+        let mut stage = Stage::Start;
+        loop {
+            let message = connection.read_message()?;
+            let next = match &message {
+                Message::ParseComplete => Some(Stage::Parsed),
+                Message::BindComplete => Some(Stage::Bound),
+                Message::RowDescription(_) | Message::NoData => Some(Stage::Described),
+                Message::DataRow(_) => Some(Stage::Rows),
+                Message::CommandComplete(_) | Message::EmptyQueryResponse => Some(Stage::Complete),
+                _ => None,
+            };
+            if let Some(next) = next {
+                let repeatable = next == Stage::Rows && stage == Stage::Rows;
+                if result.error.is_some() || (next <= stage && !repeatable) {
+                    result.out_of_order.push(format!(
+                        "{} after {:?}",
+                        message_tag(&message),
+                        stage
+                    ));
+                }
+                stage = stage.max(next);
+            }
+
+            match message {
+                Message::RowDescription(desc) => result.fields = parse_fields(&desc)?,
+                Message::DataRow(data_row) => {
+                    result.rows.push(parse_data_row(&result.fields, &data_row)?);
+                }
+                Message::CommandComplete(body) => {
+                    let tag = body.tag().unwrap_or("<invalid utf8>").to_string();
+                    if !tag_matches(statement, &tag) {
+                        result
+                            .out_of_order
+                            .push(format!("command tag '{tag}' does not match the statement"));
+                    }
+                    result.command_tag = Some(tag);
+                }
+                Message::ErrorResponse(err) => result.error = Some(format_backend_error(err)?),
+                Message::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Whether a command tag could have come from `statement`; only checked
+/// for statements whose first keyword is also their tag
+fn tag_matches(statement: &str, tag: &str) -> bool {
+    let keyword = statement
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_ascii_uppercase();
+    match keyword.as_str() {
+        "SELECT" | "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "FETCH" | "MOVE" | "COPY" => {
+            tag.split(' ').next() == Some(keyword.as_str())
+        }
+        _ => true,
+    }
+}
+
+/// Split a script on semicolons; semicolons inside quotes or dollar-quoted
+/// bodies are not understood, so keep one plain statement per semicolon
+fn split_statements(sql: &str) -> Vec<String> {
+    sql.split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_are_split_on_semicolons() {
+        assert_eq!(
+            split_statements("select 1;\n\n  insert into t values (1) ;;select 2"),
+            vec!["select 1", "insert into t values (1)", "select 2"]
+        );
+    }
+
+    #[test]
+    fn tags_are_checked_against_the_statement_keyword() {
+        assert!(tag_matches("select 1", "SELECT 1"));
+        assert!(tag_matches("Insert into t values (1)", "INSERT 0 1"));
+        assert!(!tag_matches("select 1", "INSERT 0 1"));
+        // WITH can end in any command, so it is not checked
+        assert!(tag_matches(
+            "with x as (select 1) delete from t",
+            "DELETE 0"
+        ));
+    }
+}
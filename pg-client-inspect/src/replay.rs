@@ -0,0 +1,574 @@
+use crate::{
+    Args, Connection, DisplayLimits, ExitCode, Notice, Notification, QueryReport,
+    decode_error_fields, describe_frontend_messages, format_backend_error, leading_name,
+    message_tag, parse_data_row, parse_fields,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use postgres_protocol::message::backend::Message;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+
+/// Request codes of the 8-byte messages a client may send before its
+/// startup message; the server answers each with a single byte
+const SSL_REQUEST_CODE: u32 = 80_877_103;
+const GSSENC_REQUEST_CODE: u32 = 80_877_104;
+
+/// One protocol message of a captured session
+pub(crate) struct CapturedMessage {
+    pub(crate) from_client: bool,
+    /// Message type, or None for the untyped startup-phase messages
+    pub(crate) tag: Option<u8>,
+    /// The whole message, header included
+    pub(crate) bytes: Vec<u8>,
+}
+
+/// The messages of one TCP connection, in capture order
+pub(crate) struct CapturedSession {
+    pub(crate) client: SocketAddr,
+    pub(crate) messages: Vec<CapturedMessage>,
+}
+
+/// Replay the client side of the first session in a capture written by
+/// the proxy's --export-pgwire-capture and print the server's responses
+pub(crate) fn run_replay(
+    connection: &mut Connection,
+    path: &Path,
+    args: &Args,
+    limits: &DisplayLimits,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let sessions =
+        parse_pcapng(&bytes).with_context(|| format!("failed to parse {}", path.display()))?;
+    let Some(session) = sessions.first() else {
+        bail!("{} contains no PostgreSQL sessions", path.display());
+    };
+    eprintln!(
+        "replay: {} holds {} session(s), replaying the one from {} ({} client messages)",
+        path.display(),
+        sessions.len(),
+        session.client,
+        session.messages.iter().filter(|m| m.from_client).count()
+    );
+
+    if args.skip_auth_replay {
+        connection.startup(args)?;
+    } else {
+        replay_startup(connection, &session.messages)?;
+    }
+    let reports = replay_session(connection, &session.messages)?;
+    for (idx, report) in reports.iter().enumerate() {
+        writeln!(out, "== response {} ==", idx + 1)?;
+        report.print(limits, out)?;
+    }
+    let failed = reports
+        .iter()
+        .filter(|report| report.error.is_some())
+        .count();
+    if failed > 0 {
+        return Err(
+            anyhow!("{} of {} replayed request(s) failed", failed, reports.len())
+                .context(ExitCode::QueryFailed),
+        );
+    }
+    Ok(())
+}
+
+/// Send the captured startup message and answer each authentication
+/// request with the next captured password message. Only trust and
+/// cleartext passwords survive a replay: MD5 and SCRAM depend on a salt or
+/// nonce that differs per connection.
+fn replay_startup(connection: &mut Connection, capture: &[CapturedMessage]) -> Result<()> {
+    let mut client = capture.iter().filter(|message| message.from_client);
+    let startup = client
+        .clone()
+        .find(|message| message.tag.is_none() && !is_encryption_request(&message.bytes))
+        .context("the captured session has no startup message")?;
+    let mut passwords = client.by_ref().filter(|message| message.tag == Some(b'p'));
+
+    connection.send(&startup.bytes, "captured startup message")?;
+    loop {
+        match connection.read_message()? {
+            Message::AuthenticationOk => eprintln!("authentication ok"),
+            Message::AuthenticationCleartextPassword
+            | Message::AuthenticationMd5Password(_)
+            | Message::AuthenticationSasl(_)
+            | Message::AuthenticationSaslContinue(_) => {
+                let password = passwords
+                    .next()
+                    .context(
+                        "server asked for a password the capture does not hold; use --skip-auth-replay",
+                    )
+                    .context(ExitCode::AuthFailed)?;
+                connection.send(&password.bytes, "captured password message")?;
+            }
+            Message::AuthenticationSaslFinal(_) => {}
+            Message::ParameterStatus(status) => {
+                let name = status.name().unwrap_or("<invalid utf8>");
+                let value = status.value().unwrap_or("<invalid utf8>");
+                eprintln!("parameter: {} = {}", name, value);
+                connection
+                    .server_parameters
+                    .push((name.to_string(), value.to_string()));
+            }
+            Message::BackendKeyData(data) => {
+                eprintln!(
+                    "backend key data: pid={} secret={}",
+                    data.process_id(),
+                    data.secret_key()
+                );
+            }
+            Message::ReadyForQuery(state) => {
+                eprintln!("ready for query (transaction state {})", state.status());
+                return Ok(());
+            }
+            Message::ErrorResponse(err) => {
+                return Err(anyhow!(
+                    "{}\n  use --skip-auth-replay to authenticate with --user/--password instead",
+                    format_backend_error(err)?
+                )
+                .context(ExitCode::AuthFailed));
+            }
+            other => eprintln!("unexpected message: {:?}", message_tag(&other)),
+        }
+    }
+}
+
+/// Send the captured client messages that follow authentication, reading
+/// the server's responses after every Query, Sync, FunctionCall and end
+/// of COPY IN; returns one report per ReadyForQuery
+pub(crate) fn replay_session(
+    conn: &mut Connection,
+    capture: &[CapturedMessage],
+) -> Result<Vec<QueryReport>> {
+    let mut reports = Vec::new();
+    let mut report = QueryReport::default();
+    let mut copy_in = false;
+    for message in capture.iter().filter(|message| message.from_client) {
+        // Startup-phase and password messages belong to the handshake
+        let Some(tag) = message.tag else { continue };
+        let body = message.bytes.get(5..).unwrap_or_default();
+        match tag {
+            b'p' => continue,
+            b'X' => break,
+            b'P' => report.statement_name = leading_name(body),
+            b'B' => report.portal_name = leading_name(body),
+            _ => {}
+        }
+        for (label, bytes) in describe_frontend_messages(&message.bytes) {
+            eprintln!("replaying {} ({} bytes)", label, bytes);
+        }
+        conn.send(&message.bytes, "captured message")?;
+
+        let ends_request = match tag {
+            b'Q' | b'S' | b'F' => true,
+            b'c' | b'f' => copy_in,
+            _ => false,
+        };
+        if ends_request {
+            copy_in = !read_responses(conn, &mut report)?;
+            if !copy_in {
+                reports.push(std::mem::take(&mut report));
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Read responses into `report` until ReadyForQuery (returns true) or
+/// until the server waits for COPY IN data (returns false)
+fn read_responses(conn: &mut Connection, report: &mut QueryReport) -> Result<bool> {
+    loop {
+        match conn.read_message()? {
+            Message::ParseComplete => {
+                eprintln!("parse response: ParseComplete");
+                report.parse_complete = true;
+            }
+            Message::BindComplete => {
+                eprintln!("bind response: BindComplete");
+                report.bind_complete = true;
+            }
+            Message::RowDescription(desc) => {
+                report.fields = parse_fields(&desc)?;
+                eprintln!("row description arrived ({} columns)", report.fields.len());
+            }
+            Message::DataRow(data_row) => {
+                report.rows.push(parse_data_row(&report.fields, &data_row)?);
+                report.row_count += 1;
+            }
+            Message::CommandComplete(body) => {
+                let tag = body.tag().unwrap_or("<invalid utf8>").to_string();
+                eprintln!("command tag: {}", tag);
+                report.command_tag = Some(tag);
+            }
+            Message::EmptyQueryResponse => eprintln!("empty query response"),
+            Message::ErrorResponse(err) => {
+                let error = format_backend_error(err)?;
+                eprintln!("error: {}", error);
+                report.error = Some(error);
+            }
+            Message::NoticeResponse(notice) => {
+                let notice = Notice {
+                    fields: decode_error_fields(notice.fields())?,
+                };
+                eprintln!("notice: {}", notice);
+                report.notices.push(notice);
+            }
+            Message::NotificationResponse(notification) => {
+                report.notifications.push(Notification {
+                    process_id: notification.process_id(),
+                    channel: notification
+                        .channel()
+                        .unwrap_or("<invalid utf8>")
+                        .to_string(),
+                    payload: notification
+                        .message()
+                        .unwrap_or("<invalid utf8>")
+                        .to_string(),
+                });
+            }
+            Message::CopyInResponse(_) => {
+                eprintln!("copy in response: replaying the captured CopyData");
+                return Ok(false);
+            }
+            Message::ReadyForQuery(state) => {
+                eprintln!("ready for query (transaction state {})", state.status());
+                return Ok(true);
+            }
+            other => eprintln!("response: {}", message_tag(&other)),
+        }
+    }
+}
+
+fn is_encryption_request(message: &[u8]) -> bool {
+    message.len() == 8
+        && matches!(
+            u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+            SSL_REQUEST_CODE | GSSENC_REQUEST_CODE
+        )
+}
+
+/// A TCP segment pulled out of a captured packet
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// SYN without ACK: the client opening a connection
+    opens: bool,
+    payload: &'a [u8],
+}
+
+/// Split a little-endian PCAP-NG file into PostgreSQL sessions. Each TCP
+/// connection whose opening SYN was captured becomes a session; segments
+/// are taken in capture order, so the file must not hold retransmissions
+/// (captures written by the proxy never do).
+pub(crate) fn parse_pcapng(bytes: &[u8]) -> Result<Vec<CapturedSession>> {
+    let read_u32 = |i: usize| {
+        bytes
+            .get(i..i + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    if read_u32(0) != Some(PCAPNG_SECTION_HEADER) {
+        bail!("not a PCAP-NG file");
+    }
+
+    let mut link_types = Vec::new();
+    let mut sessions: Vec<SessionBuilder> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (Some(block_type), Some(len)) = (read_u32(i), read_u32(i + 4)) else {
+            bail!("truncated block at offset {}", i);
+        };
+        let len = len as usize;
+        if len < 12 || i + len > bytes.len() {
+            bail!("invalid block length {} at offset {}", len, i);
+        }
+        let body = &bytes[i + 8..i + len - 4];
+        match block_type {
+            PCAPNG_SECTION_HEADER => {
+                if read_u32(i + 8) != Some(PCAPNG_BYTE_ORDER_MAGIC) {
+                    bail!("big-endian PCAP-NG files are not supported");
+                }
+                link_types.clear();
+            }
+            PCAPNG_INTERFACE_DESCRIPTION if body.len() >= 2 => {
+                link_types.push(u16::from_le_bytes([body[0], body[1]]));
+            }
+            PCAPNG_ENHANCED_PACKET if body.len() >= 20 => {
+                let interface = u32::from_le_bytes(body[0..4].try_into()?) as usize;
+                let captured = u32::from_le_bytes(body[12..16].try_into()?) as usize;
+                let packet = body
+                    .get(20..20 + captured)
+                    .with_context(|| format!("truncated packet at offset {}", i))?;
+                let segment = link_types
+                    .get(interface)
+                    .and_then(|&link_type| decode_segment(link_type, packet));
+                if let Some(segment) = segment {
+                    add_segment(&mut sessions, segment)?;
+                }
+            }
+            _ => {}
+        }
+        i += len;
+    }
+    Ok(sessions
+        .into_iter()
+        .map(|session| CapturedSession {
+            client: session.client,
+            messages: session.messages,
+        })
+        .collect())
+}
+
+fn decode_segment(link_type: u16, packet: &[u8]) -> Option<Segment<'_>> {
+    let ip = match link_type {
+        LINKTYPE_RAW => packet,
+        LINKTYPE_ETHERNET => packet.get(14..)?,
+        _ => return None,
+    };
+    let (src, dst, tcp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?));
+            if *ip.get(9)? != 6 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                ip.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            let payload_len = usize::from(u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?));
+            if *ip.get(6)? != 6 {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                ip.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+    let data_offset = usize::from(tcp.get(12)? >> 4) * 4;
+    let flags = *tcp.get(13)?;
+    Some(Segment {
+        src: SocketAddr::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddr::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        opens: flags & 0x12 == 0x02,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+/// A session being reassembled; bytes wait in the buffers until a whole
+/// message has arrived
+struct SessionBuilder {
+    client: SocketAddr,
+    server: SocketAddr,
+    client_buffer: Vec<u8>,
+    server_buffer: Vec<u8>,
+    /// The client has sent its startup message; later messages are typed
+    started: bool,
+    /// Single-byte answers the server still owes to SSL/GSS requests
+    server_single_bytes: usize,
+    messages: Vec<CapturedMessage>,
+}
+
+fn add_segment(sessions: &mut Vec<SessionBuilder>, segment: Segment) -> Result<()> {
+    if segment.opens {
+        sessions.push(SessionBuilder {
+            client: segment.src,
+            server: segment.dst,
+            client_buffer: Vec::new(),
+            server_buffer: Vec::new(),
+            started: false,
+            server_single_bytes: 0,
+            messages: Vec::new(),
+        });
+        return Ok(());
+    }
+    let Some((session, from_client)) = sessions.iter_mut().rev().find_map(|session| {
+        if (session.client, session.server) == (segment.src, segment.dst) {
+            Some((session, true))
+        } else if (session.client, session.server) == (segment.dst, segment.src) {
+            Some((session, false))
+        } else {
+            None
+        }
+    }) else {
+        // A connection already open when the capture started
+        return Ok(());
+    };
+    if from_client {
+        session.client_buffer.extend_from_slice(segment.payload);
+        session.split_client_messages()
+    } else {
+        session.server_buffer.extend_from_slice(segment.payload);
+        session.split_server_messages()
+    }
+}
+
+impl SessionBuilder {
+    fn split_client_messages(&mut self) -> Result<()> {
+        loop {
+            let (tag, start) = if self.started {
+                (self.client_buffer.first().copied(), 1)
+            } else {
+                (None, 0)
+            };
+            let Some(len) = message_length(&self.client_buffer, start)? else {
+                return Ok(());
+            };
+            let bytes: Vec<u8> = self.client_buffer.drain(..start + len).collect();
+            if !self.started {
+                if is_encryption_request(&bytes) {
+                    self.server_single_bytes += 1;
+                } else {
+                    self.started = true;
+                }
+            }
+            self.messages.push(CapturedMessage {
+                from_client: true,
+                tag,
+                bytes,
+            });
+        }
+    }
+
+    fn split_server_messages(&mut self) -> Result<()> {
+        while self.server_single_bytes > 0 && !self.server_buffer.is_empty() {
+            let bytes = self.server_buffer.drain(..1).collect();
+            self.server_single_bytes -= 1;
+            self.messages.push(CapturedMessage {
+                from_client: false,
+                tag: None,
+                bytes,
+            });
+        }
+        while let Some(len) = message_length(&self.server_buffer, 1)? {
+            let bytes: Vec<u8> = self.server_buffer.drain(..1 + len).collect();
+            self.messages.push(CapturedMessage {
+                from_client: false,
+                tag: Some(bytes[0]),
+                bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Length (including itself) of the message whose length word starts at
+/// `start`, once the whole message is buffered
+fn message_length(buffer: &[u8], start: usize) -> Result<Option<usize>> {
+    let Some(word) = buffer.get(start..start + 4) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(word.try_into()?) as usize;
+    if len < 4 {
+        bail!("invalid message length {} in captured stream", len);
+    }
+    Ok((buffer.len() >= start + len).then_some(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().div_ceil(4) * 4;
+        let len = (12 + padded) as u32;
+        let mut block = block_type.to_le_bytes().to_vec();
+        block.extend_from_slice(&len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.resize(8 + padded, 0);
+        block.extend_from_slice(&len.to_le_bytes());
+        block
+    }
+
+    /// An IPv4 TCP packet between 10.0.0.1:50000 (client) and 10.0.0.2:5432
+    fn packet(from_client: bool, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, src_port, dst_port) = if from_client {
+            ([10, 0, 0, 1], [10, 0, 0, 2], 50000u16, 5432u16)
+        } else {
+            ([10, 0, 0, 2], [10, 0, 0, 1], 5432, 50000)
+        };
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        ip.extend_from_slice(&src);
+        ip.extend_from_slice(&dst);
+        ip.extend_from_slice(&src_port.to_be_bytes());
+        ip.extend_from_slice(&dst_port.to_be_bytes());
+        ip.extend_from_slice(&[0; 8]);
+        ip.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        ip.extend_from_slice(payload);
+
+        let mut body = vec![0; 20];
+        body[12..16].copy_from_slice(&(ip.len() as u32).to_le_bytes());
+        body[16..20].copy_from_slice(&(ip.len() as u32).to_le_bytes());
+        body.extend_from_slice(&ip);
+        block(PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    #[test]
+    fn capture_is_split_into_messages() {
+        let mut startup = vec![0, 0, 0, 20, 0, 3, 0, 0];
+        startup.extend_from_slice(b"user\0alice\0\0");
+        let query = b"Q\0\0\0\x0dselect 1\0";
+
+        let mut file = block(
+            PCAPNG_SECTION_HEADER,
+            &[
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+        );
+        file.extend(block(
+            PCAPNG_INTERFACE_DESCRIPTION,
+            &[101, 0, 0, 0, 0, 0, 0, 0],
+        ));
+        file.extend(packet(true, 0x02, &[]));
+        file.extend(packet(false, 0x12, &[]));
+        file.extend(packet(true, 0x18, &startup));
+        // The query arrives in two segments, the second with Terminate
+        file.extend(packet(true, 0x18, &query[..7]));
+        file.extend(packet(false, 0x18, b"Z\0\0\0\x05I"));
+        let mut rest = query[7..].to_vec();
+        rest.extend_from_slice(b"X\0\0\0\x04");
+        file.extend(packet(true, 0x18, &rest));
+
+        let sessions = parse_pcapng(&file).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].client, "10.0.0.1:50000".parse().unwrap());
+        let messages: Vec<(bool, Option<u8>, usize)> = sessions[0]
+            .messages
+            .iter()
+            .map(|m| (m.from_client, m.tag, m.bytes.len()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (true, None, 20),
+                (false, Some(b'Z'), 6),
+                (true, Some(b'Q'), 14),
+                (true, Some(b'X'), 5)
+            ]
+        );
+        assert_eq!(sessions[0].messages[2].bytes, query);
+
+        assert!(parse_pcapng(b"not a capture").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::fingerprint::text_rows;
+use crate::{Connection, RowField};
+use anyhow::Result;
+
+/// The catalog row of one result column: where it comes from, whether it
+/// can be NULL and which unique constraints it takes part in
+#[derive(Debug, PartialEq)]
+struct ColumnSchema {
+    table_oid: u32,
+    column_id: i16,
+    schema: String,
+    table: String,
+    column: String,
+    not_null: bool,
+    /// e.g. `PRIMARY KEY` or `part of UNIQUE`; empty when the column is in
+    /// no unique index
+    keys: String,
+}
+
+impl ColumnSchema {
+    fn describe(&self) -> String {
+        let mut hints = vec![if self.not_null {
+            "NOT NULL"
+        } else {
+            "nullable"
+        }];
+        if !self.keys.is_empty() {
+            hints.push(&self.keys);
+        }
+        format!(
+            "{}.{}.{} ({})",
+            self.schema,
+            self.table,
+            self.column,
+            hints.join(", ")
+        )
+    }
+}
+
+/// Query pg_attribute for the table columns behind `fields`, using the table
+/// OID and attribute number of the RowDescription, and store what was found
+/// as each field's source
+pub(crate) fn resolve_schema(connection: &mut Connection, fields: &mut [RowField]) -> Result<()> {
+    let Some(sql) = catalog_query(fields) else {
+        for field in fields.iter_mut() {
+            field.source = Some(describe_source(field, &[]));
+        }
+        return Ok(());
+    };
+    let columns: Vec<ColumnSchema> = match text_rows(connection, &sql)? {
+        Ok(rows) => rows.iter().filter_map(|row| parse_column(row)).collect(),
+        Err(error) => {
+            eprintln!("schema: could not resolve result columns: {error}");
+            return Ok(());
+        }
+    };
+    for field in fields.iter_mut() {
+        field.source = Some(describe_source(field, &columns));
+    }
+    Ok(())
+}
+
+/// The catalog query for every field that comes from a table column, or None
+/// when none does
+fn catalog_query(fields: &[RowField]) -> Option<String> {
+    let mut wanted = Vec::new();
+    for field in fields.iter().filter(|field| field.table_oid != 0) {
+        let pair = format!("({}, {})", field.table_oid, field.column_id);
+        if !wanted.contains(&pair) {
+            wanted.push(pair);
+        }
+    }
+    if wanted.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "SELECT a.attrelid, a.attnum, n.nspname, c.relname, a.attname, a.attnotnull, \
+         coalesce((SELECT string_agg(DISTINCT CASE WHEN i.indnkeyatts > 1 THEN 'part of ' ELSE '' END \
+         || CASE WHEN i.indisprimary THEN 'PRIMARY KEY' ELSE 'UNIQUE' END, ', ') \
+         FROM pg_index i WHERE i.indrelid = a.attrelid AND i.indisunique AND i.indpred IS NULL \
+         AND a.attnum = ANY ((i.indkey::int2[])[0:i.indnkeyatts - 1])), '') \
+         FROM pg_attribute a JOIN pg_class c ON c.oid = a.attrelid \
+         JOIN pg_namespace n ON n.oid = c.relnamespace \
+         WHERE (a.attrelid, a.attnum) IN ({})",
+        wanted.join(", ")
+    ))
+}
+
+fn parse_column(row: &[String]) -> Option<ColumnSchema> {
+    let [table_oid, column_id, schema, table, column, not_null, keys] = row else {
+        return None;
+    };
+    Some(ColumnSchema {
+        table_oid: table_oid.parse().ok()?,
+        column_id: column_id.parse().ok()?,
+        schema: schema.clone(),
+        table: table.clone(),
+        column: column.clone(),
+        not_null: not_null == "t",
+        keys: keys.clone(),
+    })
+}
+
+fn describe_source(field: &RowField, columns: &[ColumnSchema]) -> String {
+    if field.table_oid == 0 {
+        return "none (computed)".to_string();
+    }
+    columns
+        .iter()
+        .find(|column| column.table_oid == field.table_oid && column.column_id == field.column_id)
+        .map_or_else(
+            || {
+                format!(
+                    "table oid {} column {} (not found)",
+                    field.table_oid, field.column_id
+                )
+            },
+            ColumnSchema::describe,
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(table_oid: u32, column_id: i16) -> RowField {
+        RowField {
+            name: "col".to_string(),
+            table_oid,
+            column_id,
+            ..RowField::default()
+        }
+    }
+
+    fn catalog_row(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn only_table_columns_are_looked_up() {
+        let fields = [
+            field(16396, 1),
+            field(0, 0),
+            field(16405, 2),
+            field(16396, 1),
+        ];
+        let sql = catalog_query(&fields).unwrap();
+        assert!(
+            sql.ends_with("WHERE (a.attrelid, a.attnum) IN ((16396, 1), (16405, 2))"),
+            "{sql}"
+        );
+        assert_eq!(catalog_query(&[field(0, 0)]), None);
+    }
+
+    #[test]
+    fn sources_describe_nullability_and_keys() {
+        let columns: Vec<ColumnSchema> = [
+            catalog_row(&["16396", "1", "public", "users", "id", "t", "PRIMARY KEY"]),
+            catalog_row(&["16396", "2", "public", "users", "email", "f", "UNIQUE"]),
+            catalog_row(&[
+                "16405",
+                "2",
+                "public",
+                "orders",
+                "line",
+                "t",
+                "part of PRIMARY KEY",
+            ]),
+            catalog_row(&["16405", "3", "public", "orders", "note", "f", ""]),
+        ]
+        .iter()
+        .filter_map(|row| parse_column(row))
+        .collect();
+        assert_eq!(columns.len(), 4);
+
+        let source = |table_oid, column_id| describe_source(&field(table_oid, column_id), &columns);
+        assert_eq!(source(16396, 1), "public.users.id (NOT NULL, PRIMARY KEY)");
+        assert_eq!(source(16396, 2), "public.users.email (nullable, UNIQUE)");
+        assert_eq!(
+            source(16405, 2),
+            "public.orders.line (NOT NULL, part of PRIMARY KEY)"
+        );
+        assert_eq!(source(16405, 3), "public.orders.note (nullable)");
+        assert_eq!(source(0, 0), "none (computed)");
+        assert_eq!(source(16405, 9), "table oid 16405 column 9 (not found)");
+    }
+}
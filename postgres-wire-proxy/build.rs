@@ -0,0 +1,97 @@
+//! Generates the body of `get_pg_type_name` from the vendored
+//! catalog/pg_type.dat: one match arm per built-in type and one per array
+//! type, written to `OUT_DIR/pg_type_names.rs`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const CATALOG: &str = "catalog/pg_type.dat";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", CATALOG);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let catalog =
+        fs::read_to_string(CATALOG).unwrap_or_else(|e| panic!("unable to read {}: {}", CATALOG, e));
+    let mut names = BTreeMap::new();
+    for entry in parse_entries(&catalog) {
+        let field = |key: &str| {
+            entry
+                .get(key)
+                .unwrap_or_else(|| panic!("{}: entry without {}: {:?}", CATALOG, key, entry))
+        };
+        let oid = parse_oid(field("oid"));
+        let name = field("typname").clone();
+        if let Some(array_oid) = entry.get("array_type_oid") {
+            insert(&mut names, parse_oid(array_oid), format!("{}[]", name));
+        }
+        insert(&mut names, oid, name);
+    }
+
+    let mut code = String::from("// Generated by build.rs from catalog/pg_type.dat\nmatch oid {\n");
+    for (oid, name) in &names {
+        writeln!(code, "    {} => {:?},", oid, name).unwrap();
+    }
+    code.push_str("    _ => \"unknown\",\n}\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("pg_type_names.rs");
+    fs::write(&out, code).unwrap_or_else(|e| panic!("unable to write {}: {}", out.display(), e));
+}
+
+fn insert(names: &mut BTreeMap<u32, String>, oid: u32, name: String) {
+    if let Some(previous) = names.insert(oid, name) {
+        panic!("{}: OID {} is listed twice ({})", CATALOG, oid, previous);
+    }
+}
+
+fn parse_oid(value: &str) -> u32 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("{}: invalid OID '{}'", CATALOG, value))
+}
+
+/// Read the `{ key => 'value', ... }` entries of a catalog .dat file,
+/// skipping `#` comments and honouring `\'` and `\\` inside values
+fn parse_entries(catalog: &str) -> Vec<BTreeMap<String, String>> {
+    let text: String = catalog
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut entries = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            continue;
+        }
+        let mut entry = BTreeMap::new();
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some('\'') => {
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\\') => value.extend(chars.next()),
+                            Some('\'') => break,
+                            Some(c) => value.push(c),
+                            None => panic!("{}: unterminated value", CATALOG),
+                        }
+                    }
+                    let key = key.trim().trim_end_matches("=>").trim().to_string();
+                    entry.insert(key, value);
+                }
+                Some(',') => key.clear(),
+                Some(c) => key.push(c),
+                None => panic!("{}: unterminated entry", CATALOG),
+            }
+        }
+        entries.push(entry);
+    }
+    entries
+}
@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::audit::json_string;
+use crate::metrics::{read_request_head, request_target, write_response};
+use crate::protocol::ClientState;
+
+/// What the protocol state of a session says about it
+#[derive(Debug, PartialEq)]
+pub struct SessionSummary {
+    pub database: Option<String>,
+    pub user: Option<String>,
+    pub application_name: Option<String>,
+    pub backend_pid: Option<u32>,
+    /// `starting`, `idle`, `active`, `idle in transaction` or
+    /// `idle in transaction (aborted)`
+    pub state: &'static str,
+    pub last_statement: Option<String>,
+}
+
+struct Session {
+    client_addr: String,
+    connected: Instant,
+    state: Arc<ClientState>,
+    /// Bytes forwarded from the client (0) and from the server (1)
+    bytes: [AtomicU64; 2],
+    kill: Notify,
+}
+
+/// The sessions being proxied, served on --admin-listen. Connections add
+/// themselves once their startup message is read and are removed when
+/// their [`RegisteredSession`] is dropped.
+#[derive(Default)]
+pub struct SessionRegistry {
+    last_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+impl std::fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("sessions", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// A connection's entry in the registry, removed again on drop
+pub struct RegisteredSession {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+    session: Arc<Session>,
+}
+
+impl RegisteredSession {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Count bytes forwarded in one direction
+    pub fn forwarded(&self, from_client: bool, bytes: usize) {
+        self.session.bytes[usize::from(!from_client)].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Completes once the session is killed through the admin endpoint
+    pub async fn killed(&self) {
+        self.session.kill.notified().await
+    }
+}
+
+impl Drop for RegisteredSession {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Add a session; IDs count up from 1 and are never reused
+    pub fn register(
+        self: &Arc<Self>,
+        client_addr: &str,
+        state: Arc<ClientState>,
+    ) -> RegisteredSession {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            client_addr: client_addr.to_string(),
+            connected: Instant::now(),
+            state,
+            bytes: [AtomicU64::new(0), AtomicU64::new(0)],
+            kill: Notify::new(),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        RegisteredSession {
+            registry: self.clone(),
+            id,
+            session,
+        }
+    }
+
+    /// Ask session `id` to close; false when there is no such session
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                // Stored as a permit if the connection is not waiting yet
+                session.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The sessions as a JSON array, oldest first
+    fn render(&self) -> String {
+        let sessions: Vec<(u64, Arc<Session>)> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (*id, session.clone()))
+            .collect();
+        let mut json = String::from("[");
+        for (i, (id, session)) in sessions.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let summary = session.state.summary();
+            let optional = |value: &Option<String>| {
+                value
+                    .as_deref()
+                    .map_or_else(|| "null".to_string(), json_string)
+            };
+            let _ = write!(
+                json,
+                "{{\"id\":{},\"client_addr\":{},\"user\":{},\"database\":{},\"application_name\":{},\"backend_pid\":{},\"bytes_from_client\":{},\"bytes_from_server\":{},\"state\":{},\"last_statement\":{},\"connected_seconds\":{:.3}}}",
+                id,
+                json_string(&session.client_addr),
+                optional(&summary.user),
+                optional(&summary.database),
+                optional(&summary.application_name),
+                summary
+                    .backend_pid
+                    .map_or_else(|| "null".to_string(), |pid| pid.to_string()),
+                session.bytes[0].load(Ordering::Relaxed),
+                session.bytes[1].load(Ordering::Relaxed),
+                json_string(summary.state),
+                optional(&summary.last_statement),
+                session.connected.elapsed().as_secs_f64()
+            );
+        }
+        json.push_str("]\n");
+        json
+    }
+}
+
+/// Answer admin requests on `listener` until the proxy exits
+pub async fn serve(listener: TcpListener, registry: Arc<SessionRegistry>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(socket, &registry, &addr.to_string()).await {
+                debug!("Admin request from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Read one HTTP request and answer it: `GET /sessions` lists the sessions
+/// and `POST /sessions/<id>/kill` closes one
+async fn answer(
+    mut socket: TcpStream,
+    registry: &SessionRegistry,
+    peer: &str,
+) -> std::io::Result<()> {
+    let request = read_request_head(&mut socket).await?;
+    let kill_target = |path: &str| {
+        path.strip_prefix("/sessions/")?
+            .strip_suffix("/kill")?
+            .parse::<u64>()
+            .ok()
+    };
+    let (status, body) = match request_target(&request) {
+        Some(("GET", "/sessions")) => ("200 OK", registry.render()),
+        Some(("POST", path)) if kill_target(path).is_some() => {
+            let id = kill_target(path).unwrap_or_default();
+            if registry.kill(id) {
+                info!("Admin client {} killed session {}", peer, id);
+                ("200 OK", format!("{{\"killed\":{}}}\n", id))
+            } else {
+                (
+                    "404 Not Found",
+                    format!("{{\"error\":\"no session {}\"}}\n", id),
+                )
+            }
+        }
+        Some(("GET" | "POST", _)) => ("404 Not Found", "{\"error\":\"not found\"}\n".to_string()),
+        Some(_) => (
+            "405 Method Not Allowed",
+            "{\"error\":\"method not allowed\"}\n".to_string(),
+        ),
+        None => (
+            "400 Bad Request",
+            "{\"error\":\"bad request\"}\n".to_string(),
+        ),
+    };
+    write_response(&mut socket, status, "application/json", &body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{parse_json, JsonValue};
+    use crate::protocol::{
+        encode_backend_message, encode_command_complete, encode_query, encode_ready_for_query,
+        parse_message, ConnectionTiming, MessageDirection, ProtocolOptions,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn request(addr: std::net::SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("{} {} HTTP/1.1\r\nHost: proxy\r\n\r\n", method, path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    fn startup(user: &str, database: &str) -> Vec<u8> {
+        let body = format!("user\0{}\0database\0{}\0\0", user, database);
+        let mut message = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&196608u32.to_be_bytes());
+        message.extend_from_slice(body.as_bytes());
+        message
+    }
+
+    /// A session through parse_message, counting what it forwards
+    struct ScriptedSession {
+        state: Arc<ClientState>,
+        timings: ConnectionTiming,
+        registered: RegisteredSession,
+    }
+
+    impl ScriptedSession {
+        fn open(registry: &Arc<SessionRegistry>, client_addr: &str, user: &str) -> Self {
+            let state = Arc::new(ClientState::new(
+                ProtocolOptions {
+                    sessions: Some(registry.clone()),
+                    ..ProtocolOptions::default()
+                },
+                false,
+            ));
+            let startup = startup(user, "shop");
+            state.record_startup(&startup);
+            let registered = registry.register(client_addr, state.clone());
+            registered.forwarded(true, startup.len());
+            Self {
+                state,
+                timings: ConnectionTiming::new(),
+                registered,
+            }
+        }
+
+        fn exchange(&self, from_client: bool, data: &[u8]) {
+            self.registered.forwarded(from_client, data.len());
+            let direction = if from_client {
+                MessageDirection::ClientToServer
+            } else {
+                MessageDirection::ServerToClient
+            };
+            parse_message(
+                data,
+                direction,
+                "test",
+                Some(&self.timings),
+                &self.state,
+                false,
+            );
+        }
+    }
+
+    fn member<'a>(session: &'a JsonValue, key: &str) -> &'a JsonValue {
+        session
+            .get(key)
+            .unwrap_or_else(|| panic!("{} missing", key))
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_and_killed() {
+        let registry = SessionRegistry::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, registry.clone()));
+
+        let first = ScriptedSession::open(&registry, "10.0.0.1:40001", "app");
+        let mut key = 4242u32.to_be_bytes().to_vec();
+        key.extend_from_slice(&7u32.to_be_bytes());
+        first.exchange(
+            false,
+            &[
+                encode_backend_message(b'K', &key),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        first.exchange(true, &encode_query("begin"));
+        first.exchange(
+            false,
+            &[
+                encode_command_complete("BEGIN"),
+                encode_ready_for_query(b'T'),
+            ]
+            .concat(),
+        );
+        let second = ScriptedSession::open(&registry, "10.0.0.2:40002", "report");
+        second.exchange(false, &encode_ready_for_query(b'I'));
+        second.exchange(true, &encode_query("select   pg_sleep(10)"));
+
+        let (status, body) = request(addr, "GET", "/sessions").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let JsonValue::Array(sessions) = parse_json(&body).unwrap() else {
+            panic!("not an array: {}", body);
+        };
+        assert_eq!(sessions.len(), 2);
+        let string = |value: &str| JsonValue::String(value.to_string());
+        let number = |value: u64| JsonValue::Number(value.to_string());
+        for (key, value) in [
+            ("id", number(1)),
+            ("client_addr", string("10.0.0.1:40001")),
+            ("user", string("app")),
+            ("database", string("shop")),
+            ("application_name", JsonValue::Null),
+            ("backend_pid", number(4242)),
+            ("bytes_from_client", number(43)),
+            ("bytes_from_server", number(36)),
+            ("state", string("idle in transaction")),
+            ("last_statement", string("begin")),
+        ] {
+            assert_eq!(member(&sessions[0], key), &value, "{}", key);
+        }
+        for (key, value) in [
+            ("id", number(2)),
+            ("user", string("report")),
+            ("backend_pid", JsonValue::Null),
+            ("state", string("active")),
+            ("last_statement", string("select pg_sleep(10)")),
+        ] {
+            assert_eq!(member(&sessions[1], key), &value, "{}", key);
+        }
+
+        let (status, body) = request(addr, "POST", "/sessions/2/kill").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "{\"killed\":2}\n");
+        tokio::time::timeout(Duration::from_secs(1), second.registered.killed())
+            .await
+            .expect("the killed session is signalled");
+        drop(second);
+
+        let (_, body) = request(addr, "GET", "/sessions").await;
+        let JsonValue::Array(sessions) = parse_json(&body).unwrap() else {
+            panic!("not an array: {}", body);
+        };
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(member(&sessions[0], "id"), &number(1));
+
+        let (status, _) = request(addr, "POST", "/sessions/2/kill").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = request(addr, "GET", "/sessions/1").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = request(addr, "DELETE", "/sessions/1").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), first.registered.killed())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+enum Command {
+    Write(String),
+    Finish(oneshot::Sender<()>),
+}
+
+/// Append-only JSONL file with one record per completed or failed query.
+///
+/// Records are written straight to the file rather than through the tracing
+/// subscriber so the audit trail is independent of the log level and format.
+/// Connections only queue records; a writer task writes and flushes them.
+#[derive(Debug)]
+pub struct AuditLogger {
+    sender: UnboundedSender<Command>,
+}
+
+/// One query as it appears in the audit log
+pub struct AuditRecord<'a> {
+    pub client_addr: &'a str,
+    pub database: Option<&'a str>,
+    pub user: Option<&'a str>,
+    pub application_name: Option<&'a str>,
+    /// SQL with whitespace collapsed
+    pub query: &'a str,
+    /// Bind parameters as shown in the log; `None` is SQL NULL
+    pub params: &'a [Option<String>],
+    /// Rows returned or affected; `None` when the query failed
+    pub rows: Option<u64>,
+    pub duration: Duration,
+    /// SQLSTATE of the ErrorResponse that ended the query
+    pub error_code: Option<&'a str>,
+}
+
+impl AuditLogger {
+    /// Open `path` for appending, creating it when missing, and start the
+    /// writer task
+    pub fn open(path: &Path) -> Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_records(
+            BufWriter::new(tokio::fs::File::from_std(file)),
+            receiver,
+        ));
+        Ok(Arc::new(Self { sender }))
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = record.to_json(OffsetDateTime::now_utc());
+        line.push('\n');
+        // Only fails once the writer has finished at shutdown
+        let _ = self.sender.send(Command::Write(line));
+    }
+
+    /// Write out everything queued so far and stop the writer
+    pub async fn finish(&self) {
+        let (done, finished) = oneshot::channel();
+        if self.sender.send(Command::Finish(done)).is_ok() {
+            let _ = finished.await;
+        }
+    }
+}
+
+/// Write records as they arrive, flushing whenever the queue runs empty so
+/// the log is current even if the proxy is killed
+async fn write_records(
+    mut out: BufWriter<tokio::fs::File>,
+    mut commands: UnboundedReceiver<Command>,
+) {
+    while let Some(command) = commands.recv().await {
+        let mut next = Some(command);
+        let mut finished = None;
+        while let Some(command) = next {
+            match command {
+                Command::Write(line) => {
+                    if let Err(e) = out.write_all(line.as_bytes()).await {
+                        warn!("Failed to write audit record: {}", e);
+                    }
+                }
+                Command::Finish(done) => {
+                    finished = Some(done);
+                    break;
+                }
+            }
+            next = commands.try_recv().ok();
+        }
+        if let Err(e) = out.flush().await {
+            warn!("Failed to flush audit log: {}", e);
+        }
+        if let Some(done) = finished {
+            let _ = done.send(());
+            return;
+        }
+    }
+}
+
+impl AuditRecord<'_> {
+    fn to_json(&self, timestamp: OffsetDateTime) -> String {
+        let addr = self.client_addr.parse::<SocketAddr>().ok();
+        let mut json = String::from("{");
+        let timestamp = timestamp.format(&Rfc3339).unwrap_or_default();
+        let _ = write!(json, "\"timestamp\":{}", json_string(&timestamp));
+        let _ = write!(
+            json,
+            ",\"client_ip\":{}",
+            json_optional(addr.map(|addr| addr.ip().to_string()).as_deref())
+        );
+        match addr {
+            Some(addr) => {
+                let _ = write!(json, ",\"client_port\":{}", addr.port());
+            }
+            None => json.push_str(",\"client_port\":null"),
+        }
+        let _ = write!(json, ",\"database\":{}", json_optional(self.database));
+        let _ = write!(json, ",\"user\":{}", json_optional(self.user));
+        let _ = write!(
+            json,
+            ",\"application_name\":{}",
+            json_optional(self.application_name)
+        );
+        let _ = write!(json, ",\"query_text\":{}", json_string(self.query));
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|param| json_optional(param.as_deref()))
+            .collect();
+        let _ = write!(json, ",\"param_values\":[{}]", params.join(","));
+        match self.rows {
+            Some(rows) => {
+                let _ = write!(json, ",\"rows_returned\":{}", rows);
+            }
+            None => json.push_str(",\"rows_returned\":null"),
+        }
+        let _ = write!(
+            json,
+            ",\"duration_ms\":{:.3}",
+            self.duration.as_secs_f64() * 1000.0
+        );
+        let _ = write!(json, ",\"error_code\":{}", json_optional(self.error_code));
+        json.push('}');
+        json
+    }
+}
+
+fn json_optional(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_string(), json_string)
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_is_one_json_line() {
+        let params = [Some("it's \"quoted\"".to_string()), None];
+        let record = AuditRecord {
+            client_addr: "10.0.0.1:50000",
+            database: Some("app"),
+            user: Some("alice"),
+            application_name: None,
+            query: "select $1, $2",
+            params: &params,
+            rows: Some(1),
+            duration: Duration::from_micros(1500),
+            error_code: None,
+        };
+        let json = record.to_json(OffsetDateTime::UNIX_EPOCH);
+        assert_eq!(
+            json,
+            "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"client_ip\":\"10.0.0.1\",\
+             \"client_port\":50000,\"database\":\"app\",\"user\":\"alice\",\
+             \"application_name\":null,\"query_text\":\"select $1, $2\",\
+             \"param_values\":[\"it's \\\"quoted\\\"\",null],\"rows_returned\":1,\
+             \"duration_ms\":1.500,\"error_code\":null}"
+        );
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(json_string("a\nb\\c\u{1}"), "\"a\\nb\\\\c\\u0001\"");
+    }
+}
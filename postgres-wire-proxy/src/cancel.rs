@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// Protocol code of a CancelRequest, sent in place of a protocol version
+pub const CANCEL_REQUEST_CODE: u32 = 80877102;
+
+/// Process ID and secret key the server hands out in BackendKeyData
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BackendKey {
+    pub pid: u32,
+    /// Four bytes before protocol 3.2, up to 256 after
+    pub secret: Vec<u8>,
+}
+
+/// Where the backend of a proxied session runs
+#[derive(Debug)]
+struct CancelTarget {
+    upstream: SocketAddr,
+    client_addr: String,
+    /// Key of the server session when the client was given the proxy's own,
+    /// as with --pool-mode transaction
+    backend: Option<BackendKey>,
+}
+
+/// Backend keys of every live session, so a CancelRequest arriving on its
+/// own connection reaches the server that runs the backend it names
+#[derive(Debug, Default)]
+pub struct CancelRegistry {
+    sessions: Mutex<HashMap<BackendKey, CancelTarget>>,
+}
+
+impl CancelRegistry {
+    pub fn register(&self, key: BackendKey, upstream: SocketAddr, client_addr: &str) {
+        self.sessions.lock().unwrap().insert(
+            key,
+            CancelTarget {
+                upstream,
+                client_addr: client_addr.to_string(),
+                backend: None,
+            },
+        );
+    }
+
+    /// Route the proxy's own `key` to the pooled `backend` a client holds
+    /// until it gives the backend back
+    pub fn register_pooled(
+        &self,
+        key: BackendKey,
+        backend: BackendKey,
+        upstream: SocketAddr,
+        client_addr: &str,
+    ) {
+        self.sessions.lock().unwrap().insert(
+            key,
+            CancelTarget {
+                upstream,
+                client_addr: client_addr.to_string(),
+                backend: Some(backend),
+            },
+        );
+    }
+
+    pub fn unregister(&self, key: &BackendKey) {
+        self.sessions.lock().unwrap().remove(key);
+    }
+
+    /// Upstream address, client and server-side key of the session a key
+    /// belongs to
+    fn lookup(&self, key: &BackendKey) -> Option<(SocketAddr, String, BackendKey)> {
+        let sessions = self.sessions.lock().unwrap();
+        let target = sessions.get(key)?;
+        let backend = target.backend.clone().unwrap_or_else(|| key.clone());
+        Some((target.upstream, target.client_addr.clone(), backend))
+    }
+
+    /// Send a CancelRequest on to the upstream that runs its backend.
+    ///
+    /// The server never answers a CancelRequest, so neither does the proxy;
+    /// requests for unknown keys are dropped like the server would.
+    pub async fn forward(&self, request: &[u8], client_addr: &str) -> Result<()> {
+        let Some(key) = decode_cancel_request(request) else {
+            warn!(
+                "[{}] Malformed CancelRequest ({} bytes), not forwarded",
+                client_addr,
+                request.len()
+            );
+            return Ok(());
+        };
+        let Some((upstream, session, backend)) = self.lookup(&key) else {
+            warn!(
+                "[{}] CancelRequest for unknown backend pid {}, not forwarded",
+                client_addr, key.pid
+            );
+            return Ok(());
+        };
+
+        let mut stream = TcpStream::connect(upstream)
+            .await
+            .with_context(|| format!("Failed to connect to {} for CancelRequest", upstream))?;
+        if backend == key {
+            stream.write_all(request).await?;
+        } else {
+            stream.write_all(&encode_cancel_request(&backend)).await?;
+        }
+        stream.shutdown().await?;
+        info!(
+            "[{}] CancelRequest for backend pid {} (session of {}) forwarded to {}",
+            client_addr, backend.pid, session, upstream
+        );
+        Ok(())
+    }
+}
+
+/// The backend key of a CancelRequest (length, code, pid, secret)
+pub fn decode_cancel_request(request: &[u8]) -> Option<BackendKey> {
+    let length = u32::from_be_bytes(request.get(..4)?.try_into().ok()?) as usize;
+    let code = u32::from_be_bytes(request.get(4..8)?.try_into().ok()?);
+    if code != CANCEL_REQUEST_CODE || length != request.len() {
+        return None;
+    }
+    decode_backend_key(&request[8..])
+}
+
+/// A CancelRequest for `key`
+fn encode_cancel_request(key: &BackendKey) -> Vec<u8> {
+    let mut request = ((8 + 4 + key.secret.len()) as u32).to_be_bytes().to_vec();
+    request.extend_from_slice(&CANCEL_REQUEST_CODE.to_be_bytes());
+    request.extend_from_slice(&key.pid.to_be_bytes());
+    request.extend_from_slice(&key.secret);
+    request
+}
+
+/// Body of BackendKeyData: the pid followed by the secret key
+pub fn decode_backend_key(data: &[u8]) -> Option<BackendKey> {
+    let pid = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+    let secret = data.get(4..).filter(|secret| !secret.is_empty())?;
+    Some(BackendKey {
+        pid,
+        secret: secret.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn cancel_request(pid: u32, secret: u32) -> Vec<u8> {
+        let mut request = 16u32.to_be_bytes().to_vec();
+        request.extend_from_slice(&CANCEL_REQUEST_CODE.to_be_bytes());
+        request.extend_from_slice(&pid.to_be_bytes());
+        request.extend_from_slice(&secret.to_be_bytes());
+        request
+    }
+
+    #[test]
+    fn cancel_requests_are_decoded() {
+        let key = decode_cancel_request(&cancel_request(4242, 7)).unwrap();
+        assert_eq!(key.pid, 4242);
+        assert_eq!(key.secret, [0, 0, 0, 7]);
+
+        let mut startup = cancel_request(4242, 7);
+        startup[4..8].copy_from_slice(&196_608u32.to_be_bytes());
+        assert_eq!(decode_cancel_request(&startup), None);
+        assert_eq!(decode_cancel_request(&cancel_request(1, 2)[..12]), None);
+    }
+
+    #[tokio::test]
+    async fn cancel_is_forwarded_to_the_upstream_of_its_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let registry = CancelRegistry::default();
+        let key = decode_backend_key(&[0, 0, 16, 146, 0, 0, 0, 7]).unwrap();
+        registry.register(key.clone(), upstream, "10.0.0.1:5000");
+
+        let request = cancel_request(4242, 7);
+        registry.forward(&request, "10.0.0.2:6000").await.unwrap();
+        let (mut received, _) = listener.accept().await.unwrap();
+        let mut forwarded = Vec::new();
+        received.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, request);
+
+        // Once the session is gone its key no longer cancels anything
+        registry.unregister(&key);
+        registry.forward(&request, "10.0.0.2:6000").await.unwrap();
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), listener.accept())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn pooled_cancel_is_sent_with_the_key_of_the_held_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let registry = CancelRegistry::default();
+        let proxy_key = decode_backend_key(&[0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+        let backend_key = decode_backend_key(&[0, 0, 16, 146, 0, 0, 0, 7]).unwrap();
+        registry.register_pooled(proxy_key, backend_key, upstream, "10.0.0.1:5000");
+
+        registry
+            .forward(&cancel_request(0, 9), "10.0.0.2:6000")
+            .await
+            .unwrap();
+        let (mut received, _) = listener.accept().await.unwrap();
+        let mut forwarded = Vec::new();
+        received.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, cancel_request(4242, 7));
+    }
+}
@@ -0,0 +1,571 @@
+use regex_automata::meta::Regex;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::json::{parse_json, JsonValue};
+use crate::protocol::{
+    encode_command_complete, encode_data_row, encode_ready_for_query, encode_row_description,
+    ColumnDescription,
+};
+
+/// One --respond rule: Query messages whose SQL matches the regex are
+/// answered by the proxy with the result described in `path`
+#[derive(Clone, Debug)]
+pub struct CannedResponse {
+    pattern: String,
+    pub regex: Regex,
+    pub path: String,
+    pub columns: Vec<ColumnDescription>,
+    /// Text values, None being NULL
+    pub rows: Vec<Vec<Option<String>>>,
+    pub command_tag: String,
+}
+
+impl std::fmt::Display for CannedResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "/{}/ => {} ({} columns, {} rows, '{}')",
+            self.pattern,
+            self.path,
+            self.columns.len(),
+            self.rows.len(),
+            self.command_tag
+        )
+    }
+}
+
+impl CannedResponse {
+    /// RowDescription, DataRows and CommandComplete of the result; a result
+    /// without columns (e.g. for SET) is only the CommandComplete
+    pub fn encode(&self) -> Vec<u8> {
+        let mut messages = Vec::new();
+        if !self.columns.is_empty() {
+            messages.extend(encode_row_description(&self.columns));
+            for row in &self.rows {
+                let values: Vec<Option<&str>> = row.iter().map(Option::as_deref).collect();
+                messages.extend(encode_data_row(&values));
+            }
+        }
+        messages.extend(encode_command_complete(&self.command_tag));
+        messages
+    }
+}
+
+/// Parse one `pattern=>file.json` argument of --respond and load the file.
+/// The split is at the last `=>`, so the pattern may contain one.
+pub fn parse_canned_response(value: &str) -> Result<CannedResponse, String> {
+    let (pattern, path) = value
+        .rsplit_once("=>")
+        .ok_or_else(|| "expected PATTERN=>FILE".to_string())?;
+    let regex = Regex::new(pattern).map_err(|e| format!("invalid regex: {}", e))?;
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let (columns, rows, command_tag) =
+        parse_result_description(&text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(CannedResponse {
+        pattern: pattern.to_string(),
+        regex,
+        path: path.to_string(),
+        columns,
+        rows,
+        command_tag,
+    })
+}
+
+type ResultDescription = (Vec<ColumnDescription>, Vec<Vec<Option<String>>>, String);
+
+/// Columns, rows and command tag of a response file:
+///
+/// ```json
+/// {"columns": [{"name": "id", "type_oid": 23}],
+///  "rows": [[1], [null]],
+///  "command_tag": "SELECT 2"}
+/// ```
+///
+/// `type_size` and `type_modifier` of a column default to what the server
+/// sends for the type. Values are strings, numbers, booleans or null; the
+/// tag defaults to `SELECT <rows>` but is required without columns.
+fn parse_result_description(text: &str) -> Result<ResultDescription, String> {
+    let document = parse_json(text)?;
+    let columns = match document.get("columns") {
+        None => Vec::new(),
+        Some(JsonValue::Array(columns)) => columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                parse_column(column).map_err(|e| format!("column {}: {}", index + 1, e))
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err("\"columns\" must be an array".to_string()),
+    };
+    let rows = match document.get("rows") {
+        None => Vec::new(),
+        Some(JsonValue::Array(rows)) => rows
+            .iter()
+            .enumerate()
+            .map(|(index, row)| {
+                parse_row(row, columns.len()).map_err(|e| format!("row {}: {}", index + 1, e))
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err("\"rows\" must be an array".to_string()),
+    };
+    if columns.is_empty() && !rows.is_empty() {
+        return Err("rows given without columns".to_string());
+    }
+    let command_tag = match document.get("command_tag") {
+        Some(JsonValue::String(tag)) => tag.clone(),
+        Some(_) => return Err("\"command_tag\" must be a string".to_string()),
+        None if columns.is_empty() => {
+            return Err("\"command_tag\" is required without columns".to_string())
+        }
+        None => format!("SELECT {}", rows.len()),
+    };
+    Ok((columns, rows, command_tag))
+}
+
+fn parse_column(column: &JsonValue) -> Result<ColumnDescription, String> {
+    let name = match column.get("name") {
+        Some(JsonValue::String(name)) => name.clone(),
+        _ => return Err("\"name\" must be a string".to_string()),
+    };
+    let type_oid = integer_member(column, "type_oid")?.ok_or("\"type_oid\" is required")?;
+    let type_size =
+        integer_member(column, "type_size")?.unwrap_or_else(|| default_type_size(type_oid));
+    let type_modifier = integer_member(column, "type_modifier")?.unwrap_or(-1);
+    Ok(ColumnDescription {
+        name,
+        type_oid,
+        type_size,
+        type_modifier,
+    })
+}
+
+fn integer_member<T: std::str::FromStr>(
+    object: &JsonValue,
+    key: &str,
+) -> Result<Option<T>, String> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(JsonValue::Number(text)) => text
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("\"{}\" is out of range: {}", key, text)),
+        Some(_) => Err(format!("\"{}\" must be an integer", key)),
+    }
+}
+
+fn parse_row(row: &JsonValue, width: usize) -> Result<Vec<Option<String>>, String> {
+    let JsonValue::Array(values) = row else {
+        return Err("must be an array".to_string());
+    };
+    if values.len() != width {
+        return Err(format!("has {} values for {} columns", values.len(), width));
+    }
+    values
+        .iter()
+        .map(|value| match value {
+            JsonValue::Null => Ok(None),
+            JsonValue::Bool(value) => Ok(Some(if *value { "t" } else { "f" }.to_string())),
+            JsonValue::Number(text) | JsonValue::String(text) => Ok(Some(text.clone())),
+            _ => Err("values must be strings, numbers, booleans or null".to_string()),
+        })
+        .collect()
+}
+
+/// The typlen the server reports for a built-in type: its fixed size, or
+/// -1 for variable-length types
+fn default_type_size(type_oid: u32) -> i16 {
+    match type_oid {
+        16 | 18 => 1,            // bool, char
+        19 => 64,                // name
+        20 | 701 => 8,           // int8, float8
+        21 => 2,                 // int2
+        23 | 26 | 700 => 4,      // int4, oid, float4
+        1082 => 4,               // date
+        1083 | 1114 | 1184 => 8, // time, timestamp, timestamptz
+        1186 | 2950 => 16,       // interval, uuid
+        705 => -2,               // unknown
+        _ => -1,
+    }
+}
+
+/// Canned results and injected errors waiting to be sent to the client. A
+/// reply must not overtake the responses to requests the client sent before
+/// the matching statement, so each waits for the ReadyForQuery that answers
+/// the last of them.
+pub struct LocalReplies {
+    queue: Mutex<ReplyQueue>,
+    ready: Notify,
+}
+
+struct ReplyQueue {
+    /// ReadyForQuery messages owed by the server for forwarded requests,
+    /// counting the one that ends the startup phase
+    owed: u64,
+    /// ReadyForQuery messages the server has sent
+    answered: u64,
+    /// Transaction status of the latest ReadyForQuery
+    status: u8,
+    /// The server sent an ErrorResponse since its latest ReadyForQuery
+    server_failed: bool,
+    /// The proxy failed the client's transaction block, which the server
+    /// still has open and usable
+    aborted: bool,
+    /// Replies, each with the number of ReadyForQuery messages that must
+    /// reach the client first
+    pending: VecDeque<(u64, Reply)>,
+}
+
+enum Reply {
+    /// A canned result, followed by a ReadyForQuery
+    Result(Vec<u8>),
+    /// An ErrorResponse failing a statement the server never saw
+    Error(Vec<u8>),
+    /// The ReadyForQuery answering a Sync the server never saw
+    Ready,
+    /// An ErrorResponse that goes in front of the server's ReadyForQuery
+    /// numbered by the entry, because the server saw the extended query
+    /// the statement belonged to
+    ErrorBeforeReady(Vec<u8>),
+}
+
+impl Default for LocalReplies {
+    fn default() -> Self {
+        Self {
+            queue: Mutex::new(ReplyQueue {
+                owed: 1,
+                answered: 0,
+                status: b'I',
+                server_failed: false,
+                aborted: false,
+                pending: VecDeque::new(),
+            }),
+            ready: Notify::new(),
+        }
+    }
+}
+
+/// The transaction status after an error: a transaction block is failed,
+/// outside one there is nothing to fail
+fn failed_status(status: u8) -> u8 {
+    match status {
+        b'T' => b'E',
+        status => status,
+    }
+}
+
+impl LocalReplies {
+    /// A Query, Sync or FunctionCall went to the server, which will answer
+    /// it with a ReadyForQuery
+    pub fn forwarded_request(&self) {
+        self.queue.lock().unwrap().owed += 1;
+    }
+
+    /// Queue a result for the client, after the responses it is owed
+    pub fn push(&self, reply: Vec<u8>) {
+        self.queue(Reply::Result(reply));
+    }
+
+    /// Queue the ErrorResponse of a statement failed by the proxy. When the
+    /// server saw part of the statement's extended query, it goes right
+    /// before the ReadyForQuery answering the Sync forwarded next.
+    pub fn push_error(&self, error: Vec<u8>, server_saw_query: bool) {
+        if server_saw_query {
+            let mut queue = self.queue.lock().unwrap();
+            let before = queue.owed + 1;
+            queue
+                .pending
+                .push_back((before, Reply::ErrorBeforeReady(error)));
+        } else {
+            self.queue(Reply::Error(error));
+        }
+    }
+
+    /// Queue the ReadyForQuery answering a Query or Sync the server never
+    /// saw, with the transaction status the client expects at that point
+    pub fn push_ready(&self) {
+        self.queue(Reply::Ready);
+    }
+
+    /// Whether the client was told its transaction block failed while the
+    /// server's is still usable
+    pub fn transaction_aborted(&self) -> bool {
+        self.queue.lock().unwrap().aborted
+    }
+
+    /// The client ended the transaction block the proxy failed
+    pub fn transaction_ended(&self) {
+        self.queue.lock().unwrap().aborted = false;
+    }
+
+    fn queue(&self, reply: Reply) {
+        let mut queue = self.queue.lock().unwrap();
+        let after = queue.owed;
+        queue.pending.push_back((after, reply));
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    /// Resolves after a result was queued
+    pub async fn queued(&self) {
+        self.ready.notified().await;
+    }
+
+    /// The replies that no longer wait for the server, results each
+    /// followed by a ReadyForQuery
+    pub fn take_due(&self) -> Vec<u8> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut due = Vec::new();
+        while let Some((after, reply)) = queue.pending.front() {
+            if *after > queue.answered || matches!(reply, Reply::ErrorBeforeReady(_)) {
+                break;
+            }
+            match queue.pending.pop_front().unwrap().1 {
+                Reply::Result(result) => {
+                    due.extend(result);
+                    due.extend(encode_ready_for_query(queue.status));
+                }
+                Reply::Error(error) => {
+                    due.extend(error);
+                    queue.aborted |= queue.status == b'T';
+                    queue.status = failed_status(queue.status);
+                }
+                Reply::Ready => due.extend(encode_ready_for_query(queue.status)),
+                Reply::ErrorBeforeReady(_) => unreachable!(),
+            }
+        }
+        due
+    }
+
+    /// Split whole server messages into the segments to send in order,
+    /// inserting each queued reply right after the ReadyForQuery it waits
+    /// for, or an error right before the ReadyForQuery it belongs in front
+    /// of. That ReadyForQuery then reports the failed transaction status,
+    /// unless the server failed the extended query itself.
+    pub fn interleave(&self, messages: &[u8]) -> Vec<Vec<u8>> {
+        let mut segments = Vec::new();
+        let due = self.take_due();
+        if !due.is_empty() {
+            segments.push(due);
+        }
+        let mut start = 0;
+        let mut buf = messages;
+        while buf.len() >= 5 {
+            let length = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+            if buf.len() < length + 1 {
+                break;
+            }
+            if buf[0] == b'E' {
+                self.queue.lock().unwrap().server_failed = true;
+            }
+            if buf[0] == b'Z' {
+                let offset = messages.len() - buf.len();
+                let end = offset + length + 1;
+                let mut queue = self.queue.lock().unwrap();
+                queue.answered += 1;
+                let mut status = buf.get(5).copied().unwrap_or(queue.status);
+                let error_due = matches!(
+                    queue.pending.front(),
+                    Some((before, Reply::ErrorBeforeReady(_))) if *before == queue.answered
+                );
+                if error_due {
+                    if let Some((_, Reply::ErrorBeforeReady(error))) = queue.pending.pop_front() {
+                        // After an error of its own the server skipped the
+                        // rest of the extended query, the failed statement
+                        // with it
+                        if !queue.server_failed {
+                            queue.aborted |= status == b'T';
+                            status = failed_status(status);
+                            if start < offset {
+                                segments.push(messages[start..offset].to_vec());
+                            }
+                            segments.push(error);
+                            segments.push(encode_ready_for_query(status));
+                            start = end;
+                        }
+                    }
+                }
+                queue.status = status;
+                queue.server_failed = false;
+                drop(queue);
+                let due = self.take_due();
+                if !due.is_empty() {
+                    if start < end {
+                        segments.push(messages[start..end].to_vec());
+                    }
+                    segments.push(due);
+                    start = end;
+                }
+            }
+            buf = &buf[length + 1..];
+        }
+        if start < messages.len() {
+            segments.push(messages[start..].to_vec());
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::encode_backend_message;
+
+    fn unhex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn canned(json: &str) -> Vec<u8> {
+        let (columns, rows, command_tag) = parse_result_description(json).unwrap();
+        let response = CannedResponse {
+            pattern: String::new(),
+            regex: Regex::new("").unwrap(),
+            path: String::new(),
+            columns,
+            rows,
+            command_tag,
+        };
+        let mut messages = response.encode();
+        messages.extend(encode_ready_for_query(b'I'));
+        messages
+    }
+
+    // The expected bytes were captured from PostgreSQL 15 answering the
+    // same statement in a simple Query
+
+    #[test]
+    fn rows_match_a_real_server_result() {
+        // select 1 as a, 'x'::text as b, null::int8 as c
+        let json = r#"{
+            "columns": [
+                {"name": "a", "type_oid": 23},
+                {"name": "b", "type_oid": 25},
+                {"name": "c", "type_oid": 20}
+            ],
+            "rows": [[1, "x", null]]
+        }"#;
+        assert_eq!(
+            canned(json),
+            unhex(concat!(
+                "540000004200036100000000000000000000170004ffffffff0000",
+                "620000000000000000000019ffffffffffff0000",
+                "6300000000000000000000140008ffffffff0000",
+                "44000000140003000000013100000001",
+                "78ffffffff",
+                "430000000d53454c454354203100",
+                "5a0000000549"
+            ))
+        );
+    }
+
+    #[test]
+    fn empty_results_match_a_real_server_result() {
+        // select version() where false
+        let json = r#"{"columns": [{"name": "version", "type_oid": 25}], "rows": []}"#;
+        assert_eq!(
+            canned(json),
+            unhex(concat!(
+                "5400000020000176657273696f6e0000000000000000000019ffffffffffff0000",
+                "430000000d53454c4543542030005a0000000549"
+            ))
+        );
+        // set extra_float_digits = 3
+        assert_eq!(
+            canned(r#"{"command_tag": "SET"}"#),
+            unhex("4300000008534554005a0000000549")
+        );
+    }
+
+    #[test]
+    fn malformed_descriptions_are_rejected() {
+        for json in [
+            "[]",
+            r#"{"rows": []}"#,
+            r#"{"columns": [{"name": "a"}]}"#,
+            r#"{"columns": [{"name": "a", "type_oid": -1}]}"#,
+            r#"{"columns": [{"name": "a", "type_oid": 23}], "rows": [[1, 2]]}"#,
+            r#"{"columns": [{"name": "a", "type_oid": 23}], "rows": [[[1]]]}"#,
+            r#"{"rows": [[1]], "command_tag": "SELECT 1"}"#,
+        ] {
+            assert!(parse_result_description(json).is_err(), "{json}");
+        }
+        assert!(parse_canned_response("select 1").is_err());
+        assert!(parse_canned_response("select 1=>/nonexistent.json").is_err());
+    }
+
+    #[test]
+    fn results_wait_for_the_responses_owed_before_them() {
+        let replies = LocalReplies::default();
+        let ready = encode_ready_for_query(b'I');
+        let reply = encode_command_complete("SET");
+        // Startup ReadyForQuery, then the reply to a pipelined Query
+        replies.forwarded_request();
+        replies.push(reply.clone());
+        replies.forwarded_request();
+        let mut startup = encode_backend_message(b'K', &[0; 8]);
+        startup.extend(&ready);
+        assert_eq!(replies.interleave(&startup), vec![startup.clone()]);
+
+        let mut responses = encode_command_complete("SELECT 1");
+        responses.extend(&ready);
+        let first = responses.clone();
+        responses.extend(encode_command_complete("SELECT 2"));
+        responses.extend(encode_ready_for_query(b'T'));
+        let segments = replies.interleave(&responses);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0], first);
+        assert_eq!(segments[1], [reply.clone(), ready.clone()].concat());
+        assert_eq!(segments[2], responses[first.len()..]);
+
+        // Nothing owed: due straight away, with the latest status
+        replies.push(reply.clone());
+        assert_eq!(
+            replies.take_due(),
+            [reply, encode_ready_for_query(b'T')].concat()
+        );
+        assert!(replies.take_due().is_empty());
+    }
+
+    #[test]
+    fn injected_errors_report_the_failed_transaction_status() {
+        use crate::protocol::encode_error_response;
+
+        let replies = LocalReplies::default();
+        let error = encode_error_response("ERROR", "57014", "canceled");
+        replies.interleave(&encode_ready_for_query(b'T'));
+
+        // A Query the server never saw fails the transaction block
+        replies.push_error(error.clone(), false);
+        replies.push_ready();
+        assert_eq!(
+            replies.take_due(),
+            [error.clone(), encode_ready_for_query(b'E')].concat()
+        );
+
+        // After an error of the server's own in the same extended query
+        // the injected one is dropped
+        replies.push_error(error.clone(), true);
+        replies.forwarded_request();
+        let server = [
+            encode_error_response("ERROR", "42P01", "missing"),
+            encode_ready_for_query(b'E'),
+        ]
+        .concat();
+        assert_eq!(replies.interleave(&server), vec![server.clone()]);
+
+        // Outside a transaction block, here after a ROLLBACK, the status
+        // stays idle
+        replies.forwarded_request();
+        replies.interleave(&encode_ready_for_query(b'I'));
+        replies.push_error(error.clone(), false);
+        replies.push_ready();
+        assert_eq!(
+            replies.take_due(),
+            [error, encode_ready_for_query(b'I')].concat()
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// LINKTYPE_RAW: packets start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u16 = 101;
+/// Largest TCP payload per synthesized packet, so IPv4 total length fits
+const MAX_SEGMENT: usize = 65_000;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// A PCAP-NG file holding the forwarded bytes of every connection.
+///
+/// The proxy only sees the TCP payload, so each chunk is wrapped in
+/// synthesized IP and TCP headers between the client address and the
+/// upstream address. Wireshark then reassembles the stream and decodes it
+/// with its PostgreSQL dissector (use "Decode As" for a non-standard
+/// upstream port). For TLS clients the capture holds the decrypted session.
+#[derive(Debug)]
+pub struct CaptureFile {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl CaptureFile {
+    /// Create the file and write the section and interface headers
+    pub fn create(path: &Path) -> Result<Arc<Self>> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create capture file {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        write_header(&mut writer).context("Failed to write capture header")?;
+        writer.flush()?;
+        Ok(Arc::new(Self {
+            writer: Mutex::new(writer),
+        }))
+    }
+
+    /// Start a TCP stream in the capture, beginning with a handshake
+    pub fn stream(self: &Arc<Self>, client: SocketAddr, server: SocketAddr) -> CaptureStream {
+        let stream = CaptureStream {
+            file: self.clone(),
+            client,
+            server,
+            client_seq: AtomicU32::new(0),
+            server_seq: AtomicU32::new(0),
+        };
+        stream.packet(true, TCP_SYN, &[]);
+        stream.packet(false, TCP_SYN | TCP_ACK, &[]);
+        stream.packet(true, TCP_ACK, &[]);
+        stream
+    }
+
+    fn write_packet(&self, packet: &[u8], from_client: bool) {
+        let mut writer = self.writer.lock().unwrap();
+        let result =
+            write_enhanced_packet(&mut *writer, packet, from_client).and_then(|()| writer.flush());
+        if let Err(e) = result {
+            warn!("Failed to write capture packet: {}", e);
+        }
+    }
+}
+
+/// One proxied connection inside a capture file
+pub struct CaptureStream {
+    file: Arc<CaptureFile>,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: AtomicU32,
+    server_seq: AtomicU32,
+}
+
+impl CaptureStream {
+    /// Record bytes sent by the client (`from_client`) or by the server
+    pub fn record(&self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.packet(from_client, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    /// Record the end of the session: a FIN from the side that closed
+    /// first, the other side's FIN and the last ACK
+    pub fn close(&self, closed_by_client: bool) {
+        self.packet(closed_by_client, TCP_FIN | TCP_ACK, &[]);
+        self.packet(!closed_by_client, TCP_FIN | TCP_ACK, &[]);
+        self.packet(closed_by_client, TCP_ACK, &[]);
+    }
+
+    fn packet(&self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = if from_client {
+            (self.client, self.server, &self.client_seq, &self.server_seq)
+        } else {
+            (self.server, self.client, &self.server_seq, &self.client_seq)
+        };
+        let advance = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        let seq = seq.fetch_add(advance, Ordering::Relaxed);
+        let ack = if flags & TCP_ACK != 0 {
+            ack.load(Ordering::Relaxed)
+        } else {
+            0
+        };
+        let packet = build_packet(src, dst, seq, ack, flags, payload);
+        self.file.write_packet(&packet, from_client);
+    }
+}
+
+fn write_header(out: &mut impl Write) -> io::Result<()> {
+    // Section Header Block
+    out.write_all(&0x0A0D_0D0Au32.to_le_bytes())?;
+    out.write_all(&28u32.to_le_bytes())?;
+    out.write_all(&0x1A2B_3C4Du32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&(-1i64).to_le_bytes())?;
+    out.write_all(&28u32.to_le_bytes())?;
+
+    // Interface Description Block, microsecond timestamps (the default)
+    out.write_all(&1u32.to_le_bytes())?;
+    out.write_all(&20u32.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&20u32.to_le_bytes())
+}
+
+fn write_enhanced_packet(out: &mut impl Write, packet: &[u8], from_client: bool) -> io::Result<()> {
+    let padding = (4 - packet.len() % 4) % 4;
+    // Fixed fields, the padded packet, the epb_flags option and end of options
+    let total = 32 + packet.len() + padding + 8 + 4;
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    // epb_flags direction: 1 = inbound (towards the server), 2 = outbound
+    let direction: u32 = if from_client { 1 } else { 2 };
+
+    out.write_all(&6u32.to_le_bytes())?;
+    out.write_all(&(total as u32).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&((micros >> 32) as u32).to_le_bytes())?;
+    out.write_all(&(micros as u32).to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(packet)?;
+    out.write_all(&[0u8; 3][..padding])?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&direction.to_le_bytes())?;
+    out.write_all(&[0u8; 4])?;
+    out.write_all(&(total as u32).to_le_bytes())
+}
+
+/// An IP packet carrying one TCP segment; IPv4 addresses are mapped to
+/// IPv6 when the other side of the connection is IPv6
+fn build_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    // Checksum, filled in below, and urgent pointer
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let checksum = tcp_checksum(&src.octets(), &dst.octets(), &tcp);
+            tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = 6;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let (src, dst) = (to_v6(src).octets(), to_v6(dst).octets());
+            let checksum = tcp_checksum(&src, &dst, &tcp);
+            tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            packet.push(6);
+            packet.push(64);
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+        }
+    }
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    !fold_checksum(ones_complement_sum(header))
+}
+
+/// The TCP checksum over the IPv4 or IPv6 pseudo-header and the segment
+fn tcp_checksum(src: &[u8], dst: &[u8], segment: &[u8]) -> u16 {
+    let mut pseudo_header = [src, dst].concat();
+    if src.len() == 4 {
+        pseudo_header.extend_from_slice(&[0, 6]);
+        pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    } else {
+        pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+        pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+    }
+    !fold_checksum(ones_complement_sum(&pseudo_header) + ones_complement_sum(segment))
+}
+
+/// Sum of the big-endian 16-bit words of `data`, an odd last byte padded
+/// with zero
+fn ones_complement_sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split a capture into (block type, body) pairs
+    fn blocks(bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let block_type = u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+            let len = u32::from_le_bytes(bytes[i + 4..i + 8].try_into().unwrap()) as usize;
+            assert_eq!(&bytes[i + 4..i + 8], &bytes[i + len - 4..i + len]);
+            blocks.push((block_type, &bytes[i + 8..i + len - 4]));
+            i += len;
+        }
+        blocks
+    }
+
+    #[test]
+    fn capture_holds_a_tcp_stream_per_connection() {
+        let path = std::env::temp_dir().join(format!("capture-test-{}.pcapng", std::process::id()));
+        let file = CaptureFile::create(&path).unwrap();
+        let stream = file.stream(
+            "10.0.0.1:50000".parse().unwrap(),
+            "10.0.0.2:5432".parse().unwrap(),
+        );
+        stream.record(true, b"Q\0\0\0\x0dselect 1\0");
+        stream.record(false, b"Z\0\0\0\x05I");
+        stream.close(false);
+        drop(stream);
+        drop(file);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let blocks = blocks(&bytes);
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, [0x0A0D0D0A, 1, 6, 6, 6, 6, 6, 6, 6, 6]);
+        assert_eq!(&blocks[0].1[..4], &0x1A2B3C4Du32.to_le_bytes());
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_RAW.to_le_bytes());
+
+        // The query: IPv4 client → server, seq 1 after the handshake
+        let body = blocks[5].1;
+        let len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let packet = &body[20..20 + len];
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(
+            tcp_checksum(&packet[12..16], &packet[16..20], &packet[20..]),
+            0
+        );
+        assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+        let tcp = &packet[20..];
+        assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 50000);
+        assert_eq!(u16::from_be_bytes([tcp[2], tcp[3]]), 5432);
+        assert_eq!(u32::from_be_bytes(tcp[4..8].try_into().unwrap()), 1);
+        assert_eq!(&tcp[20..], b"Q\0\0\0\x0dselect 1\0");
+
+        // The reply acknowledges the whole query
+        let body = blocks[6].1;
+        let tcp = &body[20 + 20..];
+        assert_eq!(u32::from_be_bytes(tcp[8..12].try_into().unwrap()), 1 + 14);
+
+        // The server closed first: FIN from the server, FIN from the client
+        // acknowledging it, and the server's final ACK
+        let closing: Vec<(u16, u8, u32, u32)> = blocks[7..]
+            .iter()
+            .map(|(_, body)| {
+                let tcp = &body[20 + 20..];
+                (
+                    u16::from_be_bytes([tcp[0], tcp[1]]),
+                    tcp[13],
+                    u32::from_be_bytes(tcp[4..8].try_into().unwrap()),
+                    u32::from_be_bytes(tcp[8..12].try_into().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            closing,
+            [
+                (5432, TCP_FIN | TCP_ACK, 7, 15),
+                (50000, TCP_FIN | TCP_ACK, 15, 8),
+                (5432, TCP_ACK, 8, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn enhanced_packets_are_padded_and_carry_their_direction() {
+        let mut bytes = Vec::new();
+        write_enhanced_packet(&mut bytes, b"abcde", false).unwrap();
+        // 32 fixed bytes, 5 bytes padded to 8, the epb_flags option and
+        // the end of options
+        assert_eq!(bytes.len(), 32 + 8 + 8 + 4);
+        let blocks = blocks(&bytes);
+        let body = blocks[0].1;
+        assert_eq!(&body[12..20], &[5, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(&body[20..28], b"abcde\0\0\0");
+        assert_eq!(&body[28..36], &[2, 0, 4, 0, 2, 0, 0, 0]);
+        assert_eq!(&body[36..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ipv6_segments_have_valid_checksums() {
+        let packet = build_packet(
+            "[::1]:50000".parse().unwrap(),
+            "127.0.0.1:5432".parse().unwrap(),
+            1,
+            1,
+            TCP_PSH | TCP_ACK,
+            b"odd",
+        );
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(
+            &packet[24..40],
+            &"::ffff:127.0.0.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        assert_eq!(
+            tcp_checksum(&packet[8..24], &packet[24..40], &packet[40..]),
+            0
+        );
+    }
+
+    /// Opens a capture with tshark and checks the PostgreSQL dissector
+    /// decodes it without TCP analysis warnings. Needs tshark on the PATH;
+    /// runs only when PG_WIRE_PROXY_TSHARK_TEST is set.
+    #[test]
+    fn tshark_decodes_the_capture() {
+        if std::env::var_os("PG_WIRE_PROXY_TSHARK_TEST").is_none() {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("tshark-test-{}.pcapng", std::process::id()));
+        let file = CaptureFile::create(&path).unwrap();
+        let stream = file.stream(
+            "127.0.0.1:50000".parse().unwrap(),
+            "127.0.0.1:5432".parse().unwrap(),
+        );
+        stream.record(true, b"Q\0\0\0\x0dselect 1\0");
+        stream.record(false, b"C\0\0\0\x0dSELECT 1\0Z\0\0\0\x05I");
+        stream.close(true);
+        drop(stream);
+        drop(file);
+
+        let tshark = |args: &[&str]| {
+            let output = std::process::Command::new("tshark")
+                .arg("-r")
+                .arg(&path)
+                .args(args)
+                .output()
+                .expect("tshark is not installed");
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let queries = tshark(&["-Y", "pgsql.query", "-T", "fields", "-e", "pgsql.query"]);
+        let warnings = tshark(&[
+            "-o",
+            "tcp.check_checksum:TRUE",
+            "-Y",
+            "tcp.analysis.flags || tcp.checksum.status == 0",
+        ]);
+        let packets = tshark(&[]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(queries.trim(), "select 1");
+        assert_eq!(warnings, "");
+        assert_eq!(packets.lines().count(), 8);
+    }
+}
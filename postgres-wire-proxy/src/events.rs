@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::audit::json_string;
+
+enum Command {
+    Open { id: u64, path: PathBuf, file: File },
+    Write { id: u64, line: String },
+    Close(u64),
+    Finish(oneshot::Sender<()>),
+}
+
+/// Directory of per-connection event files (--events-dir). Each connection
+/// writes `conn-<id>.jsonl` with one JSON object per decoded message; IDs
+/// continue after the highest file already in the directory. The forwarding
+/// path only queues events, and a writer task writes and flushes them.
+#[derive(Debug)]
+pub struct EventsDir {
+    dir: PathBuf,
+    last_id: AtomicU64,
+    sender: UnboundedSender<Command>,
+}
+
+impl EventsDir {
+    /// Create `dir` when missing, find the last connection ID used in it and
+    /// start the writer task
+    pub fn open(dir: &Path) -> Result<Arc<Self>> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create events directory {}", dir.display()))?;
+        let mut last_id = 0;
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read events directory {}", dir.display()))?;
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(connection_id) {
+                last_id = last_id.max(id);
+            }
+        }
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_events(receiver));
+        Ok(Arc::new(Self {
+            dir: dir.to_path_buf(),
+            last_id: AtomicU64::new(last_id),
+            sender,
+        }))
+    }
+
+    /// Create the file of a new connection, numbered after every earlier one
+    pub fn connection(&self) -> Result<EventFile> {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = self.dir.join(format!("conn-{}.jsonl", id));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create event file {}", path.display()))?;
+        let _ = self.sender.send(Command::Open { id, path, file });
+        Ok(EventFile {
+            id,
+            sender: self.sender.clone(),
+        })
+    }
+
+    /// Write out everything queued so far and stop the writer
+    pub async fn finish(&self) {
+        let (done, finished) = oneshot::channel();
+        if self.sender.send(Command::Finish(done)).is_ok() {
+            let _ = finished.await;
+        }
+    }
+}
+
+/// The ID in a file name of the form `conn-<id>.jsonl`
+fn connection_id(file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix("conn-")?
+        .strip_suffix(".jsonl")?
+        .parse()
+        .ok()
+}
+
+/// The event file of one connection; dropping it closes the file once the
+/// events queued before have been written
+#[derive(Debug)]
+pub struct EventFile {
+    id: u64,
+    sender: UnboundedSender<Command>,
+}
+
+impl EventFile {
+    /// Queue `event`, the message numbered `sequence` in its direction
+    pub fn write(&self, sequence: u64, event: &Event) {
+        let mut line = event.to_json(self.id, sequence);
+        line.push('\n');
+        // Only fails once the writer has finished at shutdown
+        let _ = self.sender.send(Command::Write { id: self.id, line });
+    }
+}
+
+impl Drop for EventFile {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Close(self.id));
+    }
+}
+
+/// Write events as they arrive, flushing whenever the queue runs empty so
+/// the files are current even if the proxy is killed
+async fn write_events(mut commands: UnboundedReceiver<Command>) {
+    let mut files: HashMap<u64, (PathBuf, BufWriter<tokio::fs::File>)> = HashMap::new();
+    let mut written = HashSet::new();
+    while let Some(command) = commands.recv().await {
+        let mut next = Some(command);
+        let mut finished = None;
+        while let Some(command) = next {
+            match command {
+                Command::Open { id, path, file } => {
+                    let file = BufWriter::new(tokio::fs::File::from_std(file));
+                    files.insert(id, (path, file));
+                }
+                Command::Write { id, line } => {
+                    if let Some((path, file)) = files.get_mut(&id) {
+                        if let Err(e) = file.write_all(line.as_bytes()).await {
+                            warn!("Failed to write event to {}: {}", path.display(), e);
+                        }
+                        written.insert(id);
+                    }
+                }
+                Command::Close(id) => {
+                    if let Some((path, mut file)) = files.remove(&id) {
+                        if let Err(e) = file.flush().await {
+                            warn!("Failed to flush {}: {}", path.display(), e);
+                        }
+                    }
+                }
+                Command::Finish(done) => {
+                    finished = Some(done);
+                    break;
+                }
+            }
+            next = commands.try_recv().ok();
+        }
+        for id in written.drain() {
+            if let Some((path, file)) = files.get_mut(&id) {
+                if let Err(e) = file.flush().await {
+                    warn!("Failed to flush {}: {}", path.display(), e);
+                }
+            }
+        }
+        if let Some(done) = finished {
+            let _ = done.send(());
+            return;
+        }
+    }
+}
+
+/// One decoded message as written to an event file: the fields every event
+/// has, then those of its message type in the order they were added
+pub struct Event {
+    timestamp: OffsetDateTime,
+    from_client: bool,
+    message: &'static str,
+    bytes: usize,
+    fields: String,
+}
+
+impl Event {
+    /// An event for a message of `bytes` body bytes, timestamped now
+    pub fn new(from_client: bool, message: &'static str, bytes: usize) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            from_client,
+            message,
+            bytes,
+            fields: String::new(),
+        }
+    }
+
+    pub fn string(&mut self, key: &str, value: &str) -> &mut Self {
+        self.json(key, json_string(value))
+    }
+
+    pub fn number(&mut self, key: &str, value: impl Display) -> &mut Self {
+        self.json(key, value)
+    }
+
+    /// An array of strings; `None` is written as null
+    pub fn strings<'a>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = Option<&'a str>>,
+    ) -> &mut Self {
+        let values: Vec<String> = values
+            .into_iter()
+            .map(|value| value.map_or_else(|| "null".to_string(), json_string))
+            .collect();
+        self.json(key, format_args!("[{}]", values.join(",")))
+    }
+
+    pub fn numbers<T: Display>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> &mut Self {
+        let values: Vec<String> = values.into_iter().map(|value| value.to_string()).collect();
+        self.json(key, format_args!("[{}]", values.join(",")))
+    }
+
+    /// Milliseconds since the request the message completes was sent
+    pub fn duration(&mut self, duration: Duration) -> &mut Self {
+        self.json(
+            "duration_ms",
+            format_args!("{:.3}", duration.as_secs_f64() * 1000.0),
+        )
+    }
+
+    /// A field whose value is already JSON
+    pub fn json(&mut self, key: &str, value: impl Display) -> &mut Self {
+        let _ = write!(self.fields, ",{}:{}", json_string(key), value);
+        self
+    }
+
+    fn to_json(&self, connection: u64, sequence: u64) -> String {
+        let timestamp = self.timestamp.format(&Rfc3339).unwrap_or_default();
+        format!(
+            "{{\"timestamp\":{},\"connection\":{},\"sequence\":{},\"direction\":{},\"type\":{},\"bytes\":{}{}}}",
+            json_string(&timestamp),
+            connection,
+            sequence,
+            if self.from_client {
+                "\"client\""
+            } else {
+                "\"server\""
+            },
+            json_string(self.message),
+            self.bytes,
+            self.fields
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_is_one_json_line() {
+        let mut event = Event::new(false, "CommandComplete", 9);
+        event.timestamp = OffsetDateTime::UNIX_EPOCH;
+        event
+            .string("tag", "SELECT 1")
+            .number("rows", 1)
+            .duration(Duration::from_micros(1500))
+            .strings("params", [Some("'42'"), None])
+            .numbers("param_types", [23, 25]);
+        assert_eq!(
+            event.to_json(3, 7),
+            "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"connection\":3,\"sequence\":7,\
+             \"direction\":\"server\",\"type\":\"CommandComplete\",\"bytes\":9,\
+             \"tag\":\"SELECT 1\",\"rows\":1,\"duration_ms\":1.500,\
+             \"params\":[\"'42'\",null],\"param_types\":[23,25]}"
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_ids_continue_after_existing_files() {
+        let dir = std::env::temp_dir().join(format!("events-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("conn-4.jsonl"), "").unwrap();
+        fs::write(dir.join("conn-x.jsonl"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let events = EventsDir::open(&dir).unwrap();
+        let first = events.connection().unwrap();
+        let second = events.connection().unwrap();
+        assert_eq!((first.id, second.id), (5, 6));
+        assert!(dir.join("conn-5.jsonl").exists());
+        assert!(dir.join("conn-6.jsonl").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
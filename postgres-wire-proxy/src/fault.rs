@@ -0,0 +1,896 @@
+use clap::ValueEnum;
+use regex_automata::meta::Regex;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Forwarded reads a paced direction may hold before reading stalls
+const PACED_QUEUE_DEPTH: usize = 64;
+
+const CLIENT_MESSAGES: &[(&str, u8)] = &[
+    ("Query", b'Q'),
+    ("Parse", b'P'),
+    ("Bind", b'B'),
+    ("Execute", b'E'),
+    ("Describe", b'D'),
+    ("Close", b'C'),
+    ("Sync", b'S'),
+    ("Flush", b'H'),
+    ("Terminate", b'X'),
+    ("FunctionCall", b'F'),
+    ("CopyData", b'd'),
+    ("CopyDone", b'c'),
+    ("CopyFail", b'f'),
+    ("PasswordMessage", b'p'),
+];
+
+const SERVER_MESSAGES: &[(&str, u8)] = &[
+    ("AuthenticationRequest", b'R'),
+    ("ParameterStatus", b'S'),
+    ("BackendKeyData", b'K'),
+    ("ReadyForQuery", b'Z'),
+    ("RowDescription", b'T'),
+    ("DataRow", b'D'),
+    ("CommandComplete", b'C'),
+    ("ErrorResponse", b'E'),
+    ("NoticeResponse", b'N'),
+    ("ParseComplete", b'1'),
+    ("BindComplete", b'2'),
+    ("CloseComplete", b'3'),
+    ("ParameterDescription", b't'),
+    ("NoData", b'n'),
+    ("EmptyQueryResponse", b'I'),
+    ("PortalSuspended", b's'),
+    ("CopyInResponse", b'G'),
+    ("CopyOutResponse", b'H'),
+    ("CopyBothResponse", b'W'),
+    ("CopyData", b'd'),
+    ("CopyDone", b'c'),
+    ("NotificationResponse", b'A'),
+    ("FunctionCallResponse", b'V'),
+    ("NegotiateProtocolVersion", b'v'),
+];
+
+/// Drop the `nth` message of one type travelling in one direction
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropRule {
+    /// Whether the message is sent by the client
+    pub from_client: bool,
+    pub msg_type: u8,
+    /// 1-based occurrence of the message type on the connection
+    pub nth: u64,
+}
+
+/// Parse one `[client:|server:]<type>:<nth>` argument of --drop-type.
+///
+/// The type is a message name such as `DataRow` or, with a direction, a
+/// type letter such as `server:D`. Names sent in both directions (CopyData,
+/// CopyDone) also need the direction.
+pub fn parse_drop_rule(value: &str) -> Result<DropRule, String> {
+    let (spec, nth) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected <type>:<nth>, got '{}'", value))?;
+    let nth = match nth.parse::<u64>() {
+        Ok(nth) if nth > 0 => nth,
+        _ => return Err(format!("invalid occurrence '{}', expected 1 or more", nth)),
+    };
+    let (direction, name) = match spec.split_once(':') {
+        Some(("client", name)) => (Some(true), name),
+        Some(("server", name)) => (Some(false), name),
+        Some((direction, _)) => {
+            return Err(format!(
+                "invalid direction '{}', expected client or server",
+                direction
+            ))
+        }
+        None => (None, spec),
+    };
+
+    let lookup = |table: &[(&str, u8)]| {
+        table
+            .iter()
+            .find(|(known, msg_type)| {
+                known.eq_ignore_ascii_case(name)
+                    || (direction.is_some() && name.as_bytes() == [*msg_type])
+            })
+            .map(|(_, msg_type)| *msg_type)
+    };
+    let (from_client, msg_type) =
+        match (direction, lookup(CLIENT_MESSAGES), lookup(SERVER_MESSAGES)) {
+            (Some(true), Some(msg_type), _) => (true, msg_type),
+            (Some(false), _, Some(msg_type)) => (false, msg_type),
+            (None, Some(msg_type), None) => (true, msg_type),
+            (None, None, Some(msg_type)) => (false, msg_type),
+            (None, Some(_), Some(_)) => {
+                return Err(format!(
+                    "'{}' is sent in both directions, use client:{} or server:{}",
+                    name, name, name
+                ))
+            }
+            _ => return Err(format!("unknown message type '{}'", spec)),
+        };
+    Ok(DropRule {
+        from_client,
+        msg_type,
+        nth,
+    })
+}
+
+/// Name of a message type sent in the given direction
+pub fn message_name(from_client: bool, msg_type: u8) -> &'static str {
+    let table = if from_client {
+        CLIENT_MESSAGES
+    } else {
+        SERVER_MESSAGES
+    };
+    table
+        .iter()
+        .find(|(_, known)| *known == msg_type)
+        .map_or("unknown message", |(name, _)| name)
+}
+
+/// Applies the drop rules of one direction of a connection
+pub struct MessageDropper {
+    from_client: bool,
+    rules: Vec<DropRule>,
+    /// Messages seen so far per type, dropped ones included
+    seen: HashMap<u8, u64>,
+}
+
+impl MessageDropper {
+    pub fn new(rules: &[DropRule], from_client: bool) -> Self {
+        Self {
+            from_client,
+            rules: rules
+                .iter()
+                .filter(|rule| rule.from_client == from_client)
+                .cloned()
+                .collect(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether any rule applies to this direction
+    pub fn is_active(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Remove the messages matched by a rule from a run of complete messages
+    pub fn filter(&mut self, messages: &[u8], client_addr: &str) -> Vec<u8> {
+        let mut forwarded = Vec::with_capacity(messages.len());
+        let mut buf = messages;
+        while buf.len() >= 5 {
+            let length = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+            let (message, rest) = buf.split_at((length + 1).min(buf.len()));
+            let msg_type = message[0];
+            let seen = self.seen.entry(msg_type).or_default();
+            *seen += 1;
+            let nth = *seen;
+            if self
+                .rules
+                .iter()
+                .any(|rule| rule.msg_type == msg_type && rule.nth == nth)
+            {
+                warn!(
+                    "[{}] DROPPED {} #{} ({} bytes) instead of forwarding it to the {}",
+                    client_addr,
+                    message_name(self.from_client, msg_type),
+                    nth,
+                    message.len(),
+                    if self.from_client { "server" } else { "client" }
+                );
+            } else {
+                forwarded.extend_from_slice(message);
+            }
+            buf = rest;
+        }
+        forwarded.extend_from_slice(buf);
+        forwarded
+    }
+}
+
+/// Connections closed when a kill condition triggers
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum KillSide {
+    Client,
+    Server,
+    #[default]
+    Both,
+}
+
+impl KillSide {
+    pub fn closes_client(self) -> bool {
+        self != KillSide::Server
+    }
+
+    pub fn closes_server(self) -> bool {
+        self != KillSide::Client
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            KillSide::Client => "the client connection",
+            KillSide::Server => "the server connection",
+            KillSide::Both => "both connections",
+        }
+    }
+}
+
+/// A --kill-on-query regex, kept with its source for the log
+#[derive(Clone, Debug)]
+pub struct QueryPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl std::fmt::Display for QueryPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl QueryPattern {
+    pub fn is_match(&self, sql: &str) -> bool {
+        self.regex.is_match(sql)
+    }
+}
+
+pub fn parse_query_pattern(value: &str) -> Result<QueryPattern, String> {
+    let regex = Regex::new(value).map_err(|e| format!("invalid regex: {}", e))?;
+    Ok(QueryPattern {
+        pattern: value.to_string(),
+        regex,
+    })
+}
+
+/// Conditions that cut a connection in the middle of a session
+#[derive(Clone, Debug, Default)]
+pub struct KillSwitch {
+    /// Client messages forwarded (not counting the startup message) before
+    /// the connection is cut
+    pub after_messages: Option<u64>,
+    /// Cut after forwarding a Query or Parse whose SQL matches
+    pub on_query: Option<QueryPattern>,
+    pub side: KillSide,
+}
+
+impl KillSwitch {
+    pub fn is_active(&self) -> bool {
+        self.after_messages.is_some() || self.on_query.is_some()
+    }
+
+    /// Why the client message numbered `sequence` trips the switch, if it does
+    pub fn trigger(&self, sequence: u64, msg_type: u8, data: &[u8]) -> Option<String> {
+        if self.after_messages == Some(sequence) {
+            return Some(format!("client message #{}", sequence));
+        }
+        let pattern = self.on_query.as_ref()?;
+        let sql = match msg_type {
+            b'Q' => data.split(|&b| b == 0).next(),
+            // The statement name comes first
+            b'P' => data.split(|&b| b == 0).nth(1),
+            _ => None,
+        }?;
+        pattern
+            .is_match(String::from_utf8_lossy(sql).as_ref())
+            .then(|| format!("{} matching /{}/", message_name(true, msg_type), pattern))
+    }
+
+    /// The warning logged when the switch trips
+    pub fn describe(&self, reason: &str) -> String {
+        format!(
+            "Fault injected: closing {} after {}",
+            self.side.describe(),
+            reason
+        )
+    }
+}
+
+/// SQLSTATE and message of --error-on-query when none are given
+const DEFAULT_INJECTED_ERROR: (&str, &str) = ("57014", "canceling statement due to user request");
+
+/// SQLSTATE and message answering a statement matched by --block-query
+const BLOCKED_QUERY_ERROR: (&str, &str) = ("42501", "blocked by postgres-wire-proxy policy");
+
+/// Patterns blocked by --block-destructive-queries
+pub const DESTRUCTIVE_QUERY_PATTERNS: &[&str] = &[
+    r"(?i)\bdrop\s+(table|database|schema)\b",
+    r"(?i)\btruncate\b",
+];
+
+/// Fail statements matching a regex with an ErrorResponse of our choosing
+#[derive(Clone, Debug)]
+pub struct ErrorRule {
+    pub pattern: QueryPattern,
+    pub code: String,
+    pub message: String,
+    /// From --block-query: a matching Parse is withheld too, so the SQL
+    /// never reaches the server
+    pub block: bool,
+}
+
+/// Parse one `<regex>[=SQLSTATE[:message]]` argument of --error-on-query
+pub fn parse_error_rule(value: &str) -> Result<ErrorRule, String> {
+    // The regex may contain '=' itself, so only a suffix that reads as a
+    // SQLSTATE splits it off
+    let split = value.match_indices('=').find_map(|(at, _)| {
+        let (code, message) = match value[at + 1..].split_once(':') {
+            Some((code, message)) => (code, Some(message)),
+            None => (&value[at + 1..], None),
+        };
+        let is_sqlstate = code.len() == 5
+            && code
+                .bytes()
+                .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase());
+        is_sqlstate.then_some((at, code, message))
+    });
+    let (pattern, code, message) = match split {
+        Some((at, code, message)) => (
+            &value[..at],
+            code,
+            message.unwrap_or("error injected by postgres-wire-proxy"),
+        ),
+        None => (value, DEFAULT_INJECTED_ERROR.0, DEFAULT_INJECTED_ERROR.1),
+    };
+    Ok(ErrorRule {
+        pattern: parse_query_pattern(pattern)?,
+        code: code.to_string(),
+        message: message.to_string(),
+        block: false,
+    })
+}
+
+/// Parse one regex argument of --block-query
+pub fn parse_block_rule(value: &str) -> Result<ErrorRule, String> {
+    Ok(ErrorRule {
+        pattern: parse_query_pattern(value)?,
+        code: BLOCKED_QUERY_ERROR.0.to_string(),
+        message: BLOCKED_QUERY_ERROR.1.to_string(),
+        block: true,
+    })
+}
+
+/// Delay added before each message forwarded in one direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InjectedLatency {
+    pub delay: Duration,
+    /// Upper bound of a uniformly random extra delay
+    pub jitter: Duration,
+}
+
+impl InjectedLatency {
+    pub fn is_active(&self) -> bool {
+        !self.delay.is_zero() || !self.jitter.is_zero()
+    }
+
+    fn next_delay(&self) -> Duration {
+        let jitter_us = self.jitter.as_micros() as u64;
+        if jitter_us == 0 {
+            return self.delay;
+        }
+        self.delay + Duration::from_micros(rand::random_range(0..=jitter_us))
+    }
+}
+
+/// Token bucket limiting the bytes forwarded in one direction
+#[derive(Debug)]
+pub struct Throttle {
+    /// Bytes per second
+    rate: f64,
+    /// Most bytes sent back to back after an idle period
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    throughput: Arc<Throughput>,
+}
+
+impl Throttle {
+    pub fn new(kbps: u64) -> Self {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        let burst = (rate / 10.0).max(1024.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+            throughput: Arc::default(),
+        }
+    }
+
+    /// Bytes written through this throttle so far
+    pub fn throughput(&self) -> Arc<Throughput> {
+        self.throughput.clone()
+    }
+
+    /// Wait until `bytes` (at most one burst) may be sent
+    async fn acquire(&mut self, bytes: usize) {
+        self.refill();
+        let deficit = bytes as f64 - self.tokens;
+        if deficit > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(deficit / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= bytes as f64;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+
+    async fn write<W>(&mut self, writer: &mut W, data: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        for chunk in data.chunks(self.burst as usize) {
+            self.acquire(chunk.len()).await;
+            let started = Instant::now();
+            writer.write_all(chunk).await?;
+            self.throughput.record(started, chunk.len());
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket limiting the messages forwarded in one direction
+#[derive(Debug)]
+pub struct MessageRate {
+    /// Messages per second
+    rate: f64,
+    /// Most messages sent back to back after an idle period
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl MessageRate {
+    pub fn new(per_second: u64) -> Self {
+        let rate = per_second as f64;
+        let burst = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Wait until one more message may be sent
+    async fn acquire(&mut self) {
+        self.refill();
+        let deficit = 1.0 - self.tokens;
+        if deficit > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(deficit / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+/// Bytes that went through a throttle, and over how long
+#[derive(Debug, Default)]
+pub struct Throughput {
+    bytes: AtomicU64,
+    /// Start of the first write and end of the last one
+    span: Mutex<Option<(Instant, Instant)>>,
+}
+
+impl Throughput {
+    fn record(&self, started: Instant, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut span = self.span.lock().unwrap();
+        let first = span.map_or(started, |(first, _)| first);
+        *span = Some((first, Instant::now()));
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Average rate between the first write and the end of the last one
+    pub fn average_kbps(&self) -> Option<f64> {
+        let (first, last) = (*self.span.lock().unwrap())?;
+        let elapsed = last.duration_since(first).as_secs_f64();
+        (elapsed > 0.0).then(|| self.bytes() as f64 * 8.0 / 1000.0 / elapsed)
+    }
+}
+
+/// Injected latency, message rate limit and throttling of one direction
+#[derive(Debug, Default)]
+pub struct Pacer {
+    pub latency: InjectedLatency,
+    pub message_rate: Option<MessageRate>,
+    pub throttle: Option<Throttle>,
+}
+
+impl Pacer {
+    pub fn is_active(&self) -> bool {
+        self.latency.is_active() || self.message_rate.is_some() || self.throttle.is_some()
+    }
+
+    /// Write forwarded bytes; with latency or a message rate they must be
+    /// complete messages, each of which is written after its own delay
+    async fn write<W>(&mut self, writer: &mut W, messages: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let per_message = self.latency.is_active() || self.message_rate.is_some();
+        let mut buf = messages;
+        while !buf.is_empty() {
+            let end = match buf.get(1..5).filter(|_| per_message) {
+                Some(length) => {
+                    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+                    (length as usize + 1).min(buf.len())
+                }
+                None => buf.len(),
+            };
+            let (message, rest) = buf.split_at(end);
+            if self.latency.is_active() {
+                tokio::time::sleep(self.latency.next_delay()).await;
+            }
+            if let Some(message_rate) = &mut self.message_rate {
+                message_rate.acquire().await;
+            }
+            match &mut self.throttle {
+                Some(throttle) => throttle.write(writer, message).await?,
+                None => writer.write_all(message).await?,
+            }
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+/// Where one direction forwards its bytes. With latency or throttling the
+/// writes happen on their own task, so reading and logging keep pace with
+/// the sender while the peer receives the bytes late.
+pub enum Outbound<W> {
+    Direct(W),
+    Paced {
+        queue: mpsc::Sender<Vec<u8>>,
+        /// Taken once the writer has stopped and its result was collected
+        writer: Option<JoinHandle<io::Result<()>>>,
+    },
+}
+
+impl<W> Outbound<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(mut writer: W, mut pacer: Pacer) -> Self {
+        if !pacer.is_active() {
+            return Outbound::Direct(writer);
+        }
+        let (queue, mut pending) = mpsc::channel::<Vec<u8>>(PACED_QUEUE_DEPTH);
+        let writer = tokio::spawn(async move {
+            while let Some(messages) = pending.recv().await {
+                pacer.write(&mut writer, &messages).await?;
+            }
+            Ok(())
+        });
+        Outbound::Paced {
+            queue,
+            writer: Some(writer),
+        }
+    }
+
+    /// Forward bytes, or queue them for the paced writer
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Outbound::Direct(writer) => writer.write_all(data).await,
+            Outbound::Paced { queue, writer } => {
+                if queue.send(data.to_vec()).await.is_ok() {
+                    return Ok(());
+                }
+                // The paced writer only stops early when a write failed
+                match writer.take() {
+                    Some(writer) => writer.await.unwrap_or_else(|e| Err(io::Error::other(e))),
+                    None => Err(io::ErrorKind::BrokenPipe.into()),
+                }
+            }
+        }
+    }
+
+    /// Wait until everything queued has been written
+    pub async fn finish(self) -> io::Result<()> {
+        match self {
+            Outbound::Direct(_) => Ok(()),
+            Outbound::Paced { queue, writer } => {
+                drop(queue);
+                match writer {
+                    Some(writer) => writer.await.unwrap_or_else(|e| Err(io::Error::other(e))),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::Instant;
+
+    fn message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![msg_type];
+        message.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn drop_rules_are_parsed() {
+        let rule = |from_client, msg_type, nth| DropRule {
+            from_client,
+            msg_type,
+            nth,
+        };
+        assert_eq!(parse_drop_rule("DataRow:3"), Ok(rule(false, b'D', 3)));
+        assert_eq!(parse_drop_rule("sync:1"), Ok(rule(true, b'S', 1)));
+        assert_eq!(
+            parse_drop_rule("client:CopyData:2"),
+            Ok(rule(true, b'd', 2))
+        );
+        assert_eq!(parse_drop_rule("server:Z:1"), Ok(rule(false, b'Z', 1)));
+        assert!(parse_drop_rule("CopyData:2").is_err());
+        assert!(parse_drop_rule("Z:1").is_err());
+        assert!(parse_drop_rule("DataRow:0").is_err());
+        assert!(parse_drop_rule("DataRow").is_err());
+        assert!(parse_drop_rule("backend:DataRow:1").is_err());
+    }
+
+    #[test]
+    fn only_the_nth_message_of_the_type_is_dropped() {
+        let rules = [
+            parse_drop_rule("DataRow:2").unwrap(),
+            parse_drop_rule("Sync:1").unwrap(),
+        ];
+        let mut dropper = MessageDropper::new(&rules, false);
+        assert!(dropper.is_active());
+
+        let first = [message(b'D', b"1"), message(b'D', b"2")].concat();
+        assert_eq!(dropper.filter(&first, "test"), message(b'D', b"1"));
+        let second = [message(b'D', b"3"), message(b'Z', b"I")].concat();
+        assert_eq!(dropper.filter(&second, "test"), second);
+
+        assert!(!MessageDropper::new(&rules[..1], true).is_active());
+    }
+
+    #[test]
+    fn kill_switch_matches_query_and_parse_sql() {
+        let switch = KillSwitch {
+            after_messages: Some(4),
+            on_query: Some(parse_query_pattern("(?i)^update ").unwrap()),
+            side: KillSide::Client,
+        };
+        assert_eq!(
+            switch.trigger(1, b'Q', b"UPDATE t SET a = 1\0"),
+            Some("Query matching /(?i)^update /".to_string())
+        );
+        assert_eq!(
+            switch.trigger(2, b'P', b"s1\0update t set a = $1\0\0\0"),
+            Some("Parse matching /(?i)^update /".to_string())
+        );
+        assert_eq!(switch.trigger(3, b'Q', b"select 'update '\0"), None);
+        assert_eq!(
+            switch.trigger(4, b'S', b""),
+            Some("client message #4".to_string())
+        );
+        assert!(!KillSwitch::default().is_active());
+        assert!(parse_query_pattern("(unclosed").is_err());
+    }
+
+    #[test]
+    fn error_rules_are_parsed() {
+        let rule = parse_error_rule("^update").unwrap();
+        assert_eq!(
+            (rule.code.as_str(), rule.message.as_str()),
+            DEFAULT_INJECTED_ERROR
+        );
+        let rule = parse_error_rule("insert into t=23505:duplicate key value").unwrap();
+        assert!(rule.pattern.is_match("insert into t values (1)"));
+        assert_eq!(rule.code, "23505");
+        assert_eq!(rule.message, "duplicate key value");
+        let rule = parse_error_rule("where id = 1=40001").unwrap();
+        assert!(rule.pattern.is_match("select * from t where id = 1"));
+        assert_eq!(rule.code, "40001");
+        // Not a SQLSTATE, so part of the regex
+        let rule = parse_error_rule("a=b").unwrap();
+        assert!(rule.pattern.is_match("a=b"));
+        assert_eq!(rule.code, "57014");
+        assert!(!rule.block);
+
+        let rule = parse_block_rule(r"(?i)drop\s+table").unwrap();
+        assert!(rule.pattern.is_match("DROP  TABLE accounts"));
+        assert_eq!(
+            (rule.code.as_str(), rule.message.as_str()),
+            BLOCKED_QUERY_ERROR
+        );
+        assert!(rule.block);
+        for pattern in DESTRUCTIVE_QUERY_PATTERNS {
+            assert!(parse_block_rule(pattern).is_ok());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_message_is_delayed() {
+        let latency = InjectedLatency {
+            delay: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+        };
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let messages = [
+            message(b'Q', b"select 1\0"),
+            message(b'S', b""),
+            message(b'X', b""),
+        ];
+        let stream = messages.concat();
+        let start = Instant::now();
+        let mut outbound = Outbound::new(
+            writer,
+            Pacer {
+                latency,
+                message_rate: None,
+                throttle: None,
+            },
+        );
+        outbound.write(&stream).await.unwrap();
+        // Queued, not written yet
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        for (i, expected) in messages.iter().enumerate() {
+            let mut received = vec![0; expected.len()];
+            reader.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, expected);
+            assert_eq!(start.elapsed(), Duration::from_millis(50) * (i as u32 + 1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_stays_within_its_bound() {
+        let latency = InjectedLatency {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+        };
+        assert!(latency.is_active());
+        assert!(!InjectedLatency::default().is_active());
+        for _ in 0..100 {
+            let delay = latency.next_delay();
+            assert!(delay >= Duration::from_millis(20) && delay <= Duration::from_millis(30));
+        }
+
+        let mut sink = Vec::new();
+        let start = Instant::now();
+        let mut pacer = Pacer {
+            latency,
+            message_rate: None,
+            throttle: None,
+        };
+        pacer.write(&mut sink, &message(b'Z', b"I")).await.unwrap();
+        assert_eq!(sink, message(b'Z', b"I"));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(start.elapsed() <= Duration::from_millis(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_transfer_keeps_to_the_rate() {
+        // 800 kbps is 100,000 bytes a second with a 10,000 byte burst
+        let throttle = Throttle::new(800);
+        let throughput = throttle.throughput();
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let mut outbound = Outbound::new(
+            writer,
+            Pacer {
+                latency: InjectedLatency::default(),
+                message_rate: None,
+                throttle: Some(throttle),
+            },
+        );
+
+        let start = Instant::now();
+        let receiver = tokio::spawn(async move {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            (received.len(), start.elapsed())
+        });
+        let copy_data = message(b'd', &[b'x'; 8187]);
+        // Writes are queued while the writer is throttled, so reading carries
+        // on until the queue is full
+        for _ in 0..PACED_QUEUE_DEPTH / 2 {
+            outbound.write(&copy_data).await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in PACED_QUEUE_DEPTH / 2..128 {
+            outbound.write(&copy_data).await.unwrap();
+        }
+        outbound.finish().await.unwrap();
+
+        let (received, elapsed) = receiver.await.unwrap();
+        assert_eq!(received, 1024 * 1024);
+        let expected = Duration::from_secs_f64((1024.0 * 1024.0 - 10_000.0) / 100_000.0);
+        assert!(elapsed >= expected, "{:?}", elapsed);
+        assert!(
+            elapsed < expected + Duration::from_millis(200),
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(throughput.bytes(), 1024 * 1024);
+        let average = throughput.average_kbps().unwrap();
+        assert!((800.0..820.0).contains(&average), "{}", average);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn small_messages_pass_an_idle_throttle_at_once() {
+        let mut pacer = Pacer {
+            latency: InjectedLatency::default(),
+            message_rate: None,
+            throttle: Some(Throttle::new(8)),
+        };
+        let mut sink = Vec::new();
+        let start = Instant::now();
+        pacer.write(&mut sink, &[b'x'; 1024]).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The burst is spent, so the next kilobyte waits a full second
+        pacer.write(&mut sink, &[b'x'; 1024]).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(1024));
+
+        // After idling the bucket is full again
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let resumed = Instant::now();
+        pacer
+            .write(&mut sink, &message(b'Q', b"select 1\0"))
+            .await
+            .unwrap();
+        assert_eq!(resumed.elapsed(), Duration::ZERO);
+        assert_eq!(sink.len(), 2048 + 14);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn message_rate_spaces_out_whole_messages() {
+        // 20 messages a second with a burst of 2
+        let mut pacer = Pacer {
+            latency: InjectedLatency::default(),
+            message_rate: Some(MessageRate::new(20)),
+            throttle: None,
+        };
+        let rows: Vec<Vec<u8>> = (0..6).map(|i| message(b'D', &[i; 3])).collect();
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        let start = Instant::now();
+        let stream = rows.concat();
+        let writes = async {
+            pacer.write(&mut writer, &stream).await.unwrap();
+            drop(writer);
+        };
+        let reads = async {
+            let mut arrivals = Vec::new();
+            for row in &rows {
+                let mut received = vec![0; row.len()];
+                reader.read_exact(&mut received).await.unwrap();
+                assert_eq!(&received, row);
+                arrivals.push(start.elapsed());
+            }
+            arrivals
+        };
+        let ((), arrivals) = tokio::join!(writes, reads);
+        let ms = |ms| Duration::from_millis(ms);
+        assert_eq!(arrivals, [ms(0), ms(0), ms(50), ms(100), ms(150), ms(200)]);
+    }
+}
@@ -1,39 +1,216 @@
+use crate::table_formatter::strip_ansi;
 use anyhow::{Context, Result};
 use clap::ValueEnum;
 use owo_colors::{AnsiColors, OwoColorize};
+use std::collections::HashMap;
 use std::fmt::{self, Write as FmtWrite};
 use std::fs::File;
+use std::future::Future;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use tracing::field::{Field, Visit};
-use tracing::{Event, Level, Subscriber};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
 use tracing_subscriber::fmt::format::Writer;
 use tracing_subscriber::fmt::{FormatEvent, FormatFields};
-use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::layer::{self, Filter, Layer, SubscriberExt};
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
 pub enum LogFormat {
+    #[default]
     #[value(name = "full")]
     Full,
     Short,
     Bare,
 }
 
-impl Default for LogFormat {
-    fn default() -> Self {
-        Self::Full
+impl LogFormat {
+    /// Fields written by the format unless --log-fields picks others
+    fn default_fields(self) -> Vec<LogField> {
+        match self {
+            LogFormat::Full => vec![
+                LogField::Timestamp,
+                LogField::Level,
+                LogField::Target,
+                LogField::Message,
+            ],
+            LogFormat::Short => vec![LogField::Timestamp, LogField::Message],
+            LogFormat::Bare => vec![LogField::Message],
+        }
     }
 }
 
-pub fn setup_logging(log_file: Option<&PathBuf>, log_format: LogFormat) -> Result<()> {
-    use tracing_subscriber::EnvFilter;
+/// One field of a full or short log line
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum LogField {
+    Timestamp,
+    Level,
+    /// Module that logged the event
+    Target,
+    Message,
+}
+
+/// Parse --log-field-separator, where `\t` stands for a tab
+pub fn parse_field_separator(value: &str) -> Result<String, String> {
+    Ok(value.replace("\\t", "\t"))
+}
+
+/// Layout of every log line: which fields, in what order, and how they are
+/// separated and timestamped
+#[derive(Clone, Debug)]
+pub struct LineLayout {
+    fields: Vec<LogField>,
+    separator: String,
+    timestamp_format: TimestampFormat,
+}
+
+impl LineLayout {
+    /// `fields` replaces the fields of the full and short formats when not
+    /// empty; bare lines are always the message alone
+    pub fn new(
+        log_format: LogFormat,
+        timestamp_format: TimestampFormat,
+        fields: &[LogField],
+        separator: &str,
+    ) -> Self {
+        let fields = if fields.is_empty() || log_format == LogFormat::Bare {
+            log_format.default_fields()
+        } else {
+            fields.to_vec()
+        };
+        Self {
+            fields,
+            separator: separator.to_string(),
+            timestamp_format,
+        }
+    }
+}
+
+/// How the timestamp of the full and short log formats is written
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum TimestampFormat {
+    /// 2025-11-07T16:00:09.564676Z
+    #[default]
+    Rfc3339,
+    /// Milliseconds since the Unix epoch, for log ingestion pipelines
+    UnixMs,
+    /// Microseconds since the Unix epoch, matching PostgreSQL's own logs
+    UnixUs,
+    /// Leave the timestamp out
+    None,
+}
+
+/// Log levels for connections from particular client addresses, set with
+/// --client-log-level and shared by every connection task
+#[derive(Debug, Default)]
+pub struct ClientLogConfig {
+    levels: HashMap<IpAddr, Level>,
+}
+
+impl ClientLogConfig {
+    pub fn new(levels: impl IntoIterator<Item = (IpAddr, Level)>) -> Self {
+        Self {
+            levels: levels.into_iter().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn level_for(&self, ip: IpAddr) -> Option<Level> {
+        self.levels.get(&ip).copied()
+    }
+}
+
+/// Parse one `<ip>=<level>` argument of --client-log-level
+pub fn parse_client_log_level(value: &str) -> Result<(IpAddr, Level), String> {
+    let (ip, level) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <ip>=<level>, got '{}'", value))?;
+    let ip = ip
+        .parse()
+        .map_err(|e| format!("invalid IP address '{}': {}", ip, e))?;
+    let level = level
+        .parse()
+        .map_err(|_| format!("invalid log level '{}'", level))?;
+    Ok((ip, level))
+}
+
+tokio::task_local! {
+    /// Level of the connection the current task serves, when overridden
+    static CONNECTION_LEVEL: Level;
+}
+
+/// Run a connection task with its client's log level, if one is set
+pub async fn with_client_level<F: Future>(level: Option<Level>, future: F) -> F::Output {
+    match level {
+        Some(level) => CONNECTION_LEVEL.scope(level, future).await,
+        None => future.await,
+    }
+}
+
+/// Level of the connection the current task serves, for passing on to the
+/// tasks it spawns
+pub fn current_client_level() -> Option<Level> {
+    CONNECTION_LEVEL.try_with(|level| *level).ok()
+}
+
+/// The RUST_LOG filter, except inside connections with their own level
+struct ClientLevelFilter {
+    env: EnvFilter,
+    per_client: bool,
+}
+
+impl<S: Subscriber> Filter<S> for ClientLevelFilter {
+    fn enabled(&self, metadata: &Metadata<'_>, cx: &layer::Context<'_, S>) -> bool {
+        match current_client_level() {
+            Some(level) if self.per_client => metadata.level() <= &level,
+            _ => Filter::<S>::enabled(&self.env, metadata, cx),
+        }
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let interest = Filter::<S>::callsite_enabled(&self.env, metadata);
+        if self.per_client {
+            // The answer depends on which connection logs the event
+            Interest::sometimes()
+        } else {
+            interest
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        if self.per_client {
+            None
+        } else {
+            Filter::<S>::max_level_hint(&self.env)
+        }
+    }
+}
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+pub fn setup_logging(
+    log_file: Option<&PathBuf>,
+    layout: LineLayout,
+    per_client_levels: bool,
+    dedupe: bool,
+) -> Result<()> {
+    let env_filter = || {
+        let env = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+        ClientLevelFilter {
+            env,
+            per_client: per_client_levels,
+        }
+    };
 
-    let stdout_formatter = ProxyEventFormatter::new(log_format, true);
+    let stdout_formatter = ProxyEventFormatter::new(layout.clone(), true, dedupe);
     let stdout_layer = tracing_subscriber::fmt::layer()
         .with_writer(std::io::stdout)
         .with_ansi(false)
@@ -44,31 +221,213 @@ pub fn setup_logging(log_file: Option<&PathBuf>, log_format: LogFormat) -> Resul
         let file_layer = tracing_subscriber::fmt::layer()
             .with_writer(Arc::new(file))
             .with_ansi(false)
-            .event_format(ProxyEventFormatter::new(log_format, false));
+            .event_format(ProxyEventFormatter::new(layout, false, dedupe));
 
         tracing_subscriber::registry()
-            .with(stdout_layer.with_filter(env_filter.clone()))
-            .with(file_layer.with_filter(env_filter))
+            .with(stdout_layer.with_filter(env_filter()))
+            .with(file_layer.with_filter(env_filter()))
             .init();
     } else {
         tracing_subscriber::registry()
-            .with(stdout_layer.with_filter(env_filter))
+            .with(stdout_layer.with_filter(env_filter()))
             .init();
     }
 
     Ok(())
 }
 
+/// Repeats further apart than this are written again
+const DEDUPE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A line to write, as the formatter received it
+#[derive(Debug, PartialEq)]
+struct LogLine {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// Request cycles of one connection: the lines from a request up to and
+/// including the ReadyForQuery that answers it
+struct ConnectionCycles {
+    /// Lines of the last cycle, with timings taken out
+    previous: Vec<String>,
+    /// Lines of the cycle in progress, with timings taken out
+    current: Vec<String>,
+    /// So far the cycle in progress repeats the previous one, and its
+    /// lines are held back
+    matching: bool,
+    held: Vec<LogLine>,
+    /// Cycles left out since the last one written, and the line that ended
+    /// the latest of them
+    repeats: u64,
+    repeated: Option<LogLine>,
+    last_seen: Instant,
+}
+
+impl ConnectionCycles {
+    fn new(now: Instant) -> Self {
+        Self {
+            previous: Vec::new(),
+            current: Vec::new(),
+            matching: false,
+            held: Vec::new(),
+            repeats: 0,
+            repeated: None,
+            last_seen: now,
+        }
+    }
+
+    /// The summary of the cycles left out, then the held lines
+    fn flush(&mut self, connection: &str) -> Vec<LogLine> {
+        let mut lines = Vec::new();
+        if let Some(repeated) = self.repeated.take() {
+            let prefix = if connection.is_empty() {
+                String::new()
+            } else {
+                format!("{} ", connection)
+            };
+            lines.push(LogLine {
+                message: format!("{}... (repeated {} times)", prefix, self.repeats),
+                ..repeated
+            });
+        }
+        self.repeats = 0;
+        lines.append(&mut self.held);
+        lines
+    }
+}
+
+/// Collapses repeated request cycles for --dedupe, like journalctl does
+/// with repeated lines. Lines are grouped per connection; a cycle that
+/// starts out like the previous one is held back until it either differs,
+/// and is written out, or ends the same way and is counted. Lines without
+/// a connection are each a cycle of their own.
+#[derive(Default)]
+struct Deduper {
+    connections: HashMap<String, ConnectionCycles>,
+}
+
+impl Deduper {
+    /// The lines to write, in order, now that `message` was logged
+    fn observe(&mut self, level: Level, target: &str, message: &str, now: Instant) -> Vec<LogLine> {
+        let mut lines = Vec::new();
+        // A connection quiet for longer than the window starts over, with
+        // what it held back written out
+        self.connections.retain(|connection, cycles| {
+            let expired = now.duration_since(cycles.last_seen) > DEDUPE_WINDOW;
+            if expired {
+                lines.extend(cycles.flush(connection));
+            }
+            !expired
+        });
+
+        let (connection, rest) = split_connection(message);
+        let ends_cycle = connection.is_empty() || rest.starts_with("← ReadyForQuery");
+        let key = without_timings(rest);
+        let line = LogLine {
+            level,
+            target: target.to_string(),
+            message: message.to_string(),
+        };
+        let cycles = self
+            .connections
+            .entry(connection.to_string())
+            .or_insert_with(|| ConnectionCycles::new(now));
+        cycles.last_seen = now;
+        if cycles.matching && cycles.previous.get(cycles.current.len()) == Some(&key) {
+            cycles.held.push(line);
+        } else {
+            if cycles.matching {
+                cycles.matching = false;
+                lines.extend(cycles.flush(connection));
+            }
+            lines.push(line);
+        }
+        cycles.current.push(key);
+
+        if ends_cycle {
+            if cycles.matching && cycles.current.len() == cycles.previous.len() {
+                cycles.repeats += 1;
+                cycles.repeated = cycles.held.pop();
+                cycles.held.clear();
+            }
+            cycles.previous = std::mem::take(&mut cycles.current);
+            cycles.matching = true;
+        }
+        // Nothing more comes for a connection once it is closed
+        if rest.starts_with("Connection closed") {
+            if let Some(mut cycles) = self.connections.remove(connection) {
+                lines.extend(cycles.flush(connection));
+            }
+        }
+        lines
+    }
+}
+
+/// The `[client]` prefix of a connection's log line and the rest of it
+fn split_connection(message: &str) -> (&str, &str) {
+    match message.find("] ") {
+        Some(end) if message.starts_with('[') => (&message[..=end], &message[end + 2..]),
+        _ => ("", message),
+    }
+}
+
+/// The line with durations such as `0.012s` blanked out, so request cycles
+/// that only differ in how long they took compare equal
+fn without_timings(line: &str) -> String {
+    let mut key = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        key.push_str(&rest[..start]);
+        let number = rest[start..]
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or(rest.len(), |end| start + end);
+        if rest[number..].starts_with('s') && rest[start..number].contains('.') {
+            key.push_str("_s");
+            rest = &rest[number + 1..];
+        } else {
+            key.push_str(&rest[start..number]);
+            rest = &rest[number..];
+        }
+    }
+    key.push_str(rest);
+    key
+}
+
 struct ProxyEventFormatter {
-    log_format: LogFormat,
+    layout: LineLayout,
     colorize: bool,
+    deduper: Option<Mutex<Deduper>>,
 }
 
 impl ProxyEventFormatter {
-    fn new(log_format: LogFormat, colorize: bool) -> Self {
+    fn new(layout: LineLayout, colorize: bool, dedupe: bool) -> Self {
         Self {
-            log_format,
+            layout,
             colorize,
+            deduper: dedupe.then(Mutex::default),
+        }
+    }
+
+    fn render(&self, level: Level, target: &str, message: &str) -> String {
+        let timestamp = if self.layout.fields.contains(&LogField::Timestamp) {
+            format_timestamp(self.layout.timestamp_format, OffsetDateTime::now_utc())
+        } else {
+            None
+        };
+        let line = format_log_line(&self.layout, timestamp, level, target, message);
+        if self.colorize {
+            if let Some(colored) = colorize_if_needed(&line) {
+                colored
+            } else {
+                line
+            }
+        } else if line.contains('\u{1b}') {
+            // Table cells may carry their own colors
+            strip_ansi(&line)
+        } else {
+            line
         }
     }
 }
@@ -84,61 +443,67 @@ where
         mut writer: Writer<'_>,
         event: &Event<'_>,
     ) -> fmt::Result {
-        let timestamp = match self.log_format {
-            LogFormat::Full | LogFormat::Short => Some(current_timestamp()),
-            LogFormat::Bare => None,
-        };
-
         let mut message = String::new();
         let mut visitor = MessageVisitor { buf: &mut message };
         event.record(&mut visitor);
 
         let metadata = event.metadata();
-        let line = format_log_line(
-            self.log_format,
-            timestamp,
-            *metadata.level(),
-            metadata.target(),
-            &message,
-        );
-        let output = if self.colorize {
-            if let Some(colored) = colorize_if_needed(&line) {
-                colored
-            } else {
-                line
+        if let Some(deduper) = &self.deduper {
+            let lines = deduper.lock().unwrap().observe(
+                *metadata.level(),
+                metadata.target(),
+                &message,
+                Instant::now(),
+            );
+            for line in lines {
+                let output = self.render(line.level, &line.target, &line.message);
+                writeln!(writer, "{output}")?;
             }
-        } else {
-            line
-        };
+            return Ok(());
+        }
 
+        let output = self.render(*metadata.level(), metadata.target(), &message);
         writeln!(writer, "{output}")
     }
 }
 
-fn current_timestamp() -> String {
-    OffsetDateTime::now_utc()
-        .format(&Rfc3339)
-        .unwrap_or_else(|_| "1970-01-01T00:00:00Z".to_string())
+fn format_timestamp(format: TimestampFormat, now: OffsetDateTime) -> Option<String> {
+    match format {
+        TimestampFormat::Rfc3339 => Some(
+            now.format(&Rfc3339)
+                .unwrap_or_else(|_| "1970-01-01T00:00:00Z".to_string()),
+        ),
+        TimestampFormat::UnixMs => Some((now.unix_timestamp_nanos() / 1_000_000).to_string()),
+        TimestampFormat::UnixUs => Some((now.unix_timestamp_nanos() / 1_000).to_string()),
+        TimestampFormat::None => None,
+    }
 }
 
 fn format_log_line(
-    log_format: LogFormat,
+    layout: &LineLayout,
     timestamp: Option<String>,
     level: Level,
     target: &str,
     message: &str,
 ) -> String {
-    match log_format {
-        LogFormat::Full => {
-            let ts = timestamp.unwrap_or_else(|| current_timestamp());
-            format!("{ts}\t{level:>5}\t{target}\t{message}")
-        }
-        LogFormat::Short => {
-            let ts = timestamp.unwrap_or_else(|| current_timestamp());
-            format!("{ts}\t{message}")
+    let mut line = String::new();
+    for field in &layout.fields {
+        let value = match field {
+            // A timestamp format of none leaves the field out entirely
+            LogField::Timestamp => match &timestamp {
+                Some(ts) => ts.clone(),
+                None => continue,
+            },
+            LogField::Level => format!("{level:>5}"),
+            LogField::Target => target.to_string(),
+            LogField::Message => message.to_string(),
+        };
+        if !line.is_empty() {
+            line.push_str(&layout.separator);
         }
-        LogFormat::Bare => message.to_string(),
+        line.push_str(&value);
     }
+    line
 }
 
 fn colorize_if_needed(line: &str) -> Option<String> {
@@ -190,46 +555,245 @@ impl<'a> Visit for MessageVisitor<'a> {
 mod tests {
     use super::*;
 
-    const TIMESTAMP: &str = "2025-11-07T16:00:09.564676Z";
+    /// Each timestamp format with its rendering of 2025-11-07T16:00:09.564676Z
+    const TIMESTAMPS: [(TimestampFormat, Option<&str>); 4] = [
+        (
+            TimestampFormat::Rfc3339,
+            Some("2025-11-07T16:00:09.564676Z"),
+        ),
+        (TimestampFormat::UnixMs, Some("1762531209564")),
+        (TimestampFormat::UnixUs, Some("1762531209564676")),
+        (TimestampFormat::None, None),
+    ];
+
+    fn timestamp(format: TimestampFormat) -> Option<String> {
+        let now = OffsetDateTime::from_unix_timestamp_nanos(1_762_531_209_564_676_000).unwrap();
+        format_timestamp(format, now)
+    }
 
     #[test]
-    fn full_format_matches_default_shape() {
-        let line = format_log_line(
-            LogFormat::Full,
-            Some(TIMESTAMP.to_string()),
-            Level::INFO,
-            "postgres_wire_proxy::protocol",
-            "[1] ← BackendKeyData",
-        );
+    fn timestamps_are_formatted() {
+        for (format, expected) in TIMESTAMPS {
+            assert_eq!(timestamp(format).as_deref(), expected, "{format:?}");
+        }
+    }
 
-        assert_eq!(
-            line,
-            "2025-11-07T16:00:09.564676Z\t INFO\tpostgres_wire_proxy::protocol\t[1] ← BackendKeyData"
-        );
+    #[test]
+    fn full_format_matches_default_shape() {
+        for (format, expected) in TIMESTAMPS {
+            let line = format_log_line(
+                &LineLayout::new(LogFormat::Full, format, &[], "\t"),
+                timestamp(format),
+                Level::INFO,
+                "postgres_wire_proxy::protocol",
+                "[1] ← BackendKeyData",
+            );
+
+            let rest = " INFO\tpostgres_wire_proxy::protocol\t[1] ← BackendKeyData";
+            match expected {
+                Some(ts) => assert_eq!(line, format!("{ts}\t{rest}")),
+                None => assert_eq!(line, rest),
+            }
+        }
     }
 
     #[test]
     fn short_format_strips_level_and_target() {
+        for (format, expected) in TIMESTAMPS {
+            let line = format_log_line(
+                &LineLayout::new(LogFormat::Short, format, &[], "\t"),
+                timestamp(format),
+                Level::INFO,
+                "postgres_wire_proxy::protocol",
+                "[1] ← BackendKeyData",
+            );
+            match expected {
+                Some(ts) => assert_eq!(line, format!("{ts}\t[1] ← BackendKeyData")),
+                None => assert_eq!(line, "[1] ← BackendKeyData"),
+            }
+        }
+    }
+
+    #[test]
+    fn bare_format_is_message_only() {
+        let layout = LineLayout::new(
+            LogFormat::Bare,
+            TimestampFormat::Rfc3339,
+            &[LogField::Level],
+            "\t",
+        );
         let line = format_log_line(
-            LogFormat::Short,
-            Some(TIMESTAMP.to_string()),
+            &layout,
+            None,
             Level::INFO,
             "postgres_wire_proxy::protocol",
             "[1] ← BackendKeyData",
         );
-        assert_eq!(line, "2025-11-07T16:00:09.564676Z\t[1] ← BackendKeyData");
+        assert_eq!(line, "[1] ← BackendKeyData");
     }
 
     #[test]
-    fn bare_format_is_message_only() {
+    fn log_fields_and_separator_are_configurable() {
+        let layout = LineLayout::new(
+            LogFormat::Full,
+            TimestampFormat::UnixMs,
+            &[LogField::Level, LogField::Message, LogField::Timestamp],
+            &parse_field_separator(" | ").unwrap(),
+        );
         let line = format_log_line(
-            LogFormat::Bare,
-            None,
-            Level::INFO,
+            &layout,
+            timestamp(TimestampFormat::UnixMs),
+            Level::WARN,
             "postgres_wire_proxy::protocol",
             "[1] ← BackendKeyData",
         );
-        assert_eq!(line, "[1] ← BackendKeyData");
+        assert_eq!(line, " WARN | [1] ← BackendKeyData | 1762531209564");
+
+        let layout = LineLayout::new(
+            LogFormat::Short,
+            TimestampFormat::None,
+            &[],
+            &parse_field_separator("\\t").unwrap(),
+        );
+        assert_eq!(layout.separator, "\t");
+        let line = format_log_line(&layout, None, Level::INFO, "target", "message");
+        assert_eq!(line, "message");
+    }
+
+    #[test]
+    fn repeated_request_cycles_are_collapsed() {
+        let mut deduper = Deduper::default();
+        let start = Instant::now();
+        let mut observe = |message: &str, after_ms: u64| -> Vec<String> {
+            let now = start + Duration::from_millis(after_ms);
+            deduper
+                .observe(Level::INFO, "proxy", message, now)
+                .into_iter()
+                .map(|line| line.message)
+                .collect()
+        };
+        let cycle = |took: &str| {
+            [
+                "[1] → Query: select 1".to_string(),
+                "[1] ← DataRow (1 fields, 7 bytes)".to_string(),
+                format!("[1] ← CommandComplete: SELECT 1 (query took {took}, 1 row, 7 B)"),
+                "[1] ← ReadyForQuery (idle)".to_string(),
+            ]
+        };
+
+        // The first cycle is written as it arrives
+        for line in cycle("0.002s") {
+            assert_eq!(observe(&line, 0), [line]);
+        }
+        // Repeats, whatever their timings, are left out; lines of another
+        // connection in between are not part of the cycle
+        for (i, line) in cycle("0.001s").iter().enumerate() {
+            assert!(observe(line, 100).is_empty());
+            if i == 1 {
+                assert_eq!(observe("[2] → Terminate", 100), ["[2] → Terminate"]);
+            }
+        }
+        for line in cycle("0.003s") {
+            assert!(observe(&line, 200).is_empty());
+        }
+        // A cycle that starts the same and then differs is written out in
+        // full, after the summary
+        assert!(observe("[1] → Query: select 1", 300).is_empty());
+        assert_eq!(
+            observe("[1] ← ErrorResponse: ERROR", 300),
+            [
+                "[1] ... (repeated 2 times)",
+                "[1] → Query: select 1",
+                "[1] ← ErrorResponse: ERROR"
+            ]
+        );
+        assert_eq!(
+            observe("[1] ← ReadyForQuery (idle)", 300),
+            ["[1] ← ReadyForQuery (idle)"]
+        );
+
+        // The summary is not lost when the client disconnects
+        let written: usize = cycle("0.002s")
+            .iter()
+            .map(|line| observe(line, 400).len())
+            .sum();
+        assert_eq!(written, 4);
+        for line in cycle("0.002s") {
+            assert!(observe(&line, 500).is_empty());
+        }
+        assert_eq!(
+            observe("[1] Client closed connection (session 0.600s)", 600),
+            [
+                "[1] ... (repeated 1 times)",
+                "[1] Client closed connection (session 0.600s)"
+            ]
+        );
+
+        // Lines without a connection collapse on their own; repeats further
+        // apart than the window are written again
+        assert_eq!(observe("accept failed", 700), ["accept failed"]);
+        assert!(observe("accept failed", 800).is_empty());
+        assert_eq!(
+            observe("listening", 900),
+            ["... (repeated 1 times)", "listening"]
+        );
+        assert_eq!(observe("listening", 10_000), ["listening"]);
+    }
+
+    #[test]
+    fn durations_are_left_out_of_comparisons() {
+        assert_eq!(
+            without_timings("CommandComplete: SELECT 12 (query took 0.012s, 12 rows, 84 B)"),
+            "CommandComplete: SELECT 12 (query took _s, 12 rows, 84 B)"
+        );
+        assert_eq!(
+            split_connection("[127.0.0.1:5000] → Sync"),
+            ("[127.0.0.1:5000]", "→ Sync")
+        );
+        assert_eq!(
+            split_connection("Listening on 0.0.0.0:6432"),
+            ("", "Listening on 0.0.0.0:6432")
+        );
+    }
+
+    #[test]
+    fn client_log_levels_are_parsed() {
+        assert_eq!(
+            parse_client_log_level("10.0.0.1=debug"),
+            Ok(("10.0.0.1".parse().unwrap(), Level::DEBUG))
+        );
+        assert_eq!(
+            parse_client_log_level("::1=WARN"),
+            Ok(("::1".parse().unwrap(), Level::WARN))
+        );
+        assert!(parse_client_log_level("10.0.0.1").is_err());
+        assert!(parse_client_log_level("host=debug").is_err());
+        assert!(parse_client_log_level("10.0.0.1=loud").is_err());
+    }
+
+    #[tokio::test]
+    async fn client_level_is_scoped_to_the_connection_task() {
+        let config = ClientLogConfig::new([("10.0.0.1".parse().unwrap(), Level::DEBUG)]);
+        assert_eq!(current_client_level(), None);
+
+        let level = config.level_for("10.0.0.1".parse().unwrap());
+        let seen = with_client_level(level, async {
+            // Spawned helper tasks inherit the level explicitly
+            let inherited = current_client_level();
+            tokio::spawn(with_client_level(inherited, async {
+                current_client_level()
+            }))
+            .await
+            .unwrap()
+        })
+        .await;
+        assert_eq!(seen, Some(Level::DEBUG));
+
+        let other = config.level_for("10.0.0.2".parse().unwrap());
+        assert_eq!(
+            with_client_level(other, async { current_client_level() }).await,
+            None
+        );
     }
 
     #[test]
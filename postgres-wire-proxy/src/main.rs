@@ -1,28 +1,97 @@
 use anyhow::{Context, Result};
 use bytes::BytesMut;
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, Subcommand};
 use std::fs::File;
 use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{error, info, warn};
 
-mod table_formatter;
 mod protocol;
-use protocol::{format_duration, parse_message, ClientState, ConnectionTiming, MessageDirection};
+mod table_formatter;
+mod tls;
+use protocol::{
+    decode_startup_parameters, encode_error_response, format_duration, parse_message,
+    rewrite_startup_message, startup_allowlist_violation, ClientState, ConnectionTiming,
+    MessageDirection, MessageFramer, ProtocolOptions,
+};
+use table_formatter::{parse_column_width, ColumnWidthOverride, DEFAULT_COL_WIDTH};
+use tls::{describe_client_hello, describe_session, format_time, CertificateSummary};
 mod logging;
-use logging::{setup_logging, LogFormat};
+use logging::{
+    current_client_level, parse_client_log_level, parse_field_separator, setup_logging,
+    with_client_level, ClientLogConfig, LineLayout, LogField, LogFormat, TimestampFormat,
+};
+mod socket_options;
+use socket2::SockRef;
+use socket_options::{describe_socket, SocketOptions};
+mod admin;
+mod audit;
+mod cancel;
+mod canned;
+mod capture;
+mod events;
+mod fault;
+mod json;
+mod metrics;
+mod otlp;
+use otlp::{parse_otlp_endpoint, OtlpEndpoint, Tracer};
+mod pool;
+mod record;
+mod replay;
+mod rewrite;
+mod step;
+mod transaction_pool;
+mod validator;
+use admin::{RegisteredSession, SessionRegistry};
+use audit::AuditLogger;
+use cancel::{BackendKey, CancelRegistry, CANCEL_REQUEST_CODE};
+use canned::{parse_canned_response, CannedResponse};
+use capture::CaptureFile;
+use events::EventsDir;
+use fault::{
+    message_name, parse_block_rule, parse_drop_rule, parse_error_rule, parse_query_pattern,
+    DropRule, ErrorRule, InjectedLatency, KillSide, KillSwitch, MessageDropper, MessageRate,
+    Outbound, Pacer, QueryPattern, Throttle, DESTRUCTIVE_QUERY_PATTERNS,
+};
+use metrics::Metrics;
+use pool::{PooledConnection, UpstreamPool};
+use record::Recording;
+use rewrite::{parse_query_rewrite, QueryRewrite};
+use step::{parse_step_command, split_messages, StepCommand, Stepper};
+use transaction_pool::{
+    encode_greeting, reset_backend, Backend, PoolKey, PoolMode, TransactionPool, TransactionTracker,
+};
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replay the sessions of a --record recording against a server and report where its
+    /// answers differ from the recorded ones
+    Replay(replay::ReplayArgs),
+}
 
 #[derive(Parser, Debug)]
-#[command(author, version, about = "PostgreSQL wire protocol proxy", long_about = None)]
+#[command(
+    author,
+    version,
+    about = "PostgreSQL wire protocol proxy",
+    long_about = None,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
-    /// Listen address
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Listen address, as a host that listens on --port or as HOST:PORT (repeatable, e.g.
+    /// both 127.0.0.1 and [::1])
     #[arg(short, long, default_value = "127.0.0.1")]
-    listen: String,
+    listen: Vec<String>,
 
-    /// Listen port
+    /// Listen port of --listen addresses given without one
     #[arg(short, long, default_value = "5466")]
     port: u16,
 
@@ -42,6 +111,15 @@ struct Args {
     #[arg(long)]
     ssl_key: Option<PathBuf>,
 
+    /// Reject clients that do not start with an SSLRequest (requires ssl-cert)
+    #[arg(long, alias = "listen-tls-only")]
+    require_ssl: bool,
+
+    /// Log each client's TLS hello and the details of the proxy's certificate (requires ssl-cert);
+    /// upstream connections are plain TCP, so there is no upstream certificate to log
+    #[arg(long, requires = "ssl_cert")]
+    tls_verbose: bool,
+
     /// Log file path (optional, logs always go to stdout)
     #[arg(long)]
     log_file: Option<PathBuf>,
@@ -53,6 +131,26 @@ struct Args {
     #[arg(long, value_enum, default_value_t = LogFormat::Full)]
     log_format: LogFormat,
 
+    /// Timestamp written by the full and short log formats
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = TimestampFormat::Rfc3339)]
+    timestamp_format: TimestampFormat,
+
+    /// Separator between the fields of full and short log lines (\t for a tab)
+    #[arg(long, value_name = "SEP", default_value = "\\t", value_parser = parse_field_separator)]
+    log_field_separator: String,
+
+    /// Fields of full and short log lines, in order, e.g. timestamp,level,message
+    #[arg(long, value_enum, value_name = "LIST", value_delimiter = ',')]
+    log_fields: Vec<LogField>,
+
+    /// Collapse request cycles (request to ReadyForQuery) that repeat the previous one on the same connection into "... (repeated N times)"
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Log level for connections from one client IP, e.g. 10.0.0.1=debug (repeatable)
+    #[arg(long, value_name = "IP=LEVEL", value_parser = parse_client_log_level)]
+    client_log_level: Vec<(std::net::IpAddr, tracing::Level)>,
+
     /// hex-dump/no-hex-dump: Include/Exclude hex dumps of wire data in logs,
     #[arg(long = "hex-dump", action = ArgAction::SetTrue, default_value_t = true)]
     #[arg(long = "no-hex-dump", action = ArgAction::SetFalse)]
@@ -61,14 +159,273 @@ struct Args {
     /// Enable table formatting for DataRow output
     #[arg(long)]
     table: bool,
+
+    /// Maximum number of bytes shown for binary DataRow values
+    #[arg(long, default_value_t = 32)]
+    max_display_bytes: usize,
+
+    /// Maximum number of characters shown for text DataRow values
+    #[arg(long, default_value_t = 100)]
+    max_display_text: usize,
+
+    /// Hide Bind parameter values in logs
+    #[arg(long)]
+    redact: bool,
+
+    /// Maximum number of bytes shown for each text Bind parameter
+    #[arg(long, default_value_t = 64)]
+    max_param_len: usize,
+
+    /// Maximum number of characters of Query and Parse SQL shown in the log (0 = no limit)
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    max_query_log_length: usize,
+
+    /// Number of text rows or binary tuples of a COPY previewed per CopyData message
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    copy_preview_lines: usize,
+
+    /// Drop the Nth message of a type instead of forwarding it, e.g. DataRow:3
+    /// or client:Sync:1 (repeatable)
+    #[arg(long, value_name = "TYPE:N", value_parser = parse_drop_rule)]
+    drop_type: Vec<DropRule>,
+
+    /// Delay each forwarded message by this long in both directions
+    #[arg(long, value_name = "MS")]
+    inject_delay_ms: Option<u64>,
+
+    /// Delay each message sent by the client by this long (overrides --inject-delay-ms)
+    #[arg(long, value_name = "MS")]
+    inject_delay_client_ms: Option<u64>,
+
+    /// Delay each message sent by the server by this long (overrides --inject-delay-ms)
+    #[arg(long, value_name = "MS")]
+    inject_delay_server_ms: Option<u64>,
+
+    /// Add a uniformly random delay of up to this long to each injected delay
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    inject_jitter_ms: u64,
+
+    /// Close the connection right after forwarding the Nth message sent by the
+    /// client (not counting the startup message)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    kill_after_messages: Option<u64>,
+
+    /// Close the connection right after forwarding a Query or Parse whose SQL
+    /// matches this regex
+    #[arg(long, value_name = "REGEX", value_parser = parse_query_pattern)]
+    kill_on_query: Option<QueryPattern>,
+
+    /// Answer a Query or Execute whose SQL matches REGEX with an ErrorResponse
+    /// instead of running it (SQLSTATE defaults to 57014; repeatable)
+    #[arg(long, value_name = "REGEX[=SQLSTATE[:MESSAGE]]", value_parser = parse_error_rule)]
+    error_on_query: Vec<ErrorRule>,
+
+    /// Never forward a Query or Parse whose SQL matches REGEX; the client gets
+    /// ErrorResponse 42501 instead (repeatable)
+    #[arg(long, value_name = "REGEX", value_parser = parse_block_rule)]
+    block_query: Vec<ErrorRule>,
+
+    /// Block DROP TABLE/DATABASE/SCHEMA and TRUNCATE as if given to --block-query
+    #[arg(long)]
+    block_destructive_queries: bool,
+
+    /// Connections closed by --kill-after-messages and --kill-on-query
+    #[arg(long, value_enum, default_value_t = KillSide::Both)]
+    kill_side: KillSide,
+
+    /// Hold every message of one session until a command is entered on stdin:
+    /// Enter/s forwards it, c continues, d dumps its bytes, k kills the session
+    #[arg(long)]
+    step: bool,
+
+    /// Session to step, numbered from 1 in the order sessions start (default:
+    /// the first session that starts while none is stepped)
+    #[arg(long, value_name = "N", requires = "step", value_parser = clap::value_parser!(u64).range(1..))]
+    step_session: Option<u64>,
+
+    /// Forward at most N protocol messages per second in each direction
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_messages_per_sec: Option<u64>,
+
+    /// Limit the bandwidth of each direction to this many kilobits per second
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u64).range(1..))]
+    throttle_kbps: Option<u64>,
+
+    /// Limit the bytes sent by the client to this rate (overrides --throttle-kbps)
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u64).range(1..))]
+    throttle_client_kbps: Option<u64>,
+
+    /// Limit the bytes sent by the server to this rate (overrides --throttle-kbps)
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u64).range(1..))]
+    throttle_server_kbps: Option<u64>,
+
+    /// Prefix each logged message with its per-direction sequence number
+    #[arg(long)]
+    sequence_numbers: bool,
+
+    /// Log client idle time before a request when it is at least this long
+    #[arg(long, default_value_t = 100)]
+    idle_threshold_ms: u64,
+
+    /// Set TCP_NODELAY on client and upstream sockets (default)
+    #[arg(long = "tcp-nodelay", action = ArgAction::SetTrue, default_value_t = true)]
+    #[arg(long = "no-tcp-nodelay", action = ArgAction::SetFalse)]
+    tcp_nodelay: bool,
+
+    /// SO_SNDBUF size in bytes for client and upstream sockets
+    #[arg(long)]
+    tcp_sndbuf: Option<usize>,
+
+    /// SO_RCVBUF size in bytes for client and upstream sockets
+    #[arg(long)]
+    tcp_rcvbuf: Option<usize>,
+
+    /// Log the socket options actually applied by the OS
+    #[arg(long)]
+    socket_options_report: bool,
+
+    /// Summarize autocommit (implicit) transactions only when they take at least this long
+    #[arg(long, default_value_t = 1000)]
+    autocommit_threshold_ms: u64,
+
+    /// Check message ordering and log protocol violations at error level
+    #[arg(long)]
+    validate_protocol: bool,
+
+    /// Number of slowest queries listed when a connection closes (0 = none)
+    #[arg(long, default_value_t = 5)]
+    top_slow: usize,
+
+    /// Color table-mode cells by column type (numbers, strings, booleans, NULL)
+    #[arg(long)]
+    table_color: bool,
+
+    /// In table mode, hold up to N rows of each result set to size the columns to their
+    /// content; beyond N the rows seen so far are printed and the rest streamed
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    table_buffer_max_rows: Option<u64>,
+
+    /// In table mode, show the column with this field name N characters wide, e.g.
+    /// id=36 (repeatable)
+    #[arg(long, value_name = "COLUMN=N", value_parser = parse_column_width)]
+    column_width: Vec<(String, usize)>,
+
+    /// In table mode, width of the columns without a --column-width while rows stream
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_COL_WIDTH as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    default_column_width: u64,
+
+    /// Keep N upstream connections pre-opened and allow at most N client sessions at once;
+    /// with --pool-mode transaction, open at most N server sessions per database and user
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pool_size: Option<u32>,
+
+    /// How pooled connections are shared: session (one per client session, the default) or
+    /// transaction (server sessions lent to clients one transaction at a time)
+    #[arg(long, value_enum, value_name = "MODE", requires = "pool_size")]
+    pool_mode: Option<PoolMode>,
+
+    /// Replace the database in every client's startup message before it is forwarded
+    #[arg(long, value_name = "NAME")]
+    rewrite_database: Option<String>,
+
+    /// Replace the user in every client's startup message before it is forwarded
+    #[arg(long, value_name = "NAME")]
+    rewrite_user: Option<String>,
+
+    /// Replace every match of PATTERN (a regex) in the SQL of Query and Parse
+    /// messages before they are forwarded; `$1` expands capture groups (repeatable)
+    #[arg(long, value_name = "PATTERN=>REPLACEMENT", value_parser = parse_query_rewrite)]
+    rewrite: Vec<QueryRewrite>,
+
+    /// Answer a Query whose SQL matches PATTERN (a regex) with the result
+    /// described in FILE instead of forwarding it (repeatable)
+    #[arg(long, value_name = "PATTERN=>FILE", value_parser = parse_canned_response)]
+    respond: Vec<CannedResponse>,
+
+    /// Only accept clients connecting to this database (repeatable; default: any)
+    #[arg(long, value_name = "NAME")]
+    allow_database: Vec<String>,
+
+    /// Only accept clients connecting as this user (repeatable; default: any)
+    #[arg(long, value_name = "NAME")]
+    allow_user: Vec<String>,
+
+    /// Write every session to a PCAP-NG file that Wireshark can open (TLS sessions are stored decrypted)
+    #[arg(long, value_name = "FILE", visible_alias = "pcap")]
+    export_pgwire_capture: Option<PathBuf>,
+
+    /// Append every message of every session, with its connection, direction and timestamp, to a binary recording FILE
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Append a JSON line per query (connection, SQL, parameters, rows, duration, error) to FILE
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Write every decoded message of each connection as a JSON line (timestamp, direction,
+    /// type and its fields) to DIR/conn-<id>.jsonl
+    #[arg(long, value_name = "DIR")]
+    events_dir: Option<PathBuf>,
+
+    /// Serve Prometheus metrics (connections, bytes, messages, queries, errors and query
+    /// durations) at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Queries at least this slow are counted in pgwire_proxy_slow_queries_total
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 1000,
+        requires = "metrics_listen"
+    )]
+    metrics_slow_query_ms: u64,
+
+    /// Export a span per query (SQL fingerprint, user, database, rows, bytes, SQLSTATE) to an
+    /// OpenTelemetry collector over OTLP/HTTP, e.g. http://localhost:4318
+    #[arg(long, value_name = "URL", value_parser = parse_otlp_endpoint)]
+    otlp_endpoint: Option<OtlpEndpoint>,
+
+    /// service.name reported with the exported spans
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "postgres-wire-proxy",
+        requires = "otlp_endpoint"
+    )]
+    otlp_service_name: String,
+
+    /// Serve the live sessions as JSON at http://ADDR/sessions; POST /sessions/ID/kill closes one
+    #[arg(long, value_name = "ADDR")]
+    admin_listen: Option<SocketAddr>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(Command::Replay(replay)) = args.command {
+        let matched = replay::run(replay).await?;
+        std::process::exit(if matched { 0 } else { 1 });
+    }
 
     // Setup logging
-    setup_logging(args.log_file.as_ref(), args.log_format)?;
+    let client_log = Arc::new(ClientLogConfig::new(args.client_log_level.clone()));
+    setup_logging(
+        args.log_file.as_ref(),
+        LineLayout::new(
+            args.log_format,
+            args.timestamp_format,
+            &args.log_fields,
+            &args.log_field_separator,
+        ),
+        !client_log.is_empty(),
+        args.dedupe,
+    )?;
 
     // Validate SSL configuration
     let ssl_config = if let Some(cert_path) = &args.ssl_cert {
@@ -76,60 +433,614 @@ async fn main() -> Result<()> {
             .ssl_key
             .as_ref()
             .context("ssl-key is required when ssl-cert is provided")?;
-        Some(load_ssl_config(cert_path, key_path)?)
+        let (config, certificate) = load_ssl_config(cert_path, key_path)?;
+        match certificate {
+            Some(certificate) => {
+                if args.tls_verbose {
+                    info!("TLS certificate: {}", certificate);
+                }
+                if certificate.expired(time::OffsetDateTime::now_utc()) {
+                    warn!(
+                        "TLS certificate {} expired on {}; clients that verify it will refuse the handshake",
+                        cert_path.display(),
+                        format_time(certificate.not_after)
+                    );
+                }
+            }
+            None => warn!(
+                "Unable to read the subject and validity of {}",
+                cert_path.display()
+            ),
+        }
+        Some(SslSettings {
+            config,
+            required: args.require_ssl,
+            verbose: args.tls_verbose,
+        })
+    } else if args.require_ssl {
+        anyhow::bail!("require-ssl needs ssl-cert and ssl-key");
     } else {
         None
     };
 
-    let listen_addr = format!("{}:{}", args.listen, args.port);
-    let listener = TcpListener::bind(&listen_addr)
-        .await
-        .context("Failed to bind to listen address")?;
-
-    if ssl_config.is_some() {
-        info!(
-            "PostgreSQL proxy listening on {} (SSL enabled)",
-            listen_addr
+    let listen_addrs: Vec<String> = args
+        .listen
+        .iter()
+        .map(|listen| listen_address(listen, args.port))
+        .collect();
+    let mut listeners = Vec::new();
+    for listen_addr in &listen_addrs {
+        listeners.push(
+            TcpListener::bind(listen_addr)
+                .await
+                .with_context(|| format!("Failed to bind to listen address {}", listen_addr))?,
         );
-    } else {
-        info!("PostgreSQL proxy listening on {} (non-SSL)", listen_addr);
+        if ssl_config.as_ref().is_some_and(|ssl| ssl.required) {
+            info!(
+                "PostgreSQL proxy listening on {} (SSL required)",
+                listen_addr
+            );
+        } else if ssl_config.is_some() {
+            info!(
+                "PostgreSQL proxy listening on {} (SSL enabled)",
+                listen_addr
+            );
+        } else {
+            info!("PostgreSQL proxy listening on {} (non-SSL)", listen_addr);
+        }
     }
     info!(
         "Forwarding to {}:{}",
         args.upstream_host, args.upstream_port
     );
+    for rule in &args.drop_type {
+        warn!(
+            "Fault injection: dropping {} #{} sent by the {} on every connection",
+            message_name(rule.from_client, rule.msg_type),
+            rule.nth,
+            if rule.from_client { "client" } else { "server" }
+        );
+    }
+    let latency = |direction_ms: Option<u64>| InjectedLatency {
+        delay: Duration::from_millis(direction_ms.or(args.inject_delay_ms).unwrap_or(0)),
+        jitter: Duration::from_millis(args.inject_jitter_ms),
+    };
+    let client_latency = latency(args.inject_delay_client_ms);
+    let server_latency = latency(args.inject_delay_server_ms);
+    for (latency, sender) in [(client_latency, "client"), (server_latency, "server")] {
+        if latency.is_active() {
+            warn!(
+                "Fault injection: delaying each message sent by the {} by {}ms (+ up to {}ms jitter)",
+                sender,
+                latency.delay.as_millis(),
+                latency.jitter.as_millis()
+            );
+        }
+    }
+    let kill_switch = KillSwitch {
+        after_messages: args.kill_after_messages,
+        on_query: args.kill_on_query.clone(),
+        side: args.kill_side,
+    };
+    if let Some(n) = kill_switch.after_messages {
+        warn!(
+            "Fault injection: closing {} after client message #{} on every connection",
+            kill_switch.side.describe(),
+            n
+        );
+    }
+    if let Some(pattern) = &kill_switch.on_query {
+        warn!(
+            "Fault injection: closing {} after a Query or Parse matching /{}/",
+            kill_switch.side.describe(),
+            pattern
+        );
+    }
+    for rule in &args.error_on_query {
+        warn!(
+            "Fault injection: answering queries matching /{}/ with ErrorResponse {} '{}'",
+            rule.pattern, rule.code, rule.message
+        );
+    }
+    let mut block_rules = args.block_query.clone();
+    if args.block_destructive_queries {
+        for pattern in DESTRUCTIVE_QUERY_PATTERNS {
+            block_rules.push(parse_block_rule(pattern).map_err(anyhow::Error::msg)?);
+        }
+    }
+    for rule in &block_rules {
+        warn!("Blocking queries matching /{}/", rule.pattern);
+    }
+    for rule in &args.rewrite {
+        info!("Rewriting SQL: {}", rule);
+    }
+    for response in &args.respond {
+        info!("Answering queries locally: {}", response);
+    }
+    if let Some(rate) = args.max_messages_per_sec {
+        warn!(
+            "Fault injection: forwarding at most {} messages per second in each direction",
+            rate
+        );
+    }
+    let client_throttle_kbps = args.throttle_client_kbps.or(args.throttle_kbps);
+    let server_throttle_kbps = args.throttle_server_kbps.or(args.throttle_kbps);
+    for (kbps, sender) in [
+        (client_throttle_kbps, "client"),
+        (server_throttle_kbps, "server"),
+    ] {
+        if let Some(kbps) = kbps {
+            warn!(
+                "Fault injection: throttling bytes sent by the {} to {} kbps",
+                sender, kbps
+            );
+        }
+    }
+    let stepper = args.step.then(|| {
+        let (stepper, commands) = Stepper::new(args.step_session);
+        tokio::spawn(read_step_commands(commands));
+        stepper
+    });
     let hex_dump = args.hex_dump;
-    let table_mode = args.table;
+    let options = ProtocolOptions {
+        table_mode: args.table,
+        max_display_bytes: args.max_display_bytes,
+        max_display_text: args.max_display_text,
+        redact: args.redact,
+        max_param_len: args.max_param_len,
+        max_query_log_length: args.max_query_log_length,
+        copy_preview_lines: args.copy_preview_lines,
+        drop_rules: args.drop_type.clone(),
+        client_latency,
+        server_latency,
+        kill_switch,
+        error_rules: args
+            .error_on_query
+            .iter()
+            .cloned()
+            .chain(block_rules)
+            .collect(),
+        query_rewrites: args.rewrite.clone(),
+        canned_responses: args.respond.clone(),
+        max_messages_per_sec: args.max_messages_per_sec,
+        client_throttle_kbps,
+        server_throttle_kbps,
+        cancel_registry: Arc::default(),
+        stepper,
+        sequence_numbers: args.sequence_numbers,
+        idle_threshold: Duration::from_millis(args.idle_threshold_ms),
+        table_color: args.table_color,
+        table_buffer_max_rows: args.table_buffer_max_rows.map(|rows| rows as usize),
+        table_column_widths: ColumnWidthOverride::new(
+            args.column_width.clone(),
+            args.default_column_width as usize,
+        ),
+        autocommit_threshold: Duration::from_millis(args.autocommit_threshold_ms),
+        validate_protocol: args.validate_protocol,
+        top_slow: args.top_slow,
+        rewrite_database: args.rewrite_database.clone(),
+        rewrite_user: args.rewrite_user.clone(),
+        allow_databases: args.allow_database.clone(),
+        allow_users: args.allow_user.clone(),
+        capture: match &args.export_pgwire_capture {
+            Some(path) => {
+                info!("Writing capture to {}", path.display());
+                Some(CaptureFile::create(path)?)
+            }
+            None => None,
+        },
+        recording: match &args.record {
+            Some(path) => {
+                info!("Recording sessions to {}", path.display());
+                Some(Recording::open(path)?)
+            }
+            None => None,
+        },
+        audit: match &args.audit_log {
+            Some(path) => {
+                info!("Writing audit log to {}", path.display());
+                Some(AuditLogger::open(path)?)
+            }
+            None => None,
+        },
+        events: match &args.events_dir {
+            Some(dir) => {
+                info!("Writing per-connection events to {}", dir.display());
+                Some(EventsDir::open(dir)?)
+            }
+            None => None,
+        },
+        metrics: match args.metrics_listen {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .context("Failed to bind the metrics address")?;
+                info!("Serving metrics on http://{}/metrics", addr);
+                let metrics = Metrics::new(Duration::from_millis(args.metrics_slow_query_ms));
+                tokio::spawn(metrics::serve(listener, metrics.clone()));
+                Some(metrics)
+            }
+            None => None,
+        },
+        tracer: match args.otlp_endpoint.clone() {
+            Some(endpoint) => {
+                info!(
+                    "Exporting query spans to {} as service {}",
+                    endpoint, args.otlp_service_name
+                );
+                Some(Tracer::start(endpoint, args.otlp_service_name.clone())?)
+            }
+            None => None,
+        },
+        sessions: match args.admin_listen {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .context("Failed to bind the admin address")?;
+                info!("Serving the session list on http://{}/sessions", addr);
+                let sessions = SessionRegistry::new();
+                tokio::spawn(admin::serve(listener, sessions.clone()));
+                Some(sessions)
+            }
+            None => None,
+        },
+    };
+    info!(
+        "{}",
+        describe_configuration(&args, &options, &listen_addrs, ssl_config.as_ref())
+    );
+    let socket_options = SocketOptions {
+        nodelay: args.tcp_nodelay,
+        send_buffer_size: args.tcp_sndbuf,
+        recv_buffer_size: args.tcp_rcvbuf,
+        report: args.socket_options_report,
+    };
+    let pool_mode = args.pool_mode.unwrap_or_default();
+    let transactions = match (args.pool_size, pool_mode) {
+        (Some(size), PoolMode::Transaction) => Some(TransactionPool::new(
+            args.upstream_host.clone(),
+            args.upstream_port,
+            socket_options,
+            size as usize,
+        )),
+        _ => None,
+    };
+    let pool = match args.pool_size {
+        Some(size) if pool_mode == PoolMode::Session => {
+            let pool = UpstreamPool::new(
+                args.upstream_host.clone(),
+                args.upstream_port,
+                socket_options,
+                size as usize,
+            )
+            .await;
+            info!(
+                "Upstream pool ready ({} of {} connections open)",
+                pool.idle_count(),
+                size
+            );
+            Some(pool)
+        }
+        _ => None,
+    };
 
+    // Every listener hands its clients to the one loop below
+    let (accepted_sender, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+    for listener in listeners {
+        let accepted_sender = accepted_sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let result = listener.accept().await;
+                let failed = result.is_err();
+                if accepted_sender.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+    }
+    drop(accepted_sender);
+
+    let recording = options.recording.clone();
+    let tracer = options.tracer.clone();
+    let audit = options.audit.clone();
+    let events = options.events.clone();
+    let flushed_on_exit =
+        recording.is_some() || audit.is_some() || events.is_some() || tracer.is_some();
     loop {
-        let (client_socket, client_addr) = listener.accept().await?;
+        // With --record, --audit-log, --events-dir or --otlp-endpoint,
+        // Ctrl-C writes out the queued records, events and spans before
+        // exiting
+        let (client_socket, client_addr) = tokio::select! {
+            Some(result) = accepted.recv() => result?,
+            _ = tokio::signal::ctrl_c(), if flushed_on_exit => {
+                if let Some(recording) = &recording {
+                    info!("Interrupted, flushing the recording");
+                    recording.finish().await;
+                }
+                if let Some(audit) = &audit {
+                    info!("Interrupted, flushing the audit log");
+                    audit.finish().await;
+                }
+                if let Some(events) = &events {
+                    info!("Interrupted, flushing the event files");
+                    events.finish().await;
+                }
+                if let Some(tracer) = &tracer {
+                    info!("Interrupted, exporting the remaining spans");
+                    tracer.flush().await;
+                }
+                return Ok(());
+            }
+        };
         info!("New connection from {}", client_addr);
 
-        let upstream_host = args.upstream_host.clone();
-        let upstream_port = args.upstream_port;
+        let upstream = Upstream {
+            host: args.upstream_host.clone(),
+            port: args.upstream_port,
+            pool: pool.clone(),
+            transactions: transactions.clone(),
+        };
         let ssl_config = ssl_config.clone();
-        let hex_dump = hex_dump;
-        let table_mode = table_mode;
+        let options = options.clone();
+        let level = client_log.level_for(client_addr.ip());
+        if let Some(level) = level {
+            info!(
+                "[{}] Logging this connection at {} level",
+                client_addr, level
+            );
+        }
 
-        tokio::spawn(async move {
+        tokio::spawn(with_client_level(level, async move {
             if let Err(e) = handle_connection(
                 client_socket,
                 client_addr.to_string(),
-                upstream_host,
-                upstream_port,
+                upstream,
                 ssl_config,
                 hex_dump,
-                table_mode,
+                options,
+                socket_options,
             )
             .await
             {
                 error!("Connection error: {:#}", e);
             }
-        });
+        }));
     }
 }
 
-fn load_ssl_config(cert_path: &PathBuf, key_path: &PathBuf) -> Result<Arc<rustls::ServerConfig>> {
+/// Address to bind for one --listen value: a host, which listens on `port`,
+/// or a HOST:PORT or [IPv6]:PORT of its own
+fn listen_address(listen: &str, port: u16) -> String {
+    if listen.parse::<SocketAddr>().is_ok() {
+        return listen.to_string();
+    }
+    if let Some((host, listen_port)) = listen.rsplit_once(':') {
+        if !host.contains(':') && listen_port.parse::<u16>().is_ok() {
+            return listen.to_string();
+        }
+    }
+    let host = listen.trim_start_matches('[').trim_end_matches(']');
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// One-line summary of the effective settings, so a log or capture can be
+/// matched to the way the proxy was started
+fn describe_configuration(
+    args: &Args,
+    options: &ProtocolOptions,
+    listen_addrs: &[String],
+    ssl: Option<&SslSettings>,
+) -> String {
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    let list = |values: &[String]| {
+        if values.is_empty() {
+            "any".to_string()
+        } else {
+            values.join(",")
+        }
+    };
+
+    let mut fields = vec![
+        format!("listen={}", listen_addrs.join(",")),
+        format!("upstream={}:{}", args.upstream_host, args.upstream_port),
+        format!(
+            "client_tls={}",
+            match ssl {
+                Some(ssl) if ssl.required => "required",
+                Some(_) => "enabled",
+                None => "off",
+            }
+        ),
+        // Upstream connections are always plain TCP
+        "upstream_tls=off".to_string(),
+        format!(
+            "pool={}",
+            match (args.pool_size, args.pool_mode.unwrap_or_default()) {
+                (None, _) => "off".to_string(),
+                (Some(size), PoolMode::Session) => size.to_string(),
+                (Some(size), PoolMode::Transaction) => format!("{},transaction", size),
+            }
+        ),
+    ];
+
+    let modes: Vec<&str> = [
+        (options.table_mode, "table"),
+        (options.table_mode && options.table_color, "color"),
+        (
+            options.table_mode && options.table_buffer_max_rows.is_some(),
+            "buffered",
+        ),
+        (args.hex_dump, "hex"),
+        (options.sequence_numbers, "sequence-numbers"),
+        (options.validate_protocol, "validate"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, mode)| enabled.then_some(mode))
+    .collect();
+    fields.push(format!(
+        "output={}",
+        if modes.is_empty() {
+            "plain".to_string()
+        } else {
+            modes.join(",")
+        }
+    ));
+    if options.table_mode {
+        fields.push(format!("column_widths={}", options.table_column_widths));
+    }
+    fields.push(format!("log_format={:?}", args.log_format).to_lowercase());
+    fields.push(format!("redact={}", on_off(options.redact)));
+    fields.push(format!(
+        "limits=display_bytes:{},display_text:{},param_len:{},query_len:{},copy_lines:{},top_slow:{}",
+        options.max_display_bytes,
+        options.max_display_text,
+        options.max_param_len,
+        options.max_query_log_length,
+        options.copy_preview_lines,
+        options.top_slow
+    ));
+    fields.push(format!(
+        "allow=databases:{},users:{}",
+        list(&options.allow_databases),
+        list(&options.allow_users)
+    ));
+    if let Some(database) = &options.rewrite_database {
+        fields.push(format!("rewrite_database={}", database));
+    }
+    if let Some(user) = &options.rewrite_user {
+        fields.push(format!("rewrite_user={}", user));
+    }
+    let blocked = options.error_rules.iter().filter(|rule| rule.block).count();
+    fields.push(format!(
+        "sql_rules=rewrite:{},block:{},respond:{}",
+        options.query_rewrites.len(),
+        blocked,
+        options.canned_responses.len()
+    ));
+
+    let mut faults = Vec::new();
+    if !options.drop_rules.is_empty() {
+        faults.push(format!("drop:{}", options.drop_rules.len()));
+    }
+    if options.client_latency.is_active() || options.server_latency.is_active() {
+        faults.push("delay".to_string());
+    }
+    if options.kill_switch.is_active() {
+        faults.push("kill".to_string());
+    }
+    if options.error_rules.len() > blocked {
+        faults.push(format!("error:{}", options.error_rules.len() - blocked));
+    }
+    if let Some(rate) = options.max_messages_per_sec {
+        faults.push(format!("rate:{}", rate));
+    }
+    if options.client_throttle_kbps.is_some() || options.server_throttle_kbps.is_some() {
+        faults.push("throttle".to_string());
+    }
+    fields.push(format!(
+        "faults={}",
+        if faults.is_empty() {
+            "none".to_string()
+        } else {
+            faults.join(",")
+        }
+    ));
+    if let Some(path) = &args.export_pgwire_capture {
+        fields.push(format!("capture={}", path.display()));
+    }
+    if let Some(path) = &args.record {
+        fields.push(format!("record={}", path.display()));
+    }
+    if let Some(path) = &args.audit_log {
+        fields.push(format!("audit_log={}", path.display()));
+    }
+    if let Some(dir) = &args.events_dir {
+        fields.push(format!("events_dir={}", dir.display()));
+    }
+    if let Some(addr) = &args.metrics_listen {
+        fields.push(format!(
+            "metrics={},slow:{}ms",
+            addr, args.metrics_slow_query_ms
+        ));
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        fields.push(format!(
+            "otlp={},service:{}",
+            endpoint, args.otlp_service_name
+        ));
+    }
+    if let Some(addr) = &args.admin_listen {
+        fields.push(format!("admin={}", addr));
+    }
+    if args.step {
+        fields.push(format!(
+            "step=session:{}",
+            args.step_session
+                .map_or("first".to_string(), |session| session.to_string())
+        ));
+    }
+    format!("Configuration: {}", fields.join(" "))
+}
+
+/// Close both connections of a session the operator killed with --step or
+/// through --admin-listen. Errors only mean the peer already went away.
+fn kill_session(sockets: &Option<Arc<(socket2::Socket, socket2::Socket)>>) {
+    if let Some(sockets) = sockets {
+        let _ = sockets.0.shutdown(Shutdown::Both);
+        let _ = sockets.1.shutdown(Shutdown::Both);
+    }
+}
+
+/// Feed the --step commands typed on stdin to the stepped session
+async fn read_step_commands(commands: tokio::sync::mpsc::UnboundedSender<StepCommand>) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match parse_step_command(&line) {
+            Some(command) => {
+                if commands.send(command).is_err() {
+                    break;
+                }
+            }
+            None => warn!(
+                "Unknown step command '{}': Enter/s forwards, c continues, d dumps, k kills",
+                line.trim()
+            ),
+        }
+    }
+}
+
+/// Largest startup message PostgreSQL accepts (MAX_STARTUP_PACKET_LENGTH)
+const MAX_STARTUP_LENGTH: usize = 10_000;
+
+/// TLS configuration for the client side of the proxy
+#[derive(Clone)]
+struct SslSettings {
+    config: Arc<rustls::ServerConfig>,
+    /// Refuse clients that try to start without TLS
+    required: bool,
+    /// Log what each client offers in its ClientHello (--tls-verbose)
+    verbose: bool,
+}
+
+/// The PostgreSQL server connections are forwarded to
+struct Upstream {
+    host: String,
+    port: u16,
+    pool: Option<Arc<UpstreamPool>>,
+    /// Set with --pool-mode transaction, in place of `pool`
+    transactions: Option<Arc<TransactionPool>>,
+}
+
+/// Load the certificate chain and key; the summary of the leaf certificate is
+/// None when it cannot be parsed
+fn load_ssl_config(
+    cert_path: &PathBuf,
+    key_path: &PathBuf,
+) -> Result<(Arc<rustls::ServerConfig>, Option<CertificateSummary>)> {
     let cert_file = File::open(cert_path).context("Failed to open certificate file")?;
     let key_file = File::open(key_path).context("Failed to open key file")?;
 
@@ -139,6 +1050,7 @@ fn load_ssl_config(cert_path: &PathBuf, key_path: &PathBuf) -> Result<Arc<rustls
     let certs = rustls_pemfile::certs(&mut cert_reader)
         .collect::<Result<Vec<_>, _>>()
         .context("Failed to parse certificate")?;
+    let certificate = certs.first().and_then(|der| CertificateSummary::parse(der));
 
     let key = rustls_pemfile::private_key(&mut key_reader)
         .context("Failed to read private key")?
@@ -149,18 +1061,32 @@ fn load_ssl_config(cert_path: &PathBuf, key_path: &PathBuf) -> Result<Arc<rustls
         .with_single_cert(certs, key)
         .context("Failed to create SSL config")?;
 
-    Ok(Arc::new(config))
+    Ok((Arc::new(config), certificate))
 }
 
 async fn handle_connection(
     mut client_socket: TcpStream,
     client_addr: String,
-    upstream_host: String,
-    upstream_port: u16,
-    ssl_config: Option<Arc<rustls::ServerConfig>>,
+    upstream: Upstream,
+    ssl_config: Option<SslSettings>,
     hex_dump: bool,
-    table_mode: bool,
+    mut options: ProtocolOptions,
+    socket_options: SocketOptions,
 ) -> Result<()> {
+    options.recording = options.recording.as_ref().map(Recording::connection);
+    let recording = options.recording.clone();
+    let _active = options.metrics.as_ref().map(Metrics::connection_opened);
+    socket_options
+        .apply_to_stream(&client_socket)
+        .context("Failed to set client socket options")?;
+    if socket_options.report {
+        info!(
+            "[{}] Client socket options: {}",
+            client_addr,
+            describe_socket(&client_socket)?
+        );
+    }
+
     // Check if client wants SSL
     let mut startup_buf = BytesMut::with_capacity(8);
     client_socket
@@ -179,29 +1105,114 @@ async fn handle_connection(
         startup_buf[2],
         startup_buf[3],
     ]);
-    let protocol = u32::from_be_bytes([
+    let mut protocol = u32::from_be_bytes([
         startup_buf[4],
         startup_buf[5],
         startup_buf[6],
         startup_buf[7],
     ]);
 
+    // GSSENCRequest code is 80877104. Decline it like a server built without
+    // GSSAPI; the client then continues with an SSLRequest or a startup
+    // message on the same connection.
+    if protocol == 80877104 {
+        warn!(
+            "[{}] Client requested GSS encryption, declined (not supported by the proxy)",
+            client_addr
+        );
+        client_socket.write_all(b"N").await?;
+        if let Some(recording) = &recording {
+            recording.record_packet(true, &startup_buf);
+            recording.record_packet(false, b"N");
+        }
+
+        startup_buf.clear();
+        client_socket
+            .read_buf(&mut startup_buf)
+            .await
+            .context("Failed to read startup after GSS encryption rejection")?;
+        if startup_buf.len() < 8 {
+            warn!("Client disconnected during startup");
+            return Ok(());
+        }
+        protocol = u32::from_be_bytes([
+            startup_buf[4],
+            startup_buf[5],
+            startup_buf[6],
+            startup_buf[7],
+        ]);
+    }
+
+    if forward_cancel_request(
+        &mut client_socket,
+        &mut startup_buf,
+        &client_addr,
+        &options.cancel_registry,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+
+    let ssl_required = ssl_config.as_ref().is_some_and(|ssl| ssl.required);
+    if ssl_required && protocol != 80877103 {
+        warn!(
+            "[{}] Rejecting client that did not request SSL",
+            client_addr
+        );
+        client_socket
+            .write_all(&encode_error_response(
+                "FATAL",
+                "08P01",
+                "SSL connection is required by the proxy",
+            ))
+            .await?;
+        return Ok(());
+    }
+
     // SSL request code is 80877103
     if protocol == 80877103 {
         info!("[{}] Client requesting SSL", client_addr);
+        if let Some(recording) = &recording {
+            recording.record_packet(true, &startup_buf);
+            recording.record_packet(false, if ssl_config.is_some() { b"S" } else { b"N" });
+        }
 
-        if let Some(config) = ssl_config {
+        if let Some(SslSettings {
+            config, verbose, ..
+        }) = ssl_config
+        {
             // Accept SSL
-            client_socket.write_all(&[b'S']).await?;
+            client_socket.write_all(b"S").await?;
             info!("[{}] SSL accepted, performing handshake", client_addr);
 
-            let acceptor = tokio_rustls::TlsAcceptor::from(config);
-            let mut tls_stream = acceptor
-                .accept(client_socket)
+            let mut tls_stream = if verbose {
+                // Read the ClientHello first, so what the client offered is
+                // logged even when the handshake then fails
+                let start = tokio_rustls::LazyConfigAcceptor::new(
+                    rustls::server::Acceptor::default(),
+                    client_socket,
+                )
                 .await
                 .context("SSL handshake failed")?;
+                info!(
+                    "[{}] TLS client hello: {}",
+                    client_addr,
+                    describe_client_hello(&start.client_hello())
+                );
+                start.into_stream(config).await
+            } else {
+                tokio_rustls::TlsAcceptor::from(config)
+                    .accept(client_socket)
+                    .await
+            }
+            .context("SSL handshake failed")?;
 
-            info!("[{}] SSL handshake complete", client_addr);
+            info!(
+                "[{}] SSL handshake complete: {}",
+                client_addr,
+                describe_session(tls_stream.get_ref().1)
+            );
 
             // Now read the actual startup message
             startup_buf.clear();
@@ -209,21 +1220,31 @@ async fn handle_connection(
                 .read_buf(&mut startup_buf)
                 .await
                 .context("Failed to read startup after SSL")?;
+            if forward_cancel_request(
+                &mut tls_stream,
+                &mut startup_buf,
+                &client_addr,
+                &options.cancel_registry,
+            )
+            .await?
+            {
+                return Ok(());
+            }
 
             // Connect to upstream and proxy with TLS stream
             return proxy_with_tls(
                 tls_stream,
                 startup_buf,
                 client_addr,
-                upstream_host,
-                upstream_port,
+                upstream,
                 hex_dump,
-                table_mode,
+                options,
+                socket_options,
             )
             .await;
         } else {
             // Reject SSL
-            client_socket.write_all(&[b'N']).await?;
+            client_socket.write_all(b"N").await?;
             info!("[{}] SSL rejected (not configured)", client_addr);
 
             // Now read the actual startup message
@@ -232,6 +1253,16 @@ async fn handle_connection(
                 .read_buf(&mut startup_buf)
                 .await
                 .context("Failed to read startup after SSL rejection")?;
+            if forward_cancel_request(
+                &mut client_socket,
+                &mut startup_buf,
+                &client_addr,
+                &options.cancel_registry,
+            )
+            .await?
+            {
+                return Ok(());
+            }
         }
     }
 
@@ -240,107 +1271,737 @@ async fn handle_connection(
         client_socket,
         startup_buf,
         client_addr,
-        upstream_host,
-        upstream_port,
+        upstream,
         hex_dump,
-        table_mode,
+        options,
+        socket_options,
     )
     .await
 }
 
+/// A CancelRequest arrives on a connection of its own instead of a startup
+/// message; send it to the upstream of the session it names rather than
+/// opening a session for it. Returns false for any other startup packet.
+async fn forward_cancel_request<C>(
+    client: &mut C,
+    startup_buf: &mut BytesMut,
+    client_addr: &str,
+    registry: &CancelRegistry,
+) -> Result<bool>
+where
+    C: AsyncReadExt + Unpin,
+{
+    let Some(header) = startup_buf.get(..8) else {
+        return Ok(false);
+    };
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let code = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if code != CANCEL_REQUEST_CODE {
+        return Ok(false);
+    }
+    if length > startup_buf.len() && length <= MAX_STARTUP_LENGTH {
+        let mut rest = vec![0; length - startup_buf.len()];
+        client
+            .read_exact(&mut rest)
+            .await
+            .context("Failed to read CancelRequest")?;
+        startup_buf.extend_from_slice(&rest);
+    }
+    info!("[{}] → CancelRequest", client_addr);
+    registry.forward(startup_buf, client_addr).await?;
+    Ok(true)
+}
+
+/// application_name quoted for the connection log, or "(not set)"
+fn describe_application_name(name: Option<String>) -> String {
+    name.map_or("(not set)".to_string(), |name| format!("'{}'", name))
+}
+
+/// Collect the rest of the startup message and check it against the
+/// --allow-database/--allow-user allowlists. A refused client gets a FATAL
+/// ErrorResponse before any upstream connection is made; returns false
+/// when the connection should be closed.
+async fn admit_client<C>(
+    client: &mut C,
+    startup_buf: &mut BytesMut,
+    client_addr: &str,
+    options: &ProtocolOptions,
+) -> Result<bool>
+where
+    C: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // The first read may stop after the length and protocol version;
+    // collect the whole startup message so it can be logged and rewritten
+    if let Some(prefix) = startup_buf.get(..4) {
+        let declared = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if declared > startup_buf.len() && declared <= MAX_STARTUP_LENGTH {
+            let mut rest = vec![0; declared - startup_buf.len()];
+            client
+                .read_exact(&mut rest)
+                .await
+                .context("Failed to read startup message")?;
+            startup_buf.extend_from_slice(&rest);
+        }
+    }
+
+    let Some(reason) =
+        startup_allowlist_violation(startup_buf, &options.allow_databases, &options.allow_users)
+    else {
+        return Ok(true);
+    };
+    warn!("[{}] Rejecting client: {}", client_addr, reason);
+    client
+        .write_all(&encode_error_response("FATAL", "28000", &reason))
+        .await?;
+    client.flush().await?;
+    Ok(false)
+}
+
+/// The TCP connection under a client stream, plain or TLS
+trait ClientSocket {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl ClientSocket for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl ClientSocket for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+}
+
 async fn proxy_with_tls(
-    client_stream: tokio_rustls::server::TlsStream<TcpStream>,
-    startup_buf: BytesMut,
+    mut client_stream: tokio_rustls::server::TlsStream<TcpStream>,
+    mut startup_buf: BytesMut,
     client_addr: String,
-    upstream_host: String,
-    upstream_port: u16,
+    upstream: Upstream,
     hex_dump: bool,
-    table_mode: bool,
+    options: ProtocolOptions,
+    socket_options: SocketOptions,
 ) -> Result<()> {
-    // Connect to upstream
-    info!(
-        "[{}] Connecting to upstream {}:{}",
-        client_addr, upstream_host, upstream_port
-    );
-    let upstream_socket = TcpStream::connect(format!("{}:{}", upstream_host, upstream_port))
-        .await
-        .context("Failed to connect to upstream")?;
-
-    info!("[{}] Connected to upstream", client_addr);
+    if !admit_client(&mut client_stream, &mut startup_buf, &client_addr, &options).await? {
+        return Ok(());
+    }
+    if let Some(pool) = upstream.transactions {
+        return run_pooled_transactions(
+            client_stream,
+            startup_buf,
+            client_addr,
+            pool,
+            true,
+            hex_dump,
+            options,
+        )
+        .await;
+    }
+    let upstream_socket = connect_upstream(&client_addr, &upstream, socket_options).await?;
 
     run_proxy(
         client_stream,
         upstream_socket,
         startup_buf,
         client_addr,
+        true,
         hex_dump,
-        table_mode,
+        options,
     )
     .await
 }
 
 async fn proxy_with_tcp(
-    client_stream: TcpStream,
-    startup_buf: BytesMut,
+    mut client_stream: TcpStream,
+    mut startup_buf: BytesMut,
     client_addr: String,
-    upstream_host: String,
-    upstream_port: u16,
+    upstream: Upstream,
     hex_dump: bool,
-    table_mode: bool,
+    options: ProtocolOptions,
+    socket_options: SocketOptions,
 ) -> Result<()> {
-    // Connect to upstream
-    info!(
-        "[{}] Connecting to upstream {}:{}",
-        client_addr, upstream_host, upstream_port
-    );
-    let upstream_socket = TcpStream::connect(format!("{}:{}", upstream_host, upstream_port))
-        .await
-        .context("Failed to connect to upstream")?;
-
-    info!("[{}] Connected to upstream", client_addr);
+    if !admit_client(&mut client_stream, &mut startup_buf, &client_addr, &options).await? {
+        return Ok(());
+    }
+    if let Some(pool) = upstream.transactions {
+        return run_pooled_transactions(
+            client_stream,
+            startup_buf,
+            client_addr,
+            pool,
+            false,
+            hex_dump,
+            options,
+        )
+        .await;
+    }
+    let upstream_socket = connect_upstream(&client_addr, &upstream, socket_options).await?;
 
     run_proxy(
         client_stream,
         upstream_socket,
         startup_buf,
         client_addr,
+        false,
         hex_dump,
-        table_mode,
+        options,
     )
     .await
 }
 
-async fn run_proxy<C>(
-    client_stream: C,
-    mut upstream_socket: TcpStream,
+async fn connect_upstream(
+    client_addr: &str,
+    upstream: &Upstream,
+    socket_options: SocketOptions,
+) -> Result<PooledConnection> {
+    let upstream_socket = if let Some(pool) = &upstream.pool {
+        info!(
+            "[{}] Taking upstream connection from pool ({} idle)",
+            client_addr,
+            pool.idle_count()
+        );
+        pool.acquire().await?
+    } else {
+        info!(
+            "[{}] Connecting to upstream {}:{}",
+            client_addr, upstream.host, upstream.port
+        );
+        PooledConnection::unpooled(
+            socket_options
+                .connect(&upstream.host, upstream.port)
+                .await?,
+        )
+    };
+
+    info!("[{}] Connected to upstream", client_addr);
+    if socket_options.report {
+        info!(
+            "[{}] Upstream socket options: {}",
+            client_addr,
+            describe_socket(upstream_socket.stream())?
+        );
+    }
+    Ok(upstream_socket)
+}
+
+/// Serve one client from the transaction pool: answer its startup locally,
+/// then borrow a backend for each transaction and give it back once
+/// ReadyForQuery reports it idle
+async fn run_pooled_transactions<C>(
+    mut client: C,
     startup_buf: BytesMut,
     client_addr: String,
+    pool: Arc<TransactionPool>,
+    tls: bool,
     hex_dump: bool,
-    table_mode: bool,
+    options: ProtocolOptions,
 ) -> Result<()>
 where
-    C: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    C: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    // Forward the startup message to upstream
-    upstream_socket.write_all(&startup_buf).await?;
+    let startup = rewrite_startup_message(
+        &startup_buf,
+        options.rewrite_database.as_deref(),
+        options.rewrite_user.as_deref(),
+    )
+    .map_or(startup_buf.to_vec(), |(rewritten, _)| rewritten);
+    let Some(key) = PoolKey::from_startup(&startup) else {
+        warn!(
+            "[{}] Rejecting client: transaction pooling needs a protocol 3.0 startup message with a user",
+            client_addr
+        );
+        client
+            .write_all(&encode_error_response(
+                "FATAL",
+                "08P01",
+                "transaction pooling needs a protocol 3.0 startup message with a user",
+            ))
+            .await?;
+        return Ok(());
+    };
+    let timings = ConnectionTiming::new();
+    let answers_locally = options.answers_locally();
+    let metrics = options.metrics.clone();
+    let sessions = options.sessions.clone();
+    let cancel_registry = options.cancel_registry.clone();
+    let client_state = Arc::new(ClientState::new(options, tls));
+    client_state.record_startup(&startup);
+    let session = sessions
+        .as_ref()
+        .map(|sessions| sessions.register(&client_addr, client_state.clone()));
+    if let Some(session) = &session {
+        info!("[{}] Admin session ID {}", client_addr, session.id());
+        session.forwarded(true, startup.len());
+    }
     info!(
-        "[{}] → Startup message (length: {})",
+        "[{}] → Startup message (length: {}, application_name {})",
         client_addr,
-        startup_buf.len()
+        startup.len(),
+        describe_application_name(client_state.application_name())
     );
 
+    // The first backend of a database and user reports the server
+    // parameters every later client is greeted with
+    match pool.acquire(&key).await {
+        Ok(backend) => pool.release(&key, backend),
+        Err(e) => {
+            error!(
+                "[{}] Failed to open pooled server session: {:#}",
+                client_addr, e
+            );
+            client
+                .write_all(&encode_error_response(
+                    "FATAL",
+                    "08006",
+                    &format!("proxy could not open a server session: {:#}", e),
+                ))
+                .await?;
+            return Ok(());
+        }
+    }
+    // Reported settings the client asked for at startup, such as
+    // application_name, are shown with the client's value
+    let requested = decode_startup_parameters(&startup).unwrap_or_default();
+    let parameters: Vec<(String, String)> = pool
+        .parameters(&key)
+        .into_iter()
+        .map(|(name, value)| {
+            let value = requested
+                .iter()
+                .find(|(requested, _)| *requested == name)
+                .map_or(value, |(_, requested)| requested.clone());
+            (name, value)
+        })
+        .collect();
+    // The client gets a key of the proxy's own; a CancelRequest with it is
+    // sent on to the backend the client holds at the time, if any
+    let secret: i32 = rand::random();
+    let client_key = BackendKey {
+        pid: 0,
+        secret: secret.to_be_bytes().to_vec(),
+    };
+    let greeting = encode_greeting(&parameters, client_key.pid as i32, secret);
+    info!(
+        "[{}] Transaction pooling: answering startup for database '{}' user '{}' locally",
+        client_addr, key.database, key.user
+    );
+    parse_message(
+        &greeting,
+        MessageDirection::ServerToClient,
+        &client_addr,
+        Some(&timings),
+        &client_state,
+        hex_dump,
+    );
+    client.write_all(&greeting).await?;
+    // The greeting ends with the ReadyForQuery local replies wait for first
+    client_state.local_replies().interleave(&greeting);
+
+    let mut tracker = TransactionTracker::default();
+    let mut held: Option<Backend> = None;
+    let mut transactions = 0;
+    let mut client_framer = MessageFramer::default();
+    let mut server_framer = MessageFramer::default();
+    let mut client_buf = BytesMut::with_capacity(8192);
+    let mut server_buf = BytesMut::with_capacity(8192);
+    loop {
+        client_buf.clear();
+        server_buf.clear();
+        tokio::select! {
+            read = client.read_buf(&mut client_buf) => {
+                let n = match read {
+                    Ok(0) => {
+                        info!(
+                            "[{}] Client closed connection (session {})",
+                            client_addr,
+                            format_duration(timings.session_elapsed())
+                        );
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("[{}] Failed to read from client: {}", client_addr, e);
+                        break;
+                    }
+                };
+                let mut messages = client_framer.push(&client_buf[..n]);
+                let plan = parse_message(
+                    &messages,
+                    MessageDirection::ClientToServer,
+                    &client_addr,
+                    Some(&timings),
+                    &client_state,
+                    hex_dump,
+                );
+                // --rewrite, --block-query and --error-on-query replacements
+                if !plan.is_unchanged() {
+                    messages = plan.apply(&messages);
+                }
+                // Terminate ends the client session, not the shared backend
+                let mut forwarded = Vec::with_capacity(messages.len());
+                let mut terminated = false;
+                for message in split_messages(&messages) {
+                    if message[0] == b'X' {
+                        terminated = true;
+                        break;
+                    }
+                    if let Some(name) = tracker.client_message(message) {
+                        warn!(
+                            "[{}] Named prepared statement '{}' only lasts until this backend is returned; a later transaction may run on another backend",
+                            client_addr, name
+                        );
+                    }
+                    forwarded.extend_from_slice(message);
+                }
+                if !forwarded.is_empty() {
+                    let backend = match &mut held {
+                        Some(backend) => backend,
+                        None => {
+                            let backend = pool.acquire(&key).await?;
+                            let (idle, open) = pool.counts(&key);
+                            info!(
+                                "[{}] Borrowed backend {} (pid {}) for database '{}' user '{}' ({} idle, {} open)",
+                                client_addr, backend.id, backend.process_id, key.database, key.user, idle, open
+                            );
+                            if let (Some(backend_key), Ok(upstream)) =
+                                (&backend.key, backend.stream.peer_addr())
+                            {
+                                cancel_registry.register_pooled(
+                                    client_key.clone(),
+                                    backend_key.clone(),
+                                    upstream,
+                                    &client_addr,
+                                );
+                            }
+                            transactions += 1;
+                            held.insert(backend)
+                        }
+                    };
+                    if let Some(metrics) = &metrics {
+                        metrics.forwarded(true, forwarded.len());
+                    }
+                    if let Some(session) = &session {
+                        session.forwarded(true, forwarded.len());
+                    }
+                    if let Err(e) = backend.stream.write_all(&forwarded).await {
+                        error!("[{}] Failed to write to backend {}: {}", client_addr, backend.id, e);
+                        break;
+                    }
+                }
+                if terminated {
+                    break;
+                }
+                // Replies of the proxy that wait for nothing the server owes
+                let replies = client_state.local_replies().take_due();
+                if !replies.is_empty() {
+                    parse_message(
+                        &replies,
+                        MessageDirection::ServerToClient,
+                        &client_addr,
+                        Some(&timings),
+                        &client_state,
+                        hex_dump,
+                    );
+                    if let Err(e) = client.write_all(&replies).await {
+                        error!("[{}] Failed to write to client: {}", client_addr, e);
+                        break;
+                    }
+                }
+            }
+            read = read_backend(&mut held, &mut server_buf) => {
+                let n = match read {
+                    Ok(0) => {
+                        error!("[{}] Backend closed the connection", client_addr);
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("[{}] Failed to read from backend: {}", client_addr, e);
+                        break;
+                    }
+                };
+                let messages = server_framer.push(&server_buf[..n]);
+                if let Some(metrics) = &metrics {
+                    metrics.forwarded(false, messages.len());
+                }
+                if let Some(session) = &session {
+                    session.forwarded(false, messages.len());
+                }
+                // Local replies go right after the ReadyForQuery they wait
+                // for; each part is logged in the order the client gets it
+                let segments = if answers_locally {
+                    client_state.local_replies().interleave(&messages)
+                } else {
+                    vec![messages.clone()]
+                };
+                for segment in &segments {
+                    parse_message(
+                        segment,
+                        MessageDirection::ServerToClient,
+                        &client_addr,
+                        Some(&timings),
+                        &client_state,
+                        hex_dump,
+                    );
+                }
+                if let Err(e) = client.write_all(&segments.concat()).await {
+                    error!("[{}] Failed to write to client: {}", client_addr, e);
+                    break;
+                }
+                if tracker.server_messages(&messages) {
+                    if let Some(backend) = held.take() {
+                        cancel_registry.unregister(&client_key);
+                        return_backend(&pool, &key, backend, tracker.take_dirty(), &client_addr)
+                            .await;
+                    }
+                }
+            }
+            _ = admin_kill(&session) => {
+                info!("[{}] Session killed through the admin endpoint", client_addr);
+                break;
+            }
+        }
+    }
+
+    cancel_registry.unregister(&client_key);
+    // A backend still borrowed is inside a transaction or owes replies;
+    // close it rather than hand that state to another client
+    if let Some(backend) = held {
+        warn!(
+            "[{}] Closing backend {}: the client left before it was idle",
+            client_addr, backend.id
+        );
+        drop(backend);
+        pool.discard(&key);
+    }
+    info!(
+        "[{}] Connection closed (application_name {}, session {}, {} transactions pooled)",
+        client_addr,
+        describe_application_name(client_state.application_name()),
+        format_duration(timings.session_elapsed()),
+        transactions
+    );
+    Ok(())
+}
+
+/// Completes when the session is killed through --admin-listen; never
+/// without it
+async fn admin_kill(session: &Option<RegisteredSession>) {
+    match session {
+        Some(session) => session.killed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Read from the borrowed backend; with none borrowed only the client side
+/// of the select can make progress
+async fn read_backend(backend: &mut Option<Backend>, buf: &mut BytesMut) -> std::io::Result<usize> {
+    match backend {
+        Some(backend) => backend.stream.read_buf(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Give a backend back to the pool, resetting it first when the client
+/// left session state behind
+async fn return_backend(
+    pool: &TransactionPool,
+    key: &PoolKey,
+    mut backend: Backend,
+    dirty: bool,
+    client_addr: &str,
+) {
+    if dirty {
+        if let Err(e) = reset_backend(&mut backend).await {
+            warn!("[{}] Closing backend {}: {:#}", client_addr, backend.id, e);
+            pool.discard(key);
+            return;
+        }
+        info!(
+            "[{}] Reset backend {} with DISCARD ALL: the session created prepared statements or settings",
+            client_addr, backend.id
+        );
+    }
+    let id = backend.id;
+    pool.release(key, backend);
+    info!(
+        "[{}] Returned backend {} to the pool ({} idle)",
+        client_addr,
+        id,
+        pool.counts(key).0
+    );
+}
+
+async fn run_proxy<C>(
+    client_stream: C,
+    mut upstream_socket: PooledConnection,
+    mut startup_buf: BytesMut,
+    client_addr: String,
+    tls: bool,
+    hex_dump: bool,
+    options: ProtocolOptions,
+) -> Result<()>
+where
+    C: AsyncReadExt + AsyncWriteExt + ClientSocket + Unpin + Send + 'static,
+{
+    let upstream_addr = upstream_socket.stream().peer_addr().ok();
+    // The client direction cuts both sockets for the kill switch while the
+    // other direction still owns its halves, so it gets handles of its own
+    let kill_sockets = if options.kill_switch.is_active() {
+        Some((
+            SockRef::from(client_stream.tcp()).try_clone()?,
+            SockRef::from(upstream_socket.stream()).try_clone()?,
+        ))
+    } else {
+        None
+    };
+    let kill_side = options.kill_switch.side;
+    let step_gate = options
+        .stepper
+        .as_ref()
+        .and_then(|stepper| stepper.attach(&client_addr));
+    // Either direction closes both sockets when the operator kills the
+    // stepped session
+    let step_sockets = match &step_gate {
+        Some(_) => Some(Arc::new((
+            SockRef::from(client_stream.tcp()).try_clone()?,
+            SockRef::from(upstream_socket.stream()).try_clone()?,
+        ))),
+        None => None,
+    };
+    // Sockets of their own, for closing the session from the admin endpoint
+    let admin_sockets = match &options.sessions {
+        Some(_) => Some(Arc::new((
+            SockRef::from(client_stream.tcp()).try_clone()?,
+            SockRef::from(upstream_socket.stream()).try_clone()?,
+        ))),
+        None => None,
+    };
+    let answers_locally = options.answers_locally();
+    let has_replacements = !options.query_rewrites.is_empty() || answers_locally;
+    let capture = match (
+        &options.capture,
+        client_addr.parse::<SocketAddr>(),
+        upstream_socket.stream().peer_addr(),
+    ) {
+        (Some(file), Ok(client), Ok(server)) => Some(Arc::new(file.stream(client, server))),
+        _ => None,
+    };
+
+    if let Some((rewritten, rewrites)) = rewrite_startup_message(
+        &startup_buf,
+        options.rewrite_database.as_deref(),
+        options.rewrite_user.as_deref(),
+    ) {
+        for rewrite in rewrites {
+            info!(
+                "[{}] Rewrote startup {}: {} -> '{}'",
+                client_addr,
+                rewrite.parameter,
+                rewrite
+                    .original
+                    .map_or("(not set)".to_string(), |original| format!(
+                        "'{}'",
+                        original
+                    )),
+                rewrite.rewritten
+            );
+        }
+        startup_buf = BytesMut::from(&rewritten[..]);
+    }
+
+    // Forward the startup message to upstream
+    if let Some(capture) = &capture {
+        capture.record(true, &startup_buf);
+    }
+    let recording = options.recording.clone();
+    if let Some(recording) = &recording {
+        recording.record_packet(true, &startup_buf);
+    }
+    let metrics = options.metrics.clone();
+    if let Some(metrics) = &metrics {
+        metrics.forwarded(true, startup_buf.len());
+    }
+    upstream_socket.write_all(&startup_buf).await?;
+
     // Proxy messages bidirectionally
-    let (mut client_read, mut client_write) = tokio::io::split(client_stream);
-    let (mut upstream_read, mut upstream_write) = upstream_socket.into_split();
+    let (mut client_read, client_write) = tokio::io::split(client_stream);
+    let (mut upstream_read, upstream_write) = tokio::io::split(upstream_socket);
     let timings = Arc::new(ConnectionTiming::new());
-    let client_state = Arc::new(ClientState::new(table_mode));
+    let options_drop_rules = options.drop_rules.clone();
+    let (client_latency, server_latency) = (options.client_latency, options.server_latency);
+    let message_rate = options.max_messages_per_sec;
+    let client_throttle = options.client_throttle_kbps.map(Throttle::new);
+    let server_throttle = options.server_throttle_kbps.map(Throttle::new);
+    let throughputs = [
+        (
+            "client",
+            "server",
+            options.client_throttle_kbps,
+            &client_throttle,
+        ),
+        (
+            "server",
+            "client",
+            options.server_throttle_kbps,
+            &server_throttle,
+        ),
+    ]
+    .map(|(from, to, kbps, throttle)| {
+        (from, to, kbps, throttle.as_ref().map(Throttle::throughput))
+    });
+    let cancel_registry = options.cancel_registry.clone();
+    let sessions = options.sessions.clone();
+    let client_state = Arc::new(ClientState::new(options, tls));
+    client_state.record_startup(&startup_buf);
+    let session = sessions
+        .as_ref()
+        .map(|sessions| Arc::new(sessions.register(&client_addr, client_state.clone())));
+    if let Some(session) = &session {
+        info!("[{}] Admin session ID {}", client_addr, session.id());
+        session.forwarded(true, startup_buf.len());
+    }
+    info!(
+        "[{}] → Startup message (length: {}, application_name {})",
+        client_addr,
+        startup_buf.len(),
+        describe_application_name(client_state.application_name())
+    );
 
     let client_addr_clone = client_addr.clone();
     let timings_clone = timings.clone();
     let client_state_clone = client_state.clone();
-    let client_to_upstream = tokio::spawn(async move {
+    let capture_clone = capture.clone();
+    let recording_clone = recording.clone();
+    let step_gate_clone = step_gate.clone();
+    let step_sockets_clone = step_sockets.clone();
+    let metrics_clone = metrics.clone();
+    let session_clone = session.clone();
+    let drop_rules = options_drop_rules.clone();
+    let level = current_client_level();
+    let client_to_upstream = tokio::spawn(with_client_level(level, async move {
         let mut buf = BytesMut::with_capacity(8192);
-        loop {
+        let mut framer = MessageFramer::default();
+        let mut dropper = MessageDropper::new(&drop_rules, true);
+        let latency = client_latency;
+        let whole_messages = dropper.is_active()
+            || latency.is_active()
+            || message_rate.is_some()
+            || kill_sockets.is_some()
+            || has_replacements
+            || step_gate_clone.is_some()
+            || recording_clone.is_some();
+        let mut killed = false;
+        let mut outbound = Outbound::new(
+            upstream_write,
+            Pacer {
+                latency,
+                message_rate: message_rate.map(MessageRate::new),
+                throttle: client_throttle,
+            },
+        );
+        'proxy: loop {
             buf.clear();
             match client_read.read_buf(&mut buf).await {
                 Ok(0) => {
@@ -352,19 +2013,68 @@ where
                     break;
                 }
                 Ok(n) => {
+                    // Without fault injection or rewrites bytes are forwarded
+                    // as soon as they arrive; with them only whole messages
+                    // can be withheld, delayed or replaced
+                    let mut messages = framer.push(&buf[..n]);
+                    if dropper.is_active() {
+                        messages = dropper.filter(&messages, &client_addr_clone);
+                    }
+                    let forwarded = if whole_messages {
+                        &messages[..]
+                    } else {
+                        &buf[..n]
+                    };
+
                     // Parse and log
-                    parse_message(
-                        &buf[..n],
+                    let plan = parse_message(
+                        &messages,
                         MessageDirection::ClientToServer,
                         &client_addr_clone,
                         Some(&*timings_clone),
-                        &*client_state_clone,
+                        &client_state_clone,
                         hex_dump,
                     );
+                    let planned;
+                    let forwarded = if plan.is_unchanged() {
+                        forwarded
+                    } else {
+                        planned = plan.apply(forwarded);
+                        &planned[..]
+                    };
+                    // With --step each message waits for the operator
+                    let parts = match &step_gate_clone {
+                        Some(_) => split_messages(forwarded),
+                        None => vec![forwarded],
+                    };
+                    for part in parts {
+                        if let Some(gate) = &step_gate_clone {
+                            if !gate.hold(&client_addr_clone, true, part).await {
+                                kill_session(&step_sockets_clone);
+                                break 'proxy;
+                            }
+                        }
+                        if let Some(capture) = &capture_clone {
+                            capture.record(true, part);
+                        }
+                        if let Some(recording) = &recording_clone {
+                            recording.record(true, part);
+                        }
+                        if let Some(metrics) = &metrics_clone {
+                            metrics.forwarded(true, part.len());
+                        }
+                        if let Some(session) = &session_clone {
+                            session.forwarded(true, part.len());
+                        }
 
-                    // Forward to upstream
-                    if let Err(e) = upstream_write.write_all(&buf[..n]).await {
-                        error!("[{}] Failed to write to upstream: {}", client_addr_clone, e);
+                        // Forward to upstream
+                        if let Err(e) = outbound.write(part).await {
+                            error!("[{}] Failed to write to upstream: {}", client_addr_clone, e);
+                            break 'proxy;
+                        }
+                    }
+                    if plan.kill_point.is_some() {
+                        killed = true;
                         break;
                     }
                 }
@@ -374,17 +2084,61 @@ where
                 }
             }
         }
-    });
+        // Deliver what a paced writer still holds before the connection closes
+        if let Err(e) = outbound.finish().await {
+            error!("[{}] Failed to write to upstream: {}", client_addr_clone, e);
+        }
+        if let (true, Some((client_socket, upstream_socket))) = (killed, &kill_sockets) {
+            // Errors only mean the peer already went away
+            if kill_side.closes_server() {
+                let _ = upstream_socket.shutdown(Shutdown::Both);
+            }
+            if kill_side.closes_client() {
+                let _ = client_socket.shutdown(Shutdown::Both);
+            }
+        }
+    }));
 
     let client_addr_clone = client_addr.clone();
     let timings_clone = timings.clone();
     let client_state_clone = client_state.clone();
-    let upstream_to_client = tokio::spawn(async move {
+    let capture_clone = capture.clone();
+    let drop_rules = options_drop_rules;
+    let registry = cancel_registry.clone();
+    let session_clone = session.clone();
+    let upstream_to_client = tokio::spawn(with_client_level(level, async move {
         let mut buf = BytesMut::with_capacity(8192);
-        loop {
+        let mut registered = false;
+        let mut framer = MessageFramer::default();
+        let mut dropper = MessageDropper::new(&drop_rules, false);
+        let latency = server_latency;
+        let mut outbound = Outbound::new(
+            client_write,
+            Pacer {
+                latency,
+                message_rate: message_rate.map(MessageRate::new),
+                throttle: server_throttle,
+            },
+        );
+        'proxy: loop {
             buf.clear();
-            match upstream_read.read_buf(&mut buf).await {
-                Ok(0) => {
+            // A canned --respond result or an injected error is sent as
+            // soon as the server has answered everything the client sent
+            // before it
+            let read = tokio::select! {
+                read = upstream_read.read_buf(&mut buf) => Some(read),
+                _ = client_state_clone.local_replies().queued(), if answers_locally => None,
+            };
+            let mut messages;
+            let mut raw = None;
+            match read {
+                None => {
+                    messages = client_state_clone.local_replies().take_due();
+                    if messages.is_empty() {
+                        continue;
+                    }
+                }
+                Some(Ok(0)) => {
                     info!(
                         "[{}] Upstream closed connection (session {})",
                         client_addr_clone,
@@ -392,24 +2146,25 @@ where
                     );
                     break;
                 }
-                Ok(n) => {
-                    // Parse and log
-                    parse_message(
-                        &buf[..n],
-                        MessageDirection::ServerToClient,
-                        &client_addr_clone,
-                        Some(&*timings_clone),
-                        &*client_state_clone,
-                        hex_dump,
-                    );
-
-                    // Forward to client
-                    if let Err(e) = client_write.write_all(&buf[..n]).await {
-                        error!("[{}] Failed to write to client: {}", client_addr_clone, e);
-                        break;
+                Some(Ok(n)) => {
+                    // Without fault injection or canned results bytes are
+                    // forwarded as soon as they arrive; with them only whole
+                    // messages can be withheld, delayed or interleaved
+                    messages = framer.push(&buf[..n]);
+                    if dropper.is_active() {
+                        messages = dropper.filter(&messages, &client_addr_clone);
+                    }
+                    if !(dropper.is_active()
+                        || latency.is_active()
+                        || message_rate.is_some()
+                        || answers_locally
+                        || step_gate.is_some()
+                        || recording.is_some())
+                    {
+                        raw = Some(n);
                     }
                 }
-                Err(e) => {
+                Some(Err(e)) => {
                     error!(
                         "[{}] Failed to read from upstream: {}",
                         client_addr_clone, e
@@ -417,19 +2172,136 @@ where
                     break;
                 }
             }
+            let forwarded = match raw {
+                Some(n) => &buf[..n],
+                None => &messages[..],
+            };
+            let interleaved;
+            let segments = if answers_locally && read.is_some() {
+                interleaved = client_state_clone.local_replies().interleave(&messages);
+                interleaved
+                    .iter()
+                    .map(|segment| (&segment[..], &segment[..]))
+                    .collect()
+            } else {
+                vec![(&messages[..], forwarded)]
+            };
+
+            for (parsed, forwarded) in segments {
+                // Parse and log
+                parse_message(
+                    parsed,
+                    MessageDirection::ServerToClient,
+                    &client_addr_clone,
+                    Some(&*timings_clone),
+                    &client_state_clone,
+                    hex_dump,
+                );
+                // Known before the client sees BackendKeyData, so it
+                // can cancel straight away
+                if !registered {
+                    if let (Some(key), Some(addr)) =
+                        (client_state_clone.backend_key(), upstream_addr)
+                    {
+                        registry.register(key, addr, &client_addr_clone);
+                        registered = true;
+                    }
+                }
+                let parts = match &step_gate {
+                    Some(_) => split_messages(forwarded),
+                    None => vec![forwarded],
+                };
+                for part in parts {
+                    if let Some(gate) = &step_gate {
+                        if !gate.hold(&client_addr_clone, false, part).await {
+                            kill_session(&step_sockets);
+                            break 'proxy;
+                        }
+                    }
+                    if let Some(capture) = &capture_clone {
+                        capture.record(false, part);
+                    }
+                    if let Some(recording) = &recording {
+                        recording.record(false, part);
+                    }
+                    if let Some(metrics) = &metrics {
+                        metrics.forwarded(false, part.len());
+                    }
+                    if let Some(session) = &session_clone {
+                        session.forwarded(false, part.len());
+                    }
+
+                    // Forward to client
+                    if let Err(e) = outbound.write(part).await {
+                        error!("[{}] Failed to write to client: {}", client_addr_clone, e);
+                        break 'proxy;
+                    }
+                }
+            }
         }
-    });
+        if let Err(e) = outbound.finish().await {
+            error!("[{}] Failed to write to client: {}", client_addr_clone, e);
+        }
+    }));
 
     // Wait for either direction to complete
-    tokio::select! {
-        _ = client_to_upstream => {},
-        _ = upstream_to_client => {},
+    let closed_by_client = tokio::select! {
+        _ = client_to_upstream => true,
+        _ = upstream_to_client => false,
+        _ = async {
+            match &session {
+                Some(session) => session.killed().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            info!("[{}] Session killed through the admin endpoint", client_addr);
+            kill_session(&admin_sockets);
+            true
+        }
+    };
+    if let Some(capture) = &capture {
+        capture.close(closed_by_client);
+    }
+    if let Some(key) = client_state.backend_key() {
+        cancel_registry.unregister(&key);
     }
 
+    let (busy, idle) = timings.busy_and_idle();
     info!(
-        "[{}] Connection closed (session {})",
+        "[{}] Connection closed (application_name {}, session {}, busy {}, idle {})",
         client_addr,
-        format_duration(timings.session_elapsed())
+        describe_application_name(client_state.application_name()),
+        format_duration(timings.session_elapsed()),
+        format_duration(busy),
+        format_duration(idle)
     );
+    for (from, to, kbps, throughput) in &throughputs {
+        if let (Some(kbps), Some(throughput)) = (kbps, throughput) {
+            info!(
+                "[{}] Throttled {} → {}: {} bytes, {} kbps average (limit {} kbps)",
+                client_addr,
+                from,
+                to,
+                throughput.bytes(),
+                throughput
+                    .average_kbps()
+                    .map_or("-".to_string(), |average| format!("{:.1}", average)),
+                kbps
+            );
+        }
+    }
+    let slowest = client_state.slowest_queries();
+    if !slowest.is_empty() {
+        info!("[{}] Slowest queries:", client_addr);
+        for (rank, (duration, sql)) in slowest.iter().enumerate() {
+            info!(
+                "[{}]   {}. {} {}",
+                client_addr,
+                rank + 1,
+                format_duration(*duration),
+                sql
+            );
+        }
+    }
     Ok(())
 }
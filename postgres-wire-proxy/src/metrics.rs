@@ -0,0 +1,492 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::fault::message_name;
+
+/// Upper bounds of the query duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Characters of a SQLSTATE class: 0-9 then A-Z
+const SQLSTATE_CHARS: usize = 36;
+/// Longest HTTP request head read from a scraper or admin client
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Counters and histograms served on --metrics-listen.
+///
+/// Everything is a relaxed atomic so the forwarding and parsing paths only
+/// pay for an increment; the text exposition is built when scraped.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Queries at least this slow count in the slow query total
+    slow_query: Duration,
+    connections_active: AtomicU64,
+    connections_total: AtomicU64,
+    /// Bytes forwarded from the client (0) and from the server (1)
+    bytes: [AtomicU64; 2],
+    /// Messages per direction and type byte
+    messages: [[AtomicU64; 256]; 2],
+    /// Per-bucket (not cumulative) counts, the last one being +Inf
+    query_durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    query_duration_micros: AtomicU64,
+    queries: AtomicU64,
+    slow_queries: AtomicU64,
+    /// ErrorResponses per SQLSTATE class, indexed by its two characters
+    errors: [AtomicU64; SQLSTATE_CHARS * SQLSTATE_CHARS],
+}
+
+/// Keeps a connection counted as active until dropped
+pub struct ActiveConnection(Arc<Metrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new(slow_query: Duration) -> Arc<Self> {
+        let zero = |_| AtomicU64::new(0);
+        Arc::new(Self {
+            slow_query,
+            connections_active: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+            bytes: std::array::from_fn(zero),
+            messages: std::array::from_fn(|_| std::array::from_fn(zero)),
+            query_durations: std::array::from_fn(zero),
+            query_duration_micros: AtomicU64::new(0),
+            queries: AtomicU64::new(0),
+            slow_queries: AtomicU64::new(0),
+            errors: std::array::from_fn(zero),
+        })
+    }
+
+    /// Count a new client connection, active until the guard is dropped
+    pub fn connection_opened(self: &Arc<Self>) -> ActiveConnection {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    pub fn forwarded(&self, from_client: bool, bytes: usize) {
+        self.bytes[direction_index(from_client)].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn message(&self, from_client: bool, msg_type: u8) {
+        self.messages[direction_index(from_client)][msg_type as usize]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A query or Execute ended with CommandComplete, EmptyQueryResponse or
+    /// ErrorResponse after `duration`
+    pub fn query_finished(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.query_durations[bucket].fetch_add(1, Ordering::Relaxed);
+        self.query_duration_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if duration >= self.slow_query {
+            self.slow_queries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count an ErrorResponse by the class (first two characters) of its
+    /// SQLSTATE; malformed codes are not counted
+    pub fn error(&self, sqlstate: &str) {
+        let class = sqlstate.as_bytes();
+        if let (Some(first), Some(second)) = (
+            class.first().and_then(|c| sqlstate_char_index(*c)),
+            class.get(1).and_then(|c| sqlstate_char_index(*c)),
+        ) {
+            self.errors[first * SQLSTATE_CHARS + second].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The Prometheus text exposition of every metric
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_connections_active",
+            "gauge",
+            "Client connections currently open",
+        );
+        let _ = writeln!(
+            out,
+            "pgwire_proxy_connections_active {}",
+            load(&self.connections_active)
+        );
+        write_header(
+            &mut out,
+            "pgwire_proxy_connections_total",
+            "counter",
+            "Client connections accepted",
+        );
+        let _ = writeln!(
+            out,
+            "pgwire_proxy_connections_total {}",
+            load(&self.connections_total)
+        );
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_bytes_total",
+            "counter",
+            "Bytes forwarded, by the side that sent them",
+        );
+        for from_client in [true, false] {
+            let _ = writeln!(
+                out,
+                "pgwire_proxy_bytes_total{{direction=\"{}\"}} {}",
+                direction_label(from_client),
+                load(&self.bytes[direction_index(from_client)])
+            );
+        }
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_messages_total",
+            "counter",
+            "Protocol messages seen, by direction and type",
+        );
+        for from_client in [true, false] {
+            for (msg_type, count) in self.messages[direction_index(from_client)]
+                .iter()
+                .enumerate()
+            {
+                let count = load(count);
+                if count == 0 {
+                    continue;
+                }
+                let name = match message_name(from_client, msg_type as u8) {
+                    "unknown message" => format!("0x{:02x}", msg_type),
+                    name => name.to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "pgwire_proxy_messages_total{{direction=\"{}\",type=\"{}\"}} {}",
+                    direction_label(from_client),
+                    name,
+                    count
+                );
+            }
+        }
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_queries_total",
+            "counter",
+            "Queries and Executes that finished, successfully or not",
+        );
+        let _ = writeln!(out, "pgwire_proxy_queries_total {}", load(&self.queries));
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_errors_total",
+            "counter",
+            "ErrorResponses from the server, by SQLSTATE class",
+        );
+        for (index, count) in self.errors.iter().enumerate() {
+            let count = load(count);
+            if count > 0 {
+                let _ = writeln!(
+                    out,
+                    "pgwire_proxy_errors_total{{class=\"{}{}\"}} {}",
+                    sqlstate_char(index / SQLSTATE_CHARS),
+                    sqlstate_char(index % SQLSTATE_CHARS),
+                    count
+                );
+            }
+        }
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_query_duration_seconds",
+            "histogram",
+            "Time from a query or Execute to its last response",
+        );
+        let mut cumulative = 0;
+        for (index, count) in self.query_durations.iter().enumerate() {
+            cumulative += load(count);
+            let bound = DURATION_BUCKETS
+                .get(index)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(
+                out,
+                "pgwire_proxy_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "pgwire_proxy_query_duration_seconds_sum {}",
+            load(&self.query_duration_micros) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "pgwire_proxy_query_duration_seconds_count {}",
+            cumulative
+        );
+
+        write_header(
+            &mut out,
+            "pgwire_proxy_slow_queries_total",
+            "counter",
+            &format!(
+                "Queries that took at least {}ms",
+                self.slow_query.as_millis()
+            ),
+        );
+        let _ = writeln!(
+            out,
+            "pgwire_proxy_slow_queries_total {}",
+            load(&self.slow_queries)
+        );
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn direction_index(from_client: bool) -> usize {
+    if from_client {
+        0
+    } else {
+        1
+    }
+}
+
+fn direction_label(from_client: bool) -> &'static str {
+    if from_client {
+        "client_to_server"
+    } else {
+        "server_to_client"
+    }
+}
+
+fn sqlstate_char_index(c: u8) -> Option<usize> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as usize),
+        b'A'..=b'Z' => Some((c - b'A') as usize + 10),
+        _ => None,
+    }
+}
+
+fn sqlstate_char(index: usize) -> char {
+    if index < 10 {
+        (b'0' + index as u8) as char
+    } else {
+        (b'A' + (index - 10) as u8) as char
+    }
+}
+
+/// Answer scrapes on `listener` until the proxy exits
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_scrape(socket, &metrics).await {
+                debug!("Metrics request from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Read one HTTP request and answer it; only `GET /metrics` is served
+async fn answer_scrape(mut socket: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let request = read_request_head(&mut socket).await?;
+    let (status, content_type, body) = match request_target(&request) {
+        Some(("GET", "/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        ),
+        Some(("GET", _)) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        Some(_) => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+        None => ("400 Bad Request", "text/plain", "Bad request\n".to_string()),
+    };
+    write_response(&mut socket, status, content_type, &body).await
+}
+
+/// The request line and headers of an HTTP request; a body is not read
+pub async fn read_request_head(socket: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_BYTES
+    {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+/// Answer with `body` and close the connection
+pub async fn write_response(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Method and path (without a query string) of an HTTP request line
+pub fn request_target(request: &[u8]) -> Option<(&str, &str)> {
+    let line = request.split(|b| *b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    parts.next()?.starts_with("HTTP/").then_some(())?;
+    Some((method, target.split('?').next().unwrap_or(target)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        encode_command_complete, encode_error_response, encode_query, encode_ready_for_query,
+        parse_message, ClientState, ConnectionTiming, MessageDirection, ProtocolOptions,
+    };
+
+    async fn scrape(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: proxy\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn scraped_metrics_follow_a_session() {
+        let metrics = Metrics::new(Duration::from_millis(50));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, metrics.clone()));
+
+        let connection = metrics.connection_opened();
+        let state = ClientState::new(
+            ProtocolOptions {
+                metrics: Some(metrics.clone()),
+                ..ProtocolOptions::default()
+            },
+            false,
+        );
+        let timings = ConnectionTiming::new();
+        let exchange = |from_client: bool, data: &[u8]| {
+            metrics.forwarded(from_client, data.len());
+            let direction = if from_client {
+                MessageDirection::ClientToServer
+            } else {
+                MessageDirection::ServerToClient
+            };
+            parse_message(data, direction, "test", Some(&timings), &state, false);
+        };
+        // A quick query, then a slow one that fails
+        exchange(true, &encode_query("select 1"));
+        exchange(
+            false,
+            &[
+                encode_command_complete("SELECT 1"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        exchange(true, &encode_query("select nope"));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        exchange(
+            false,
+            &[
+                encode_error_response("ERROR", "42703", "column \"nope\" does not exist"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let series: Vec<&str> = body.lines().filter(|line| !line.starts_with('#')).collect();
+        for expected in [
+            "pgwire_proxy_connections_active 1",
+            "pgwire_proxy_connections_total 1",
+            "pgwire_proxy_bytes_total{direction=\"client_to_server\"} 31",
+            "pgwire_proxy_messages_total{direction=\"client_to_server\",type=\"Query\"} 2",
+            "pgwire_proxy_messages_total{direction=\"server_to_client\",type=\"ReadyForQuery\"} 2",
+            "pgwire_proxy_messages_total{direction=\"server_to_client\",type=\"ErrorResponse\"} 1",
+            "pgwire_proxy_queries_total 2",
+            "pgwire_proxy_errors_total{class=\"42\"} 1",
+            "pgwire_proxy_query_duration_seconds_bucket{le=\"0.05\"} 1",
+            "pgwire_proxy_query_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "pgwire_proxy_query_duration_seconds_count 2",
+            "pgwire_proxy_slow_queries_total 1",
+        ] {
+            assert!(
+                series.contains(&expected),
+                "{} missing from\n{}",
+                expected,
+                body
+            );
+        }
+
+        drop(connection);
+        let response = scrape(addr, "/metrics?format=text").await;
+        assert!(response.contains("\npgwire_proxy_connections_active 0\n"));
+        assert!(scrape(addr, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn request_lines_are_parsed() {
+        assert_eq!(
+            request_target(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            request_target(b"POST /metrics?x=1 HTTP/1.0\r\n\r\n"),
+            Some(("POST", "/metrics"))
+        );
+        assert_eq!(request_target(b"garbage\r\n\r\n"), None);
+    }
+}
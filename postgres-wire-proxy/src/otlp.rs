@@ -0,0 +1,844 @@
+use anyhow::{Context as _, Result};
+use opentelemetry::trace::{
+    Span as _, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+    TraceState, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{Context, InstrumentationScope, KeyValue};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{
+    BatchConfigBuilder, BatchSpanProcessor, SdkTracer, SdkTracerProvider,
+};
+use opentelemetry_sdk::Resource;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Spans waiting for the exporter; later ones are dropped
+const QUEUE_LIMIT: usize = 8192;
+/// Spans sent in one request at most
+const MAX_BATCH: usize = 512;
+/// How long the exporter waits for more spans before sending a batch
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+/// Time allowed for one export request
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Span names are cut after this many characters
+const SPAN_NAME_CHARS: usize = 256;
+
+/// Where spans are sent: an OTLP/HTTP collector, given as
+/// `http://HOST[:PORT][/PATH]`. The port defaults to 4318 and the path to
+/// `/v1/traces`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl std::fmt::Display for OtlpEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+pub fn parse_otlp_endpoint(url: &str) -> Result<OtlpEndpoint, String> {
+    if url.starts_with("https://") {
+        return Err("only http:// collectors are supported; run one next to the proxy".into());
+    }
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("expected http://HOST[:PORT][/PATH], got {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, ""),
+    };
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| format!("unterminated IPv6 address in {}", url))?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in {}", url));
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("invalid port in {}", url))?,
+        None => 4318,
+    };
+    Ok(OtlpEndpoint {
+        host: host.to_string(),
+        port,
+        path: if path.is_empty() || path == "/" {
+            "/v1/traces".to_string()
+        } else {
+            path.to_string()
+        },
+    })
+}
+
+/// A finished query, as reported by the connection that ran it
+pub struct QuerySpan<'a> {
+    pub client_addr: &'a str,
+    pub database: Option<&'a str>,
+    pub user: Option<&'a str>,
+    /// SQL with whitespace collapsed
+    pub query: &'a str,
+    /// Rows returned or affected; `None` when the query failed
+    pub rows: Option<u64>,
+    /// DataRow bytes sent back to the client
+    pub bytes: u64,
+    pub duration: Duration,
+    /// SQLSTATE of the ErrorResponse that ended the query
+    pub error_code: Option<&'a str>,
+    /// Messages of the query, exported as child spans
+    pub messages: Vec<MessageSpan>,
+}
+
+/// A protocol message inside a query: a Bind until its BindComplete, a
+/// DataRow, or an ErrorResponse. Each becomes a child of the query's span.
+#[derive(Clone, Debug)]
+pub struct MessageSpan {
+    name: &'static str,
+    start: SystemTime,
+    /// None until the message is answered; the query's end then ends it
+    end: Option<SystemTime>,
+    attributes: Vec<KeyValue>,
+    error: Option<String>,
+}
+
+impl MessageSpan {
+    fn new(name: &'static str, attributes: Vec<KeyValue>) -> Self {
+        Self {
+            name,
+            start: SystemTime::now(),
+            end: None,
+            attributes,
+            error: None,
+        }
+    }
+
+    pub fn bind(portal: &str, statement: &str) -> Self {
+        Self::new(
+            "Bind",
+            vec![
+                KeyValue::new("db.portal_name", portal.to_string()),
+                KeyValue::new("db.statement_name", statement.to_string()),
+            ],
+        )
+    }
+
+    /// The `row`th DataRow of the query, which ends as it arrives
+    pub fn data_row(row: u64) -> Self {
+        let mut span = Self::new("DataRow", vec![KeyValue::new("db.rows_count", row as i64)]);
+        span.end = Some(span.start);
+        span
+    }
+
+    pub fn error_response(code: &str) -> Self {
+        let mut span = Self::new(
+            "ErrorResponse",
+            vec![
+                KeyValue::new("db.sql.state", code.to_string()),
+                KeyValue::new("otel.status_code", "ERROR"),
+            ],
+        );
+        span.end = Some(span.start);
+        span.error = Some(code.to_string());
+        span
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.end.is_some()
+    }
+
+    pub fn finish(&mut self) {
+        self.end = Some(SystemTime::now());
+    }
+}
+
+/// Turns finished queries into spans of the OpenTelemetry SDK. Connections
+/// only queue them; its batch processor sends them to the collector from a
+/// thread of its own and drops spans while `QUEUE_LIMIT` are waiting.
+#[derive(Debug)]
+pub struct Tracer {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+impl Tracer {
+    /// Start exporting to `endpoint`, reporting spans as coming from
+    /// `service_name`
+    pub fn start(endpoint: OtlpEndpoint, service_name: String) -> Result<Arc<Self>> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(endpoint.to_string())
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()
+            .with_context(|| format!("Failed to set up the exporter for {}", endpoint))?;
+        let batches = BatchConfigBuilder::default()
+            .with_max_queue_size(QUEUE_LIMIT)
+            .with_max_export_batch_size(MAX_BATCH)
+            .with_scheduled_delay(BATCH_DELAY)
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(
+                BatchSpanProcessor::builder(exporter)
+                    .with_batch_config(batches)
+                    .build(),
+            )
+            .with_resource(
+                Resource::builder_empty()
+                    .with_service_name(service_name)
+                    .build(),
+            )
+            .build();
+        Ok(Self::with_provider(provider))
+    }
+
+    fn with_provider(provider: SdkTracerProvider) -> Arc<Self> {
+        let scope = InstrumentationScope::builder("postgres-wire-proxy")
+            .with_version(env!("CARGO_PKG_VERSION"))
+            .build();
+        let tracer = provider.tracer_with_scope(scope);
+        Arc::new(Self { provider, tracer })
+    }
+
+    /// Queue the spans of a finished query and of its messages
+    pub fn record(&self, query: &QuerySpan) {
+        let end = SystemTime::now();
+        // The caller's span from a traceparent comment; the proxy exports
+        // every query whatever the caller sampled
+        let parent = match traceparent(query.query) {
+            Some((trace_id, span_id)) => Context::new().with_remote_span_context(SpanContext::new(
+                TraceId::from_bytes(trace_id),
+                SpanId::from_bytes(span_id),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            )),
+            None => Context::new(),
+        };
+        let mut attributes = vec![KeyValue::new("db.system", "postgresql")];
+        if let Some(user) = query.user {
+            attributes.push(KeyValue::new("db.user", user.to_string()));
+        }
+        if let Some(database) = query.database {
+            attributes.push(KeyValue::new("db.name", database.to_string()));
+        }
+        if let Ok(addr) = query.client_addr.parse::<SocketAddr>() {
+            attributes.push(KeyValue::new("client.address", addr.ip().to_string()));
+            attributes.push(KeyValue::new("client.port", i64::from(addr.port())));
+        }
+        if let Some(rows) = query.rows {
+            attributes.push(KeyValue::new("db.rows", rows as i64));
+        }
+        attributes.push(KeyValue::new("db.bytes", query.bytes as i64));
+        if let Some(code) = query.error_code {
+            attributes.push(KeyValue::new("db.response.status_code", code.to_string()));
+        }
+        let name: String = fingerprint(query.query)
+            .chars()
+            .take(SPAN_NAME_CHARS)
+            .collect();
+        let span = self
+            .tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Client)
+            .with_start_time(end.checked_sub(query.duration).unwrap_or(end))
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &parent);
+        let context = parent.with_span(span);
+        for message in &query.messages {
+            let mut span = self
+                .tracer
+                .span_builder(message.name)
+                .with_start_time(message.start)
+                .with_attributes(message.attributes.clone())
+                .start_with_context(&self.tracer, &context);
+            if let Some(code) = &message.error {
+                span.set_status(Status::error(format!("SQLSTATE {}", code)));
+            }
+            span.end_with_timestamp(message.end.unwrap_or(end));
+        }
+        let span = context.span();
+        if let Some(code) = query.error_code {
+            span.set_status(Status::error(format!("SQLSTATE {}", code)));
+        }
+        span.end_with_timestamp(end);
+    }
+
+    /// Export everything queued so far
+    pub async fn flush(&self) {
+        let provider = self.provider.clone();
+        // The processor blocks until its thread has sent the queue
+        let flushed = tokio::task::spawn_blocking(move || provider.force_flush()).await;
+        if let Ok(Err(e)) = flushed {
+            warn!("Failed to export the remaining spans: {}", e);
+        }
+    }
+}
+
+/// Trace and parent span ID of a sqlcommenter comment such as
+/// `/*traceparent='00-<trace id>-<span id>-01'*/`
+fn traceparent(sql: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut rest = sql;
+    while let Some(start) = rest.find("/*") {
+        let after = &rest[start + 2..];
+        let end = after.find("*/").unwrap_or(after.len());
+        for pair in after[..end].split(',') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            if key.trim() != "traceparent" {
+                continue;
+            }
+            let value = value.trim().trim_matches('\'');
+            if let Some(parent) = parse_traceparent(&percent_decode(value)) {
+                return Some(parent);
+            }
+        }
+        rest = &after[end..];
+    }
+    None
+}
+
+/// A W3C traceparent header value: version, trace ID, parent ID, flags
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || version == "ff" || flags.len() != 2 {
+        return None;
+    }
+    let trace_id: [u8; 16] = decode_hex(trace_id)?.try_into().ok()?;
+    let span_id: [u8; 8] = decode_hex(span_id)?.try_into().ok()?;
+    decode_hex(version)?;
+    decode_hex(flags)?;
+    // All-zero IDs are invalid
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// sqlcommenter URL-encodes its values
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The statement with comments dropped, literals replaced by `?` and
+/// whitespace collapsed, so runs that differ only in their values share a
+/// span name
+fn fingerprint(sql: &str) -> String {
+    let chars: Vec<char> = sql.chars().collect();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    let mut out = String::with_capacity(sql.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let previous = out.chars().last();
+        match c {
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                out.push(' ');
+            }
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 2;
+                out.push(' ');
+            }
+            '\'' => {
+                // E'...' strings take backslash escapes; the prefix goes too
+                let mut escapes = false;
+                if let Some(prefix) = previous.filter(|p| "eEbBxXnN".contains(*p)) {
+                    let before = out.chars().rev().nth(1);
+                    if !before.is_some_and(is_word) {
+                        out.pop();
+                        escapes = prefix == 'e' || prefix == 'E';
+                    }
+                }
+                i += 1;
+                while i < chars.len() {
+                    match chars[i] {
+                        '\\' if escapes => i += 2,
+                        '\'' if chars.get(i + 1) == Some(&'\'') => i += 2,
+                        '\'' => break,
+                        _ => i += 1,
+                    }
+                }
+                i += 1;
+                out.push('?');
+            }
+            '"' => {
+                out.push(c);
+                i += 1;
+                while i < chars.len() {
+                    out.push(chars[i]);
+                    i += 1;
+                    if chars[i - 1] == '"' {
+                        if chars.get(i) == Some(&'"') {
+                            out.push('"');
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            '$' if !previous.is_some_and(is_word) && !next.is_some_and(|n| n.is_ascii_digit()) => {
+                // A dollar-quoted string: $tag$ ... $tag$
+                let tag_end = chars[i + 1..]
+                    .iter()
+                    .position(|&t| !(t.is_alphanumeric() || t == '_'))
+                    .map(|n| i + 1 + n)
+                    .filter(|&end| chars[end] == '$');
+                match tag_end {
+                    Some(end) => {
+                        let tag = &chars[i..=end];
+                        i = end + 1;
+                        while i < chars.len() && !chars[i..].starts_with(tag) {
+                            i += 1;
+                        }
+                        i += tag.len();
+                        out.push('?');
+                    }
+                    None => {
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            c if (c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())))
+                && !previous.is_some_and(is_word) =>
+            {
+                while i < chars.len() {
+                    let d = chars[i];
+                    let exponent_sign =
+                        (d == '+' || d == '-') && matches!(chars.get(i - 1), Some('e') | Some('E'));
+                    if d.is_ascii_alphanumeric() || d == '.' || exponent_sign {
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                out.push('?');
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+impl Tracer {
+    /// A tracer keeping its spans in memory instead of exporting them
+    fn in_memory() -> (Arc<Self>, opentelemetry_sdk::trace::InMemorySpanExporter) {
+        let exported = opentelemetry_sdk::trace::InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exported.clone())
+            .build();
+        (Self::with_provider(provider), exported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        encode_backend_message, encode_command_complete, encode_error_response, encode_query,
+        encode_ready_for_query, parse_message, ClientState, ConnectionTiming, MessageDirection,
+        ProtocolOptions,
+    };
+    use opentelemetry::Value;
+    use opentelemetry_sdk::trace::SpanData;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    /// The exported spans called `name`, oldest first
+    fn named<'a>(spans: &'a [SpanData], name: &str) -> Vec<&'a SpanData> {
+        let mut named: Vec<&SpanData> = spans.iter().filter(|span| span.name == name).collect();
+        named.sort_by_key(|span| span.start_time);
+        named
+    }
+
+    fn startup(user: &str, database: &str) -> Vec<u8> {
+        let body = format!("user\0{}\0database\0{}\0\0", user, database);
+        let mut message = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&196608u32.to_be_bytes());
+        message.extend_from_slice(body.as_bytes());
+        message
+    }
+
+    #[tokio::test]
+    async fn a_scripted_session_becomes_spans() {
+        let (tracer, exported) = Tracer::in_memory();
+        let state = ClientState::new(
+            ProtocolOptions {
+                tracer: Some(tracer.clone()),
+                ..ProtocolOptions::default()
+            },
+            false,
+        );
+        state.record_startup(&startup("app", "shop"));
+        let timings = ConnectionTiming::new();
+        let exchange = |from_client: bool, data: &[u8]| {
+            let direction = if from_client {
+                MessageDirection::ClientToServer
+            } else {
+                MessageDirection::ServerToClient
+            };
+            parse_message(
+                data,
+                direction,
+                "10.0.0.7:51000",
+                Some(&timings),
+                &state,
+                false,
+            );
+        };
+
+        // A simple query carrying the application's trace context
+        let query = format!(
+            "select id from orders where total > 100 /*controller='cart',traceparent='00-{}-{}-01'*/",
+            TRACE_ID, PARENT_ID
+        );
+        exchange(true, &encode_query(&query));
+        let mut row_description = b"\0\x01id\0".to_vec();
+        row_description.extend_from_slice(&[0; 6]);
+        row_description.extend_from_slice(&23u32.to_be_bytes());
+        row_description.extend_from_slice(&4i16.to_be_bytes());
+        row_description.extend_from_slice(&(-1i32).to_be_bytes());
+        row_description.extend_from_slice(&0u16.to_be_bytes());
+        exchange(
+            false,
+            &[
+                encode_backend_message(b'T', &row_description),
+                encode_backend_message(b'D', b"\0\x01\0\0\0\x0242"),
+                encode_backend_message(b'D', b"\0\x01\0\0\0\x0243"),
+                encode_command_complete("SELECT 2"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        // An extended query that fails
+        exchange(
+            true,
+            &[
+                encode_backend_message(
+                    b'P',
+                    b"\0update orders set total = $1 where id = 'x'\0\0\0",
+                ),
+                encode_backend_message(b'B', b"\0\0\0\0\0\x01\0\0\0\x015\0\0"),
+                encode_backend_message(b'E', b"\0\0\0\0\0"),
+                encode_backend_message(b'S', b""),
+            ]
+            .concat(),
+        );
+        exchange(
+            false,
+            &[
+                encode_backend_message(b'1', b""),
+                encode_backend_message(b'2', b""),
+                encode_error_response("ERROR", "22P02", "invalid input syntax for type bigint"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        tracer.flush().await;
+
+        let spans = exported.get_finished_spans().unwrap();
+        let mut names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "Bind",
+                "DataRow",
+                "DataRow",
+                "ErrorResponse",
+                "select id from orders where total > ?",
+                "update orders set total = $1 where id = ?",
+            ]
+        );
+        let select = named(&spans, "select id from orders where total > ?")[0];
+        let update = named(&spans, "update orders set total = $1 where id = ?")[0];
+        let rows = named(&spans, "DataRow");
+        let children = [
+            (rows[0], select),
+            (rows[1], select),
+            (named(&spans, "Bind")[0], update),
+            (named(&spans, "ErrorResponse")[0], update),
+        ];
+        for (child, parent) in children {
+            assert_eq!(
+                child.span_context.trace_id(),
+                parent.span_context.trace_id()
+            );
+            assert_eq!(child.parent_span_id, parent.span_context.span_id());
+        }
+
+        assert_eq!(select.span_kind, SpanKind::Client);
+        assert_eq!(select.span_context.trace_id().to_string(), TRACE_ID);
+        assert_eq!(select.parent_span_id.to_string(), PARENT_ID);
+        assert!(select.start_time <= select.end_time);
+        assert_eq!(select.status, Status::Unset);
+        assert_eq!(select.instrumentation_scope.name(), "postgres-wire-proxy");
+        for (key, value) in [
+            ("db.system", Value::from("postgresql")),
+            ("db.user", Value::from("app")),
+            ("db.name", Value::from("shop")),
+            ("client.address", Value::from("10.0.0.7")),
+            ("client.port", Value::I64(51000)),
+            ("db.rows", Value::I64(2)),
+            ("db.bytes", Value::I64(16)),
+        ] {
+            assert_eq!(attribute(select, key), Some(value), "{}", key);
+        }
+        assert_eq!(attribute(select, "db.response.status_code"), None);
+
+        assert_eq!(update.parent_span_id, SpanId::INVALID);
+        assert_ne!(
+            update.span_context.trace_id(),
+            select.span_context.trace_id()
+        );
+        assert_eq!(update.status, Status::error("SQLSTATE 22P02"));
+        assert_eq!(
+            attribute(update, "db.response.status_code"),
+            Some(Value::from("22P02"))
+        );
+        assert_eq!(attribute(update, "db.rows"), None);
+    }
+
+    #[tokio::test]
+    async fn message_spans_follow_the_messages() {
+        let (tracer, exported) = Tracer::in_memory();
+        let state = ClientState::new(
+            ProtocolOptions {
+                tracer: Some(tracer.clone()),
+                ..ProtocolOptions::default()
+            },
+            false,
+        );
+        let timings = ConnectionTiming::new();
+        let exchange = |from_client: bool, data: &[u8]| {
+            let direction = if from_client {
+                MessageDirection::ClientToServer
+            } else {
+                MessageDirection::ServerToClient
+            };
+            parse_message(data, direction, "test", Some(&timings), &state, false);
+        };
+
+        let execute = |portal: &str| {
+            let mut body = portal.as_bytes().to_vec();
+            body.extend_from_slice(b"\0\0\0\0\0");
+            encode_backend_message(b'E', &body)
+        };
+        exchange(
+            true,
+            &[
+                encode_backend_message(b'P', b"orders_by_id\0select id from orders\0\0\0"),
+                encode_backend_message(b'B', b"cur1\0orders_by_id\0\0\0\0\0\0\0"),
+                execute("cur1"),
+                encode_backend_message(b'S', b""),
+            ]
+            .concat(),
+        );
+        exchange(
+            false,
+            &[
+                encode_backend_message(b'1', b""),
+                encode_backend_message(b'2', b""),
+                encode_backend_message(b'D', b"\0\x01\0\0\0\x011"),
+                encode_backend_message(b'D', b"\0\x01\0\0\0\x012"),
+                encode_backend_message(b'D', b"\0\x01\0\0\0\x013"),
+                encode_command_complete("SELECT 3"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        exchange(true, &encode_query("select pg_sleep(10)"));
+        exchange(
+            false,
+            &[
+                encode_error_response("ERROR", "57014", "canceling statement"),
+                encode_ready_for_query(b'I'),
+            ]
+            .concat(),
+        );
+        tracer.flush().await;
+
+        let spans = exported.get_finished_spans().unwrap();
+        let query = named(&spans, "select id from orders")[0];
+        let bind = named(&spans, "Bind")[0];
+        assert_eq!(
+            bind.attributes,
+            [
+                KeyValue::new("db.portal_name", "cur1"),
+                KeyValue::new("db.statement_name", "orders_by_id")
+            ]
+        );
+        assert_eq!(bind.status, Status::Unset);
+        assert_eq!(bind.parent_span_id, query.span_context.span_id());
+        assert_eq!(bind.span_context.trace_id(), query.span_context.trace_id());
+        // One span per DataRow, each ending as its row arrives
+        let rows = named(&spans, "DataRow");
+        assert_eq!(rows.len(), 3);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(
+                row.attributes,
+                [KeyValue::new("db.rows_count", i as i64 + 1)]
+            );
+            assert_eq!(row.start_time, row.end_time);
+            assert_eq!(row.parent_span_id, query.span_context.span_id());
+        }
+
+        let error = named(&spans, "ErrorResponse")[0];
+        assert_eq!(
+            error.attributes,
+            [
+                KeyValue::new("db.sql.state", "57014"),
+                KeyValue::new("otel.status_code", "ERROR")
+            ]
+        );
+        assert_eq!(error.status, Status::error("SQLSTATE 57014"));
+        let query = named(&spans, "select pg_sleep(?)")[0];
+        assert_eq!(error.parent_span_id, query.span_context.span_id());
+    }
+
+    #[test]
+    fn statements_are_fingerprinted() {
+        for (sql, expected) in [
+            (
+                "SELECT * FROM t WHERE id = 42",
+                "SELECT * FROM t WHERE id = ?",
+            ),
+            (
+                "select 'it''s', E'a\\'b', x'ff', 1.5e-3, .5 from t2 -- note\nwhere c1 = $1",
+                "select ?, ?, ?, ?, ? from t2 where c1 = $1",
+            ),
+            (
+                "insert into \"Weird\"\"Name\" values ($$body$$, $q$it's$q$) /* c */",
+                "insert into \"Weird\"\"Name\" values (?, ?)",
+            ),
+            ("select col2, t.x1 from t", "select col2, t.x1 from t"),
+        ] {
+            assert_eq!(fingerprint(sql), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn traceparent_comments_are_read() {
+        let id = |sql: &str| {
+            traceparent(sql).map(|(trace, span)| {
+                (
+                    TraceId::from_bytes(trace).to_string(),
+                    SpanId::from_bytes(span).to_string(),
+                )
+            })
+        };
+        let expected = Some((TRACE_ID.to_string(), PARENT_ID.to_string()));
+        assert_eq!(
+            id(&format!(
+                "select 1 /*traceparent='00-{}-{}-01'*/",
+                TRACE_ID, PARENT_ID
+            )),
+            expected
+        );
+        // URL-encoded, after other sqlcommenter keys
+        assert_eq!(
+            id(&format!(
+                "select 1 /*action='index',traceparent='00%2D{}%2D{}%2D00'*/",
+                TRACE_ID, PARENT_ID
+            )),
+            expected
+        );
+        assert_eq!(id("select 1 /* no context */"), None);
+        assert_eq!(
+            id(&format!(
+                "select 1 /*traceparent='00-{}-0000000000000000-01'*/",
+                TRACE_ID
+            )),
+            None
+        );
+        assert_eq!(id("select 1 /*traceparent='00-abc-def-01'*/"), None);
+    }
+
+    #[test]
+    fn endpoints_are_parsed() {
+        assert_eq!(
+            parse_otlp_endpoint("http://collector:4318"),
+            Ok(OtlpEndpoint {
+                host: "collector".into(),
+                port: 4318,
+                path: "/v1/traces".into()
+            })
+        );
+        let endpoint = parse_otlp_endpoint("http://[::1]/otlp/v1/traces").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("::1", 4318));
+        assert_eq!(endpoint.to_string(), "http://[::1]:4318/otlp/v1/traces");
+        assert!(parse_otlp_endpoint("https://collector:4318").is_err());
+        assert!(parse_otlp_endpoint("collector:4318").is_err());
+        assert!(parse_otlp_endpoint("http://collector:http").is_err());
+    }
+}
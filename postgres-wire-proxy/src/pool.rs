@@ -0,0 +1,183 @@
+use crate::socket_options::SocketOptions;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+/// Pre-opened TCP connections to the upstream server.
+///
+/// A PostgreSQL session cannot be handed to a second client (startup only
+/// happens once per connection), so connections are never returned to the
+/// pool after use. What the pool saves is the TCP connect on the client's
+/// critical path, and the semaphore caps how many sessions run at once.
+pub struct UpstreamPool {
+    host: String,
+    port: u16,
+    socket_options: SocketOptions,
+    size: usize,
+    idle: Mutex<VecDeque<TcpStream>>,
+    permits: Arc<Semaphore>,
+}
+
+impl UpstreamPool {
+    /// Create the pool and open its initial connections
+    pub async fn new(
+        host: String,
+        port: u16,
+        socket_options: SocketOptions,
+        size: usize,
+    ) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            host,
+            port,
+            socket_options,
+            size,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            permits: Arc::new(Semaphore::new(size)),
+        });
+        for _ in 0..size {
+            pool.refill().await;
+        }
+        pool
+    }
+
+    /// Wait for a free slot and hand out an idle connection, reconnecting
+    /// when the idle one was closed by the server in the meantime
+    pub async fn acquire(self: &Arc<Self>) -> Result<PooledConnection> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let stream = loop {
+            let Some(stream) = self.idle.lock().unwrap().pop_front() else {
+                break self.socket_options.connect(&self.host, self.port).await?;
+            };
+            if is_alive(&stream) {
+                break stream;
+            }
+            debug!("Dropping closed idle upstream connection");
+        };
+
+        let pool = self.clone();
+        tokio::spawn(async move { pool.refill().await });
+        Ok(PooledConnection {
+            stream,
+            _permit: Some(permit),
+        })
+    }
+
+    /// Number of connections currently waiting in the pool
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    async fn refill(&self) {
+        if self.idle_count() >= self.size {
+            return;
+        }
+        match self.socket_options.connect(&self.host, self.port).await {
+            Ok(stream) => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < self.size {
+                    idle.push_back(stream);
+                }
+            }
+            Err(e) => warn!("Failed to open pooled upstream connection: {:#}", e),
+        }
+    }
+}
+
+/// An idle connection is still usable when the server has not closed it;
+/// PostgreSQL drops connections that never send a startup packet after
+/// authentication_timeout
+fn is_alive(stream: &TcpStream) -> bool {
+    match stream.try_read(&mut [0u8; 1]) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        // EOF, or unexpected bytes before startup
+        Ok(_) => false,
+    }
+}
+
+/// An upstream connection owned by one client session. The pool slot is
+/// released when it is dropped.
+pub struct PooledConnection {
+    stream: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PooledConnection {
+    /// Wrap a connection that was opened directly, outside any pool
+    pub fn unpooled(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            _permit: None,
+        }
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl AsyncRead for PooledConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PooledConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn pool_limits_sessions_and_replaces_closed_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = UpstreamPool::new("127.0.0.1".into(), port, SocketOptions::default(), 1).await;
+        assert_eq!(pool.idle_count(), 1);
+
+        // The server closes the pre-opened connection
+        let (accepted, _) = listener.accept().await.unwrap();
+        drop(accepted);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let first = pool.acquire().await.unwrap();
+        let (_server_side, _) = listener.accept().await.unwrap();
+        assert!(is_alive(first.stream()));
+
+        // The only slot is taken until the first session ends
+        let pool_clone = pool.clone();
+        let waiting = tokio::spawn(async move { pool_clone.acquire().await.map(|_| ()) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        waiting.await.unwrap().unwrap();
+    }
+}
@@ -1,8 +1,26 @@
-use std::sync::Mutex;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tracing::info;
+use tracing::{debug, error, info, warn, Level};
 
-use crate::table_formatter::{FieldInfo, TableState};
+use crate::admin::{SessionRegistry, SessionSummary};
+use crate::audit::{json_string, AuditLogger, AuditRecord};
+use crate::cancel::{decode_backend_key, BackendKey, CancelRegistry};
+use crate::canned::{CannedResponse, LocalReplies};
+use crate::capture::CaptureFile;
+use crate::events::{Event, EventFile, EventsDir};
+use crate::fault::{message_name, DropRule, ErrorRule, InjectedLatency, KillSwitch};
+use crate::metrics::Metrics;
+use crate::otlp::{MessageSpan, QuerySpan, Tracer};
+use crate::record::Recording;
+use crate::rewrite::{rewrite_message, QueryRewrite, MAX_MESSAGE_LENGTH};
+use crate::step::Stepper;
+use crate::table_formatter::{ColumnWidthOverride, FieldInfo, TableState};
+use crate::validator::ProtocolStateMachine;
 
 #[derive(Debug)]
 pub enum MessageDirection {
@@ -10,12 +28,49 @@ pub enum MessageDirection {
     ServerToClient,
 }
 
+/// A query or Execute in flight: its SQL with whitespace collapsed and,
+/// for an Execute, the parameters its portal was bound with
+#[derive(Debug)]
+pub struct TimedQuery {
+    pub sql: String,
+    pub params: Vec<Option<String>>,
+    /// The portal an Execute ran; None for a simple query
+    pub portal: Option<String>,
+}
+
+/// Start times of requests still waiting for their completion message.
+/// Each kind is a FIFO because a pipelining client can send several
+/// requests before the server answers the first one.
 #[derive(Default)]
 struct TimingState {
-    simple_query: Option<Instant>,
-    execute: Option<Instant>,
-    parse: Option<Instant>,
-    bind: Option<Instant>,
+    /// Start time and SQL of each query in flight
+    simple_query: VecDeque<(Instant, TimedQuery)>,
+    /// In-flight Executes per portal; a pipelining client can run the same
+    /// (usually unnamed) portal again before the first run completes
+    execute: HashMap<String, VecDeque<(Instant, TimedQuery)>>,
+    /// Portal of each Execute in flight, in the order the server answers
+    execute_order: VecDeque<String>,
+    parse: VecDeque<Instant>,
+    bind: VecDeque<Instant>,
+    error_seen: bool,
+    /// Per result set: whether its first DataRow was already timed
+    first_row_seen: bool,
+    /// Written by the server-direction task, read by the client-direction one
+    last_ready_for_query: Option<Instant>,
+    /// Start of the request the server is currently working on
+    busy_since: Option<Instant>,
+    busy_total: Duration,
+    idle_total: Duration,
+}
+
+impl TimingState {
+    fn clear(&mut self) {
+        self.simple_query.clear();
+        self.execute.clear();
+        self.execute_order.clear();
+        self.parse.clear();
+        self.bind.clear();
+    }
 }
 
 pub struct ConnectionTiming {
@@ -31,38 +86,68 @@ impl ConnectionTiming {
         }
     }
 
-    pub fn mark_simple_query(&self) {
-        self.state.lock().unwrap().simple_query = Some(Instant::now());
+    pub fn mark_simple_query(&self, sql: String) {
+        self.state.lock().unwrap().simple_query.push_back((
+            Instant::now(),
+            TimedQuery {
+                sql,
+                params: Vec::new(),
+                portal: None,
+            },
+        ));
     }
 
-    pub fn mark_execute(&self) {
-        self.state.lock().unwrap().execute = Some(Instant::now());
+    pub fn mark_execute(&self, portal: &str, sql: String, params: Vec<Option<String>>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .execute
+            .entry(portal.to_string())
+            .or_default()
+            .push_back((
+                Instant::now(),
+                TimedQuery {
+                    sql,
+                    params,
+                    portal: Some(portal.to_string()),
+                },
+            ));
+        state.execute_order.push_back(portal.to_string());
     }
 
     pub fn mark_parse(&self) {
-        self.state.lock().unwrap().parse = Some(Instant::now());
+        self.state.lock().unwrap().parse.push_back(Instant::now());
     }
 
     pub fn mark_bind(&self) {
-        self.state.lock().unwrap().bind = Some(Instant::now());
+        self.state.lock().unwrap().bind.push_back(Instant::now());
     }
 
-    pub fn finish_simple_query(&self) -> Option<Duration> {
+    pub fn finish_simple_query(&self) -> Option<(Duration, TimedQuery)> {
         self.state
             .lock()
             .unwrap()
             .simple_query
-            .take()
-            .map(|start| start.elapsed())
+            .pop_front()
+            .map(|(start, sql)| (start.elapsed(), sql))
     }
 
-    pub fn finish_execute(&self) -> Option<Duration> {
-        self.state
-            .lock()
-            .unwrap()
-            .execute
-            .take()
-            .map(|start| start.elapsed())
+    /// Stop the timer of the oldest Execute, answered by CommandComplete,
+    /// EmptyQueryResponse or PortalSuspended
+    pub fn finish_execute(&self) -> Option<(Duration, TimedQuery)> {
+        let mut state = self.state.lock().unwrap();
+        let portal = state.execute_order.pop_front()?;
+        let runs = state.execute.get_mut(&portal)?;
+        let (start, sql) = runs.pop_front()?;
+        if runs.is_empty() {
+            state.execute.remove(&portal);
+        }
+        Some((start.elapsed(), sql))
+    }
+
+    /// Stop the timer of the query or Execute the server is answering, for
+    /// responses that end either kind (ErrorResponse, EmptyQueryResponse)
+    pub fn finish_query(&self) -> Option<(Duration, TimedQuery)> {
+        self.finish_simple_query().or_else(|| self.finish_execute())
     }
 
     pub fn finish_parse(&self) -> Option<Duration> {
@@ -70,7 +155,7 @@ impl ConnectionTiming {
             .lock()
             .unwrap()
             .parse
-            .take()
+            .pop_front()
             .map(|start| start.elapsed())
     }
 
@@ -79,10 +164,77 @@ impl ConnectionTiming {
             .lock()
             .unwrap()
             .bind
-            .take()
+            .pop_front()
             .map(|start| start.elapsed())
     }
 
+    /// A RowDescription starts a new result set
+    pub fn start_result_set(&self) {
+        self.state.lock().unwrap().first_row_seen = false;
+    }
+
+    /// Time from the pending Query or Execute to the first DataRow of the
+    /// current result set; `None` for every later row
+    pub fn first_row(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if std::mem::replace(&mut state.first_row_seen, true) {
+            return None;
+        }
+        let execute = state
+            .execute_order
+            .front()
+            .and_then(|portal| state.execute.get(portal))
+            .and_then(|runs| runs.front());
+        state
+            .simple_query
+            .front()
+            .or(execute)
+            .map(|(start, _)| start.elapsed())
+    }
+
+    /// CommandComplete ends the result set
+    pub fn finish_result_set(&self) {
+        self.state.lock().unwrap().first_row_seen = false;
+    }
+
+    pub fn record_error(&self) {
+        self.state.lock().unwrap().error_seen = true;
+    }
+
+    /// After an error the server discards the rest of the pipelined work,
+    /// so none of the pending requests will ever complete
+    pub fn ready_for_query(&self) {
+        let mut state = self.state.lock().unwrap();
+        if std::mem::take(&mut state.error_seen) {
+            state.clear();
+        }
+        let now = Instant::now();
+        if let Some(busy_since) = state.busy_since.take() {
+            state.busy_total += now - busy_since;
+        }
+        state.last_ready_for_query = Some(now);
+    }
+
+    /// Called for client Query/Parse/Bind; returns how long the client sat
+    /// idle since the last ReadyForQuery when this message starts new work
+    pub fn request_started(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.busy_since.is_some() {
+            return None;
+        }
+        let now = Instant::now();
+        state.busy_since = Some(now);
+        let idle = now - state.last_ready_for_query.take()?;
+        state.idle_total += idle;
+        Some(idle)
+    }
+
+    /// Total time spent waiting on the server and on the client
+    pub fn busy_and_idle(&self) -> (Duration, Duration) {
+        let state = self.state.lock().unwrap();
+        (state.busy_total, state.idle_total)
+    }
+
     pub fn session_elapsed(&self) -> Duration {
         self.start.elapsed()
     }
@@ -92,1069 +244,6901 @@ pub fn format_duration(duration: Duration) -> String {
     format!("{:.3}s", duration.as_secs_f64())
 }
 
+/// Decoding and display settings shared by every connection
+#[derive(Clone, Debug)]
+pub struct ProtocolOptions {
+    pub table_mode: bool,
+    pub max_display_bytes: usize,
+    /// Maximum number of characters shown for text DataRow values
+    pub max_display_text: usize,
+    /// Hide Bind parameter values in logs
+    pub redact: bool,
+    /// Maximum number of bytes shown for each text Bind parameter
+    pub max_param_len: usize,
+    /// Maximum number of characters of Query and Parse SQL shown in the
+    /// log; 0 shows it all
+    pub max_query_log_length: usize,
+    /// Prefix each message with its per-direction sequence number
+    pub sequence_numbers: bool,
+    /// Client idle gaps shorter than this are not logged
+    pub idle_threshold: Duration,
+    /// Color table-mode cells by column type
+    pub table_color: bool,
+    /// Buffer up to this many rows per result set to size table columns to
+    /// their content; None streams rows with fixed widths
+    pub table_buffer_max_rows: Option<usize>,
+    /// Table column widths by field name, and for every other column
+    pub table_column_widths: ColumnWidthOverride,
+    /// Implicit (autocommit) transactions faster than this are not summarized
+    pub autocommit_threshold: Duration,
+    /// Check server responses against the messages the client sent
+    pub validate_protocol: bool,
+    /// Number of slowest queries listed when the connection closes
+    pub top_slow: usize,
+    /// Write the forwarded bytes of every connection to this capture file
+    pub capture: Option<Arc<CaptureFile>>,
+    /// Append every message to this recording (--record); within a
+    /// connection the handle carries that connection's number
+    pub recording: Option<Recording>,
+    /// Database substituted into every client's startup message
+    pub rewrite_database: Option<String>,
+    /// User substituted into every client's startup message
+    pub rewrite_user: Option<String>,
+    /// Databases clients may connect to; empty allows any (--allow-database)
+    pub allow_databases: Vec<String>,
+    /// Users clients may connect as; empty allows any (--allow-user)
+    pub allow_users: Vec<String>,
+    /// Append a record for every completed or failed query to this file
+    pub audit: Option<Arc<AuditLogger>>,
+    /// Write every decoded message of each connection to its own JSONL
+    /// file in this directory (--events-dir)
+    pub events: Option<Arc<EventsDir>>,
+    /// Text rows or binary tuples of a COPY previewed per CopyData message
+    pub copy_preview_lines: usize,
+    /// Messages withheld from the other side to test client error handling
+    pub drop_rules: Vec<DropRule>,
+    /// Delay before each message forwarded from the client to the server
+    pub client_latency: InjectedLatency,
+    /// Delay before each message forwarded from the server to the client
+    pub server_latency: InjectedLatency,
+    /// Closes the connection mid-session for --kill-after-messages and
+    /// --kill-on-query
+    pub kill_switch: KillSwitch,
+    /// Statements answered with an injected ErrorResponse (--error-on-query)
+    pub error_rules: Vec<ErrorRule>,
+    /// Substitutions applied to the SQL of Query and Parse (--rewrite)
+    pub query_rewrites: Vec<QueryRewrite>,
+    /// Queries answered by the proxy with a canned result (--respond)
+    pub canned_responses: Vec<CannedResponse>,
+    /// Most messages forwarded per second in each direction
+    pub max_messages_per_sec: Option<u64>,
+    /// Bandwidth limit of the bytes sent by the client, in kilobits per second
+    pub client_throttle_kbps: Option<u64>,
+    /// Bandwidth limit of the bytes sent by the server, in kilobits per second
+    pub server_throttle_kbps: Option<u64>,
+    /// Backend keys of live sessions, for routing CancelRequests
+    pub cancel_registry: Arc<CancelRegistry>,
+    /// Holds each message of one session for the operator (--step)
+    pub stepper: Option<Arc<Stepper>>,
+    /// Counters served on --metrics-listen
+    pub metrics: Option<Arc<Metrics>>,
+    /// Exports a span per query to --otlp-endpoint
+    pub tracer: Option<Arc<Tracer>>,
+    /// Live sessions listed and killed through --admin-listen
+    pub sessions: Option<Arc<SessionRegistry>>,
+}
+
+impl Default for ProtocolOptions {
+    fn default() -> Self {
+        Self {
+            table_mode: false,
+            max_display_bytes: 32,
+            max_display_text: 100,
+            redact: false,
+            max_param_len: 64,
+            max_query_log_length: 1000,
+            sequence_numbers: false,
+            idle_threshold: Duration::from_millis(100),
+            table_color: false,
+            table_buffer_max_rows: None,
+            table_column_widths: ColumnWidthOverride::default(),
+            autocommit_threshold: Duration::from_secs(1),
+            validate_protocol: false,
+            top_slow: 5,
+            capture: None,
+            recording: None,
+            rewrite_database: None,
+            rewrite_user: None,
+            allow_databases: Vec::new(),
+            allow_users: Vec::new(),
+            audit: None,
+            events: None,
+            copy_preview_lines: 3,
+            drop_rules: Vec::new(),
+            client_latency: InjectedLatency::default(),
+            server_latency: InjectedLatency::default(),
+            kill_switch: KillSwitch::default(),
+            error_rules: Vec::new(),
+            query_rewrites: Vec::new(),
+            canned_responses: Vec::new(),
+            max_messages_per_sec: None,
+            client_throttle_kbps: None,
+            server_throttle_kbps: None,
+            cancel_registry: Arc::default(),
+            stepper: None,
+            metrics: None,
+            tracer: None,
+            sessions: None,
+        }
+    }
+}
+
+impl ProtocolOptions {
+    /// Whether some replies come from the proxy rather than the server
+    /// (--respond, --error-on-query, --block-query)
+    pub fn answers_locally(&self) -> bool {
+        !self.canned_responses.is_empty() || !self.error_rules.is_empty()
+    }
+}
+
+/// Where the client's current extended query, the messages up to its Sync,
+/// stands
+#[derive(Debug, Default)]
+struct ExtendedQuery {
+    /// A message of it reached the server, which will answer the Sync
+    forwarded: bool,
+    /// The proxy failed a statement of it; the rest is withheld up to Sync
+    failed: bool,
+}
+
 /// Per-client state for managing table formatting and row descriptions
 pub struct ClientState {
+    options: ProtocolOptions,
+    /// Whether the client side of the connection is TLS-encrypted
+    tls: bool,
     table_state: TableState,
+    result_set: Mutex<ResultSetStats>,
+    /// Child spans of the queries in flight; only kept with a tracer
+    message_spans: Mutex<MessageSpans>,
+    /// Type OID and format code of each column of the latest RowDescription
+    result_columns: Mutex<Vec<(u32, u16)>>,
+    statements: Mutex<StatementTracker>,
+    /// Channels the client subscribed to with LISTEN in simple queries
+    listening: Mutex<BTreeSet<String>>,
+    transaction: Mutex<TransactionTracker>,
+    slow_queries: Mutex<SlowQueries>,
+    /// The COPY in progress, from its Copy*Response to CopyDone
+    copy: Mutex<Option<CopyState>>,
+    /// Name and result format of FunctionCalls awaiting their response
+    function_calls: Mutex<VecDeque<(String, u16)>>,
+    /// Session parameters recorded in the audit log
+    session: Mutex<SessionInfo>,
+    /// Present with --validate-protocol
+    validator: Option<Mutex<ProtocolStateMachine>>,
+    /// Number of framed messages seen so far in each direction
+    client_messages: AtomicU64,
+    server_messages: AtomicU64,
+    /// The extended query the client is sending
+    extended_query: Mutex<ExtendedQuery>,
+    /// Canned results and injected errors waiting for their turn to reach
+    /// the client
+    local_replies: LocalReplies,
+    /// This connection's file in --events-dir
+    events: Option<EventFile>,
 }
 
 impl ClientState {
-    pub fn new(table_mode: bool) -> Self {
+    pub fn new(options: ProtocolOptions, tls: bool) -> Self {
         Self {
-            table_state: TableState::new(table_mode),
+            table_state: TableState::new(
+                options.table_mode,
+                options.table_color,
+                options.table_buffer_max_rows,
+                options.table_column_widths.clone(),
+            ),
+            result_set: Mutex::new(ResultSetStats::default()),
+            message_spans: Mutex::new(MessageSpans::default()),
+            result_columns: Mutex::new(Vec::new()),
+            statements: Mutex::new(StatementTracker::default()),
+            listening: Mutex::new(BTreeSet::new()),
+            transaction: Mutex::new(TransactionTracker::default()),
+            slow_queries: Mutex::new(SlowQueries::new(options.top_slow)),
+            copy: Mutex::new(None),
+            function_calls: Mutex::new(VecDeque::new()),
+            session: Mutex::new(SessionInfo::default()),
+            validator: options
+                .validate_protocol
+                .then(|| Mutex::new(ProtocolStateMachine::default())),
+            client_messages: AtomicU64::new(0),
+            server_messages: AtomicU64::new(0),
+            extended_query: Mutex::new(ExtendedQuery::default()),
+            local_replies: LocalReplies::default(),
+            events: options
+                .events
+                .as_ref()
+                .and_then(|events| events.connection().inspect_err(|e| warn!("{:#}", e)).ok()),
+            options,
+            tls,
         }
     }
-}
-
-pub fn parse_message(
-    data: &[u8],
-    direction: MessageDirection,
-    client_addr: &str,
-    timings: Option<&ConnectionTiming>,
-    client_state: &ClientState,
-    hex_dump: bool,
-) {
-    let mut buf = data;
-    let arrow = match direction {
-        MessageDirection::ClientToServer => "→",
-        MessageDirection::ServerToClient => "←",
-    };
 
-    while buf.len() >= 5 {
-        let msg_type = buf[0] as char;
-        let length = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    /// Replies of --respond, --error-on-query and --block-query, sent by the
+    /// server-direction task
+    pub fn local_replies(&self) -> &LocalReplies {
+        &self.local_replies
+    }
 
-        if buf.len() < length + 1 {
-            // Incomplete message
-            break;
-        }
+    /// Sequence number of the next message in `direction`, starting at 1
+    fn next_sequence(&self, direction: &MessageDirection) -> u64 {
+        let counter = match direction {
+            MessageDirection::ClientToServer => &self.client_messages,
+            MessageDirection::ServerToClient => &self.server_messages,
+        };
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
 
-        // Full message including type byte and length
-        let full_message = &buf[..length + 1];
-        let msg_data = &buf[5..length + 1];
+    /// The slowest queries of the session, slowest first
+    pub fn slowest_queries(&self) -> Vec<(Duration, String)> {
+        self.slow_queries.lock().unwrap().sorted()
+    }
 
-        match direction {
-            MessageDirection::ClientToServer => {
-                parse_client_message(msg_type, msg_data, client_addr, arrow, timings, client_state);
+    /// Remember the database, user and application_name of the startup
+    /// message as forwarded to the server
+    pub fn record_startup(&self, message: &[u8]) {
+        let parameters = decode_startup_parameters(message).unwrap_or_default();
+        if let Some(events) = &self.events {
+            // Numbered 0: it comes before the first framed message
+            let mut event = Event::new(true, "StartupMessage", message.len());
+            for (name, value) in &parameters {
+                event.string(name, value);
             }
-            MessageDirection::ServerToClient => {
-                parse_server_message(msg_type, msg_data, client_addr, arrow, timings, client_state);
+            events.write(0, &event);
+        }
+        let mut session = self.session.lock().unwrap();
+        for (name, value) in parameters {
+            match name.as_str() {
+                "database" => session.database = Some(value),
+                "user" => session.user = Some(value),
+                "application_name" => session.application_name = Some(value),
+                "replication" => {
+                    session.replication = !matches!(
+                        value.to_ascii_lowercase().as_str(),
+                        "false" | "off" | "no" | "0"
+                    )
+                }
+                _ => {}
             }
         }
+        // The server defaults the database to the user name
+        if session.database.is_none() {
+            session.database = session.user.clone();
+        }
+    }
 
-        // Log hex dump
-        if hex_dump {
-            log_hex_dump(full_message, client_addr);
+    /// application_name the client connected with or last switched to
+    pub fn application_name(&self) -> Option<String> {
+        self.session.lock().unwrap().application_name.clone()
+    }
+
+    /// Process ID and secret key from the server's BackendKeyData
+    pub fn backend_key(&self) -> Option<BackendKey> {
+        self.session.lock().unwrap().backend_key.clone()
+    }
+
+    /// The session as listed by --admin-listen
+    pub fn summary(&self) -> SessionSummary {
+        let session = self.session.lock().unwrap();
+        // Named like the state column of pg_stat_activity
+        let state = match (session.active, session.ready_status) {
+            (true, _) => "active",
+            (false, Some(b'I')) => "idle",
+            (false, Some(b'T')) => "idle in transaction",
+            (false, Some(b'E')) => "idle in transaction (aborted)",
+            (false, _) => "starting",
+        };
+        SessionSummary {
+            database: session.database.clone(),
+            user: session.user.clone(),
+            application_name: session.application_name.clone(),
+            backend_pid: session.backend_key.as_ref().map(|key| key.pid),
+            state,
+            last_statement: session.last_statement.clone(),
         }
+    }
 
-        buf = &buf[length + 1..];
+    /// A Query or Execute of `sql` went to the server
+    fn statement_sent(&self, sql: Option<String>) {
+        let mut session = self.session.lock().unwrap();
+        session.active = true;
+        if sql.is_some() {
+            session.last_statement = sql;
+        }
     }
 
-    // If there's remaining data that doesn't form a complete message
-    if !buf.is_empty() && buf.len() < 5 {
-        info!(
-            "[{}] {} Partial message ({} bytes)",
+    /// Count a finished query in the --metrics-listen metrics
+    fn observe_query(&self, duration: Duration) {
+        if let Some(metrics) = &self.options.metrics {
+            metrics.query_finished(duration);
+        }
+    }
+
+    /// Update the --otlp-endpoint message spans; does nothing without a
+    /// tracer
+    fn message_span(&self, update: impl FnOnce(&mut MessageSpans)) {
+        if self.options.tracer.is_some() {
+            update(&mut self.message_spans.lock().unwrap());
+        }
+    }
+
+    /// Queue the --otlp-endpoint span of a query that completed (`rows`)
+    /// or failed (`error_code`), having sent `bytes` of rows
+    fn trace(
+        &self,
+        client_addr: &str,
+        duration: Duration,
+        query: &TimedQuery,
+        rows: Option<u64>,
+        bytes: u64,
+        error_code: Option<&str>,
+    ) {
+        let Some(tracer) = &self.options.tracer else {
+            return;
+        };
+        let messages = self.message_spans.lock().unwrap().take(query);
+        let session = self.session.lock().unwrap();
+        tracer.record(&QuerySpan {
             client_addr,
-            arrow,
-            buf.len()
-        );
+            database: session.database.as_deref(),
+            user: session.user.as_deref(),
+            query: &query.sql,
+            rows,
+            bytes,
+            duration,
+            error_code,
+            messages,
+        });
+    }
+
+    /// Write an audit record for a query that completed (`rows`) or failed
+    /// (`error_code`)
+    fn audit(
+        &self,
+        client_addr: &str,
+        duration: Duration,
+        query: &TimedQuery,
+        rows: Option<u64>,
+        error_code: Option<&str>,
+    ) {
+        let Some(audit) = &self.options.audit else {
+            return;
+        };
+        let session = self.session.lock().unwrap();
+        audit.record(&AuditRecord {
+            client_addr,
+            database: session.database.as_deref(),
+            user: session.user.as_deref(),
+            application_name: session.application_name.as_deref(),
+            query: &query.sql,
+            params: &query.params,
+            rows,
+            duration,
+            error_code,
+        });
     }
 }
 
-fn log_hex_dump(data: &[u8], client_addr: &str) {
-    const BYTES_PER_LINE: usize = 16;
+/// Session parameters from the startup message; application_name follows
+/// the server's ParameterStatus reports when the client changes it
+#[derive(Default)]
+struct SessionInfo {
+    database: Option<String>,
+    user: Option<String>,
+    application_name: Option<String>,
+    /// Whether the startup message asked for a replication connection
+    replication: bool,
+    /// Key a CancelRequest has to present to cancel this session's queries
+    backend_key: Option<BackendKey>,
+    /// Status of the latest ReadyForQuery
+    ready_status: Option<u8>,
+    /// A Query or Execute was sent after the latest ReadyForQuery
+    active: bool,
+    /// SQL of the latest Query or Execute, whitespace collapsed
+    last_statement: Option<String>,
+}
 
-    for (i, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
-        let offset = i * BYTES_PER_LINE;
-        let hex_string: String = chunk
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join(" ");
+/// The `limit` slowest queries seen so far, kept in a min-heap so the
+/// fastest of them is the one replaced
+struct SlowQueries {
+    limit: usize,
+    heap: BinaryHeap<Reverse<(Duration, String)>>,
+}
 
-        let ascii_string: String = chunk
-            .iter()
-            .map(|&b| {
-                if b >= 0x20 && b <= 0x7e {
-                    b as char
-                } else {
-                    '.'
-                }
-            })
-            .collect();
+impl SlowQueries {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            heap: BinaryHeap::with_capacity(limit + 1),
+        }
+    }
 
-        info!(
-            "[{}]   {:04x}: {:<48}  {}",
-            client_addr, offset, hex_string, ascii_string
-        );
+    fn record(&mut self, duration: Duration, sql: String) {
+        if self.limit == 0 {
+            return;
+        }
+        self.heap.push(Reverse((duration, sql)));
+        if self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+    }
+
+    fn sorted(&self) -> Vec<(Duration, String)> {
+        let mut queries: Vec<_> = self.heap.iter().map(|Reverse(q)| q.clone()).collect();
+        queries.sort_by(|a, b| b.cmp(a));
+        queries
     }
 }
 
-fn parse_client_message(
-    msg_type: char,
-    data: &[u8],
-    client_addr: &str,
-    arrow: &str,
-    timings: Option<&ConnectionTiming>,
-    _client_state: &ClientState,
-) {
-    match msg_type {
-        'Q' => {
-            // Simple query
-            if let Some(t) = timings {
-                t.mark_simple_query();
-            }
-            if let Ok(query) = std::str::from_utf8(&data[..data.len().saturating_sub(1)]) {
-                info!("[{}] {} Query: {}", client_addr, arrow, query);
-            } else {
-                info!(
-                    "[{}] {} Query (invalid UTF-8, {} bytes)",
-                    client_addr,
-                    arrow,
-                    data.len()
-                );
-            }
+/// DataRow totals for the result set currently being returned
+#[derive(Default)]
+struct ResultSetStats {
+    rows: u64,
+    bytes: u64,
+    /// Rows since the last PortalSuspended; a CommandComplete tag only
+    /// counts the rows of the final Execute
+    rows_since_suspend: u64,
+    /// Whether a RowDescription announced this result set
+    described: bool,
+}
+
+impl ResultSetStats {
+    fn start(&mut self) {
+        *self = Self {
+            described: true,
+            ..Self::default()
+        };
+    }
+
+    fn record_row(&mut self, bytes: usize) {
+        self.rows += 1;
+        self.rows_since_suspend += 1;
+        self.bytes += bytes as u64;
+    }
+
+    fn suspend(&mut self) {
+        self.rows_since_suspend = 0;
+    }
+}
+
+/// DataRow spans kept per query; later rows only count in its db.rows
+const MAX_ROW_SPANS: u64 = 1000;
+
+/// Message spans of the queries in flight, kept with --otlp-endpoint
+#[derive(Default)]
+struct MessageSpans {
+    /// Bind spans by portal, oldest first
+    binds: VecDeque<(String, MessageSpan)>,
+    /// DataRow and ErrorResponse spans of the query being answered
+    current: Vec<MessageSpan>,
+    /// DataRows of the query being answered
+    rows: u64,
+}
+
+impl MessageSpans {
+    fn bind_complete(&mut self) {
+        if let Some((_, bind)) = self.binds.iter_mut().find(|(_, bind)| !bind.is_finished()) {
+            bind.finish();
         }
-        'P' => {
-            // Parse (prepared statement)
-            if let Some(t) = timings {
-                t.mark_parse();
-            }
-            info!(
-                "[{}] {} Parse (prepared statement, {} bytes)",
-                client_addr,
-                arrow,
-                data.len()
+    }
+
+    fn data_row(&mut self) {
+        self.rows += 1;
+        if self.rows <= MAX_ROW_SPANS {
+            self.current.push(MessageSpan::data_row(self.rows));
+        }
+    }
+
+    /// The spans of a query that just finished, the Bind of its portal
+    /// first
+    fn take(&mut self, query: &TimedQuery) -> Vec<MessageSpan> {
+        let mut spans = Vec::new();
+        if let Some(portal) = &query.portal {
+            let bind = self.binds.iter().position(|(bound, _)| bound == portal);
+            spans.extend(
+                bind.and_then(|i| self.binds.remove(i))
+                    .map(|(_, bind)| bind),
             );
-            if let Some(details) = parse_parse_message(data) {
-                info!("[{}]    {}", client_addr, details);
-            }
         }
-        'B' => {
-            // Bind
-            if let Some(t) = timings {
-                t.mark_bind();
+        spans.append(&mut self.current);
+        self.rows = 0;
+        spans
+    }
+
+    /// At ReadyForQuery nothing is in flight any more
+    fn clear(&mut self) {
+        self.binds.clear();
+        self.current.clear();
+        self.rows = 0;
+    }
+}
+
+/// Maximum number of prepared statements remembered per connection
+const MAX_TRACKED_STATEMENTS: usize = 256;
+
+/// Number of SQL characters shown on Bind/Execute lines
+const SQL_PREVIEW_CHARS: usize = 60;
+
+/// SQL and parameter types known for a prepared statement
+struct TrackedStatement {
+    sql: String,
+    param_types: Vec<u32>,
+}
+
+/// Statement a portal was bound to and its parameters as shown in the log
+struct TrackedPortal {
+    statement: String,
+    params: Vec<Option<String>>,
+    /// A PortalSuspended left rows to fetch; the next Execute resumes it
+    suspended: bool,
+}
+
+/// The transaction in progress, delimited by ReadyForQuery status changes
+#[derive(Default)]
+struct TransactionTracker {
+    current: Option<Transaction>,
+}
+
+struct Transaction {
+    started: Instant,
+    statements: u64,
+    rows: u64,
+    /// Started with BEGIN (or START TRANSACTION) rather than implicitly
+    explicit: bool,
+    /// ReadyForQuery reported 'E' (failed transaction block)
+    aborted: bool,
+    error: bool,
+    last_tag: String,
+}
+
+impl TransactionTracker {
+    /// A request arrived or a result came back; opens a transaction if
+    /// none is in progress
+    fn current(&mut self) -> &mut Transaction {
+        self.current.get_or_insert_with(|| Transaction {
+            started: Instant::now(),
+            statements: 0,
+            rows: 0,
+            explicit: false,
+            aborted: false,
+            error: false,
+            last_tag: String::new(),
+        })
+    }
+
+    fn command_complete(&mut self, tag: &str, rows: u64) {
+        let transaction = self.current();
+        transaction.statements += 1;
+        transaction.rows += rows;
+        if tag == "BEGIN" || tag == "START TRANSACTION" {
+            transaction.explicit = true;
+        }
+        transaction.last_tag = tag.to_string();
+    }
+
+    fn error(&mut self) {
+        self.current().error = true;
+    }
+
+    /// Returns a summary line when `status` ends the transaction; implicit
+    /// transactions are only summarized when they took at least `threshold`
+    fn ready_for_query(&mut self, status: u8, threshold: Duration) -> Option<String> {
+        match status {
+            b'T' => None,
+            b'E' => {
+                if let Some(transaction) = self.current.as_mut() {
+                    transaction.aborted = true;
+                }
+                None
             }
-            info!("[{}] {} Bind ({} bytes)", client_addr, arrow, data.len());
-            if let Some(bind_info) = parse_bind_message(data) {
-                info!("[{}]    {}", client_addr, bind_info);
+            _ => {
+                let transaction = self.current.take()?;
+                let elapsed = transaction.started.elapsed();
+                if !transaction.explicit && elapsed < threshold {
+                    return None;
+                }
+                let outcome = if transaction.aborted {
+                    "rolled back after error"
+                } else if transaction.error {
+                    "failed"
+                } else if transaction.last_tag == "ROLLBACK" {
+                    "rolled back"
+                } else {
+                    "committed"
+                };
+                Some(format!(
+                    "{} {} ({} {}, {} {}, {})",
+                    if transaction.explicit {
+                        "Transaction"
+                    } else {
+                        "Implicit transaction"
+                    },
+                    outcome,
+                    transaction.statements,
+                    if transaction.statements == 1 {
+                        "statement"
+                    } else {
+                        "statements"
+                    },
+                    transaction.rows,
+                    if transaction.rows == 1 { "row" } else { "rows" },
+                    format_duration(elapsed)
+                ))
             }
         }
-        'E' => {
-            // Execute
-            if let Some(t) = timings {
-                t.mark_execute();
-            }
-            info!("[{}] {} Execute ({} bytes)", client_addr, arrow, data.len());
+    }
+}
+
+/// Statement name to SQL mapping learned from Parse messages, plus the
+/// portal to statement mapping learned from Bind messages
+#[derive(Default)]
+struct StatementTracker {
+    statements: HashMap<String, TrackedStatement>,
+    order: VecDeque<String>,
+    portals: HashMap<String, TrackedPortal>,
+    /// Statement Describes and Closes awaiting their response, in order
+    pending_responses: VecDeque<PendingResponse>,
+    /// An ErrorResponse arrived with no Sync outstanding, so the server
+    /// discards every client message until the next Sync
+    awaiting_sync: bool,
+}
+
+/// Client messages the server answers in order, used to pair each
+/// ParameterDescription and CloseComplete with the message that asked for it
+enum PendingResponse {
+    Describe(String),
+    /// Description of the closed object, e.g. `statement '_p3'`
+    Close(String),
+    /// Portal of an Execute, answered by CommandComplete, PortalSuspended
+    /// or EmptyQueryResponse
+    Execute(String),
+    /// A Sync or simple Query; ends the batch answered by one ReadyForQuery
+    Sync,
+}
+
+impl StatementTracker {
+    fn record_parse(&mut self, name: &str, sql: &str) {
+        let statement = TrackedStatement {
+            sql: sql.to_string(),
+            param_types: Vec::new(),
+        };
+        if self
+            .statements
+            .insert(name.to_string(), statement)
+            .is_some()
+        {
+            self.order.retain(|existing| existing != name);
         }
-        'D' => {
-            // Describe
-            if data.is_empty() {
-                info!("[{}] {} Describe (unknown)", client_addr, arrow);
-                return;
+        self.order.push_back(name.to_string());
+
+        while self.order.len() > MAX_TRACKED_STATEMENTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.statements.remove(&oldest);
             }
+        }
+    }
 
-            let describe_target = data[0] as char;
-            let name = if data.len() > 1 {
-                let rest = &data[1..];
-                let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
-                let raw = &rest[..end];
-                String::from_utf8_lossy(raw).to_string()
-            } else {
-                String::new()
-            };
-            let formatted_name = if name.is_empty() {
-                "(unnamed)".to_string()
-            } else {
-                name
-            };
+    fn close(&mut self, name: &str) -> Option<String> {
+        let removed = self.statements.remove(name);
+        if removed.is_some() {
+            self.order.retain(|existing| existing != name);
+        }
+        // Closing a statement implicitly closes the portals built from it
+        self.portals.retain(|_, portal| portal.statement != name);
+        removed.map(|statement| statement.sql)
+    }
 
-            let describe_type = match describe_target {
-                'S' => "statement",
-                'P' => "portal",
-                _ => "unknown",
-            };
+    fn sql_for(&self, name: &str) -> Option<&str> {
+        self.statements
+            .get(name)
+            .map(|statement| statement.sql.as_str())
+    }
 
-            match describe_target {
-                'S' => info!(
-                    "[{}] {} Describe (statement '{}', {} bytes)",
-                    client_addr,
-                    arrow,
-                    formatted_name,
-                    data.len()
-                ),
-                'P' => info!(
-                    "[{}] {} Describe (portal '{}', {} bytes)",
-                    client_addr,
-                    arrow,
-                    formatted_name,
-                    data.len()
-                ),
-                _ => info!(
-                    "[{}] {} Describe ({}, {} bytes)",
-                    client_addr,
-                    arrow,
-                    describe_type,
-                    data.len()
-                ),
-            };
-        }
-        'S' => {
-            // Sync
-            info!("[{}] {} Sync", client_addr, arrow);
-        }
-        'X' => {
-            // Terminate
-            info!("[{}] {} Terminate", client_addr, arrow);
-        }
-        'p' => {
-            // Password message
-            info!(
-                "[{}] {} PasswordMessage ({} bytes)",
-                client_addr,
-                arrow,
-                data.len()
-            );
-        }
-        'C' => {
-            // Close
-            info!("[{}] {} Close ({} bytes)", client_addr, arrow, data.len());
+    fn param_types_for(&self, name: &str) -> &[u32] {
+        self.statements
+            .get(name)
+            .map(|statement| statement.param_types.as_slice())
+            .unwrap_or(&[])
+    }
+
+    fn record_describe(&mut self, statement: &str) {
+        self.pending_responses
+            .push_back(PendingResponse::Describe(statement.to_string()));
+    }
+
+    fn record_sync(&mut self) {
+        self.pending_responses.push_back(PendingResponse::Sync);
+    }
+
+    /// An ERROR ends the batch early; unless the client already sent the
+    /// Sync that closes it, the connection waits for one
+    fn record_error(&mut self) {
+        self.awaiting_sync = !self
+            .pending_responses
+            .iter()
+            .any(|pending| matches!(pending, PendingResponse::Sync));
+    }
+
+    fn record_close(&mut self, target: String) {
+        self.pending_responses
+            .push_back(PendingResponse::Close(target));
+    }
+
+    /// The object a server CloseComplete confirms closing
+    fn close_complete(&mut self) -> Option<String> {
+        match self.pending_responses.pop_front() {
+            Some(PendingResponse::Close(target)) => Some(target),
+            Some(other) => {
+                self.pending_responses.push_front(other);
+                None
+            }
+            None => None,
         }
-        'H' => {
-            // Flush
-            info!("[{}] {} Flush", client_addr, arrow);
+    }
+
+    fn record_execute(&mut self, portal: &str) {
+        self.pending_responses
+            .push_back(PendingResponse::Execute(portal.to_string()));
+    }
+
+    /// The portal whose Execute a CommandComplete, PortalSuspended or
+    /// EmptyQueryResponse answers. A portal that runs to completion is no
+    /// longer suspended.
+    fn finish_execute(&mut self, suspended: bool) -> Option<String> {
+        match self.pending_responses.pop_front() {
+            Some(PendingResponse::Execute(portal)) => {
+                if let Some(tracked) = self.portals.get_mut(&portal) {
+                    tracked.suspended = suspended;
+                }
+                Some(portal)
+            }
+            Some(other) => {
+                self.pending_responses.push_front(other);
+                None
+            }
+            None => None,
         }
-        'd' => {
-            // CopyData
-            info!(
-                "[{}] {} CopyData ({} bytes)",
-                client_addr,
-                arrow,
-                data.len()
-            );
+    }
+
+    fn is_suspended(&self, portal: &str) -> bool {
+        self.portals
+            .get(portal)
+            .is_some_and(|portal| portal.suspended)
+    }
+
+    /// Attach a server ParameterDescription to the oldest pending Describe
+    fn record_parameter_description(&mut self, param_types: Vec<u32>) {
+        if let Some(PendingResponse::Describe(name)) = self.pending_responses.front() {
+            if let Some(statement) = self.statements.get_mut(name) {
+                statement.param_types = param_types;
+            }
+            self.pending_responses.pop_front();
         }
-        'c' => {
-            // CopyDone
-            info!("[{}] {} CopyDone", client_addr, arrow);
+    }
+
+    /// Drop whatever the server skipped in the batch ReadyForQuery closes,
+    /// e.g. Describes discarded after an ErrorResponse
+    fn finish_sync(&mut self) {
+        while let Some(pending) = self.pending_responses.pop_front() {
+            if matches!(pending, PendingResponse::Sync) {
+                break;
+            }
         }
-        'f' => {
-            // CopyFail
-            if let Ok(msg) = std::str::from_utf8(&data[..data.len().saturating_sub(1)]) {
-                info!("[{}] {} CopyFail: {}", client_addr, arrow, msg);
-            } else {
-                info!("[{}] {} CopyFail", client_addr, arrow);
+    }
+
+    /// Bind replaces any existing portal of the same name, which is how the
+    /// unnamed portal is implicitly destroyed by the next unnamed Bind
+    fn record_bind(&mut self, portal: &str, statement: &str, params: Vec<Option<String>>) {
+        self.portals.insert(
+            portal.to_string(),
+            TrackedPortal {
+                statement: statement.to_string(),
+                params,
+                suspended: false,
+            },
+        );
+    }
+
+    fn close_portal(&mut self, portal: &str) {
+        self.portals.remove(portal);
+    }
+
+    /// A simple Query destroys the unnamed portal and, like Sync, is
+    /// answered by its own ReadyForQuery
+    fn record_simple_query(&mut self) {
+        self.portals.remove("");
+        self.record_sync();
+    }
+
+    /// Portals do not survive the end of a transaction
+    fn end_transaction(&mut self) {
+        self.portals.clear();
+    }
+
+    fn portal_sql(&self, portal: &str) -> Option<&str> {
+        self.portals
+            .get(portal)
+            .and_then(|portal| self.sql_for(&portal.statement))
+    }
+
+    fn portal_params(&self, portal: &str) -> Vec<Option<String>> {
+        self.portals
+            .get(portal)
+            .map(|portal| portal.params.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Target ('S' or 'P') and name of a Close message, or None when truncated
+fn decode_close_message(data: &[u8]) -> Option<(u8, String)> {
+    let target = *data.first()?;
+    let mut i = 1;
+    let name = read_cstring(data, &mut i)?;
+    Some((target, String::from_utf8_lossy(&name).into_owned()))
+}
+
+/// SQL with runs of whitespace collapsed to single spaces
+fn normalize_sql(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// SQL as logged for a Query or Parse: cut after `max_chars` characters,
+/// saying how long it was, unless `max_chars` is 0
+fn truncate_query(sql: &str, max_chars: usize) -> Cow<'_, str> {
+    let total = sql.chars().count();
+    if max_chars == 0 || total <= max_chars {
+        return Cow::Borrowed(sql);
+    }
+    let truncated: String = sql.chars().take(max_chars).collect();
+    Cow::Owned(format!(
+        "{}... [truncated, {} chars total]",
+        truncated, total
+    ))
+}
+
+/// Collapse whitespace and cut SQL text down to a single short log line
+fn sql_preview(sql: &str, max_chars: usize) -> String {
+    let collapsed = normalize_sql(sql);
+    if collapsed.chars().count() > max_chars {
+        let truncated: String = collapsed.chars().take(max_chars).collect();
+        format!("{}...", truncated)
+    } else {
+        collapsed
+    }
+}
+
+/// Reassembles protocol messages that arrive split across socket reads
+#[derive(Debug, Default)]
+pub struct MessageFramer {
+    pending: Vec<u8>,
+}
+
+impl MessageFramer {
+    /// Add the bytes of one read and take every message completed so far
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let mut complete = 0;
+        while let Some(header) = self.pending.get(complete..complete + 5) {
+            let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            if self.pending.len() < complete + length + 1 {
+                break;
             }
+            complete += length + 1;
         }
-        _ => {
-            info!(
-                "[{}] {} Unknown message type '{}' ({} bytes)",
-                client_addr,
-                arrow,
-                msg_type,
-                data.len()
-            );
+        self.pending.drain(..complete).collect()
+    }
+}
+
+/// How fault injection changes the client messages of one read
+#[derive(Debug, Default, PartialEq)]
+pub struct ForwardPlan {
+    /// Bytes of the read sent before the kill switch cuts the connection
+    pub kill_point: Option<usize>,
+    /// Messages sent upstream in place of the ones at these byte ranges
+    replacements: Vec<(Range<usize>, Vec<u8>)>,
+}
+
+impl ForwardPlan {
+    pub fn is_unchanged(&self) -> bool {
+        self.kill_point.is_none() && self.replacements.is_empty()
+    }
+
+    /// The bytes of `data` to send upstream
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        let end = self.kill_point.unwrap_or(data.len());
+        let mut forwarded = Vec::with_capacity(end);
+        let mut copied = 0;
+        for (range, replacement) in &self.replacements {
+            forwarded.extend_from_slice(&data[copied..range.start]);
+            forwarded.extend_from_slice(replacement);
+            copied = range.end;
         }
+        forwarded.extend_from_slice(&data[copied..end]);
+        forwarded
     }
 }
 
-fn parse_server_message(
-    msg_type: char,
+/// Log every complete message in `data`. Client messages are also checked
+/// against the kill switch, --error-on-query, --respond and --rewrite,
+/// whose changes to what is forwarded are returned.
+pub fn parse_message(
     data: &[u8],
+    direction: MessageDirection,
     client_addr: &str,
-    arrow: &str,
     timings: Option<&ConnectionTiming>,
     client_state: &ClientState,
-) {
-    match msg_type {
-        'R' => {
-            // Authentication
-            if data.len() >= 4 {
-                let auth_type = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-                let auth_name = match auth_type {
-                    0 => "AuthenticationOk",
-                    2 => "AuthenticationKerberosV5",
-                    3 => "AuthenticationCleartextPassword",
-                    5 => "AuthenticationMD5Password",
-                    6 => "AuthenticationSCMCredential",
-                    7 => "AuthenticationGSS",
-                    8 => "AuthenticationGSSContinue",
-                    9 => "AuthenticationSSPI",
-                    10 => "AuthenticationSASL",
-                    11 => "AuthenticationSASLContinue",
-                    12 => "AuthenticationSASLFinal",
-                    _ => "Unknown",
-                };
-                info!("[{}] {} Authentication: {}", client_addr, arrow, auth_name);
-            } else {
-                info!("[{}] {} Authentication", client_addr, arrow);
-            }
-        }
-        'K' => {
-            // BackendKeyData
-            info!("[{}] {} BackendKeyData", client_addr, arrow);
-        }
-        'Z' => {
-            // ReadyForQuery
-            let status = if !data.is_empty() {
-                match data[0] as char {
-                    'I' => "idle",
-                    'T' => "in transaction",
-                    'E' => "error in transaction",
-                    _ => "unknown",
-                }
-            } else {
-                "unknown"
-            };
-            info!("[{}] {} ReadyForQuery ({})", client_addr, arrow, status);
+    hex_dump: bool,
+) -> ForwardPlan {
+    let mut plan = ForwardPlan::default();
+    let mut buf = data;
+    let arrow = match direction {
+        MessageDirection::ClientToServer => "→",
+        MessageDirection::ServerToClient => "←",
+    };
+
+    while buf.len() >= 5 {
+        let msg_type = buf[0] as char;
+        let length = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+
+        if buf.len() < length + 1 {
+            // Incomplete message
+            break;
         }
-        'S' => {
-            // ParameterStatus
-            if let Some((name, value)) = parse_cstring_pair(data) {
-                info!(
-                    "[{}] {} ParameterStatus: {} = {}",
-                    client_addr, arrow, name, value
-                );
-            } else {
-                info!("[{}] {} ParameterStatus", client_addr, arrow);
-            }
+
+        let start = data.len() - buf.len();
+        // Full message including type byte and length
+        let full_message = &buf[..length + 1];
+        let msg_data = &buf[5..length + 1];
+
+        let sequence = client_state.next_sequence(&direction);
+        if let Some(metrics) = &client_state.options.metrics {
+            metrics.message(
+                matches!(direction, MessageDirection::ClientToServer),
+                msg_type as u8,
+            );
         }
-        'T' => {
-            // RowDescription
-            if data.len() >= 2 {
-                let field_count = u16::from_be_bytes([data[0], data[1]]);
-                info!(
-                    "[{}] {} RowDescription ({} fields)",
-                    client_addr, arrow, field_count
-                );
-                if let Some(fields) = parse_row_description(data) {
-                    for (i, field) in fields.iter().enumerate() {
-                        info!("[{}]    Field {}: {}", client_addr, i + 1, field.description);
-                    }
+        let numbered_arrow;
+        let arrow = if client_state.options.sequence_numbers {
+            numbered_arrow = format!("{} #{}", arrow, sequence);
+            numbered_arrow.as_str()
+        } else {
+            arrow
+        };
 
-                    // Set up table formatter if in table mode
-                    if client_state.table_state.is_table_mode() {
-                        let field_infos: Vec<FieldInfo> = fields
-                            .iter()
-                            .map(|f| f.field_info.clone())
-                            .collect();
-                        client_state.table_state.set_row_description(field_infos);
-                    }
-                }
-            } else {
-                info!("[{}] {} RowDescription", client_addr, arrow);
-            }
+        let event = match direction {
+            MessageDirection::ClientToServer => parse_client_message(
+                msg_type,
+                msg_data,
+                client_addr,
+                arrow,
+                timings,
+                client_state,
+            ),
+            MessageDirection::ServerToClient => parse_server_message(
+                msg_type,
+                msg_data,
+                client_addr,
+                arrow,
+                timings,
+                client_state,
+            ),
+        };
+        if let (Some(events), Some(event)) = (&client_state.events, event) {
+            events.write(sequence, &event);
         }
-        'D' => {
-            // DataRow
-            if data.len() >= 2 {
-                let field_count = u16::from_be_bytes([data[0], data[1]]);
 
-                if let Some(values) = parse_data_row(data) {
-                    // If in table mode, print as table row
-                    if client_state.table_state.is_table_mode() {
-                        client_state.table_state.print_data_row(&values, client_addr);
-                    } else {
-                        // Original logging format
-                        info!(
-                            "[{}] {} DataRow ({} fields, {} bytes)",
-                            client_addr,
-                            arrow,
-                            field_count,
-                            data.len()
+        if let Some(validator) = &client_state.validator {
+            let mut validator = validator.lock().unwrap();
+            match direction {
+                MessageDirection::ClientToServer => validator.client_message(msg_type, msg_data),
+                MessageDirection::ServerToClient => {
+                    if let Some(violation) = validator.server_message(msg_type) {
+                        error!(
+                            "[{}] Protocol violation: {} in state '{}', expected {}",
+                            client_addr, violation.message, violation.state, violation.expected
                         );
-                        for (i, value) in values.iter().enumerate() {
-                            info!("[{}]    Value {}: {}", client_addr, i + 1, value);
-                        }
                     }
                 }
-            } else {
-                info!("[{}] {} DataRow ({} bytes)", client_addr, arrow, data.len());
             }
         }
-        'C' => {
-            // CommandComplete
-            // Finish table formatting if active
-            if client_state.table_state.is_table_mode() {
-                client_state.table_state.finish_result_set(client_addr);
-            }
 
-            let tag = std::str::from_utf8(&data[..data.len().saturating_sub(1)]).ok();
-            if let Some(t) = timings {
-                if let Some(duration) = t.finish_simple_query() {
-                    if let Some(tag) = tag {
-                        info!(
-                            "[{}] {} CommandComplete: {} (query took {})",
-                            client_addr,
-                            arrow,
-                            tag,
-                            format_duration(duration)
-                        );
-                    } else {
-                        info!(
-                            "[{}] {} CommandComplete (query took {})",
-                            client_addr,
-                            arrow,
-                            format_duration(duration)
-                        );
-                    }
-                    return;
-                } else if let Some(duration) = t.finish_execute() {
-                    if let Some(tag) = tag {
-                        info!(
-                            "[{}] {} CommandComplete: {} (execute took {})",
-                            client_addr,
-                            arrow,
-                            tag,
-                            format_duration(duration)
-                        );
-                    } else {
-                        info!(
-                            "[{}] {} CommandComplete (execute took {})",
-                            client_addr,
-                            arrow,
-                            format_duration(duration)
-                        );
-                    }
-                    return;
+        // Log hex dump
+        if hex_dump {
+            log_hex_dump(full_message, client_addr);
+        }
+
+        buf = &buf[length + 1..];
+
+        if matches!(direction, MessageDirection::ClientToServer) {
+            let end = data.len() - buf.len();
+            let mut withheld = false;
+            if withheld_after_failed_statement(msg_type, client_addr, client_state) {
+                plan.replacements.push((start..end, Vec::new()));
+                withheld = true;
+            } else if let Some(request) =
+                failed_transaction_request(msg_type, msg_data, client_addr, client_state)
+            {
+                withheld = request.is_empty();
+                plan.replacements.push((start..end, request));
+            } else if injected_error(msg_type, msg_data, client_addr, client_state) {
+                plan.replacements.push((start..end, Vec::new()));
+                withheld = true;
+            } else if let Some(reply) = canned_reply(msg_type, msg_data, client_addr, client_state)
+            {
+                plan.replacements.push((start..end, Vec::new()));
+                client_state.local_replies.push(reply);
+                withheld = true;
+            } else if let Some(message) = rewritten_query(
+                msg_type,
+                msg_data,
+                client_addr,
+                client_state,
+                MAX_MESSAGE_LENGTH,
+            ) {
+                plan.replacements.push((start..end, message));
+            }
+            if !withheld {
+                if client_state.options.answers_locally() && matches!(msg_type, 'Q' | 'S' | 'F') {
+                    client_state.local_replies.forwarded_request();
+                }
+                if matches!(msg_type, 'P' | 'B' | 'D' | 'E' | 'C' | 'H') {
+                    client_state.extended_query.lock().unwrap().forwarded = true;
                 }
             }
-
-            if let Some(tag) = tag {
-                info!("[{}] {} CommandComplete: {}", client_addr, arrow, tag);
-            } else {
-                info!("[{}] {} CommandComplete", client_addr, arrow);
+            // Only what the client sent up to here reaches the server
+            let kill_switch = &client_state.options.kill_switch;
+            if let Some(reason) = kill_switch.trigger(sequence, msg_type as u8, msg_data) {
+                warn!("[{}] {}", client_addr, kill_switch.describe(&reason));
+                plan.kill_point = Some(end);
+                return plan;
             }
         }
-        'E' => {
-            // ErrorResponse
-            info!("[{}] {} ErrorResponse", client_addr, arrow);
-            if let Some(error_msg) = parse_error_response(data) {
-                info!("[{}]    {}", client_addr, error_msg);
-            }
+    }
+
+    // If there's remaining data that doesn't form a complete message
+    if !buf.is_empty() && buf.len() < 5 {
+        info!(
+            "[{}] {} Partial message ({} bytes)",
+            client_addr,
+            arrow,
+            buf.len()
+        );
+    }
+    plan
+}
+
+/// Whether an extended query message comes after a statement the proxy
+/// failed. The server would skip it after the error anyway; withholding it
+/// keeps parameter values and the rest of the extended query from reaching
+/// it. The Sync that ends the extended query is answered locally when the
+/// server saw none of it, and otherwise forwarded so the server's
+/// ReadyForQuery can carry the error.
+fn withheld_after_failed_statement(
+    msg_type: char,
+    client_addr: &str,
+    client_state: &ClientState,
+) -> bool {
+    let mut query = client_state.extended_query.lock().unwrap();
+    if msg_type == 'S' {
+        let ExtendedQuery { forwarded, failed } = std::mem::take(&mut *query);
+        if !failed || forwarded {
+            return false;
         }
-        'N' => {
-            // NoticeResponse
-            info!("[{}] {} NoticeResponse", client_addr, arrow);
-            if let Some(notice_msg) = parse_error_response(data) {
-                info!("[{}]    {}", client_addr, notice_msg);
-            }
+        info!(
+            "[{}] Sync answered locally with ReadyForQuery, the server saw none of its extended query",
+            client_addr
+        );
+        client_state.local_replies.push_ready();
+        return true;
+    }
+    if !matches!(msg_type, 'P' | 'B' | 'D' | 'E' | 'C' | 'H') || !query.failed {
+        return false;
+    }
+    info!(
+        "[{}] {} not forwarded, part of a failed extended query",
+        client_addr,
+        message_name(true, msg_type as u8)
+    );
+    true
+}
+
+/// What is forwarded in place of a statement sent after the proxy failed
+/// the client's transaction block, whose server side is still usable. As
+/// a failed transaction would, statements get ErrorResponse 25P02 without
+/// reaching the server, ROLLBACK goes through and COMMIT is forwarded as
+/// ROLLBACK. A Bind of a prepared COMMIT cannot be rewritten and goes
+/// through as it is.
+fn failed_transaction_request(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    client_state: &ClientState,
+) -> Option<Vec<u8>> {
+    let replies = &client_state.local_replies;
+    if !matches!(msg_type, 'Q' | 'P' | 'B') || !replies.transaction_aborted() {
+        return None;
+    }
+    let mut cstrings = data.split(|&b| b == 0);
+    let first_cstring = cstrings.next()?;
+    let second_cstring = String::from_utf8_lossy(cstrings.next().unwrap_or_default());
+    let sql = match msg_type {
+        'Q' => String::from_utf8_lossy(first_cstring).into_owned(),
+        'P' => second_cstring.into_owned(),
+        _ => {
+            let statements = client_state.statements.lock().unwrap();
+            statements
+                .sql_for(&second_cstring)
+                .unwrap_or_default()
+                .to_string()
         }
-        '1' => {
-            // ParseComplete
-            if let Some(t) = timings {
-                if let Some(duration) = t.finish_parse() {
-                    info!(
-                        "[{}] {} ParseComplete (took {})",
-                        client_addr,
-                        arrow,
-                        format_duration(duration)
-                    );
-                    return;
-                }
-            }
-            info!("[{}] {} ParseComplete", client_addr, arrow);
+    };
+    let keyword = sql
+        .trim_start()
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match keyword.as_str() {
+        "rollback" | "abort" => {
+            replies.transaction_ended();
+            None
         }
-        '2' => {
-            // BindComplete
-            if let Some(t) = timings {
-                if let Some(duration) = t.finish_bind() {
-                    info!(
-                        "[{}] {} BindComplete (took {})",
-                        client_addr,
-                        arrow,
-                        format_duration(duration)
-                    );
-                    return;
-                }
-            }
-            info!("[{}] {} BindComplete", client_addr, arrow);
+        "commit" | "end" if msg_type != 'B' => {
+            info!(
+                "[{}] {} of a transaction failed by the proxy forwarded as ROLLBACK",
+                client_addr,
+                message_name(true, msg_type as u8)
+            );
+            replies.transaction_ended();
+            // The statement name of a Parse and everything after the SQL
+            // are kept
+            let sql_start = if msg_type == 'P' {
+                first_cstring.len() + 1
+            } else {
+                0
+            };
+            let sql_end = sql_start + data[sql_start..].iter().position(|&b| b == 0)?;
+            let mut body = data[..sql_start].to_vec();
+            body.extend_from_slice(b"ROLLBACK");
+            body.extend_from_slice(&data[sql_end..]);
+            Some(encode_frontend_message(msg_type as u8, &body))
         }
-        '3' => {
-            // CloseComplete
-            info!("[{}] {} CloseComplete", client_addr, arrow);
+        "commit" | "end" => {
+            replies.transaction_ended();
+            None
         }
-        'n' => {
-            // NoData
-            info!("[{}] {} NoData", client_addr, arrow);
+        _ => {
+            info!(
+                "[{}] {} not forwarded, the proxy failed the transaction: ErrorResponse 25P02",
+                client_addr,
+                message_name(true, msg_type as u8)
+            );
+            let error = encode_error_response(
+                "ERROR",
+                "25P02",
+                "current transaction is aborted, commands ignored until end of transaction block",
+            );
+            if msg_type == 'Q' {
+                replies.push_error(error, false);
+                replies.push_ready();
+            } else {
+                let mut query = client_state.extended_query.lock().unwrap();
+                replies.push_error(error, query.forwarded);
+                query.failed = true;
+            }
+            Some(Vec::new())
         }
-        's' => {
-            // PortalSuspended
-            info!("[{}] {} PortalSuspended", client_addr, arrow);
+    }
+}
+
+/// Whether a Query, or the Execute of a portal, whose SQL matches an
+/// --error-on-query or --block-query rule, or a Parse matching a
+/// --block-query rule, is failed by the proxy. Nothing is forwarded in its
+/// place; the rule's ErrorResponse is queued as a local reply.
+fn injected_error(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    client_state: &ClientState,
+) -> bool {
+    let rules = &client_state.options.error_rules;
+    if rules.is_empty() {
+        return false;
+    }
+    let mut cstrings = data.split(|&b| b == 0);
+    let first_cstring = String::from_utf8_lossy(cstrings.next().unwrap_or_default());
+    let (request, sql) = match msg_type {
+        'Q' => ("Query", first_cstring.into_owned()),
+        'P' => (
+            "Parse",
+            String::from_utf8_lossy(cstrings.next().unwrap_or_default()).into_owned(),
+        ),
+        'E' => {
+            let statements = client_state.statements.lock().unwrap();
+            let Some(sql) = statements.portal_sql(&first_cstring) else {
+                return false;
+            };
+            ("Execute", sql.to_string())
         }
-        't' => {
-            // ParameterDescription
-            if data.len() >= 2 {
-                let param_count = u16::from_be_bytes([data[0], data[1]]);
-                info!(
-                    "[{}] {} ParameterDescription ({} parameters)",
-                    client_addr, arrow, param_count
+        _ => return false,
+    };
+    let Some(rule) = rules
+        .iter()
+        .find(|rule| (rule.block || msg_type != 'P') && rule.pattern.is_match(&sql))
+    else {
+        return false;
+    };
+    if rule.block {
+        warn!(
+            "[{}] Blocked {} matching /{}/: not forwarded, the client gets ErrorResponse {} '{}' instead",
+            client_addr, request, rule.pattern, rule.code, rule.message
+        );
+    } else {
+        warn!(
+            "[{}] Fault injected: {} not forwarded, the client gets ErrorResponse {} '{}' instead",
+            client_addr, request, rule.code, rule.message
+        );
+    }
+    let error = encode_error_response("ERROR", &rule.code, &rule.message);
+    let replies = &client_state.local_replies;
+    if msg_type == 'Q' {
+        replies.push_error(error, false);
+        replies.push_ready();
+    } else {
+        let mut query = client_state.extended_query.lock().unwrap();
+        replies.push_error(error, query.forwarded);
+        query.failed = true;
+    }
+    true
+}
+
+/// The canned result of the first --respond rule matching the SQL of a
+/// Query, which the server never sees
+fn canned_reply(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    client_state: &ClientState,
+) -> Option<Vec<u8>> {
+    let responses = &client_state.options.canned_responses;
+    if msg_type != 'Q' || responses.is_empty() {
+        return None;
+    }
+    let sql = String::from_utf8_lossy(data.split(|&b| b == 0).next()?);
+    let response = responses
+        .iter()
+        .find(|response| response.regex.is_match(sql.as_ref()))?;
+    info!(
+        "[{}] Query answered locally from {} ({} rows, '{}'), not forwarded",
+        client_addr,
+        response.path,
+        response.rows.len(),
+        response.command_tag
+    );
+    Some(response.encode())
+}
+
+/// A Query or Parse re-encoded with the --rewrite rules applied to its SQL.
+/// A rewrite whose message would exceed `max_length` is dropped so the
+/// original goes through instead.
+fn rewritten_query(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    client_state: &ClientState,
+    max_length: usize,
+) -> Option<Vec<u8>> {
+    let rules = &client_state.options.query_rewrites;
+    if rules.is_empty() {
+        return None;
+    }
+    let rewritten = rewrite_message(msg_type as u8, data, rules)?;
+    let request = if msg_type == 'Q' { "Query" } else { "Parse" };
+    if rewritten.length() > max_length {
+        warn!(
+            "[{}] Rewrite of {} not applied: the message would be {} bytes (limit {}), forwarding the original",
+            client_addr,
+            request,
+            rewritten.length(),
+            max_length
+        );
+        return None;
+    }
+    info!(
+        "[{}] Rewrote {} SQL: '{}' -> '{}'",
+        client_addr, request, rewritten.original, rewritten.rewritten
+    );
+    Some(rewritten.message)
+}
+
+pub fn log_hex_dump(data: &[u8], client_addr: &str) {
+    const BYTES_PER_LINE: usize = 16;
+
+    for (i, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let offset = i * BYTES_PER_LINE;
+        let hex_string: String = chunk
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let ascii_string: String = chunk
+            .iter()
+            .map(|&b| {
+                if (0x20..=0x7e).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        info!(
+            "[{}]   {:04x}: {:<48}  {}",
+            client_addr, offset, hex_string, ascii_string
+        );
+    }
+}
+
+/// A client message decoded from its type byte and body
+#[derive(Debug, PartialEq)]
+enum FrontendMessage<'a> {
+    Query {
+        text: String,
+    },
+    Parse {
+        name: String,
+        query: String,
+        /// Pre-specified parameter types; None when the list is truncated
+        param_oids: Option<Vec<u32>>,
+    },
+    Bind(BindMessage),
+    Execute {
+        portal: String,
+        max_rows: u32,
+    },
+    Describe {
+        /// `S` for a statement, `P` for a portal
+        target: u8,
+        name: String,
+    },
+    Sync,
+    Terminate,
+    Close {
+        /// `S` for a statement, `P` for a portal
+        target: u8,
+        name: String,
+    },
+    CopyData(&'a [u8]),
+    CopyDone,
+    CopyFail(String),
+    PasswordMessage(&'a [u8]),
+    Flush,
+    FunctionCall(FunctionCall),
+}
+
+/// Decode a client message, or None for an unknown type or a body too
+/// short for its type
+fn parse_frontend_message(type_byte: u8, data: &[u8]) -> Option<FrontendMessage<'_>> {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    Some(match type_byte {
+        b'Q' => FrontendMessage::Query {
+            text: text(cstring_body(data)),
+        },
+        b'P' => {
+            let (name, query) = decode_parse_message(data);
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_oids: decode_parse_param_types(data),
+            }
+        }
+        b'B' => FrontendMessage::Bind(decode_bind_message(data)?),
+        b'E' => {
+            let mut i = 0;
+            let portal = read_cstring(data, &mut i).unwrap_or_default();
+            let max_rows = data
+                .get(i..i + 4)
+                .map_or(0, |rows| u32::from_be_bytes(rows.try_into().unwrap()));
+            FrontendMessage::Execute {
+                portal: text(&portal),
+                max_rows,
+            }
+        }
+        b'D' => {
+            let (&target, rest) = data.split_first()?;
+            let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            FrontendMessage::Describe {
+                target,
+                name: text(&rest[..end]),
+            }
+        }
+        b'S' => FrontendMessage::Sync,
+        b'X' => FrontendMessage::Terminate,
+        b'C' => {
+            let (target, name) = decode_close_message(data)?;
+            FrontendMessage::Close { target, name }
+        }
+        b'd' => FrontendMessage::CopyData(data),
+        b'c' => FrontendMessage::CopyDone,
+        b'f' => FrontendMessage::CopyFail(text(cstring_body(data))),
+        b'p' => FrontendMessage::PasswordMessage(data),
+        b'H' => FrontendMessage::Flush,
+        b'F' => FrontendMessage::FunctionCall(decode_function_call(data)?),
+        _ => return None,
+    })
+}
+
+/// The body of a message holding one string, without its terminator
+fn cstring_body(data: &[u8]) -> &[u8] {
+    &data[..data.len().saturating_sub(1)]
+}
+
+/// Log a message the typed decoders could not make sense of
+fn log_undecoded_message(
+    from_client: bool,
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    arrow: &str,
+) {
+    match message_name(from_client, msg_type as u8) {
+        "unknown message" => info!(
+            "[{}] {} Unknown message type '{}' ({} bytes)",
+            client_addr,
+            arrow,
+            msg_type,
+            data.len()
+        ),
+        name => info!(
+            "[{}] {} {} (malformed, {} bytes)",
+            client_addr,
+            arrow,
+            name,
+            data.len()
+        ),
+    }
+}
+
+/// Event of a message the typed decoders could not make sense of
+fn undecoded_event(from_client: bool, msg_type: char, data: &[u8]) -> Event {
+    let mut event = Event::new(
+        from_client,
+        message_name(from_client, msg_type as u8),
+        data.len(),
+    );
+    event
+        .string("type_byte", &msg_type.to_string())
+        .json("malformed", true);
+    event
+}
+
+/// Name of the target of a Describe or Close
+fn target_name(target: u8) -> &'static str {
+    match target {
+        b'S' => "statement",
+        b'P' => "portal",
+        _ => "unknown",
+    }
+}
+
+/// Event of a client message with the fields the message itself carries;
+/// fields that depend on the session are added while it is handled
+fn frontend_event(message: &FrontendMessage, msg_type: char, data: &[u8]) -> Event {
+    let mut event = Event::new(true, message_name(true, msg_type as u8), data.len());
+    match message {
+        FrontendMessage::Query { text } => {
+            event.string("sql", text);
+        }
+        FrontendMessage::Parse {
+            name,
+            query,
+            param_oids,
+        } => {
+            event.string("statement", name).string("sql", query);
+            if let Some(param_oids) = param_oids {
+                event.numbers("param_types", param_oids);
+            }
+        }
+        FrontendMessage::Bind(bind) => {
+            event
+                .string("portal", &bind.portal)
+                .string("statement", &bind.statement)
+                .numbers("param_formats", &bind.param_formats)
+                .numbers("result_formats", &bind.result_formats);
+        }
+        FrontendMessage::Execute { portal, max_rows } => {
+            event.string("portal", portal).number("max_rows", max_rows);
+        }
+        FrontendMessage::Describe { target, name } | FrontendMessage::Close { target, name } => {
+            event
+                .string("target", target_name(*target))
+                .string("name", name);
+        }
+        FrontendMessage::CopyFail(reason) => {
+            event.string("reason", reason);
+        }
+        FrontendMessage::FunctionCall(call) => {
+            match function_name(call.oid) {
+                Some(name) => event.string("function", name),
+                None => event.json("function", "null"),
+            };
+            event
+                .number("function_oid", call.oid)
+                .number("args", call.args.len())
+                .number("result_format", call.result_format);
+        }
+        FrontendMessage::Sync
+        | FrontendMessage::Terminate
+        | FrontendMessage::CopyData(_)
+        | FrontendMessage::CopyDone
+        | FrontendMessage::PasswordMessage(_)
+        | FrontendMessage::Flush => {}
+    }
+    event
+}
+
+/// Event of a server message with the fields the message itself carries;
+/// timings and row counts are added while it is handled
+fn backend_event(message: &BackendMessage, msg_type: char, data: &[u8]) -> Event {
+    let mut event = Event::new(false, message_name(false, msg_type as u8), data.len());
+    match message {
+        BackendMessage::Authentication { code } => {
+            event.number("code", code);
+        }
+        BackendMessage::BackendKeyData(key) => {
+            event.number("pid", key.pid);
+        }
+        BackendMessage::ReadyForQuery { status } => {
+            event.string("status", &(*status as char).to_string());
+        }
+        BackendMessage::ParameterStatus { name, value } => {
+            event.string("name", name).string("value", value);
+        }
+        BackendMessage::RowDescription { fields } => {
+            let columns: Vec<String> = fields
+                .iter()
+                .map(|field| {
+                    format!(
+                        "{{\"name\":{},\"type_oid\":{},\"type\":{},\"format\":{}}}",
+                        json_string(&field.name),
+                        field.type_oid,
+                        json_string(get_pg_type_name(field.type_oid)),
+                        field.format_code
+                    )
+                })
+                .collect();
+            event.json("columns", format_args!("[{}]", columns.join(",")));
+        }
+        BackendMessage::CommandComplete { tag } => {
+            event.string("tag", tag);
+        }
+        BackendMessage::ErrorResponse(fields) | BackendMessage::NoticeResponse(fields) => {
+            event.string("severity", fields.severity());
+            for (key, code) in [
+                ("sqlstate", 'C'),
+                ("message", 'M'),
+                ("detail", 'D'),
+                ("hint", 'H'),
+            ] {
+                if let Some(value) = fields.get(code) {
+                    event.string(key, value);
+                }
+            }
+        }
+        BackendMessage::ParameterDescription { param_oids } => {
+            event.numbers("param_types", param_oids);
+        }
+        BackendMessage::CopyInResponse(copy)
+        | BackendMessage::CopyOutResponse(copy)
+        | BackendMessage::CopyBothResponse(copy) => {
+            event
+                .string("format", if copy.binary { "binary" } else { "text" })
+                .numbers("column_formats", &copy.column_formats);
+        }
+        BackendMessage::NotificationResponse {
+            pid,
+            channel,
+            payload,
+        } => {
+            event
+                .number("pid", pid)
+                .string("channel", channel)
+                .string("payload", payload);
+        }
+        BackendMessage::FunctionCallResponse { value } => {
+            event.json("null", value.is_none());
+        }
+        BackendMessage::NegotiateProtocolVersion { minor, options } => {
+            event.number("minor_version", minor).strings(
+                "unrecognized_options",
+                options.iter().map(|o| Some(o.as_str())),
+            );
+        }
+        BackendMessage::DataRow { .. }
+        | BackendMessage::ParseComplete
+        | BackendMessage::BindComplete
+        | BackendMessage::CloseComplete
+        | BackendMessage::NoData
+        | BackendMessage::PortalSuspended
+        | BackendMessage::EmptyQueryResponse
+        | BackendMessage::CopyData(_)
+        | BackendMessage::CopyDone => {}
+    }
+    event
+}
+
+/// Log a client message and update the session state. Returns the
+/// message's event when --events-dir is set.
+fn parse_client_message(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    arrow: &str,
+    timings: Option<&ConnectionTiming>,
+    client_state: &ClientState,
+) -> Option<Event> {
+    if matches!(msg_type, 'Q' | 'P' | 'B' | 'E') {
+        client_state.transaction.lock().unwrap().current();
+    }
+    if !matches!(msg_type, 'S' | 'X') && client_state.statements.lock().unwrap().awaiting_sync {
+        warn!(
+            "[{}] {} sent after an ErrorResponse without a Sync: the server discards it until the client sends Sync",
+            client_addr,
+            message_name(true, msg_type as u8)
+        );
+    }
+    let idle = match msg_type {
+        'Q' | 'P' | 'B' => timings
+            .and_then(|t| t.request_started())
+            .filter(|idle| *idle >= client_state.options.idle_threshold)
+            .map(|idle| format!(" (idle {})", format_duration(idle)))
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+    let Some(message) = parse_frontend_message(msg_type as u8, data) else {
+        log_undecoded_message(true, msg_type, data, client_addr, arrow);
+        return client_state
+            .events
+            .as_ref()
+            .map(|_| undecoded_event(true, msg_type, data));
+    };
+    let mut event = client_state
+        .events
+        .as_ref()
+        .map(|_| frontend_event(&message, msg_type, data));
+
+    match message {
+        FrontendMessage::Query { text } => {
+            if let Some(t) = timings {
+                t.mark_simple_query(normalize_sql(&text));
+            }
+            client_state.statement_sent(Some(normalize_sql(&text)));
+            client_state
+                .statements
+                .lock()
+                .unwrap()
+                .record_simple_query();
+            track_listen(&text, &mut client_state.listening.lock().unwrap());
+            info!(
+                "[{}] {} Query: {}{}",
+                client_addr,
+                arrow,
+                truncate_query(&text, client_state.options.max_query_log_length),
+                idle
+            );
+        }
+        FrontendMessage::Parse {
+            name,
+            query,
+            param_oids,
+        } => {
+            if let Some(t) = timings {
+                t.mark_parse();
+            }
+            info!(
+                "[{}] {} Parse (prepared statement, {} bytes){}",
+                client_addr,
+                arrow,
+                data.len(),
+                idle
+            );
+            if let Some(details) = describe_parse(
+                &name,
+                &query,
+                param_oids.as_deref(),
+                client_state.options.max_query_log_length,
+            ) {
+                info!("[{}]    {}", client_addr, details);
+            }
+            client_state
+                .statements
+                .lock()
+                .unwrap()
+                .record_parse(&name, &query);
+        }
+        FrontendMessage::Bind(bind) => {
+            if let Some(t) = timings {
+                t.mark_bind();
+            }
+            client_state.message_span(|spans| {
+                spans.binds.push_back((
+                    bind.portal.clone(),
+                    MessageSpan::bind(&bind.portal, &bind.statement),
+                ))
+            });
+            let mut statements = client_state.statements.lock().unwrap();
+            let values = bind_param_values(
+                &bind,
+                statements.param_types_for(&bind.statement),
+                &client_state.options,
+            );
+            let params = format_bind_params(&values);
+            if let Some(event) = &mut event {
+                event.strings("params", values.iter().map(Option::as_deref));
+            }
+            statements.record_bind(&bind.portal, &bind.statement, values);
+            let sql = statements
+                .sql_for(&bind.statement)
+                .map(|sql| sql_preview(sql, SQL_PREVIEW_CHARS));
+            drop(statements);
+            if let Some(sql) = sql {
+                info!(
+                    "[{}] {} Bind ({} bytes): {}{}",
+                    client_addr,
+                    arrow,
+                    data.len(),
+                    sql,
+                    idle
+                );
+            } else {
+                info!(
+                    "[{}] {} Bind ({} bytes){}",
+                    client_addr,
+                    arrow,
+                    data.len(),
+                    idle
+                );
+            }
+            info!("[{}]    {}", client_addr, describe_bind_message(&bind));
+            if let Some(params) = params {
+                info!("[{}]    {}", client_addr, params);
+            }
+        }
+        FrontendMessage::Execute { portal, .. } => {
+            let mut statements = client_state.statements.lock().unwrap();
+            let full_sql = statements.portal_sql(&portal).map(normalize_sql);
+            let resumes = statements.is_suspended(&portal);
+            statements.record_execute(&portal);
+            if let Some(t) = timings {
+                t.mark_execute(
+                    &portal,
+                    full_sql
+                        .clone()
+                        .unwrap_or_else(|| "<unknown portal>".to_string()),
+                    statements.portal_params(&portal),
+                );
+            }
+            drop(statements);
+            client_state.statement_sent(full_sql.clone());
+            let resuming = if resumes {
+                format!(
+                    ", resumes portal '{}'",
+                    format_identifier(portal.as_bytes())
+                )
+            } else {
+                String::new()
+            };
+            let sql = full_sql.map(|sql| sql_preview(&sql, SQL_PREVIEW_CHARS));
+            if let Some(sql) = sql {
+                info!(
+                    "[{}] {} Execute ({} bytes{}): {}",
+                    client_addr,
+                    arrow,
+                    data.len(),
+                    resuming,
+                    sql
+                );
+            } else {
+                info!(
+                    "[{}] {} Execute ({} bytes{})",
+                    client_addr,
+                    arrow,
+                    data.len(),
+                    resuming
+                );
+            }
+        }
+        FrontendMessage::Describe { target, name } => {
+            let sql = {
+                let mut statements = client_state.statements.lock().unwrap();
+                match target {
+                    b'S' => {
+                        statements.record_describe(&name);
+                        None
+                    }
+                    b'P' => statements
+                        .portal_sql(&name)
+                        .map(|sql| sql_preview(sql, SQL_PREVIEW_CHARS)),
+                    _ => None,
+                }
+            };
+            let formatted_name = if name.is_empty() {
+                "(unnamed)".to_string()
+            } else {
+                name
+            };
+
+            match (target, sql) {
+                (b'S', _) => info!(
+                    "[{}] {} Describe (statement '{}', {} bytes)",
+                    client_addr,
+                    arrow,
+                    formatted_name,
+                    data.len()
+                ),
+                (b'P', Some(sql)) => info!(
+                    "[{}] {} Describe (portal '{}', {} bytes): {}",
+                    client_addr,
+                    arrow,
+                    formatted_name,
+                    data.len(),
+                    sql
+                ),
+                (b'P', None) => info!(
+                    "[{}] {} Describe (portal '{}', {} bytes)",
+                    client_addr,
+                    arrow,
+                    formatted_name,
+                    data.len()
+                ),
+                _ => info!(
+                    "[{}] {} Describe (unknown, {} bytes)",
+                    client_addr,
+                    arrow,
+                    data.len()
+                ),
+            };
+        }
+        FrontendMessage::Sync => {
+            info!("[{}] {} Sync", client_addr, arrow);
+            let mut statements = client_state.statements.lock().unwrap();
+            statements.awaiting_sync = false;
+            statements.record_sync();
+        }
+        FrontendMessage::Terminate => {
+            info!("[{}] {} Terminate", client_addr, arrow);
+        }
+        FrontendMessage::PasswordMessage(password) => {
+            info!(
+                "[{}] {} PasswordMessage ({} bytes)",
+                client_addr,
+                arrow,
+                password.len()
+            );
+        }
+        FrontendMessage::Close { target, name } => {
+            let formatted_name = if name.is_empty() {
+                "(unnamed)"
+            } else {
+                name.as_str()
+            };
+            let mut statements = client_state.statements.lock().unwrap();
+            let (described, sql) = match target {
+                b'S' => (
+                    format!("statement '{}'", formatted_name),
+                    statements.close(&name),
+                ),
+                b'P' => {
+                    let sql = statements.portal_sql(&name).map(str::to_string);
+                    statements.close_portal(&name);
+                    // A suspended portal's table ends when it is closed
+                    client_state.table_state.finish_result_set(client_addr);
+                    (format!("portal '{}'", formatted_name), sql)
+                }
+                other => (format!("unknown target '{}'", other as char), None),
+            };
+            match sql {
+                Some(sql) => info!(
+                    "[{}] {} Close ({}): {}",
+                    client_addr,
+                    arrow,
+                    described,
+                    sql_preview(&sql, SQL_PREVIEW_CHARS)
+                ),
+                None => info!("[{}] {} Close ({})", client_addr, arrow, described),
+            }
+            statements.record_close(described);
+        }
+        FrontendMessage::Flush => {
+            info!("[{}] {} Flush", client_addr, arrow);
+        }
+        FrontendMessage::CopyData(copy_data) => {
+            log_copy_data(client_addr, arrow, copy_data, client_state);
+        }
+        FrontendMessage::CopyDone => {
+            log_copy_done(client_addr, arrow, client_state);
+        }
+        FrontendMessage::FunctionCall(call) => {
+            // Fast-path interface
+            let name = function_name(call.oid);
+            let sizes = call
+                .args
+                .iter()
+                .map(|arg| {
+                    arg.as_ref()
+                        .map_or("NULL".to_string(), |a| format_bytes(a.len() as u64))
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            info!(
+                "[{}] {} FunctionCall: {} (OID {}), {} args ({}), result format {}",
+                client_addr,
+                arrow,
+                name.unwrap_or("function"),
+                call.oid,
+                call.args.len(),
+                sizes,
+                format_code_name(call.result_format)
+            );
+            if let Some(args) = format_function_args(&call, &client_state.options) {
+                info!("[{}]    {}", client_addr, args);
+            }
+            client_state.function_calls.lock().unwrap().push_back((
+                name.map_or_else(|| format!("OID {}", call.oid), str::to_string),
+                call.result_format,
+            ));
+        }
+        FrontendMessage::CopyFail(reason) => {
+            info!("[{}] {} CopyFail: {}", client_addr, arrow, reason);
+        }
+    }
+    event
+}
+
+/// A server message decoded from its type byte and body
+#[derive(Debug, PartialEq)]
+enum BackendMessage<'a> {
+    Authentication {
+        /// 0 for AuthenticationOk, otherwise the method requested
+        code: u32,
+    },
+    BackendKeyData(BackendKey),
+    ReadyForQuery {
+        /// `I` idle, `T` in a transaction block, `E` in a failed one
+        status: u8,
+    },
+    ParameterStatus {
+        name: String,
+        value: String,
+    },
+    RowDescription {
+        fields: Vec<RowDescriptionField>,
+    },
+    DataRow {
+        /// Column values; None is SQL NULL
+        values: Vec<Option<&'a [u8]>>,
+    },
+    CommandComplete {
+        tag: String,
+    },
+    ErrorResponse(ErrorFields),
+    NoticeResponse(ErrorFields),
+    ParseComplete,
+    BindComplete,
+    CloseComplete,
+    NoData,
+    PortalSuspended,
+    ParameterDescription {
+        param_oids: Vec<u32>,
+    },
+    EmptyQueryResponse,
+    CopyData(&'a [u8]),
+    CopyDone,
+    CopyInResponse(CopyResponse),
+    CopyOutResponse(CopyResponse),
+    CopyBothResponse(CopyResponse),
+    NotificationResponse {
+        pid: i32,
+        channel: String,
+        payload: String,
+    },
+    FunctionCallResponse {
+        /// The result; None is SQL NULL
+        value: Option<&'a [u8]>,
+    },
+    NegotiateProtocolVersion {
+        /// Newest minor protocol version the server supports
+        minor: u32,
+        /// Protocol options the server did not recognize
+        options: Vec<String>,
+    },
+}
+
+/// Decode a server message, or None for an unknown type or a body too
+/// short for its type
+fn parse_backend_message(type_byte: u8, data: &[u8]) -> Option<BackendMessage<'_>> {
+    Some(match type_byte {
+        b'R' => BackendMessage::Authentication {
+            code: u32::from_be_bytes(data.get(..4)?.try_into().ok()?),
+        },
+        b'K' => BackendMessage::BackendKeyData(decode_backend_key(data)?),
+        b'Z' => BackendMessage::ReadyForQuery {
+            status: *data.first()?,
+        },
+        b'S' => {
+            let (name, value) = parse_cstring_pair(data)?;
+            BackendMessage::ParameterStatus { name, value }
+        }
+        b'T' => BackendMessage::RowDescription {
+            fields: decode_row_description(data)?,
+        },
+        b'D' => BackendMessage::DataRow {
+            values: decode_data_row(data)?,
+        },
+        b'C' => BackendMessage::CommandComplete {
+            tag: String::from_utf8_lossy(cstring_body(data)).into_owned(),
+        },
+        b'E' => BackendMessage::ErrorResponse(parse_error_response(data).unwrap_or_default()),
+        b'N' => BackendMessage::NoticeResponse(parse_error_response(data).unwrap_or_default()),
+        b'1' => BackendMessage::ParseComplete,
+        b'2' => BackendMessage::BindComplete,
+        b'3' => BackendMessage::CloseComplete,
+        b'n' => BackendMessage::NoData,
+        b's' => BackendMessage::PortalSuspended,
+        b't' => BackendMessage::ParameterDescription {
+            param_oids: decode_parameter_description(data)?,
+        },
+        b'I' => BackendMessage::EmptyQueryResponse,
+        b'd' => BackendMessage::CopyData(data),
+        b'c' => BackendMessage::CopyDone,
+        b'G' => BackendMessage::CopyInResponse(decode_copy_response(data)?),
+        b'H' => BackendMessage::CopyOutResponse(decode_copy_response(data)?),
+        b'W' => BackendMessage::CopyBothResponse(decode_copy_response(data)?),
+        b'A' => {
+            let (pid, channel, payload) = decode_notification(data)?;
+            BackendMessage::NotificationResponse {
+                pid,
+                channel,
+                payload,
+            }
+        }
+        b'V' => BackendMessage::FunctionCallResponse {
+            value: decode_function_result(data)?,
+        },
+        b'v' => {
+            let (minor, options) = decode_negotiate_protocol_version(data)?;
+            BackendMessage::NegotiateProtocolVersion { minor, options }
+        }
+        _ => return None,
+    })
+}
+
+/// Log a server message and update the session state. Returns the
+/// message's event when --events-dir is set.
+fn parse_server_message(
+    msg_type: char,
+    data: &[u8],
+    client_addr: &str,
+    arrow: &str,
+    timings: Option<&ConnectionTiming>,
+    client_state: &ClientState,
+) -> Option<Event> {
+    let Some(message) = parse_backend_message(msg_type as u8, data) else {
+        log_undecoded_message(false, msg_type, data, client_addr, arrow);
+        return client_state
+            .events
+            .as_ref()
+            .map(|_| undecoded_event(false, msg_type, data));
+    };
+    let mut event = client_state
+        .events
+        .as_ref()
+        .map(|_| backend_event(&message, msg_type, data));
+
+    match message {
+        BackendMessage::Authentication { code } => {
+            let auth_name = match code {
+                0 => "AuthenticationOk",
+                2 => "AuthenticationKerberosV5",
+                3 => "AuthenticationCleartextPassword",
+                5 => "AuthenticationMD5Password",
+                6 => "AuthenticationSCMCredential",
+                7 => "AuthenticationGSS",
+                8 => "AuthenticationGSSContinue",
+                9 => "AuthenticationSSPI",
+                10 => "AuthenticationSASL",
+                11 => "AuthenticationSASLContinue",
+                12 => "AuthenticationSASLFinal",
+                _ => "Unknown",
+            };
+            info!("[{}] {} Authentication: {}", client_addr, arrow, auth_name);
+            if !client_state.tls {
+                match code {
+                    3 => warn!(
+                        "[{}] Cleartext password sent over unencrypted connection",
+                        client_addr
+                    ),
+                    5 => warn!(
+                        "[{}] MD5 password hash sent over unencrypted connection",
+                        client_addr
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        BackendMessage::BackendKeyData(key) => {
+            info!(
+                "[{}] {} BackendKeyData (pid {})",
+                client_addr, arrow, key.pid
+            );
+            client_state.session.lock().unwrap().backend_key = Some(key);
+        }
+        BackendMessage::ReadyForQuery { status } => {
+            let described = match status {
+                b'I' => "idle",
+                b'T' => "in transaction",
+                b'E' => "error in transaction",
+                _ => "unknown",
+            };
+            info!("[{}] {} ReadyForQuery ({})", client_addr, arrow, described);
+            {
+                let mut session = client_state.session.lock().unwrap();
+                session.ready_status = Some(status);
+                session.active = false;
+            }
+            if let Some(t) = timings {
+                t.ready_for_query();
+            }
+            let summary = client_state
+                .transaction
+                .lock()
+                .unwrap()
+                .ready_for_query(status, client_state.options.autocommit_threshold);
+            if let Some(summary) = summary {
+                info!("[{}] {}", client_addr, summary);
+            }
+            let mut statements = client_state.statements.lock().unwrap();
+            // The unnamed statement does not outlive the current exchange
+            statements.close("");
+            statements.finish_sync();
+            if status == b'I' {
+                statements.end_transaction();
+            }
+            client_state.function_calls.lock().unwrap().clear();
+            *client_state.copy.lock().unwrap() = None;
+            client_state.message_span(MessageSpans::clear);
+        }
+        BackendMessage::ParameterStatus { name, value } => {
+            info!(
+                "[{}] {} ParameterStatus: {} = {}",
+                client_addr, arrow, name, value
+            );
+            if name == "application_name" {
+                let mut session = client_state.session.lock().unwrap();
+                // The server also reports the startup value right after
+                // authentication; only a SET is worth calling out
+                let previous = session.application_name.as_deref().unwrap_or("");
+                if previous != value {
+                    info!(
+                        "[{}] application_name changed: '{}' -> '{}'",
+                        client_addr, previous, value
+                    );
+                }
+                session.application_name = Some(value);
+            }
+        }
+        BackendMessage::RowDescription { fields } => {
+            info!(
+                "[{}] {} RowDescription ({} fields)",
+                client_addr,
+                arrow,
+                fields.len()
+            );
+            *client_state.result_columns.lock().unwrap() = fields
+                .iter()
+                .map(|field| (field.type_oid, field.format_code))
+                .collect();
+            for (i, field) in fields.iter().enumerate() {
+                info!("[{}]    Field {}: {}", client_addr, i + 1, field.describe());
+            }
+
+            // Set up table formatter if in table mode
+            if client_state.table_state.is_table_mode() && !fields.is_empty() {
+                let field_infos: Vec<FieldInfo> = fields
+                    .iter()
+                    .map(|field| FieldInfo {
+                        name: field.name.clone(),
+                        type_name: get_pg_type_name(field.type_oid).to_string(),
+                    })
+                    .collect();
+                client_state
+                    .table_state
+                    .set_row_description(field_infos, client_addr);
+            }
+            if let Some(t) = timings {
+                t.start_result_set();
+            }
+            client_state.result_set.lock().unwrap().start();
+        }
+        BackendMessage::DataRow { values: row } => {
+            let first_row = timings.and_then(|t| t.first_row());
+            client_state.message_span(MessageSpans::data_row);
+            client_state
+                .result_set
+                .lock()
+                .unwrap()
+                .record_row(data.len());
+            let values = format_data_row(
+                &row,
+                client_state.options.max_display_bytes,
+                client_state.options.max_display_text,
+                &client_state.result_columns.lock().unwrap(),
+            );
+            if let Some(event) = &mut event {
+                let shown = values.iter().zip(&row);
+                event.strings(
+                    "values",
+                    shown.map(|(value, raw)| raw.map(|_| value.as_str())),
+                );
+            }
+            if values.is_empty() {
+                return event;
+            }
+            // If in table mode, print as table row
+            if client_state.table_state.is_table_mode() {
+                client_state
+                    .table_state
+                    .print_data_row(&values, client_addr);
+                return event;
+            }
+            match first_row {
+                Some(duration) => info!(
+                    "[{}] {} DataRow ({} fields, {} bytes, first row after {})",
+                    client_addr,
+                    arrow,
+                    values.len(),
+                    data.len(),
+                    format_duration(duration)
+                ),
+                None => info!(
+                    "[{}] {} DataRow ({} fields, {} bytes)",
+                    client_addr,
+                    arrow,
+                    values.len(),
+                    data.len()
+                ),
+            }
+            for (i, value) in values.iter().enumerate() {
+                info!("[{}]    Value {}: {}", client_addr, i + 1, value);
+            }
+        }
+        BackendMessage::CommandComplete { tag } => {
+            client_state
+                .statements
+                .lock()
+                .unwrap()
+                .finish_execute(false);
+            // Finish table formatting if active
+            if client_state.table_state.is_table_mode() {
+                client_state.table_state.finish_result_set(client_addr);
+            }
+
+            let stats = std::mem::take(&mut *client_state.result_set.lock().unwrap());
+            let returns_rows = stats.described
+                || stats.rows > 0
+                || tag.starts_with("SELECT")
+                || tag.starts_with("FETCH");
+            let mut notes = Vec::new();
+            let mut finished = None;
+            if let Some(t) = timings {
+                t.finish_result_set();
+                if let Some((duration, query)) = t.finish_simple_query() {
+                    notes.push(format!("query took {}", format_duration(duration)));
+                    finished = Some((duration, query));
+                } else if let Some((duration, query)) = t.finish_execute() {
+                    notes.push(format!("execute took {}", format_duration(duration)));
+                    finished = Some((duration, query));
+                }
+            }
+            if let Some((duration, query)) = &finished {
+                client_state.observe_query(*duration);
+                client_state
+                    .slow_queries
+                    .lock()
+                    .unwrap()
+                    .record(*duration, sql_preview(&query.sql, SQL_PREVIEW_CHARS));
+            }
+            if stats.rows > 0 {
+                notes.push(format!(
+                    "{} {}, {}",
+                    stats.rows,
+                    if stats.rows == 1 { "row" } else { "rows" },
+                    format_bytes(stats.bytes)
+                ));
+            } else if returns_rows {
+                notes.push("no rows".to_string());
+            }
+            let notes = if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            };
+
+            info!(
+                "[{}] {} CommandComplete: {}{}",
+                client_addr, arrow, tag, notes
+            );
+
+            if returns_rows {
+                if let Some(tagged) = command_tag_rows(&tag) {
+                    if tagged != stats.rows_since_suspend {
+                        warn!(
+                            "[{}] CommandComplete reports {} rows but {} DataRows were seen",
+                            client_addr, tagged, stats.rows_since_suspend
+                        );
+                    }
+                }
+            }
+
+            let rows = if returns_rows {
+                stats.rows
+            } else {
+                command_tag_rows(&tag).unwrap_or(0)
+            };
+            if let Some(event) = &mut event {
+                event.number("rows", rows);
+                if let Some((duration, query)) = &finished {
+                    event.string("sql", &query.sql).duration(*duration);
+                }
+            }
+            if let Some((duration, query)) = &finished {
+                client_state.audit(client_addr, *duration, query, Some(rows), None);
+                client_state.trace(client_addr, *duration, query, Some(rows), stats.bytes, None);
+            }
+            client_state
+                .transaction
+                .lock()
+                .unwrap()
+                .command_complete(&tag, rows);
+        }
+        BackendMessage::ErrorResponse(fields) => {
+            let code = fields.get('C');
+            if let Some(code) = code {
+                client_state
+                    .message_span(|spans| spans.current.push(MessageSpan::error_response(code)));
+            }
+            if let Some(t) = timings {
+                t.record_error();
+                if let Some((duration, query)) = t.finish_query() {
+                    if let Some(event) = &mut event {
+                        event.string("sql", &query.sql).duration(duration);
+                    }
+                    client_state.observe_query(duration);
+                    client_state.audit(client_addr, duration, &query, None, code);
+                    let bytes = client_state.result_set.lock().unwrap().bytes;
+                    client_state.trace(client_addr, duration, &query, None, bytes, code);
+                }
+            }
+            client_state.transaction.lock().unwrap().error();
+            // The error ends any result set being returned
+            client_state.table_state.finish_result_set(client_addr);
+            // FATAL and PANIC end the session instead
+            if fields.get('V').or_else(|| fields.get('S')) == Some("ERROR") {
+                client_state.statements.lock().unwrap().record_error();
+            }
+            if let (Some(metrics), Some(code)) = (&client_state.options.metrics, code) {
+                metrics.error(code);
+            }
+            log_error_fields(client_addr, arrow, "ErrorResponse", &fields);
+        }
+        BackendMessage::NoticeResponse(fields) => {
+            log_error_fields(client_addr, arrow, "NoticeResponse", &fields);
+        }
+        BackendMessage::ParseComplete => match timings.and_then(|t| t.finish_parse()) {
+            Some(duration) => {
+                if let Some(event) = &mut event {
+                    event.duration(duration);
+                }
+                info!(
+                    "[{}] {} ParseComplete (took {})",
+                    client_addr,
+                    arrow,
+                    format_duration(duration)
+                )
+            }
+            None => info!("[{}] {} ParseComplete", client_addr, arrow),
+        },
+        BackendMessage::BindComplete => {
+            client_state.message_span(MessageSpans::bind_complete);
+            match timings.and_then(|t| t.finish_bind()) {
+                Some(duration) => {
+                    if let Some(event) = &mut event {
+                        event.duration(duration);
+                    }
+                    info!(
+                        "[{}] {} BindComplete (took {})",
+                        client_addr,
+                        arrow,
+                        format_duration(duration)
+                    )
+                }
+                None => info!("[{}] {} BindComplete", client_addr, arrow),
+            }
+        }
+        BackendMessage::CloseComplete => {
+            let closed = client_state.statements.lock().unwrap().close_complete();
+            match closed {
+                Some(target) => info!("[{}] {} CloseComplete ({})", client_addr, arrow, target),
+                None => info!("[{}] {} CloseComplete", client_addr, arrow),
+            }
+        }
+        BackendMessage::NoData => {
+            info!("[{}] {} NoData", client_addr, arrow);
+        }
+        BackendMessage::PortalSuspended => {
+            // The next Execute of the portal resumes it and is timed anew
+            let mut notes = Vec::new();
+            let portal = client_state.statements.lock().unwrap().finish_execute(true);
+            let mut result_set = client_state.result_set.lock().unwrap();
+            if let Some(portal) = portal {
+                let rows = result_set.rows_since_suspend;
+                notes.push(format!(
+                    "portal '{}' suspended after {} {}, will resume",
+                    format_identifier(portal.as_bytes()),
+                    rows,
+                    if rows == 1 { "row" } else { "rows" }
+                ));
+            }
+            result_set.suspend();
+            drop(result_set);
+            // The portal's rows continue after the next Execute
+            client_state.table_state.flush_rows(client_addr);
+            // The rows so far belong to an Execute that gets no span; the
+            // portal's Bind waits for the Execute that completes it
+            client_state.message_span(|spans| {
+                spans.current.clear();
+                spans.rows = 0;
+            });
+            if let Some((duration, query)) = timings.and_then(|t| t.finish_execute()) {
+                if let Some(event) = &mut event {
+                    event.string("sql", &query.sql).duration(duration);
+                }
+                notes.push(format!("execute took {}", format_duration(duration)));
+            }
+            if notes.is_empty() {
+                info!("[{}] {} PortalSuspended", client_addr, arrow);
+            } else {
+                info!(
+                    "[{}] {} PortalSuspended ({})",
+                    client_addr,
+                    arrow,
+                    notes.join("; ")
+                );
+            }
+        }
+        BackendMessage::ParameterDescription { param_oids } => {
+            info!(
+                "[{}] {} ParameterDescription ({} parameters)",
+                client_addr,
+                arrow,
+                param_oids.len()
+            );
+            for (i, oid) in param_oids.iter().enumerate() {
+                info!(
+                    "[{}]    Param {}: type={} (OID={})",
+                    client_addr,
+                    i + 1,
+                    get_pg_type_name(*oid),
+                    oid
+                );
+            }
+            client_state
+                .statements
+                .lock()
+                .unwrap()
+                .record_parameter_description(param_oids);
+        }
+        BackendMessage::EmptyQueryResponse => {
+            // Ends the query or Execute like CommandComplete
+            info!("[{}] {} EmptyQueryResponse", client_addr, arrow);
+            client_state
+                .statements
+                .lock()
+                .unwrap()
+                .finish_execute(false);
+            if let Some((duration, query)) = timings.and_then(|t| t.finish_query()) {
+                if let Some(event) = &mut event {
+                    event.string("sql", &query.sql).duration(duration);
+                }
+                client_state.observe_query(duration);
+                client_state.audit(client_addr, duration, &query, Some(0), None);
+                client_state.trace(client_addr, duration, &query, Some(0), 0, None);
+            }
+        }
+        BackendMessage::CopyData(copy_data) => {
+            log_copy_data(client_addr, arrow, copy_data, client_state);
+        }
+        BackendMessage::CopyDone => {
+            log_copy_done(client_addr, arrow, client_state);
+        }
+        BackendMessage::CopyInResponse(copy)
+        | BackendMessage::CopyOutResponse(copy)
+        | BackendMessage::CopyBothResponse(copy) => {
+            let name = message_name(false, msg_type as u8);
+            info!(
+                "[{}] {} {} ({}, {} columns, formats=[{}])",
+                client_addr,
+                arrow,
+                name,
+                if copy.binary { "binary" } else { "text" },
+                copy.column_formats.len(),
+                summarize_format_codes(&copy.column_formats)
+            );
+            // Streaming replication is the only user of CopyBoth
+            let replication = msg_type == 'W' || client_state.session.lock().unwrap().replication;
+            *client_state.copy.lock().unwrap() = Some(CopyState::new(copy.binary, replication));
+        }
+        BackendMessage::NotificationResponse {
+            pid,
+            channel,
+            payload,
+        } => {
+            // Sent whenever a NOTIFY arrives for a channel this session
+            // listens on, not only in reply to a query
+            let note = if client_state.listening.lock().unwrap().contains(&channel) {
+                ""
+            } else {
+                " (no LISTEN seen for this channel)"
+            };
+            info!(
+                "[{}] {} Notification: channel={} payload={} (pid {}){}",
+                client_addr, arrow, channel, payload, pid, note
+            );
+        }
+        BackendMessage::FunctionCallResponse { value } => {
+            let call = client_state.function_calls.lock().unwrap().pop_front();
+            let label = call
+                .as_ref()
+                .map_or(String::new(), |(name, _)| format!(" ({})", name));
+            match value {
+                None => info!(
+                    "[{}] {} FunctionCallResponse{}: NULL",
+                    client_addr, arrow, label
+                ),
+                Some(value) => {
+                    let text = call.is_some_and(|(_, format)| format == 0);
+                    info!(
+                        "[{}] {} FunctionCallResponse{}: {}, {}",
+                        client_addr,
+                        arrow,
+                        label,
+                        format_bytes(value.len() as u64),
+                        format_function_value(value, text, &client_state.options)
+                    );
+                }
+            }
+        }
+        BackendMessage::NegotiateProtocolVersion { minor, options } => {
+            info!(
+                "[{}] {} NegotiateProtocolVersion: server supports up to 3.{}",
+                client_addr, arrow, minor
+            );
+            if !options.is_empty() {
+                info!(
+                    "[{}]    unrecognized options: {}",
+                    client_addr,
+                    options.join(", ")
+                );
+            }
+        }
+    }
+    event
+}
+
+fn parse_cstring_pair(data: &[u8]) -> Option<(String, String)> {
+    let mut parts = data.split(|&b| b == 0);
+    let name = parts.next()?.to_vec();
+    let value = parts.next()?.to_vec();
+
+    Some((
+        String::from_utf8_lossy(&name).to_string(),
+        String::from_utf8_lossy(&value).to_string(),
+    ))
+}
+
+/// Row count carried by a CommandComplete tag such as `SELECT 5` or
+/// `INSERT 0 5`; the count is always the last word
+fn command_tag_rows(tag: &str) -> Option<u64> {
+    let (command, rest) = tag.split_once(' ')?;
+    match command {
+        "SELECT" | "FETCH" | "MOVE" | "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "COPY" => {
+            rest.rsplit(' ').next()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Protocol version 3.0, the only startup message carrying parameters
+const PROTOCOL_VERSION_3: u32 = 196_608;
+
+/// Parameters of a protocol 3.0 StartupMessage, in message order
+pub fn decode_startup_parameters(message: &[u8]) -> Option<Vec<(String, String)>> {
+    let len = u32::from_be_bytes(message.get(0..4)?.try_into().ok()?) as usize;
+    let version = u32::from_be_bytes(message.get(4..8)?.try_into().ok()?);
+    if version != PROTOCOL_VERSION_3 || len != message.len() {
+        return None;
+    }
+    let mut parameters = Vec::new();
+    let mut i = 8;
+    while message.get(i).is_some_and(|&b| b != 0) {
+        let name = read_cstring(message, &mut i)?;
+        let value = read_cstring(message, &mut i)?;
+        parameters.push((
+            String::from_utf8_lossy(&name).into_owned(),
+            String::from_utf8_lossy(&value).into_owned(),
+        ));
+    }
+    Some(parameters)
+}
+
+pub fn encode_startup_message(parameters: &[(String, String)]) -> Vec<u8> {
+    let mut body = PROTOCOL_VERSION_3.to_be_bytes().to_vec();
+    for (name, value) in parameters {
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+
+    let mut message = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(&body);
+    message
+}
+
+/// A message from the client, with its type byte and length field in
+/// front of `body`; framed the same way as a server message
+pub fn encode_frontend_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    encode_backend_message(msg_type, body)
+}
+
+/// Encode a Query ('Q') message
+pub fn encode_query(sql: &str) -> Vec<u8> {
+    let mut body = sql.as_bytes().to_vec();
+    body.push(0);
+    encode_frontend_message(b'Q', &body)
+}
+
+/// One parameter substituted by `rewrite_startup_message`
+#[derive(Debug, PartialEq, Eq)]
+pub struct StartupRewrite {
+    pub parameter: &'static str,
+    pub original: Option<String>,
+    pub rewritten: String,
+}
+
+/// Re-encode a StartupMessage with the database and/or user replaced,
+/// keeping every other parameter in place. Returns None when there is
+/// nothing to substitute or the message is not a complete protocol 3.0
+/// StartupMessage (e.g. a CancelRequest).
+pub fn rewrite_startup_message(
+    message: &[u8],
+    database: Option<&str>,
+    user: Option<&str>,
+) -> Option<(Vec<u8>, Vec<StartupRewrite>)> {
+    if database.is_none() && user.is_none() {
+        return None;
+    }
+    let mut parameters = decode_startup_parameters(message)?;
+    let original = |parameters: &[(String, String)], name: &str| {
+        parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    // The server defaults the database to the user name; pin it before
+    // the user changes so only the requested substitution takes effect
+    if user.is_some() && database.is_none() && original(&parameters, "database").is_none() {
+        if let Some(user) = original(&parameters, "user") {
+            parameters.push(("database".to_string(), user));
+        }
+    }
+
+    let mut rewrites = Vec::new();
+    for (parameter, value) in [("database", database), ("user", user)] {
+        let Some(value) = value else { continue };
+        let previous = original(&parameters, parameter);
+        match parameters.iter_mut().find(|(key, _)| key == parameter) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => parameters.push((parameter.to_string(), value.to_string())),
+        }
+        rewrites.push(StartupRewrite {
+            parameter,
+            original: previous,
+            rewritten: value.to_string(),
+        });
+    }
+    Some((encode_startup_message(&parameters), rewrites))
+}
+
+/// Check the user and database of a StartupMessage against the
+/// allowlists, before any rewrite. Returns the reason for refusing the
+/// connection, or None when it may proceed. Empty allowlists accept
+/// everything; a message that cannot be decoded is refused once either
+/// list is set.
+pub fn startup_allowlist_violation(
+    message: &[u8],
+    allow_databases: &[String],
+    allow_users: &[String],
+) -> Option<String> {
+    if allow_databases.is_empty() && allow_users.is_empty() {
+        return None;
+    }
+    let Some(parameters) = decode_startup_parameters(message) else {
+        return Some("startup message could not be checked by the proxy".to_string());
+    };
+    let value = |name: &str| {
+        parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let user = value("user").unwrap_or_default();
+    // The server defaults the database to the user name
+    let database = value("database").unwrap_or(user);
+    if !allow_users.is_empty() && !allow_users.iter().any(|allowed| allowed == user) {
+        return Some(format!("user \"{}\" is not allowed by the proxy", user));
+    }
+    if !allow_databases.is_empty() && !allow_databases.iter().any(|allowed| allowed == database) {
+        return Some(format!(
+            "database \"{}\" is not allowed by the proxy",
+            database
+        ));
+    }
+    None
+}
+
+/// Build a complete ErrorResponse message the proxy can send to a client
+pub fn encode_error_response(severity: &str, code: &str, message: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (field, value) in [
+        (b'S', severity),
+        (b'V', severity),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        body.push(field);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    encode_backend_message(b'E', &body)
+}
+
+/// A message with its type byte and length field in front of `body`
+pub fn encode_backend_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(body.len() + 5);
+    message.push(msg_type);
+    message.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// One field of a synthesized RowDescription. Fields are never tied to a
+/// table column and are always sent in text format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnDescription {
+    pub name: String,
+    pub type_oid: u32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+}
+
+/// Encode a RowDescription ('T') message
+pub fn encode_row_description(columns: &[ColumnDescription]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(columns.len() as u16).to_be_bytes());
+    for column in columns {
+        body.extend_from_slice(column.name.as_bytes());
+        body.push(0);
+        // Table OID and attribute number: not a table column
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&column.type_oid.to_be_bytes());
+        body.extend_from_slice(&column.type_size.to_be_bytes());
+        body.extend_from_slice(&column.type_modifier.to_be_bytes());
+        // Text format
+        body.extend_from_slice(&0u16.to_be_bytes());
+    }
+    encode_backend_message(b'T', &body)
+}
+
+/// Encode a DataRow ('D') message of text values, None being NULL
+pub fn encode_data_row(values: &[Option<&str>]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(values.len() as u16).to_be_bytes());
+    for value in values {
+        match value {
+            Some(value) => {
+                body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                body.extend_from_slice(value.as_bytes());
+            }
+            None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+    encode_backend_message(b'D', &body)
+}
+
+/// Encode a CommandComplete ('C') message
+pub fn encode_command_complete(tag: &str) -> Vec<u8> {
+    let mut body = tag.as_bytes().to_vec();
+    body.push(0);
+    encode_backend_message(b'C', &body)
+}
+
+/// Encode a ReadyForQuery ('Z') message with transaction status `status`
+pub fn encode_ready_for_query(status: u8) -> Vec<u8> {
+    encode_backend_message(b'Z', &[status])
+}
+
+/// Fields of an ErrorResponse or NoticeResponse, in message order
+#[derive(Debug, Default, PartialEq)]
+struct ErrorFields {
+    fields: Vec<(char, String)>,
+}
+
+impl ErrorFields {
+    fn get(&self, code: char) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == code)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The non-localized severity when the server sends one
+    fn severity(&self) -> &str {
+        self.get('V').or_else(|| self.get('S')).unwrap_or("UNKNOWN")
+    }
+
+    /// Log level matching the severity, so errors can be filtered on
+    fn level(&self) -> Level {
+        match self.severity() {
+            "PANIC" | "FATAL" => Level::ERROR,
+            "ERROR" | "WARNING" => Level::WARN,
+            "NOTICE" | "INFO" | "LOG" => Level::INFO,
+            _ => Level::DEBUG,
+        }
+    }
+
+    /// `ERROR sqlstate=22012: division by zero`
+    fn summary(&self) -> String {
+        let mut summary = self.severity().to_string();
+        if let Some(code) = self.get('C') {
+            summary.push_str(&format!(" sqlstate={}", code));
+        }
+        if let Some(message) = self.get('M') {
+            summary.push_str(&format!(": {}", message));
+        }
+        summary
+    }
+
+    /// The fields not already part of the summary
+    fn details(&self) -> Option<String> {
+        let details: Vec<String> = self
+            .fields
+            .iter()
+            .filter(|(code, _)| !matches!(code, 'S' | 'V' | 'C' | 'M'))
+            .map(|(code, value)| format!("{}: {}", error_field_name(*code), value))
+            .collect();
+        if details.is_empty() {
+            None
+        } else {
+            Some(details.join(", "))
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (code, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}", error_field_name(*code), value)?;
+        }
+        Ok(())
+    }
+}
+
+fn error_field_name(code: char) -> &'static str {
+    match code {
+        'S' => "Severity",
+        'V' => "Severity",
+        'C' => "Code",
+        'M' => "Message",
+        'D' => "Detail",
+        'H' => "Hint",
+        'P' => "Position",
+        'p' => "Internal position",
+        'q' => "Internal query",
+        'W' => "Where",
+        's' => "Schema",
+        't' => "Table",
+        'c' => "Column",
+        'd' => "Data type",
+        'n' => "Constraint",
+        'F' => "File",
+        'L' => "Line",
+        'R' => "Routine",
+        _ => "Unknown",
+    }
+}
+
+/// `FATAL sqlstate=28000: ...` for an ErrorResponse body
+pub fn describe_error_response(data: &[u8]) -> String {
+    parse_error_response(data).map_or("ErrorResponse".to_string(), |fields| fields.summary())
+}
+
+fn parse_error_response(data: &[u8]) -> Option<ErrorFields> {
+    let mut fields = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let field_type = data[i] as char;
+        if field_type == '\0' {
+            break;
+        }
+
+        i += 1;
+        let mut field_value = Vec::new();
+        while i < data.len() && data[i] != 0 {
+            field_value.push(data[i]);
+            i += 1;
+        }
+        i += 1; // Skip null terminator
+
+        fields.push((
+            field_type,
+            String::from_utf8_lossy(&field_value).into_owned(),
+        ));
+    }
+
+    if fields.is_empty() {
+        None
+    } else {
+        Some(ErrorFields { fields })
+    }
+}
+
+/// Log an ErrorResponse or NoticeResponse at the level of its severity,
+/// with the remaining fields on a second line
+fn log_error_fields(client_addr: &str, arrow: &str, name: &str, fields: &ErrorFields) {
+    if fields.fields.is_empty() {
+        info!("[{}] {} {}", client_addr, arrow, name);
+        return;
+    }
+    let level = fields.level();
+    log_at(
+        level,
+        &format!("[{}] {} {}: {}", client_addr, arrow, name, fields.summary()),
+    );
+    if let Some(details) = fields.details() {
+        log_at(level, &format!("[{}]    {}", client_addr, details));
+    }
+}
+
+fn log_at(level: Level, line: &str) {
+    match level {
+        Level::ERROR => error!("{}", line),
+        Level::WARN => warn!("{}", line),
+        Level::INFO => info!("{}", line),
+        _ => debug!("{}", line),
+    }
+}
+
+pub fn decode_parse_message(data: &[u8]) -> (String, String) {
+    let mut i = 0;
+
+    // Statement name
+    let mut stmt_name = Vec::new();
+    while i < data.len() && data[i] != 0 {
+        stmt_name.push(data[i]);
+        i += 1;
+    }
+    i += 1; // Skip null terminator
+
+    // Query string
+    let mut query = Vec::new();
+    while i < data.len() && data[i] != 0 {
+        query.push(data[i]);
+        i += 1;
+    }
+
+    (
+        String::from_utf8_lossy(&stmt_name).to_string(),
+        String::from_utf8_lossy(&query).to_string(),
+    )
+}
+
+/// `Statement: 'name', Query: '...'` and any pre-specified parameter types
+/// of a Parse; `param_oids` is None when the list was cut short
+fn describe_parse(
+    name: &str,
+    query: &str,
+    param_oids: Option<&[u32]>,
+    max_query_chars: usize,
+) -> Option<String> {
+    if name.is_empty() && query.is_empty() {
+        return None;
+    }
+    let param_types = match param_oids {
+        Some([]) => String::new(),
+        Some(oids) => format!(
+            ", ParamTypes=[{}]",
+            oids.iter()
+                .map(|&oid| match oid {
+                    0 => "unspecified".to_string(),
+                    oid => match get_pg_type_name(oid) {
+                        "unknown" => format!("OID {}", oid),
+                        name => name.to_string(),
+                    },
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => ", ParamTypes=<truncated>".to_string(),
+    };
+    Some(format!(
+        "Statement: '{}', Query: '{}'{}",
+        if name.is_empty() { "(unnamed)" } else { name },
+        truncate_query(query, max_query_chars),
+        param_types
+    ))
+}
+
+/// Parameter type OIDs the client pre-specified in a Parse message (0
+/// leaves the type to the server); None when the body is truncated
+fn decode_parse_param_types(data: &[u8]) -> Option<Vec<u32>> {
+    let mut i = 0;
+    read_cstring(data, &mut i)?;
+    read_cstring(data, &mut i)?;
+    let count = u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?) as usize;
+    i += 2;
+    (0..count)
+        .map(|n| {
+            let start = i + n * 4;
+            Some(u32::from_be_bytes(
+                data.get(start..start + 4)?.try_into().ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// One column of a RowDescription
+#[derive(Debug, PartialEq)]
+struct RowDescriptionField {
+    name: String,
+    table_oid: u32,
+    column_id: i16,
+    type_oid: u32,
+    type_size: i16,
+    type_modifier: i32,
+    format_code: u16,
+}
+
+impl RowDescriptionField {
+    fn describe(&self) -> String {
+        format!(
+            "name='{}', type={} (OID={}), size={}, typemod={}, format={}",
+            self.name,
+            get_pg_type_name(self.type_oid),
+            self.type_oid,
+            self.type_size,
+            self.type_modifier,
+            match self.format_code {
+                0 => "text",
+                1 => "binary",
+                _ => "unknown",
+            }
+        )
+    }
+}
+
+/// The columns of a RowDescription, or None when it is cut short
+fn decode_row_description(data: &[u8]) -> Option<Vec<RowDescriptionField>> {
+    let field_count = u16::from_be_bytes(data.get(..2)?.try_into().ok()?);
+    let mut i = 2;
+    (0..field_count)
+        .map(|_| {
+            let name = read_cstring(data, &mut i)?;
+            let fixed = data.get(i..i + 18)?;
+            i += 18;
+            Some(RowDescriptionField {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                table_oid: u32::from_be_bytes(fixed[0..4].try_into().ok()?),
+                column_id: i16::from_be_bytes(fixed[4..6].try_into().ok()?),
+                type_oid: u32::from_be_bytes(fixed[6..10].try_into().ok()?),
+                type_size: i16::from_be_bytes(fixed[10..12].try_into().ok()?),
+                type_modifier: i32::from_be_bytes(fixed[12..16].try_into().ok()?),
+                format_code: u16::from_be_bytes(fixed[16..18].try_into().ok()?),
+            })
+        })
+        .collect()
+}
+
+/// The column values of a DataRow (None is NULL), or None when it is cut
+/// short
+fn decode_data_row(data: &[u8]) -> Option<Vec<Option<&[u8]>>> {
+    let field_count = u16::from_be_bytes(data.get(..2)?.try_into().ok()?);
+    let mut i = 2;
+    (0..field_count)
+        .map(|_| {
+            let length = i32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?);
+            i += 4;
+            if length < 0 {
+                return Some(None);
+            }
+            let value = data.get(i..i + length as usize)?;
+            i += length as usize;
+            Some(Some(value))
+        })
+        .collect()
+}
+
+/// Values of a DataRow for display. `columns` holds the type OID and format
+/// code of each column; binary arrays are shown in their text form.
+fn format_data_row(
+    values: &[Option<&[u8]>],
+    max_display_bytes: usize,
+    max_display_text: usize,
+    columns: &[(u32, u16)],
+) -> Vec<String> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let Some(value_bytes) = value else {
+                return "NULL".to_string();
+            };
+            if let Some((type_oid, 1)) = columns.get(index).copied() {
+                if get_pg_type_name(type_oid).ends_with("[]") {
+                    if let Some(text) = decode_binary_array(value_bytes) {
+                        return format!("'{}'", text);
+                    }
+                }
+            }
+
+            // Try to display as UTF-8 string, otherwise show hex
+            match std::str::from_utf8(value_bytes) {
+                // Truncate long values on a character boundary
+                Ok(s) => match s.char_indices().nth(max_display_text) {
+                    Some((end, _)) => format!("'{}...' ({} bytes)", &s[..end], s.len()),
+                    None => format!("'{}'", s),
+                },
+                Err(_) => hex_preview(value_bytes, max_display_bytes),
+            }
+        })
+        .collect()
+}
+
+/// Name of a built-in type, with `[]` for arrays, or "unknown"
+fn get_pg_type_name(oid: u32) -> &'static str {
+    // A match over every type in catalog/pg_type.dat, written by build.rs
+    include!(concat!(env!("OUT_DIR"), "/pg_type_names.rs"))
+}
+
+/// Decoded body of a client Bind message
+#[derive(Debug, PartialEq)]
+struct BindMessage {
+    portal: String,
+    statement: String,
+    param_formats: Vec<u16>,
+    params: Vec<Option<Vec<u8>>>,
+    result_formats: Vec<u16>,
+}
+
+impl BindMessage {
+    /// Format code in effect for the parameter at `index`
+    fn param_format(&self, index: usize) -> u16 {
+        match self.param_formats.len() {
+            0 => 0,
+            1 => self.param_formats[0],
+            _ => self.param_formats.get(index).copied().unwrap_or(0),
+        }
+    }
+}
+
+fn decode_bind_message(data: &[u8]) -> Option<BindMessage> {
+    let mut i = 0;
+
+    let portal_name = read_cstring(data, &mut i)?;
+    let stmt_name = read_cstring(data, &mut i)?;
+
+    if i + 2 > data.len() {
+        return None;
+    }
+
+    // Parameter format codes
+    let param_format_count = u16::from_be_bytes([data[i], data[i + 1]]);
+    i += 2;
+    let mut param_formats = Vec::new();
+    for _ in 0..param_format_count {
+        if i + 2 > data.len() {
+            return None;
+        }
+        param_formats.push(u16::from_be_bytes([data[i], data[i + 1]]));
+        i += 2;
+    }
+
+    if i + 2 > data.len() {
+        return None;
+    }
+
+    // Parameter count
+    let param_count = u16::from_be_bytes([data[i], data[i + 1]]);
+    i += 2;
+
+    // Parameter values
+    let mut params = Vec::new();
+    for _ in 0..param_count {
+        if i + 4 > data.len() {
+            return None;
+        }
+        let value_len = i32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        i += 4;
+
+        if value_len < 0 {
+            params.push(None);
+            continue;
+        }
+
+        let value_len = value_len as usize;
+        if i + value_len > data.len() {
+            return None;
+        }
+        params.push(Some(data[i..i + value_len].to_vec()));
+        i += value_len;
+    }
+
+    if i + 2 > data.len() {
+        return None;
+    }
+
+    // Result format codes
+    let result_format_count = u16::from_be_bytes([data[i], data[i + 1]]);
+    i += 2;
+    let mut result_formats = Vec::new();
+    for _ in 0..result_format_count {
+        if i + 2 > data.len() {
+            return None;
+        }
+        result_formats.push(u16::from_be_bytes([data[i], data[i + 1]]));
+        i += 2;
+    }
+
+    Some(BindMessage {
+        portal: String::from_utf8_lossy(&portal_name).to_string(),
+        statement: String::from_utf8_lossy(&stmt_name).to_string(),
+        param_formats,
+        params,
+        result_formats,
+    })
+}
+
+fn describe_bind_message(bind: &BindMessage) -> String {
+    let portal_str = format_identifier(bind.portal.as_bytes());
+    let stmt_str = format_identifier(bind.statement.as_bytes());
+    let param_formats_desc = describe_format_codes(
+        "ParamFormats",
+        bind.param_formats.len() as u16,
+        &bind.param_formats,
+    );
+    let result_formats_desc = describe_format_codes(
+        "ResultFormats",
+        bind.result_formats.len() as u16,
+        &bind.result_formats,
+    );
+
+    format!(
+        "Portal='{}', Statement='{}', Parameters={}, {}, {}",
+        portal_str,
+        stmt_str,
+        bind.params.len(),
+        param_formats_desc,
+        result_formats_desc
+    )
+}
+
+/// Bind parameter values as shown in the log; `None` is NULL
+fn bind_param_values(
+    bind: &BindMessage,
+    param_types: &[u32],
+    options: &ProtocolOptions,
+) -> Vec<Option<String>> {
+    bind.params
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let bytes = value.as_ref()?;
+            Some(if options.redact {
+                "<redacted>".to_string()
+            } else if bind.param_format(index) == 0 {
+                format_text_param(bytes, options.max_param_len)
+            } else {
+                param_types
+                    .get(index)
+                    .and_then(|oid| decode_binary_value(*oid, bytes))
+                    .unwrap_or_else(|| hex_preview(bytes, options.max_display_bytes))
+            })
+        })
+        .collect()
+}
+
+/// Render Bind parameter values as `$1='abc', $2=NULL, $3=42`
+fn format_bind_params(values: &[Option<String>]) -> Option<String> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let rendered = values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| format!("${}={}", index + 1, value.as_deref().unwrap_or("NULL")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(rendered)
+}
+
+/// Body of CopyInResponse, CopyOutResponse and CopyBothResponse
+#[derive(Debug, PartialEq)]
+struct CopyResponse {
+    binary: bool,
+    column_formats: Vec<u16>,
+}
+
+fn decode_copy_response(data: &[u8]) -> Option<CopyResponse> {
+    let binary = *data.first()? != 0;
+    let count = u16::from_be_bytes(data.get(1..3)?.try_into().ok()?) as usize;
+    let column_formats = (0..count)
+        .map(|n| {
+            let start = 3 + n * 2;
+            Some(u16::from_be_bytes(
+                data.get(start..start + 2)?.try_into().ok()?,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(CopyResponse {
+        binary,
+        column_formats,
+    })
+}
+
+/// Format codes with runs collapsed, e.g. `text x2, binary`
+fn summarize_format_codes(codes: &[u16]) -> String {
+    let mut runs: Vec<(u16, usize)> = Vec::new();
+    for &code in codes {
+        match runs.last_mut() {
+            Some((last, count)) if *last == code => *count += 1,
+            _ => runs.push((code, 1)),
+        }
+    }
+    runs.iter()
+        .map(|&(code, count)| match count {
+            1 => format_code_name(code).to_string(),
+            _ => format!("{} x{}", format_code_name(code), count),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Most bytes of an unfinished text COPY row kept for the next CopyData
+const COPY_CARRY_LIMIT: usize = 4096;
+
+/// Progress of the COPY in progress
+struct CopyState {
+    binary: bool,
+    /// CopyData carries streaming replication messages
+    replication: bool,
+    /// Replication messages decoded so far
+    replication_messages: u64,
+    /// Text rows, or tuples of a binary COPY
+    rows: u64,
+    bytes: u64,
+    /// Start of a text row that continues in the next CopyData
+    partial_row: Vec<u8>,
+    /// Tuple decoder of a binary COPY
+    decoder: BinaryCopyDecoder,
+}
+
+impl CopyState {
+    fn new(binary: bool, replication: bool) -> Self {
+        Self {
+            binary,
+            replication,
+            replication_messages: 0,
+            rows: 0,
+            bytes: 0,
+            partial_row: Vec::new(),
+            decoder: BinaryCopyDecoder::default(),
+        }
+    }
+
+    /// Count the text rows a CopyData completes, joining the row carried
+    /// over from the previous message; returns the number of rows and the
+    /// first `limit` of them
+    fn text_rows(&mut self, data: &[u8], limit: usize) -> (u64, Vec<Vec<u8>>) {
+        let mut completed = 0;
+        let mut preview = Vec::new();
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let mut row = std::mem::take(&mut self.partial_row);
+            if preview.len() < limit {
+                row.extend_from_slice(&rest[..end]);
+                preview.push(row);
+            }
+            completed += 1;
+            rest = &rest[end + 1..];
+        }
+        let room = COPY_CARRY_LIMIT.saturating_sub(self.partial_row.len());
+        self.partial_row
+            .extend_from_slice(&rest[..rest.len().min(room)]);
+        self.rows += completed;
+        (completed, preview)
+    }
+
+    /// Totals at CopyDone; a final text row without a newline still counts
+    fn finish(&mut self) -> (u64, u64) {
+        if !std::mem::take(&mut self.partial_row).is_empty() {
+            self.rows += 1;
+        }
+        (self.rows, self.bytes)
+    }
+}
+
+/// Signature opening every binary COPY stream
+const PGCOPY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// Header flag: every tuple carries an OID before its fields (pre-12 servers)
+const PGCOPY_WITH_OIDS: u32 = 1 << 16;
+
+/// Where the decoder is in the binary COPY format
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum BinaryCopyStage {
+    #[default]
+    Signature,
+    Flags,
+    ExtensionLength,
+    /// Header extension bytes still to skip
+    Extension(u32),
+    FieldCount,
+    FieldLength,
+    /// Field bytes still to skip
+    FieldData(u32),
+    /// After the -1 field count that ends the stream
+    Trailer,
+    /// The data was malformed; nothing more is decoded
+    Failed,
+}
+
+/// A tuple decoded from a binary COPY stream
+#[derive(Debug, PartialEq)]
+struct CopyTuple {
+    fields: u16,
+    /// Bytes of the tuple on the wire, including counts and lengths
+    bytes: u64,
+}
+
+/// Incremental decoder for the binary COPY format. Tuples routinely span
+/// CopyData messages, so fixed-size items are collected in `pending` and
+/// field contents are skipped without being buffered.
+#[derive(Default)]
+struct BinaryCopyDecoder {
+    stage: BinaryCopyStage,
+    pending: Vec<u8>,
+    with_oids: bool,
+    fields: u16,
+    fields_left: u32,
+    tuple_bytes: u64,
+}
+
+impl BinaryCopyDecoder {
+    /// Feed one CopyData payload; returns the tuples it completes and, the
+    /// first time the stream turns out to be malformed, what is wrong
+    fn feed(&mut self, mut data: &[u8]) -> (Vec<CopyTuple>, Option<String>) {
+        let mut tuples = Vec::new();
+        while !data.is_empty() {
+            match self.stage {
+                BinaryCopyStage::Failed => break,
+                BinaryCopyStage::Trailer => {
+                    let error = self.fail(format!("{} bytes after the trailer", data.len()));
+                    return (tuples, error.err());
+                }
+                BinaryCopyStage::Extension(left) | BinaryCopyStage::FieldData(left) => {
+                    let taken = data.len().min(left as usize);
+                    data = &data[taken..];
+                    let left = left - taken as u32;
+                    if let BinaryCopyStage::FieldData(_) = self.stage {
+                        self.tuple_bytes += taken as u64;
+                        self.stage = BinaryCopyStage::FieldData(left);
+                        if left == 0 {
+                            self.finish_field(&mut tuples);
+                        }
+                    } else if left == 0 {
+                        self.stage = BinaryCopyStage::FieldCount;
+                    } else {
+                        self.stage = BinaryCopyStage::Extension(left);
+                    }
+                }
+                stage => {
+                    let size = match stage {
+                        BinaryCopyStage::Signature => PGCOPY_SIGNATURE.len(),
+                        BinaryCopyStage::FieldCount => 2,
+                        _ => 4,
+                    };
+                    let taken = data.len().min(size - self.pending.len());
+                    self.pending.extend_from_slice(&data[..taken]);
+                    data = &data[taken..];
+                    if self.pending.len() == size {
+                        let item = std::mem::take(&mut self.pending);
+                        if let Err(error) = self.read_item(&item, &mut tuples) {
+                            return (tuples, Some(error));
+                        }
+                    }
+                }
+            }
+        }
+        (tuples, None)
+    }
+
+    /// Handle a complete signature, flags word, length or count
+    fn read_item(&mut self, item: &[u8], tuples: &mut Vec<CopyTuple>) -> Result<(), String> {
+        let word = || u32::from_be_bytes(item.try_into().unwrap_or_default());
+        match self.stage {
+            BinaryCopyStage::Signature if item != PGCOPY_SIGNATURE => {
+                return self.fail("missing PGCOPY signature".to_string());
+            }
+            BinaryCopyStage::Signature => self.stage = BinaryCopyStage::Flags,
+            BinaryCopyStage::Flags => {
+                let flags = word();
+                // Bits 16-31 flag critical format changes and a reader must
+                // fail on unknown ones; bits 0-15 are backwards-compatible
+                // and ignored. Bit 16 (OIDs) is the only one defined.
+                if flags & !PGCOPY_WITH_OIDS & 0xffff_0000 != 0 {
+                    return self.fail(format!("unknown header flags 0x{:08x}", flags));
+                }
+                self.with_oids = flags & PGCOPY_WITH_OIDS != 0;
+                self.stage = BinaryCopyStage::ExtensionLength;
+            }
+            BinaryCopyStage::ExtensionLength => {
+                self.stage = match word() {
+                    0 => BinaryCopyStage::FieldCount,
+                    len => BinaryCopyStage::Extension(len),
+                };
+            }
+            BinaryCopyStage::FieldCount => {
+                let count = i16::from_be_bytes([item[0], item[1]]);
+                if count == -1 {
+                    self.stage = BinaryCopyStage::Trailer;
+                    return Ok(());
+                }
+                if count < 0 {
+                    return self.fail(format!("invalid field count {}", count));
+                }
+                self.fields = count as u16;
+                self.fields_left = u32::from(self.fields) + u32::from(self.with_oids);
+                self.tuple_bytes = 2;
+                if self.fields_left == 0 {
+                    self.finish_tuple(tuples);
+                } else {
+                    self.stage = BinaryCopyStage::FieldLength;
+                }
+            }
+            BinaryCopyStage::FieldLength => {
+                let len = word() as i32;
+                self.tuple_bytes += 4;
+                match len {
+                    -1 | 0 => self.finish_field(tuples),
+                    len if len < 0 => {
+                        return self.fail(format!("invalid field length {}", len));
+                    }
+                    len => self.stage = BinaryCopyStage::FieldData(len as u32),
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn finish_field(&mut self, tuples: &mut Vec<CopyTuple>) {
+        self.fields_left -= 1;
+        if self.fields_left == 0 {
+            self.finish_tuple(tuples);
+        } else {
+            self.stage = BinaryCopyStage::FieldLength;
+        }
+    }
+
+    fn finish_tuple(&mut self, tuples: &mut Vec<CopyTuple>) {
+        tuples.push(CopyTuple {
+            fields: self.fields,
+            bytes: self.tuple_bytes,
+        });
+        self.stage = BinaryCopyStage::FieldCount;
+    }
+
+    fn fail(&mut self, reason: String) -> Result<(), String> {
+        self.stage = BinaryCopyStage::Failed;
+        Err(reason)
+    }
+
+    fn failed(&self) -> bool {
+        self.stage == BinaryCopyStage::Failed
+    }
+}
+
+/// Log a CopyData message; rows of a text COPY are previewed, binary ones
+/// only counted
+fn log_copy_data(client_addr: &str, arrow: &str, data: &[u8], client_state: &ClientState) {
+    let mut copy = client_state.copy.lock().unwrap();
+    let Some(copy) = copy.as_mut() else {
+        info!(
+            "[{}] {} CopyData ({} bytes)",
+            client_addr,
+            arrow,
+            data.len()
+        );
+        return;
+    };
+    copy.bytes += data.len() as u64;
+    let options = &client_state.options;
+    if copy.replication && log_replication_message(client_addr, arrow, data, options) {
+        copy.replication_messages += 1;
+        return;
+    }
+    if copy.binary {
+        log_binary_copy_data(client_addr, arrow, data, copy, options.copy_preview_lines);
+        return;
+    }
+
+    let (completed, preview) = copy.text_rows(data, options.copy_preview_lines);
+    info!(
+        "[{}] {} CopyData ({} bytes, {} {})",
+        client_addr,
+        arrow,
+        data.len(),
+        completed,
+        if completed == 1 { "row" } else { "rows" }
+    );
+    if options.redact {
+        return;
+    }
+    let first = copy.rows - completed;
+    for (i, row) in preview.iter().enumerate() {
+        info!(
+            "[{}]    Row {}: {}",
+            client_addr,
+            first + i as u64 + 1,
+            copy_row_preview(row, options.max_param_len)
+        );
+    }
+}
+
+/// Log a binary CopyData with a summary of the tuples it completes; once
+/// the stream is found malformed only byte counts are logged
+fn log_binary_copy_data(
+    client_addr: &str,
+    arrow: &str,
+    data: &[u8],
+    copy: &mut CopyState,
+    limit: usize,
+) {
+    if copy.decoder.failed() {
+        info!(
+            "[{}] {} CopyData ({} bytes, binary)",
+            client_addr,
+            arrow,
+            data.len()
+        );
+        return;
+    }
+    let in_trailer = |copy: &CopyState| copy.decoder.stage == BinaryCopyStage::Trailer;
+    let before_trailer = !in_trailer(copy);
+    let (tuples, error) = copy.decoder.feed(data);
+    info!(
+        "[{}] {} CopyData ({} bytes, binary, {} {})",
+        client_addr,
+        arrow,
+        data.len(),
+        tuples.len(),
+        if tuples.len() == 1 { "tuple" } else { "tuples" }
+    );
+    let first = copy.rows;
+    copy.rows += tuples.len() as u64;
+    for (i, tuple) in tuples.iter().take(limit).enumerate() {
+        info!(
+            "[{}]    Tuple {}: {} fields, {} bytes",
+            client_addr,
+            first + i as u64 + 1,
+            tuple.fields,
+            tuple.bytes
+        );
+    }
+    if before_trailer && in_trailer(copy) {
+        info!(
+            "[{}]    Trailer ({} tuples in total)",
+            client_addr, copy.rows
+        );
+    }
+    if let Some(error) = error {
+        warn!(
+            "[{}] Malformed binary COPY data ({}), no longer decoding this COPY",
+            client_addr, error
+        );
+    }
+}
+
+/// A streaming replication message carried in CopyData
+#[derive(Debug, PartialEq)]
+enum ReplicationMessage<'a> {
+    /// WAL sent by the server, starting at `start`
+    XLogData {
+        start: u64,
+        wal_end: u64,
+        sent: i64,
+        payload: &'a [u8],
+    },
+    /// Primary keepalive from the server
+    Keepalive {
+        wal_end: u64,
+        sent: i64,
+        reply_requested: bool,
+    },
+    /// Standby status update from the client
+    StandbyStatus {
+        written: u64,
+        flushed: u64,
+        applied: u64,
+        sent: i64,
+        reply_requested: bool,
+    },
+}
+
+fn decode_replication_message(data: &[u8]) -> Option<ReplicationMessage<'_>> {
+    let read_u64 = |i: usize| Some(u64::from_be_bytes(data.get(i..i + 8)?.try_into().ok()?));
+    match data.first()? {
+        b'w' => Some(ReplicationMessage::XLogData {
+            start: read_u64(1)?,
+            wal_end: read_u64(9)?,
+            sent: read_u64(17)? as i64,
+            payload: data.get(25..)?,
+        }),
+        b'k' if data.len() == 18 => Some(ReplicationMessage::Keepalive {
+            wal_end: read_u64(1)?,
+            sent: read_u64(9)? as i64,
+            reply_requested: data[17] != 0,
+        }),
+        b'r' if data.len() == 34 => Some(ReplicationMessage::StandbyStatus {
+            written: read_u64(1)?,
+            flushed: read_u64(9)?,
+            applied: read_u64(17)?,
+            sent: read_u64(25)? as i64,
+            reply_requested: data[33] != 0,
+        }),
+        _ => None,
+    }
+}
+
+/// A WAL location in the `X/XXXXXXXX` notation of pg_lsn
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:08X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// Log a CopyData of a replication stream; returns false when it does not
+/// hold a replication message so it is logged as plain COPY data
+fn log_replication_message(
+    client_addr: &str,
+    arrow: &str,
+    data: &[u8],
+    options: &ProtocolOptions,
+) -> bool {
+    let Some(message) = decode_replication_message(data) else {
+        return false;
+    };
+    let reply = |requested: bool| if requested { ", reply requested" } else { "" };
+    match message {
+        ReplicationMessage::XLogData {
+            start,
+            wal_end,
+            sent,
+            payload,
+        } => {
+            info!(
+                "[{}] {} XLogData (start {}, server WAL end {}, sent {}, {} bytes)",
+                client_addr,
+                arrow,
+                format_lsn(start),
+                format_lsn(wal_end),
+                format_timestamp(sent, "+00"),
+                payload.len()
+            );
+            if !options.redact && !payload.is_empty() {
+                // Logical decoding plugins such as test_decoding send text
+                let preview = match std::str::from_utf8(payload) {
+                    Ok(_) => copy_row_preview(payload, options.max_param_len),
+                    Err(_) => hex_preview(payload, options.max_display_bytes),
+                };
+                info!("[{}]    Payload: {}", client_addr, preview);
+            }
+        }
+        ReplicationMessage::Keepalive {
+            wal_end,
+            sent,
+            reply_requested,
+        } => info!(
+            "[{}] {} Primary keepalive (server WAL end {}, sent {}{})",
+            client_addr,
+            arrow,
+            format_lsn(wal_end),
+            format_timestamp(sent, "+00"),
+            reply(reply_requested)
+        ),
+        ReplicationMessage::StandbyStatus {
+            written,
+            flushed,
+            applied,
+            sent,
+            reply_requested,
+        } => info!(
+            "[{}] {} Standby status update (write {}, flush {}, apply {}, sent {}{})",
+            client_addr,
+            arrow,
+            format_lsn(written),
+            format_lsn(flushed),
+            format_lsn(applied),
+            format_timestamp(sent, "+00"),
+            reply(reply_requested)
+        ),
+    }
+    true
+}
+
+/// A text COPY row, with tabs and other escapes visible
+fn copy_row_preview(row: &[u8], max_chars: usize) -> String {
+    let text = String::from_utf8_lossy(row);
+    let preview: String = text.chars().take(max_chars).collect();
+    let ellipsis = if preview.len() < text.len() {
+        "..."
+    } else {
+        ""
+    };
+    format!("'{}{}'", preview.escape_debug(), ellipsis)
+}
+
+/// CopyDone ends the COPY; the totals are reported with it
+fn log_copy_done(client_addr: &str, arrow: &str, client_state: &ClientState) {
+    match client_state.copy.lock().unwrap().take() {
+        Some(copy) if copy.replication => info!(
+            "[{}] {} CopyDone ({} replication {}, {} copied)",
+            client_addr,
+            arrow,
+            copy.replication_messages,
+            if copy.replication_messages == 1 {
+                "message"
+            } else {
+                "messages"
+            },
+            format_bytes(copy.bytes)
+        ),
+        Some(mut copy) => {
+            let binary = copy.binary;
+            let decoded = !copy.decoder.failed();
+            let (rows, bytes) = copy.finish();
+            if binary && decoded {
+                info!(
+                    "[{}] {} CopyDone ({} {}, {} copied)",
+                    client_addr,
+                    arrow,
+                    rows,
+                    if rows == 1 { "tuple" } else { "tuples" },
+                    format_bytes(bytes)
+                );
+            } else if binary {
+                info!(
+                    "[{}] {} CopyDone ({} copied)",
+                    client_addr,
+                    arrow,
+                    format_bytes(bytes)
+                );
+            } else {
+                info!(
+                    "[{}] {} CopyDone ({} {}, {} copied)",
+                    client_addr,
+                    arrow,
+                    rows,
+                    if rows == 1 { "row" } else { "rows" },
+                    format_bytes(bytes)
+                );
+            }
+        }
+        None => info!("[{}] {} CopyDone", client_addr, arrow),
+    }
+}
+
+/// A fast-path FunctionCall message
+#[derive(Debug, PartialEq)]
+struct FunctionCall {
+    oid: u32,
+    arg_formats: Vec<u16>,
+    args: Vec<Option<Vec<u8>>>,
+    result_format: u16,
+}
+
+fn decode_function_call(data: &[u8]) -> Option<FunctionCall> {
+    let read_u16 = |i: usize| Some(u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?));
+    let oid = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?);
+    let mut i = 4;
+
+    let format_count = read_u16(i)?;
+    i += 2;
+    let mut arg_formats = Vec::new();
+    for _ in 0..format_count {
+        arg_formats.push(read_u16(i)?);
+        i += 2;
+    }
+
+    let arg_count = read_u16(i)?;
+    i += 2;
+    let mut args = Vec::new();
+    for _ in 0..arg_count {
+        let len = i32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?);
+        i += 4;
+        if len < 0 {
+            args.push(None);
+            continue;
+        }
+        let value = data.get(i..i + len as usize)?;
+        args.push(Some(value.to_vec()));
+        i += len as usize;
+    }
+
+    Some(FunctionCall {
+        oid,
+        arg_formats,
+        args,
+        result_format: read_u16(i)?,
+    })
+}
+
+/// The result value of a FunctionCallResponse; `Some(None)` is NULL
+fn decode_function_result(data: &[u8]) -> Option<Option<&[u8]>> {
+    let len = i32::from_be_bytes(data.get(0..4)?.try_into().ok()?);
+    if len < 0 {
+        return Some(None);
+    }
+    data.get(4..4 + len as usize).map(Some)
+}
+
+/// Name of the fast-path functions drivers commonly call
+fn function_name(oid: u32) -> Option<&'static str> {
+    match oid {
+        715 => Some("lo_create"),
+        952 => Some("lo_open"),
+        953 => Some("lo_close"),
+        954 => Some("loread"),
+        955 => Some("lowrite"),
+        956 => Some("lo_lseek"),
+        957 => Some("lo_creat"),
+        958 => Some("lo_tell"),
+        964 => Some("lo_unlink"),
+        1004 => Some("lo_truncate"),
+        3170 => Some("lo_lseek64"),
+        3171 => Some("lo_tell64"),
+        3172 => Some("lo_truncate64"),
+        _ => None,
+    }
+}
+
+fn format_code_name(code: u16) -> &'static str {
+    match code {
+        0 => "text",
+        1 => "binary",
+        _ => "unknown",
+    }
+}
+
+/// Render FunctionCall arguments as `$1=3, $2=<binary: ...>`
+fn format_function_args(call: &FunctionCall, options: &ProtocolOptions) -> Option<String> {
+    if call.args.is_empty() {
+        return None;
+    }
+    let rendered = call
+        .args
+        .iter()
+        .enumerate()
+        .map(|(index, arg)| {
+            let format = match call.arg_formats.len() {
+                0 => 0,
+                1 => call.arg_formats[0],
+                _ => call.arg_formats.get(index).copied().unwrap_or(0),
+            };
+            let shown = match arg {
+                None => "NULL".to_string(),
+                Some(bytes) => format_function_value(bytes, format == 0, options),
+            };
+            format!("${}={}", index + 1, shown)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(rendered)
+}
+
+/// Fast-path values carry no type, so binary 4- and 8-byte values are
+/// shown as the integers large-object calls use and the rest as hex
+fn format_function_value(bytes: &[u8], text: bool, options: &ProtocolOptions) -> String {
+    if options.redact {
+        return "<redacted>".to_string();
+    }
+    if text {
+        return format_text_param(bytes, options.max_param_len);
+    }
+    match bytes.len() {
+        4 => decode_binary_value(23, bytes),
+        8 => decode_binary_value(20, bytes),
+        _ => None,
+    }
+    .unwrap_or_else(|| hex_preview(bytes, options.max_display_bytes))
+}
+
+/// Quote a text parameter, truncating long values and noting their full length
+fn format_text_param(bytes: &[u8], max_len: usize) -> String {
+    if bytes.len() <= max_len {
+        format!("'{}'", String::from_utf8_lossy(bytes))
+    } else {
+        format!(
+            "'{}...' ({} bytes)",
+            String::from_utf8_lossy(&bytes[..max_len]),
+            bytes.len()
+        )
+    }
+}
+
+/// Decode a binary-format value of a well-known type into its text form
+fn decode_binary_value(type_oid: u32, bytes: &[u8]) -> Option<String> {
+    match (type_oid, bytes.len()) {
+        (16, 1) => Some(if bytes[0] != 0 { "true" } else { "false" }.to_string()),
+        (21, 2) => Some(i16::from_be_bytes([bytes[0], bytes[1]]).to_string()),
+        (23, 4) => Some(i32::from_be_bytes(bytes.try_into().ok()?).to_string()),
+        (20, 8) => Some(i64::from_be_bytes(bytes.try_into().ok()?).to_string()),
+        (26, 4) => Some(u32::from_be_bytes(bytes.try_into().ok()?).to_string()),
+        (700, 4) => Some(f32::from_be_bytes(bytes.try_into().ok()?).to_string()),
+        (701, 8) => Some(f64::from_be_bytes(bytes.try_into().ok()?).to_string()),
+        // text, varchar, bpchar and name are sent as raw UTF-8 in binary too
+        (25 | 1043 | 1042 | 19, _) => std::str::from_utf8(bytes)
+            .ok()
+            .map(|text| format!("'{}'", text)),
+        (2950, 16) => Some(format_uuid(bytes)),
+        (1184, 8) => Some(format_timestamp(
+            i64::from_be_bytes(bytes.try_into().ok()?),
+            "+00",
+        )),
+        (1114, 8) => Some(format_timestamp(
+            i64::from_be_bytes(bytes.try_into().ok()?),
+            "",
+        )),
+        _ => None,
+    }
+}
+
+/// The text form of a binary array, e.g. `{{1,NULL},{3,4}}` or
+/// `[0:1]={a,b}` when a lower bound is not 1. None when the layout is
+/// malformed or an element type cannot be decoded.
+fn decode_binary_array(bytes: &[u8]) -> Option<String> {
+    let read_i32 = |at: usize| -> Option<i32> {
+        Some(i32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+    };
+    let ndim = usize::try_from(read_i32(0)?).ok()?;
+    // Flags (bit 0: has NULLs) are not needed to decode
+    let element_oid = read_i32(8)? as u32;
+    if ndim == 0 {
+        return Some("{}".to_string());
+    }
+    if ndim > 6 {
+        return None;
+    }
+    // Size and lower bound of each dimension
+    let mut dims = Vec::with_capacity(ndim);
+    for d in 0..ndim {
+        let size = usize::try_from(read_i32(12 + d * 8)?).ok()?;
+        dims.push((size, read_i32(16 + d * 8)?));
+    }
+
+    let mut elements = Vec::new();
+    let mut at = 12 + ndim * 8;
+    let count: usize = dims.iter().map(|(size, _)| size).product();
+    for _ in 0..count {
+        let length = read_i32(at)?;
+        at += 4;
+        if length < 0 {
+            elements.push("NULL".to_string());
+            continue;
+        }
+        let element = bytes.get(at..at + length as usize)?;
+        at += length as usize;
+        elements.push(quote_array_element(&array_element_text(
+            element_oid,
+            element,
+        )?));
+    }
+    if at != bytes.len() {
+        return None;
+    }
+
+    let mut text = String::new();
+    if dims.iter().any(|(_, lower)| *lower != 1) {
+        for (size, lower) in &dims {
+            text.push_str(&format!("[{}:{}]", lower, lower + *size as i32 - 1));
+        }
+        text.push('=');
+    }
+    let sizes: Vec<usize> = dims.iter().map(|(size, _)| *size).collect();
+    write_array_dimension(&sizes, &mut elements.into_iter(), &mut text);
+    Some(text)
+}
+
+fn write_array_dimension(
+    dims: &[usize],
+    elements: &mut impl Iterator<Item = String>,
+    text: &mut String,
+) {
+    text.push('{');
+    for index in 0..dims[0] {
+        if index > 0 {
+            text.push(',');
+        }
+        if dims.len() > 1 {
+            write_array_dimension(&dims[1..], elements, text);
+        } else if let Some(element) = elements.next() {
+            text.push_str(&element);
+        }
+    }
+    text.push('}');
+}
+
+/// Text of one binary array element, as PostgreSQL prints it inside an array
+fn array_element_text(element_oid: u32, bytes: &[u8]) -> Option<String> {
+    match element_oid {
+        16 => Some(if bytes.first()? != &0 { "t" } else { "f" }.to_string()),
+        25 | 1043 | 1042 | 19 | 18 => std::str::from_utf8(bytes).ok().map(str::to_string),
+        _ => decode_binary_value(element_oid, bytes),
+    }
+}
+
+/// Double-quote an element the way array_out does when it would otherwise
+/// be ambiguous
+fn quote_array_element(text: &str) -> String {
+    let needs_quotes = text.is_empty()
+        || text.eq_ignore_ascii_case("NULL")
+        || text
+            .chars()
+            .any(|c| matches!(c, '{' | '}' | ',' | '"' | '\\') || c.is_ascii_whitespace());
+    if !needs_quotes {
+        return text.to_string();
+    }
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Render a binary timestamp (microseconds since 2000-01-01 00:00:00 UTC)
+fn format_timestamp(micros: i64, zone: &str) -> String {
+    const POSTGRES_EPOCH_UNIX_SECONDS: i64 = 946_684_800;
+
+    match micros {
+        i64::MAX => return "infinity".to_string(),
+        i64::MIN => return "-infinity".to_string(),
+        _ => {}
+    }
+    let unix_seconds = POSTGRES_EPOCH_UNIX_SECONDS + micros.div_euclid(1_000_000);
+    let Ok(datetime) = time::OffsetDateTime::from_unix_timestamp(unix_seconds) else {
+        return format!("<timestamp out of range: {}>", micros);
+    };
+    let fraction = micros.rem_euclid(1_000_000);
+    let mut rendered = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        datetime.year(),
+        u8::from(datetime.month()),
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    );
+    if fraction != 0 {
+        rendered.push_str(format!(".{:06}", fraction).trim_end_matches('0'));
+    }
+    rendered.push_str(zone);
+    rendered
+}
+
+/// Space-separated hex of the first `max_bytes` bytes of a binary value
+fn hex_preview(bytes: &[u8], max_bytes: usize) -> String {
+    let hex = bytes
+        .iter()
+        .take(max_bytes)
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if bytes.len() > max_bytes {
+        format!("<binary: {} ...> ({} bytes)", hex, bytes.len())
+    } else {
+        format!("<binary: {}>", hex)
+    }
+}
+
+fn read_cstring(data: &[u8], index: &mut usize) -> Option<Vec<u8>> {
+    if *index >= data.len() {
+        return None;
+    }
+
+    let start = *index;
+    while *index < data.len() && data[*index] != 0 {
+        *index += 1;
+    }
+
+    if *index >= data.len() {
+        return None;
+    }
+
+    let value = data[start..*index].to_vec();
+    *index += 1; // Skip null terminator
+    Some(value)
+}
+
+fn format_identifier(bytes: &[u8]) -> String {
+    let name = String::from_utf8_lossy(bytes).to_string();
+    if name.is_empty() {
+        "(unnamed)".to_string()
+    } else {
+        name
+    }
+}
+
+fn describe_format_codes(label: &str, count: u16, codes: &[u16]) -> String {
+    match count {
+        0 => format!("{label}=text (all)"),
+        1 => {
+            let code = codes.first().copied().unwrap_or(0);
+            format!("{label}={} (all)", format_format(code))
+        }
+        _ => {
+            let formats = codes
+                .iter()
+                .map(|code| format_format(*code))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{label}=[{}]", formats)
+        }
+    }
+}
+
+fn format_format(code: u16) -> &'static str {
+    match code {
+        0 => "text",
+        1 => "binary",
+        _ => "unknown",
+    }
+}
+
+/// Parameter type OIDs of a ParameterDescription, or None when it is cut
+/// short
+fn decode_parameter_description(data: &[u8]) -> Option<Vec<u32>> {
+    let param_count = u16::from_be_bytes(data.get(..2)?.try_into().ok()?) as usize;
+    (0..param_count)
+        .map(|n| {
+            let start = 2 + n * 4;
+            Some(u32::from_be_bytes(
+                data.get(start..start + 4)?.try_into().ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Decode the newest supported minor version and the protocol options the
+/// server did not recognize
+fn decode_negotiate_protocol_version(data: &[u8]) -> Option<(u32, Vec<String>)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let minor = u32::from_be_bytes(data[0..4].try_into().ok()?);
+    let count = u32::from_be_bytes(data[4..8].try_into().ok()?);
+    let mut i = 8;
+    let mut options = Vec::new();
+    for _ in 0..count {
+        let option = read_cstring(data, &mut i)?;
+        options.push(String::from_utf8_lossy(&option).into_owned());
+    }
+    Some((minor, options))
+}
+
+/// Decode the sending backend's pid, the channel and the payload
+fn decode_notification(data: &[u8]) -> Option<(i32, String, String)> {
+    let pid = i32::from_be_bytes(data.get(0..4)?.try_into().ok()?);
+    let mut i = 4;
+    let channel = read_cstring(data, &mut i)?;
+    let payload = read_cstring(data, &mut i)?;
+    Some((
+        pid,
+        String::from_utf8_lossy(&channel).into_owned(),
+        String::from_utf8_lossy(&payload).into_owned(),
+    ))
+}
+
+/// Apply the LISTEN/UNLISTEN statements of a simple query to `channels`
+fn track_listen(query: &str, channels: &mut BTreeSet<String>) {
+    for statement in query.split(';') {
+        let mut words = statement.split_whitespace();
+        let (Some(command), Some(channel)) = (words.next(), words.next()) else {
+            continue;
+        };
+        if command.eq_ignore_ascii_case("listen") {
+            channels.insert(normalize_identifier(channel));
+        } else if command.eq_ignore_ascii_case("unlisten") {
+            if channel == "*" {
+                channels.clear();
+            } else {
+                channels.remove(&normalize_identifier(channel));
+            }
+        }
+    }
+}
+
+/// Fold an SQL identifier the way the server does: quoted names keep their
+/// case, unquoted ones are lowercased
+fn normalize_identifier(name: &str) -> String {
+    match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => name.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `f` with a subscriber that records every log message as a line
+    fn capture_logs<F: FnOnce()>(f: F) -> Vec<String> {
+        capture(f, false)
+    }
+
+    /// Like `capture_logs`, with each line prefixed by its level
+    fn capture_logs_with_level<F: FnOnce()>(f: F) -> Vec<String> {
+        capture(f, true)
+    }
+
+    fn capture<F: FnOnce()>(f: F, with_level: bool) -> Vec<String> {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .with_level(with_level)
+            .with_target(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let bytes = logs.0.lock().unwrap().clone();
+        String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    /// Frame a message body with its type byte and length prefix
+    fn frame(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![msg_type];
+        out.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn framer_reassembles_split_messages() {
+        let messages = [frame(b'D', b"abc"), frame(b'Z', b"I")].concat();
+        for split in 0..=messages.len() {
+            let mut framer = MessageFramer::default();
+            let mut framed = framer.push(&messages[..split]);
+            framed.extend(framer.push(&messages[split..]));
+            assert_eq!(framed, messages, "split at {split}");
+        }
+
+        let mut framer = MessageFramer::default();
+        assert!(framer.push(&messages[..7]).is_empty());
+        assert_eq!(framer.push(&messages[7..]), messages);
+    }
+
+    fn client(data: &[u8], state: &ClientState) -> Vec<String> {
+        capture_logs(|| {
+            parse_message(
+                data,
+                MessageDirection::ClientToServer,
+                "test",
+                None,
+                state,
+                false,
+            );
+        })
+    }
+
+    fn server(data: &[u8], state: &ClientState) -> Vec<String> {
+        capture_logs(|| {
+            parse_message(
+                data,
+                MessageDirection::ServerToClient,
+                "test",
+                None,
+                state,
+                false,
+            );
+        })
+    }
+
+    fn timed(
+        data: &[u8],
+        direction: MessageDirection,
+        state: &ClientState,
+        timing: &ConnectionTiming,
+    ) -> Vec<String> {
+        capture_logs(|| {
+            parse_message(data, direction, "test", Some(timing), state, false);
+        })
+    }
+
+    fn data_row(values: &[&[u8]]) -> Vec<u8> {
+        let mut body = (values.len() as u16).to_be_bytes().to_vec();
+        for value in values {
+            body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+            body.extend_from_slice(value);
+        }
+        body
+    }
+
+    #[test]
+    fn first_data_row_reports_time_since_execute() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let timing = ConnectionTiming::new();
+        let to_server =
+            |data: &[u8]| timed(data, MessageDirection::ClientToServer, &state, &timing);
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+
+        to_server(&frame(b'E', b"\0\0\0\0\0"));
+        let first = to_client(&frame(b'D', &data_row(&[b"1"])));
+        assert!(
+            first[0].starts_with("[test] ← DataRow (1 fields, 7 bytes, first row after "),
+            "{first:?}"
+        );
+        let second = to_client(&frame(b'D', &data_row(&[b"2"])));
+        assert_eq!(second[0], "[test] ← DataRow (1 fields, 7 bytes)");
+
+        let complete = to_client(&frame(b'C', b"SELECT 2\0"));
+        assert!(complete[0].starts_with("[test] ← CommandComplete: SELECT 2 (execute took "));
+        assert!(!complete[0].contains("no rows"));
+
+        // The next result set is timed again
+        to_server(&frame(b'E', b"\0\0\0\0\0"));
+        let first = to_client(&frame(b'D', &data_row(&[b"3"])));
+        assert!(first[0].contains("first row after "));
+    }
+
+    #[test]
+    fn pipelined_executes_are_timed_per_portal() {
+        let timing = ConnectionTiming::new();
+        timing.mark_execute("a", "select a".to_string(), Vec::new());
+        timing.mark_execute("", "select 1".to_string(), Vec::new());
+        timing.mark_execute("", "select 2".to_string(), Vec::new());
+
+        // Portal 'a' is suspended and resumed after the other Executes
+        assert_eq!(timing.finish_execute().unwrap().1.sql, "select a");
+        timing.mark_execute("a", "select a again".to_string(), Vec::new());
+        assert_eq!(timing.finish_execute().unwrap().1.sql, "select 1");
+        assert_eq!(timing.finish_execute().unwrap().1.sql, "select 2");
+        assert_eq!(timing.finish_execute().unwrap().1.sql, "select a again");
+        assert!(timing.finish_execute().is_none());
+    }
+
+    #[test]
+    fn portal_suspended_ends_the_execute_timer() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let timing = ConnectionTiming::new();
+        let to_server =
+            |data: &[u8]| timed(data, MessageDirection::ClientToServer, &state, &timing);
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+
+        to_server(&frame(
+            b'P',
+            b"s\0select n from generate_series(1, 3) n\0\0\0",
+        ));
+        to_server(&frame(b'B', b"p\0s\0\0\0\0\0\0\0"));
+        to_server(&frame(b'E', b"p\0\0\0\0\x02"));
+        to_client(&frame(b'D', b"\0\x01\0\0\0\x011"));
+        to_client(&frame(b'D', b"\0\x01\0\0\0\x012"));
+        let suspended = to_client(&frame(b's', b""));
+        assert!(
+            suspended[0].starts_with(
+                "[test] ← PortalSuspended (portal 'p' suspended after 2 rows, will resume; \
+                 execute took "
+            ),
+            "{suspended:?}"
+        );
+        let resumed = to_server(&frame(b'E', b"p\0\0\0\0\x02"));
+        assert!(
+            resumed[0].starts_with("[test] → Execute (6 bytes, resumes portal 'p'): "),
+            "{resumed:?}"
+        );
+        to_client(&frame(b'D', b"\0\x01\0\0\0\x013"));
+        let complete = to_client(&frame(b'C', b"SELECT 1\0"));
+        assert!(complete[0].contains("execute took "), "{complete:?}");
+        assert!(timing.finish_execute().is_none());
+    }
+
+    #[test]
+    fn empty_result_set_reports_no_rows() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let timing = ConnectionTiming::new();
+        let to_server =
+            |data: &[u8]| timed(data, MessageDirection::ClientToServer, &state, &timing);
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+
+        to_server(&frame(b'Q', b"select 1 where false\0"));
+        to_client(&frame(b'T', &[0, 0]));
+        let complete = to_client(&frame(b'C', b"SELECT 0\0"));
+        assert!(complete[0].ends_with(", no rows)"), "{complete:?}");
+
+        // Commands that never return rows are not flagged
+        to_server(&frame(b'Q', b"create table t ()\0"));
+        let complete = to_client(&frame(b'C', b"CREATE TABLE\0"));
+        assert!(!complete[0].contains("no rows"), "{complete:?}");
+    }
+
+    #[test]
+    fn command_complete_reports_rows_and_bytes() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        server(&frame(b'T', &[0, 0]), &state);
+        for value in [b"a", b"b", b"c"] {
+            server(&frame(b'D', &data_row(&[value])), &state);
+        }
+        let lines = server(&frame(b'C', b"SELECT 3\0"), &state);
+        assert_eq!(
+            lines,
+            vec!["[test] ← CommandComplete: SELECT 3 (3 rows, 21 B)"]
+        );
+
+        // Counters start over for the next result set
+        server(&frame(b'T', &[0, 0]), &state);
+        let lines = server(&frame(b'C', b"SELECT 0\0"), &state);
+        assert_eq!(lines, vec!["[test] ← CommandComplete: SELECT 0 (no rows)"]);
+    }
+
+    #[test]
+    fn row_count_mismatch_with_tag_warns() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        server(&frame(b'D', &data_row(&[b"a"])), &state);
+        let lines = server(&frame(b'C', b"SELECT 2\0"), &state);
+        assert_eq!(
+            lines,
+            vec![
+                "[test] ← CommandComplete: SELECT 2 (1 row, 7 B)",
+                "[test] CommandComplete reports 2 rows but 1 DataRows were seen",
+            ]
+        );
+    }
+
+    #[test]
+    fn row_counts_accumulate_across_portal_suspended() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        server(&frame(b'D', &data_row(&[b"a"])), &state);
+        server(&frame(b'D', &data_row(&[b"b"])), &state);
+        server(&frame(b's', b""), &state);
+        server(&frame(b'D', &data_row(&[b"c"])), &state);
+        // The tag only counts rows of the final Execute, so no warning
+        let lines = server(&frame(b'C', b"SELECT 1\0"), &state);
+        assert_eq!(
+            lines,
+            vec!["[test] ← CommandComplete: SELECT 1 (3 rows, 21 B)"]
+        );
+    }
+
+    #[test]
+    fn byte_sizes_are_human_readable() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3_250_586), "3.1 MiB");
+    }
+
+    #[test]
+    fn client_idle_time_is_logged_above_threshold() {
+        let options = ProtocolOptions {
+            idle_threshold: Duration::from_millis(20),
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let timing = ConnectionTiming::new();
+
+        timed(
+            &frame(b'Z', b"I"),
+            MessageDirection::ServerToClient,
+            &state,
+            &timing,
+        );
+        std::thread::sleep(Duration::from_millis(30));
+        let lines = timed(
+            &frame(b'Q', b"select 1\0"),
+            MessageDirection::ClientToServer,
+            &state,
+            &timing,
+        );
+        assert!(
+            lines[0].starts_with("[test] → Query: select 1 (idle 0.0"),
+            "{lines:?}"
+        );
+
+        // A quick follow-up stays under the threshold
+        timed(
+            &frame(b'Z', b"I"),
+            MessageDirection::ServerToClient,
+            &state,
+            &timing,
+        );
+        let lines = timed(
+            &frame(b'Q', b"select 2\0"),
+            MessageDirection::ClientToServer,
+            &state,
+            &timing,
+        );
+        assert_eq!(lines[0], "[test] → Query: select 2");
+
+        let (busy, idle) = timing.busy_and_idle();
+        assert!(idle >= Duration::from_millis(30));
+        assert!(busy < idle);
+    }
+
+    #[test]
+    fn only_the_first_message_of_a_request_counts_as_idle() {
+        let timing = ConnectionTiming::new();
+        timing.ready_for_query();
+        assert!(timing.request_started().is_some());
+        assert!(timing.request_started().is_none());
+        timing.ready_for_query();
+        assert!(timing.request_started().is_some());
+    }
+
+    #[test]
+    fn simple_query_timing_measures_once() {
+        let timing = ConnectionTiming::new();
+        timing.mark_simple_query("select 1".to_string());
+        assert_eq!(timing.finish_simple_query().unwrap().1.sql, "select 1");
+        assert!(timing.finish_simple_query().is_none());
+    }
+
+    #[test]
+    fn pipelined_parses_complete_in_order() {
+        let timing = ConnectionTiming::new();
+        for _ in 0..3 {
+            timing.mark_parse();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let durations: Vec<Duration> = (0..3).map(|_| timing.finish_parse().unwrap()).collect();
+        assert!(timing.finish_parse().is_none());
+        // The oldest Parse is completed first and has waited the longest
+        assert!(durations[0] > durations[1]);
+        assert!(durations[1] > durations[2]);
+    }
+
+    #[test]
+    fn ready_for_query_after_error_drops_pending_timings() {
+        let timing = ConnectionTiming::new();
+        timing.mark_parse();
+        timing.mark_bind();
+        timing.mark_execute("", String::new(), Vec::new());
+        timing.mark_execute("", String::new(), Vec::new());
+
+        // A ReadyForQuery without an error keeps pending work
+        timing.ready_for_query();
+        assert!(timing.finish_execute().is_some());
+
+        timing.record_error();
+        timing.ready_for_query();
+        assert!(timing.finish_parse().is_none());
+        assert!(timing.finish_bind().is_none());
+        assert!(timing.finish_execute().is_none());
+    }
+
+    #[test]
+    fn slowest_queries_keep_the_top_n() {
+        let mut slow = SlowQueries::new(2);
+        for (ms, sql) in [(30, "b"), (10, "a"), (50, "c"), (20, "d")] {
+            slow.record(Duration::from_millis(ms), sql.to_string());
+        }
+        assert_eq!(
+            slow.sorted(),
+            vec![
+                (Duration::from_millis(50), "c".to_string()),
+                (Duration::from_millis(30), "b".to_string()),
+            ]
+        );
+
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let timing = ConnectionTiming::new();
+        timed(
+            &frame(b'Q', b"select\n pg_sleep(0)\0"),
+            MessageDirection::ClientToServer,
+            &state,
+            &timing,
+        );
+        timed(
+            &frame(b'C', b"SELECT 1\0"),
+            MessageDirection::ServerToClient,
+            &state,
+            &timing,
+        );
+        let slowest = state.slowest_queries();
+        assert_eq!(slowest.len(), 1);
+        assert_eq!(slowest[0].1, "select pg_sleep(0)");
+    }
+
+    #[test]
+    fn format_duration_outputs_seconds() {
+        let dur = Duration::from_millis(1500);
+        assert_eq!(format_duration(dur), "1.500s");
+    }
+
+    #[test]
+    fn bind_message_reports_all_binary_result_format() {
+        let data = vec![
+            0, // portal ""
+            b'_', b'p', b'1', 0, // statement "_p1"
+            0, 0, // param format count = 0
+            0, 0, // param count = 0
+            0, 1, // result format count = 1
+            0, 1, // binary for all
+        ];
+
+        let summary = describe_bind_message(&decode_bind_message(&data).expect("bind parsed"));
+        assert!(
+            summary.contains("ResultFormats=binary (all)"),
+            "summary missing binary all: {summary}"
+        );
+        assert!(
+            summary.contains("ParamFormats=text (all)"),
+            "summary missing default param format: {summary}"
+        );
+    }
+
+    #[test]
+    fn bind_message_reports_per_column_formats() {
+        let data = vec![
+            0, // portal ""
+            b'_', b'p', b'1', 0, // statement "_p1"
+            0, 1, // param format count = 1
+            0, 1, // binary params
+            0, 0, // param count = 0
+            0, 2, // result format count = 2
+            0, 0, // column 1 text
+            0, 1, // column 2 binary
+        ];
+
+        let summary = describe_bind_message(&decode_bind_message(&data).expect("bind parsed"));
+        assert!(
+            summary.contains("ParamFormats=binary (all)"),
+            "summary missing binary params: {summary}"
+        );
+        assert!(
+            summary.contains("ResultFormats=[text, binary]"),
+            "summary missing per-column formats: {summary}"
+        );
+    }
+
+    #[test]
+    fn execute_line_shows_sql_from_parse() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"_p3\0select * from users\0\0\0"), &state);
+        let bind = client(&frame(b'B', b"\0_p3\0\0\0\0\0\0\0"), &state);
+        assert_eq!(bind[0], "[test] → Bind (11 bytes): select * from users");
+
+        let execute = client(&frame(b'E', b"\0\0\0\0\0"), &state);
+        assert_eq!(
+            execute,
+            vec!["[test] → Execute (5 bytes): select * from users"]
+        );
+    }
+
+    #[test]
+    fn named_portals_resolve_to_their_statements() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"s1\0select 1\0\0\0"), &state);
+        client(&frame(b'P', b"s2\0select 2\0\0\0"), &state);
+        client(&frame(b'B', b"p1\0s1\0\0\0\0\0\0\0"), &state);
+        client(&frame(b'B', b"p2\0s2\0\0\0\0\0\0\0"), &state);
+
+        let describe = client(&frame(b'D', b"Pp1\0"), &state);
+        assert_eq!(
+            describe,
+            vec!["[test] → Describe (portal 'p1', 4 bytes): select 1"]
+        );
+        let execute = client(&frame(b'E', b"p2\0\0\0\0\0"), &state);
+        assert_eq!(execute, vec!["[test] → Execute (7 bytes): select 2"]);
+        let execute = client(&frame(b'E', b"p1\0\0\0\0\0"), &state);
+        assert_eq!(execute, vec!["[test] → Execute (7 bytes): select 1"]);
+    }
+
+    #[test]
+    fn rebinding_a_portal_replaces_its_statement() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"s1\0select 1\0\0\0"), &state);
+        client(&frame(b'P', b"s2\0select 2\0\0\0"), &state);
+        client(&frame(b'B', b"p1\0s1\0\0\0\0\0\0\0"), &state);
+        client(&frame(b'C', b"Pp1\0"), &state);
+        client(&frame(b'B', b"p1\0s2\0\0\0\0\0\0\0"), &state);
+
+        let execute = client(&frame(b'E', b"p1\0\0\0\0\0"), &state);
+        assert_eq!(execute, vec!["[test] → Execute (7 bytes): select 2"]);
+    }
+
+    #[test]
+    fn unnamed_portal_lifecycle() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"s1\0select 1\0\0\0"), &state);
+        client(&frame(b'B', b"\0s1\0\0\0\0\0\0\0"), &state);
+        assert_eq!(
+            state.statements.lock().unwrap().portal_sql(""),
+            Some("select 1")
+        );
+
+        // A simple query destroys the unnamed portal
+        client(&frame(b'Q', b"select 3\0"), &state);
+        assert!(state.statements.lock().unwrap().portal_sql("").is_none());
+
+        // Portals opened inside a transaction survive until it ends
+        client(&frame(b'B', b"\0s1\0\0\0\0\0\0\0"), &state);
+        server(&frame(b'Z', b"T"), &state);
+        assert!(state.statements.lock().unwrap().portal_sql("").is_some());
+        server(&frame(b'Z', b"I"), &state);
+        assert!(state.statements.lock().unwrap().portal_sql("").is_none());
+    }
+
+    #[test]
+    fn close_statement_forgets_sql() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"_p3\0select 1\0\0\0"), &state);
+        client(&frame(b'C', b"S_p3\0"), &state);
+        assert!(state.statements.lock().unwrap().sql_for("_p3").is_none());
+
+        client(&frame(b'B', b"\0_p3\0\0\0\0\0\0\0"), &state);
+        let execute = client(&frame(b'E', b"\0\0\0\0\0"), &state);
+        assert_eq!(execute, vec!["[test] → Execute (5 bytes)"]);
+    }
+
+    /// The details line logged for a Parse body
+    fn parse_details(body: &[u8], max_query_chars: usize) -> Option<String> {
+        let Some(FrontendMessage::Parse {
+            name,
+            query,
+            param_oids,
+        }) = parse_frontend_message(b'P', body)
+        else {
+            panic!("not decoded as a Parse");
+        };
+        describe_parse(&name, &query, param_oids.as_deref(), max_query_chars)
+    }
+
+    #[test]
+    fn parse_param_types_are_listed() {
+        let parse_body = |oids: &[u32]| {
+            let mut body = b"s1\0select $1\0".to_vec();
+            body.extend_from_slice(&(oids.len() as u16).to_be_bytes());
+            for oid in oids {
+                body.extend_from_slice(&oid.to_be_bytes());
+            }
+            body
+        };
+
+        assert_eq!(
+            parse_details(&parse_body(&[]), 1000).unwrap(),
+            "Statement: 's1', Query: 'select $1'"
+        );
+        assert_eq!(
+            parse_details(&parse_body(&[23]), 1000).unwrap(),
+            "Statement: 's1', Query: 'select $1', ParamTypes=[int4]"
+        );
+        assert_eq!(
+            parse_details(&parse_body(&[23, 0, 25]), 1000).unwrap(),
+            "Statement: 's1', Query: 'select $1', ParamTypes=[int4, unspecified, text]"
+        );
+        assert_eq!(
+            decode_parse_param_types(&parse_body(&[1_000_000])),
+            Some(vec![1_000_000])
+        );
+
+        // The count promises two OIDs but the body ends after one
+        let mut truncated = parse_body(&[23, 25]);
+        truncated.truncate(truncated.len() - 4);
+        assert_eq!(
+            parse_details(&truncated, 1000).unwrap(),
+            "Statement: 's1', Query: 'select $1', ParamTypes=<truncated>"
+        );
+    }
+
+    #[test]
+    fn close_messages_are_decoded() {
+        assert_eq!(
+            decode_close_message(b"S_p3\0"),
+            Some((b'S', "_p3".to_string()))
+        );
+        assert_eq!(decode_close_message(b"P\0"), Some((b'P', String::new())));
+        assert_eq!(decode_close_message(b"S_p3"), None);
+        assert_eq!(decode_close_message(b""), None);
+
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        client(&frame(b'P', b"_p3\0select 1\0\0\0"), &state);
+        client(&frame(b'B', b"\0_p3\0\0\0\0\0\0\0"), &state);
+        assert_eq!(
+            client(&frame(b'C', b"P\0"), &state),
+            vec!["[test] → Close (portal '(unnamed)'): select 1"]
+        );
+        assert_eq!(
+            client(&frame(b'C', b"S_p3\0"), &state),
+            vec!["[test] → Close (statement '_p3'): select 1"]
+        );
+        assert_eq!(
+            client(&frame(b'C', b"S_p3"), &state),
+            vec!["[test] → Close (malformed, 4 bytes)"]
+        );
+        assert_eq!(
+            server(&frame(b'3', b""), &state),
+            vec!["[test] ← CloseComplete (portal '(unnamed)')"]
+        );
+        assert_eq!(
+            server(&frame(b'3', b""), &state),
+            vec!["[test] ← CloseComplete (statement '_p3')"]
+        );
+        assert_eq!(
+            server(&frame(b'3', b""), &state),
+            vec!["[test] ← CloseComplete"]
+        );
+    }
+
+    #[test]
+    fn ready_for_query_forgets_unnamed_statement() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"\0select 1\0\0\0"), &state);
+        client(&frame(b'P', b"named\0select 2\0\0\0"), &state);
+        server(&frame(b'Z', b"I"), &state);
+
+        let statements = state.statements.lock().unwrap();
+        assert!(statements.sql_for("").is_none());
+        assert_eq!(statements.sql_for("named"), Some("select 2"));
+    }
+
+    /// Bind body for the unnamed portal with the given format codes and values
+    fn bind_body(statement: &str, formats: &[u16], values: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut body = vec![0];
+        body.extend_from_slice(statement.as_bytes());
+        body.push(0);
+        body.extend_from_slice(&(formats.len() as u16).to_be_bytes());
+        for format in formats {
+            body.extend_from_slice(&format.to_be_bytes());
+        }
+        body.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        for value in values {
+            match value {
+                Some(bytes) => {
+                    body.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+                    body.extend_from_slice(bytes);
+                }
+                None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn audit_log_records_executes_and_errors() {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.jsonl", std::process::id()));
+        let audit = AuditLogger::open(&path).unwrap();
+        let options = ProtocolOptions {
+            audit: Some(audit.clone()),
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        state.record_startup(&encode_startup_message(&[
+            ("user".to_string(), "alice".to_string()),
+            ("application_name".to_string(), "psql".to_string()),
+        ]));
+        let timing = ConnectionTiming::new();
+        let to_server =
+            |data: &[u8]| timed(data, MessageDirection::ClientToServer, &state, &timing);
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+
+        to_server(&frame(b'P', b"\0select\n  $1\0\0\0"));
+        to_server(&frame(b'B', &bind_body("", &[], &[Some(b"42"), None])));
+        to_server(&frame(b'E', b"\0\0\0\0\0"));
+        to_client(&frame(b'D', &data_row(&[b"42"])));
+        to_client(&frame(b'C', b"SELECT 1\0"));
+        to_client(&frame(b'S', b"application_name\0batch\0"));
+        to_server(&frame(b'Q', b"select 1/0\0"));
+        to_client(&frame(b'E', b"SERROR\0C22012\0Mdivision by zero\0\0"));
+        drop(state);
+        audit.finish().await;
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].contains(
+            "\"database\":\"alice\",\"user\":\"alice\",\"application_name\":\"psql\",\
+             \"query_text\":\"select $1\",\"param_values\":[\"'42'\",null],\"rows_returned\":1,"
+        ));
+        assert!(lines[0].ends_with(",\"error_code\":null}"));
+        assert!(lines[1].contains(
+            "\"application_name\":\"batch\",\"query_text\":\"select 1/0\",\
+             \"param_values\":[],\"rows_returned\":null,"
+        ));
+        assert!(lines[1].ends_with(",\"error_code\":\"22012\"}"));
+    }
+
+    #[test]
+    fn buffered_table_rows_are_printed_when_the_result_set_is_cut_short() {
+        let options = ProtocolOptions {
+            table_mode: true,
+            table_buffer_max_rows: Some(10),
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let timing = ConnectionTiming::new();
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+        let row_description = encode_row_description(&[ColumnDescription {
+            name: "name".to_string(),
+            type_oid: 25,
+            type_size: -1,
+            type_modifier: -1,
+        }]);
+        let printed = |logs: &[String], value: &str| {
+            logs.iter()
+                .any(|line| line.contains('│') && line.contains(value))
+        };
+
+        // An error in the middle of a result set
+        to_client(&row_description);
+        assert!(to_client(&encode_data_row(&[Some("alice")])).is_empty());
+        to_client(&encode_data_row(&[Some("bob")]));
+        let logs = to_client(&frame(b'E', b"SERROR\0C22012\0Mdivision by zero\0\0"));
+        assert!(printed(&logs, "alice") && printed(&logs, "bob"), "{logs:?}");
+        assert!(logs.iter().any(|line| line.contains('└')), "{logs:?}");
+        to_client(&frame(b'Z', b"I"));
+
+        // A suspended portal prints its rows and streams the ones after it
+        to_client(&row_description);
+        to_client(&encode_data_row(&[Some("carol")]));
+        let logs = to_client(&frame(b's', b""));
+        assert!(printed(&logs, "carol"), "{logs:?}");
+        let logs = to_client(&encode_data_row(&[Some("dave")]));
+        assert!(printed(&logs, "dave"), "{logs:?}");
+        to_client(&frame(b'C', b"SELECT 1\0"));
+
+        // A new result set after one that never completed
+        to_client(&row_description);
+        to_client(&encode_data_row(&[Some("erin")]));
+        let logs = to_client(&row_description);
+        assert!(printed(&logs, "erin"), "{logs:?}");
+    }
+
+    #[tokio::test]
+    async fn events_dir_writes_one_json_line_per_message() {
+        use crate::json::{parse_json, JsonValue};
+
+        let dir = std::env::temp_dir().join(format!("events-dir-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let events_dir = EventsDir::open(&dir).unwrap();
+        let options = ProtocolOptions {
+            events: Some(events_dir.clone()),
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        state.record_startup(&encode_startup_message(&[(
+            "user".to_string(),
+            "alice".to_string(),
+        )]));
+        let timing = ConnectionTiming::new();
+        let to_server =
+            |data: &[u8]| timed(data, MessageDirection::ClientToServer, &state, &timing);
+        let to_client =
+            |data: &[u8]| timed(data, MessageDirection::ServerToClient, &state, &timing);
+
+        to_server(&frame(b'P', b"s1\0select $1::int\0\0\x01\0\0\0\x17"));
+        to_server(&frame(b'B', &bind_body("s1", &[], &[Some(b"7"), None])));
+        to_server(&frame(b'E', b"\0\0\0\0\0"));
+        to_server(&frame(b'S', b""));
+        to_client(&frame(b'1', b""));
+        to_client(&frame(b'2', b""));
+        to_client(&encode_row_description(&[ColumnDescription {
+            name: "int4".to_string(),
+            type_oid: 23,
+            type_size: 4,
+            type_modifier: -1,
+        }]));
+        to_client(&encode_data_row(&[Some("7")]));
+        to_client(&encode_data_row(&[None]));
+        to_client(&frame(b'C', b"SELECT 2\0"));
+        to_client(&frame(b'Z', b"I"));
+        to_server(&frame(b'Q', b"select 1/0\0"));
+        to_client(&frame(b'E', b"SERROR\0C22012\0Mdivision by zero\0\0"));
+        to_client(&frame(b'?', b"xyz"));
+        drop(state);
+        events_dir.finish().await;
+        let log = std::fs::read_to_string(dir.join("conn-1.jsonl")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let events: Vec<_> = log.lines().map(|line| parse_json(line).unwrap()).collect();
+        let text = |value: Option<&JsonValue>| match value {
+            Some(JsonValue::String(text)) => text.clone(),
+            Some(JsonValue::Number(number)) => number.clone(),
+            other => format!("{:?}", other),
+        };
+        let summary: Vec<String> = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "{} {} {}",
+                    text(event.get("direction")),
+                    text(event.get("sequence")),
+                    text(event.get("type"))
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "client 0 StartupMessage",
+                "client 1 Parse",
+                "client 2 Bind",
+                "client 3 Execute",
+                "client 4 Sync",
+                "server 1 ParseComplete",
+                "server 2 BindComplete",
+                "server 3 RowDescription",
+                "server 4 DataRow",
+                "server 5 DataRow",
+                "server 6 CommandComplete",
+                "server 7 ReadyForQuery",
+                "client 5 Query",
+                "server 8 ErrorResponse",
+                "server 9 unknown message",
+            ]
+        );
+        for event in &events {
+            let keys: Vec<&str> = match event {
+                JsonValue::Object(members) => members.iter().map(|(key, _)| key.as_str()).collect(),
+                other => panic!("not an object: {:?}", other),
+            };
+            assert_eq!(
+                keys[..6],
+                [
+                    "timestamp",
+                    "connection",
+                    "sequence",
+                    "direction",
+                    "type",
+                    "bytes"
+                ]
+            );
+            assert_eq!(text(event.get("connection")), "1");
+            assert!(text(event.get("timestamp")).ends_with('Z'));
+        }
+
+        assert_eq!(text(events[0].get("user")), "alice");
+        assert_eq!(text(events[1].get("statement")), "s1");
+        assert_eq!(text(events[1].get("sql")), "select $1::int");
+        assert_eq!(
+            events[1].get("param_types"),
+            Some(&JsonValue::Array(vec![JsonValue::Number("23".to_string())]))
+        );
+        assert_eq!(
+            events[2].get("params"),
+            Some(&JsonValue::Array(vec![
+                JsonValue::String("'7'".to_string()),
+                JsonValue::Null
+            ]))
+        );
+        assert_eq!(text(events[3].get("max_rows")), "0");
+        let column = match events[7].get("columns") {
+            Some(JsonValue::Array(columns)) => &columns[0],
+            other => panic!("no columns: {:?}", other),
+        };
+        assert_eq!(text(column.get("type")), "int4");
+        assert_eq!(text(column.get("type_oid")), "23");
+        assert_eq!(
+            events[9].get("values"),
+            Some(&JsonValue::Array(vec![JsonValue::Null]))
+        );
+        assert_eq!(text(events[10].get("tag")), "SELECT 2");
+        assert_eq!(text(events[10].get("rows")), "2");
+        assert_eq!(text(events[10].get("sql")), "select $1::int");
+        assert!(matches!(
+            events[10].get("duration_ms"),
+            Some(JsonValue::Number(_))
+        ));
+        assert_eq!(text(events[11].get("status")), "I");
+        assert_eq!(text(events[13].get("sqlstate")), "22012");
+        assert_eq!(text(events[13].get("severity")), "ERROR");
+        assert_eq!(text(events[13].get("sql")), "select 1/0");
+        assert!(events[13].get("duration_ms").is_some());
+        assert_eq!(text(events[14].get("type_byte")), "?");
+    }
+
+    #[test]
+    fn kill_after_messages_cuts_the_session_at_the_configured_message() {
+        let options = ProtocolOptions {
+            kill_switch: KillSwitch {
+                after_messages: Some(5),
+                ..KillSwitch::default()
+            },
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let parse = frame(b'P', b"\0select pg_sleep(1)\0\0\0");
+        let bind = frame(b'B', &bind_body("", &[], &[]));
+        let describe = frame(b'D', b"P\0");
+        let execute = frame(b'E', b"\0\0\0\0\0");
+        let sync = frame(b'S', b"");
+        let query = frame(b'Q', b"select 1\0");
+
+        let mut kill_point = None;
+        let logs = capture_logs(|| {
+            let first = [parse.clone(), bind.clone()].concat();
+            assert_eq!(
+                parse_message(
+                    &first,
+                    MessageDirection::ClientToServer,
+                    "test",
+                    None,
+                    &state,
+                    false
+                )
+                .kill_point,
+                None
+            );
+            // The fifth message arrives in the middle of a read
+            let second = [
+                describe.clone(),
+                execute.clone(),
+                sync.clone(),
+                query.clone(),
+            ]
+            .concat();
+            kill_point = parse_message(
+                &second,
+                MessageDirection::ClientToServer,
+                "test",
+                None,
+                &state,
+                false,
+            )
+            .kill_point;
+        });
+        assert_eq!(
+            kill_point,
+            Some(describe.len() + execute.len() + sync.len())
+        );
+        assert_eq!(
+            logs.last().unwrap(),
+            "[test] Fault injected: closing both connections after client message #5"
+        );
+        assert!(
+            !logs.iter().any(|line| line.contains("select 1")),
+            "{logs:?}"
+        );
+    }
+
+    #[test]
+    fn matching_statements_are_replaced_before_reaching_the_server() {
+        let options = ProtocolOptions {
+            error_rules: vec![crate::fault::parse_error_rule("(?i)^delete").unwrap()],
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let forward = |data: &[u8]| {
+            let mut plan = ForwardPlan::default();
+            let logs = capture_logs(|| {
+                plan = parse_message(
+                    data,
+                    MessageDirection::ClientToServer,
+                    "test",
+                    None,
+                    &state,
+                    false,
+                );
+            });
+            (plan.apply(data), logs)
+        };
+
+        let (forwarded, logs) = forward(&frame(b'Q', b"DELETE FROM accounts\0"));
+        assert!(forwarded.is_empty());
+        assert!(logs.iter().any(|line| line
+            == "[test] Fault injected: Query not forwarded, the client gets ErrorResponse \
+                57014 'canceling statement due to user request' instead"));
+
+        // The error and its ReadyForQuery follow the startup ReadyForQuery
+        let error =
+            encode_error_response("ERROR", "57014", "canceling statement due to user request");
+        let replies = state.local_replies();
+        let idle = encode_ready_for_query(b'I');
+        assert_eq!(
+            replies.interleave(&idle),
+            vec![idle.clone(), [error.clone(), idle.clone()].concat()]
+        );
+
+        // Parse, Bind, Describe and Sync still go through; the error goes
+        // in front of the server's ReadyForQuery, which then reports the
+        // failed transaction block
+        let parse = frame(b'P', b"\0delete from accounts where id = $1\0\0\0");
+        let bind = frame(b'B', &bind_body("", &[], &[Some(b"7")]));
+        let describe = frame(b'D', b"P\0");
+        let execute = frame(b'E', b"\0\0\0\0\0");
+        let sync = frame(b'S', b"");
+        let (forwarded, _) = forward(
+            &[
+                parse.clone(),
+                bind.clone(),
+                describe.clone(),
+                execute,
+                frame(b'E', b"\0\0\0\0\0"),
+                sync.clone(),
+            ]
+            .concat(),
+        );
+        assert_eq!(forwarded, [parse, bind, describe, sync].concat());
+        let responses = [frame(b'1', b""), frame(b'2', b""), frame(b'n', b"")].concat();
+        assert_eq!(
+            replies.interleave(&[responses.clone(), encode_ready_for_query(b'T')].concat()),
+            vec![responses, error, encode_ready_for_query(b'E')]
+        );
+
+        // The server's transaction block is still usable, so statements
+        // get the error of a failed transaction up to its end, and COMMIT
+        // rolls back as it would on the server
+        let select = frame(b'Q', b"select 1\0");
+        let (forwarded, logs) = forward(&select);
+        assert!(forwarded.is_empty());
+        assert!(logs.iter().any(|line| {
+            line
+            == "[test] Query not forwarded, the proxy failed the transaction: ErrorResponse 25P02"
+        }));
+        let aborted = encode_error_response(
+            "ERROR",
+            "25P02",
+            "current transaction is aborted, commands ignored until end of transaction block",
+        );
+        assert_eq!(
+            replies.take_due(),
+            [aborted, encode_ready_for_query(b'E')].concat()
+        );
+        let parse = frame(b'P', b"s1\0COMMIT\0\0\0");
+        assert_eq!(forward(&parse).0, frame(b'P', b"s1\0ROLLBACK\0\0\0"));
+        assert_eq!(forward(&frame(b'S', b"")).0, frame(b'S', b""));
+        replies.interleave(&encode_ready_for_query(b'I'));
+        assert_eq!(forward(&select).0, select);
+    }
+
+    #[test]
+    fn blocked_statements_never_reach_the_server() {
+        let options = ProtocolOptions {
+            error_rules: vec![
+                crate::fault::parse_error_rule("^select pg_sleep").unwrap(),
+                crate::fault::parse_block_rule(r"(?i)drop\s+table").unwrap(),
+            ],
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let forward = |data: &[u8]| {
+            let mut plan = ForwardPlan::default();
+            let logs = capture_logs_with_level(|| {
+                plan = parse_message(
+                    data,
+                    MessageDirection::ClientToServer,
+                    "test",
+                    None,
+                    &state,
+                    false,
                 );
-                if let Some(params) = parse_parameter_description(data) {
-                    for (i, param) in params.iter().enumerate() {
-                        info!("[{}]    Param {}: {}", client_addr, i + 1, param);
-                    }
-                }
-            } else {
-                info!("[{}] {} ParameterDescription", client_addr, arrow);
-            }
-        }
-        'I' => {
-            // EmptyQueryResponse
-            info!("[{}] {} EmptyQueryResponse", client_addr, arrow);
-        }
-        'd' => {
-            // CopyData
-            info!(
-                "[{}] {} CopyData ({} bytes)",
-                client_addr,
-                arrow,
-                data.len()
+            });
+            (plan.apply(data), logs)
+        };
+
+        // Simple protocol: nothing is forwarded, the proxy answers the
+        // Query after the startup ReadyForQuery
+        let (forwarded, logs) = forward(&frame(b'Q', b"DROP TABLE accounts\0"));
+        assert!(forwarded.is_empty());
+        assert!(logs.contains(
+            &"WARN [test] Blocked Query matching /(?i)drop\\s+table/: not forwarded, \
+              the client gets ErrorResponse 42501 'blocked by postgres-wire-proxy policy' instead"
+                .to_string()
+        ));
+
+        let error =
+            encode_error_response("ERROR", "42501", "blocked by postgres-wire-proxy policy");
+        let replies = state.local_replies();
+        let idle = encode_ready_for_query(b'I');
+        assert_eq!(
+            replies.interleave(&idle),
+            vec![idle.clone(), [error.clone(), idle.clone()].concat()]
+        );
+
+        // Extended protocol: the whole group is withheld and its Sync
+        // answered locally; the next group flows normally
+        let parse = frame(b'P', b"s1\0drop table accounts\0\0\0");
+        let bind = frame(b'B', &bind_body("s1", &[], &[Some(b"7")]));
+        let describe = frame(b'D', b"P\0");
+        let execute = frame(b'E', b"\0\0\0\0\0");
+        let sync = frame(b'S', b"");
+        let (forwarded, logs) = forward(
+            &[
+                parse,
+                bind.clone(),
+                describe.clone(),
+                execute.clone(),
+                sync.clone(),
+            ]
+            .concat(),
+        );
+        assert!(forwarded.is_empty());
+        assert!(logs.contains(
+            &"INFO [test] Bind not forwarded, part of a failed extended query".to_string()
+        ));
+        assert_eq!(replies.take_due(), [error, idle].concat());
+
+        let parse = frame(b'P', b"\0select $1\0\0\0");
+        let bind = frame(b'B', &bind_body("", &[], &[Some(b"7")]));
+        let next_group = [parse, bind, describe, execute, sync].concat();
+        assert_eq!(forward(&next_group).0, next_group);
+
+        // --error-on-query rules still let Parse through
+        let parse = frame(b'P', b"\0select pg_sleep(1)\0\0\0");
+        assert_eq!(forward(&parse).0, parse);
+    }
+
+    #[test]
+    fn rewritten_sql_is_reframed_before_reaching_the_server() {
+        let options = ProtocolOptions {
+            query_rewrites: vec![
+                crate::rewrite::parse_query_rewrite(r"\borders\b=>orders_shadow").unwrap(),
+            ],
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        let forward = |data: &[u8]| {
+            let mut plan = ForwardPlan::default();
+            let logs = capture_logs(|| {
+                plan = parse_message(
+                    data,
+                    MessageDirection::ClientToServer,
+                    "test",
+                    None,
+                    &state,
+                    false,
+                );
+            });
+            (plan.apply(data), logs)
+        };
+
+        let (forwarded, logs) = forward(&frame(b'Q', b"SELECT * FROM orders\0"));
+        assert_eq!(forwarded, frame(b'Q', b"SELECT * FROM orders_shadow\0"));
+        assert!(logs.iter().any(|line| {
+            line
+            == "[test] Rewrote Query SQL: 'SELECT * FROM orders' -> 'SELECT * FROM orders_shadow'"
+        }));
+
+        // Parse is rewritten in place; the Bind value that mentions the
+        // table and the messages around it are forwarded byte for byte
+        let parse = frame(b'P', b"s1\0SELECT * FROM orders WHERE note = $1\0\0\0");
+        let bind = frame(b'B', &bind_body("s1", &[], &[Some(b"orders")]));
+        let sync = frame(b'S', b"");
+        let (forwarded, _) = forward(&[parse, bind.clone(), sync.clone()].concat());
+        let rewritten_parse = frame(
+            b'P',
+            b"s1\0SELECT * FROM orders_shadow WHERE note = $1\0\0\0",
+        );
+        assert_eq!(forwarded, [rewritten_parse, bind, sync].concat());
+
+        // A rewrite past the length limit leaves the message untouched
+        let logs = capture_logs_with_level(|| {
+            assert_eq!(
+                rewritten_query('Q', b"SELECT * FROM orders\0", "test", &state, 16),
+                None
             );
-        }
-        'c' => {
-            // CopyDone
-            info!("[{}] {} CopyDone", client_addr, arrow);
-        }
-        'G' => {
-            // CopyInResponse
-            info!("[{}] {} CopyInResponse", client_addr, arrow);
-        }
-        'H' => {
-            // CopyOutResponse
-            info!("[{}] {} CopyOutResponse", client_addr, arrow);
-        }
-        'W' => {
-            // CopyBothResponse
-            info!("[{}] {} CopyBothResponse", client_addr, arrow);
-        }
-        _ => {
-            info!(
-                "[{}] {} Unknown message type '{}' ({} bytes)",
-                client_addr,
-                arrow,
-                msg_type,
-                data.len()
+        });
+        assert_eq!(
+            logs,
+            vec![
+                "WARN [test] Rewrite of Query not applied: the message would be 32 bytes \
+                  (limit 16), forwarding the original"
+            ]
+        );
+    }
+
+    #[test]
+    fn canned_queries_are_answered_without_reaching_the_server() {
+        let path = std::env::temp_dir().join(format!("respond-test-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"command_tag": "SET"}"#).unwrap();
+        let rule = format!("^set extra_float_digits=>{}", path.display());
+        let options = ProtocolOptions {
+            canned_responses: vec![crate::canned::parse_canned_response(&rule).unwrap()],
+            ..ProtocolOptions::default()
+        };
+        std::fs::remove_file(&path).unwrap();
+        let state = ClientState::new(options, false);
+
+        let set = frame(b'Q', b"set extra_float_digits = 3\0");
+        let select = frame(b'Q', b"SELECT 1\0");
+        let data = [select.clone(), set, select.clone()].concat();
+        let mut plan = ForwardPlan::default();
+        let logs = capture_logs(|| {
+            plan = parse_message(
+                &data,
+                MessageDirection::ClientToServer,
+                "test",
+                None,
+                &state,
+                false,
             );
-        }
+        });
+        assert_eq!(plan.apply(&data), [select.clone(), select].concat());
+        assert!(logs.iter().any(|line| {
+            line == &format!(
+                "[test] Query answered locally from {} (0 rows, 'SET'), not forwarded",
+                path.display()
+            )
+        }));
+
+        // The answer waits for the startup ReadyForQuery and the first
+        // SELECT's, and goes before the second SELECT's
+        let ready = encode_ready_for_query(b'I');
+        let complete = encode_command_complete("SELECT 1");
+        let server = [ready.clone(), complete.clone(), ready.clone()].concat();
+        assert_eq!(
+            state.local_replies().interleave(&server),
+            vec![
+                server.clone(),
+                [encode_command_complete("SET"), ready.clone()].concat()
+            ]
+        );
+        assert_eq!(
+            state
+                .local_replies()
+                .interleave(&[complete.clone(), ready.clone()].concat()),
+            vec![[complete, ready].concat()]
+        );
     }
-}
 
-fn parse_cstring_pair(data: &[u8]) -> Option<(String, String)> {
-    let mut parts = data.split(|&b| b == 0);
-    let name = parts.next()?.to_vec();
-    let value = parts.next()?.to_vec();
+    #[test]
+    fn messages_before_the_sync_that_ends_an_error_are_reported() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let send = |direction: MessageDirection, data: &[u8]| {
+            capture_logs_with_level(|| {
+                parse_message(data, direction, "test", None, &state, false);
+            })
+            .into_iter()
+            .filter(|line| line.starts_with("WARN"))
+            .collect::<Vec<_>>()
+        };
+        let parse = frame(b'P', b"\0SELECT * FROM missing\0\0\0");
+        let bind = frame(b'B', &bind_body("", &[], &[]));
+        let execute = frame(b'E', b"\0\0\0\0\0");
+        let error = encode_error_response("ERROR", "42P01", "relation \"missing\" does not exist");
 
-    Some((
-        String::from_utf8_lossy(&name).to_string(),
-        String::from_utf8_lossy(&value).to_string(),
-    ))
-}
+        // The client flushed instead of syncing, then carried on after the
+        // error as if the pipeline were still running
+        send(
+            MessageDirection::ClientToServer,
+            &[parse.clone(), frame(b'H', b"")].concat(),
+        );
+        send(MessageDirection::ServerToClient, &error);
+        assert_eq!(
+            send(
+                MessageDirection::ClientToServer,
+                &[bind.clone(), execute.clone()].concat()
+            ),
+            vec![
+                "WARN [test] Bind sent after an ErrorResponse without a Sync: the server \
+                 discards it until the client sends Sync",
+                "WARN [test] Execute sent after an ErrorResponse without a Sync: the server \
+                 discards it until the client sends Sync",
+            ]
+        );
+        assert!(send(
+            MessageDirection::ClientToServer,
+            &[frame(b'S', b""), parse.clone()].concat()
+        )
+        .is_empty());
 
-fn parse_error_response(data: &[u8]) -> Option<String> {
-    let mut result = String::new();
-    let mut i = 0;
+        // A Sync already on its way covers the error
+        send(MessageDirection::ServerToClient, &frame(b'Z', b"I"));
+        send(
+            MessageDirection::ClientToServer,
+            &[bind.clone(), execute.clone(), frame(b'S', b"")].concat(),
+        );
+        send(MessageDirection::ServerToClient, &error);
+        assert!(send(MessageDirection::ClientToServer, &[parse, bind].concat()).is_empty());
+    }
 
-    while i < data.len() {
-        let field_type = data[i] as char;
-        if field_type == '\0' {
-            break;
-        }
+    #[test]
+    fn application_name_follows_parameter_status() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        state.record_startup(&encode_startup_message(&[(
+            "user".to_string(),
+            "alice".to_string(),
+        )]));
+        assert_eq!(state.application_name(), None);
 
-        i += 1;
-        let mut field_value = Vec::new();
-        while i < data.len() && data[i] != 0 {
-            field_value.push(data[i]);
-            i += 1;
+        let logs = server(&frame(b'S', b"application_name\0\0"), &state);
+        assert!(
+            !logs.iter().any(|line| line.contains("changed")),
+            "{logs:?}"
+        );
+        assert_eq!(state.application_name().as_deref(), Some(""));
+        let logs = server(&frame(b'S', b"application_name\0billing-worker\0"), &state);
+        assert!(logs
+            .iter()
+            .any(|line| line.contains("application_name changed: '' -> 'billing-worker'")));
+        assert_eq!(state.application_name().as_deref(), Some("billing-worker"));
+    }
+
+    /// ParameterDescription body listing the given type OIDs
+    fn parameter_description(oids: &[u32]) -> Vec<u8> {
+        let mut body = (oids.len() as u16).to_be_bytes().to_vec();
+        for oid in oids {
+            body.extend_from_slice(&oid.to_be_bytes());
         }
-        i += 1; // Skip null terminator
+        body
+    }
 
-        let value = String::from_utf8_lossy(&field_value);
-
-        let field_name = match field_type {
-            'S' => "Severity",
-            'V' => "Severity",
-            'C' => "Code",
-            'M' => "Message",
-            'D' => "Detail",
-            'H' => "Hint",
-            'P' => "Position",
-            'p' => "Internal position",
-            'q' => "Internal query",
-            'W' => "Where",
-            's' => "Schema",
-            't' => "Table",
-            'c' => "Column",
-            'd' => "Data type",
-            'n' => "Constraint",
-            'F' => "File",
-            'L' => "Line",
-            'R' => "Routine",
-            _ => "Unknown",
-        };
+    #[test]
+    fn parameter_descriptions_follow_describe_order() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
 
-        if !result.is_empty() {
-            result.push_str(", ");
-        }
-        result.push_str(&format!("{}: {}", field_name, value));
+        client(&frame(b'P', b"a\0select $1\0\0\0"), &state);
+        client(&frame(b'P', b"b\0select $1\0\0\0"), &state);
+        client(&frame(b'D', b"Sa\0"), &state);
+        client(&frame(b'D', b"Pportal\0"), &state);
+        client(&frame(b'D', b"Sb\0"), &state);
+        client(&frame(b'S', b""), &state);
+        server(&frame(b't', &parameter_description(&[23])), &state);
+        server(&frame(b't', &parameter_description(&[16])), &state);
+        server(&frame(b'Z', b"I"), &state);
+
+        let statements = state.statements.lock().unwrap();
+        assert_eq!(statements.param_types_for("a"), &[23]);
+        assert_eq!(statements.param_types_for("b"), &[16]);
     }
 
-    if result.is_empty() {
-        None
-    } else {
-        Some(result)
+    #[test]
+    fn describes_skipped_after_an_error_are_discarded() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"a\0select $1\0\0\0"), &state);
+        client(&frame(b'P', b"b\0select $1\0\0\0"), &state);
+        // The first batch fails before its Describe is answered
+        client(&frame(b'D', b"Smissing\0"), &state);
+        client(&frame(b'S', b""), &state);
+        client(&frame(b'D', b"Sb\0"), &state);
+        client(&frame(b'S', b""), &state);
+        server(&frame(b'Z', b"I"), &state);
+        server(&frame(b't', &parameter_description(&[20])), &state);
+        server(&frame(b'Z', b"I"), &state);
+
+        let statements = state.statements.lock().unwrap();
+        assert_eq!(statements.param_types_for("a"), &[] as &[u32]);
+        assert_eq!(statements.param_types_for("b"), &[20]);
     }
-}
 
-fn parse_parse_message(data: &[u8]) -> Option<String> {
-    let mut i = 0;
+    #[test]
+    fn binary_values_of_common_types_are_decoded() {
+        assert_eq!(decode_binary_value(16, &[1]).unwrap(), "true");
+        assert_eq!(
+            decode_binary_value(20, &(-7i64).to_be_bytes()).unwrap(),
+            "-7"
+        );
+        assert_eq!(
+            decode_binary_value(701, &1.5f64.to_be_bytes()).unwrap(),
+            "1.5"
+        );
+        assert_eq!(decode_binary_value(25, b"hello").unwrap(), "'hello'");
+        assert_eq!(
+            decode_binary_value(
+                2950,
+                &[
+                    0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14,
+                    0x17, 0x40, 0x00
+                ]
+            )
+            .unwrap(),
+            "123e4567-e89b-12d3-a456-426614174000"
+        );
+        // 2024-01-02 03:04:05.5 UTC
+        let micros = 757_479_845_500_000i64;
+        assert_eq!(
+            decode_binary_value(1184, &micros.to_be_bytes()).unwrap(),
+            "2024-01-02 03:04:05.5+00"
+        );
+        assert_eq!(
+            decode_binary_value(1184, &i64::MAX.to_be_bytes()).unwrap(),
+            "infinity"
+        );
+        assert_eq!(decode_binary_value(1700, &[0, 1]), None);
+    }
 
-    // Statement name
-    let mut stmt_name = Vec::new();
-    while i < data.len() && data[i] != 0 {
-        stmt_name.push(data[i]);
-        i += 1;
+    #[test]
+    fn bind_falls_back_to_hex_for_undescribed_binary_parameters() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"s1\0select $1, $2\0\0\0"), &state);
+        client(&frame(b'D', b"Ss1\0"), &state);
+        server(&frame(b't', &parameter_description(&[2950, 1700])), &state);
+
+        let mut uuid = [0u8; 16];
+        uuid[15] = 1;
+        let bind = bind_body("s1", &[1], &[Some(&uuid), Some(&[0xca, 0xfe])]);
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(
+            lines.last().unwrap(),
+            "[test]    $1=00000000-0000-0000-0000-000000000001, $2=<binary: ca fe>"
+        );
     }
-    i += 1; // Skip null terminator
 
-    // Query string
-    let mut query = Vec::new();
-    while i < data.len() && data[i] != 0 {
-        query.push(data[i]);
-        i += 1;
+    #[test]
+    fn bind_logs_parameter_values_using_described_types() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+
+        client(&frame(b'P', b"s1\0select $1, $2, $3\0\0\0"), &state);
+        client(&frame(b'D', b"Ss1\0"), &state);
+        server(&frame(b't', &parameter_description(&[25, 23, 20])), &state);
+
+        let bind = bind_body(
+            "s1",
+            &[0, 1, 1],
+            &[Some(b"abc"), Some(&42i32.to_be_bytes()), None],
+        );
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(lines.last().unwrap(), "[test]    $1='abc', $2=42, $3=NULL");
     }
 
-    let stmt_name_str = String::from_utf8_lossy(&stmt_name);
-    let query_str = String::from_utf8_lossy(&query);
+    #[test]
+    fn bind_truncates_long_text_parameters() {
+        let options = ProtocolOptions {
+            max_param_len: 4,
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
 
-    if stmt_name_str.is_empty() && query_str.is_empty() {
-        None
-    } else {
-        Some(format!(
-            "Statement: '{}', Query: '{}'",
-            if stmt_name_str.is_empty() {
-                "(unnamed)"
-            } else {
-                &stmt_name_str
-            },
-            query_str
-        ))
+        let bind = bind_body(
+            "",
+            &[0, 1, 0],
+            &[Some(b"abcdefgh"), Some(&[0u8; 8]), Some(b"abcd")],
+        );
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(
+            lines.last().unwrap(),
+            "[test]    $1='abcd...' (8 bytes), $2=<binary: 00 00 00 00 00 00 00 00>, $3='abcd'"
+        );
     }
-}
 
-struct RowDescriptionField {
-    field_info: FieldInfo,
-    description: String,
-}
+    #[test]
+    fn bind_with_single_format_code_applies_to_all_parameters() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
 
-fn parse_row_description(data: &[u8]) -> Option<Vec<RowDescriptionField>> {
-    if data.len() < 2 {
-        return None;
+        let bind = bind_body("", &[0], &[Some(b"x"), None, Some(b"y")]);
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(lines.last().unwrap(), "[test]    $1='x', $2=NULL, $3='y'");
     }
 
-    let field_count = u16::from_be_bytes([data[0], data[1]]) as usize;
-    let mut fields = Vec::new();
-    let mut i = 2;
+    #[test]
+    fn bind_parameters_can_be_redacted() {
+        let options = ProtocolOptions {
+            redact: true,
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
 
-    for _ in 0..field_count {
-        // Field name (null-terminated string)
-        let mut field_name = Vec::new();
-        while i < data.len() && data[i] != 0 {
-            field_name.push(data[i]);
-            i += 1;
-        }
-        i += 1; // Skip null terminator
+        let bind = bind_body("", &[0, 1], &[Some(b"hunter2"), None]);
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(lines.last().unwrap(), "[test]    $1=<redacted>, $2=NULL");
 
-        if i + 18 > data.len() {
-            break;
-        }
+        let bind = bind_body("", &[1], &[Some(&[0u8; 8])]);
+        let lines = client(&frame(b'B', &bind), &state);
+        assert_eq!(lines.last().unwrap(), "[test]    $1=<redacted>");
+    }
 
-        // Table OID (4 bytes)
-        let _table_oid = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
+    #[test]
+    fn sequence_numbers_count_each_direction_separately() {
+        let options = ProtocolOptions {
+            sequence_numbers: true,
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
 
-        // Column attribute number (2 bytes)
-        let _col_attr = u16::from_be_bytes([data[i], data[i + 1]]);
-        i += 2;
+        let mut batch = frame(b'S', b"");
+        batch.extend_from_slice(&frame(b'S', b""));
+        assert_eq!(
+            client(&batch, &state),
+            vec!["[test] → #1 Sync", "[test] → #2 Sync"]
+        );
+        assert_eq!(
+            server(&frame(b'1', b""), &state),
+            vec!["[test] ← #1 ParseComplete"]
+        );
+        assert_eq!(
+            client(&frame(b'X', b""), &state),
+            vec!["[test] → #3 Terminate"]
+        );
+    }
 
-        // Type OID (4 bytes)
-        let type_oid = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
+    #[test]
+    fn negotiate_protocol_version_is_decoded() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let mut body = Vec::new();
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(b"_pq_.foo\0_pq_.bar\0");
 
-        // Type size (2 bytes, signed)
-        let type_size = i16::from_be_bytes([data[i], data[i + 1]]);
-        i += 2;
+        let lines = server(&frame(b'v', &body), &state);
+        assert_eq!(
+            lines,
+            vec![
+                "[test] ← NegotiateProtocolVersion: server supports up to 3.2",
+                "[test]    unrecognized options: _pq_.foo, _pq_.bar",
+            ]
+        );
+    }
 
-        // Type modifier (4 bytes, signed)
-        let type_mod = i32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
+    #[test]
+    fn notifications_are_decoded_and_matched_to_listen() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        client(&frame(b'Q', b"LISTEN MyChan; listen \"Other\"\0"), &state);
 
-        // Format code (2 bytes)
-        let format_code = u16::from_be_bytes([data[i], data[i + 1]]);
-        i += 2;
+        let notification = |channel: &str| {
+            let mut body = 4242i32.to_be_bytes().to_vec();
+            body.extend_from_slice(channel.as_bytes());
+            body.extend_from_slice(b"\0hello\0");
+            frame(b'A', &body)
+        };
+        assert_eq!(
+            server(&notification("mychan"), &state),
+            vec!["[test] ← Notification: channel=mychan payload=hello (pid 4242)"]
+        );
+        assert_eq!(
+            server(&notification("Other"), &state),
+            vec!["[test] ← Notification: channel=Other payload=hello (pid 4242)"]
+        );
+
+        client(&frame(b'Q', b"UNLISTEN mychan\0"), &state);
+        assert_eq!(
+            server(&notification("mychan"), &state),
+            vec!["[test] ← Notification: channel=mychan payload=hello (pid 4242) (no LISTEN seen for this channel)"]
+        );
+        client(&frame(b'Q', b"UNLISTEN *\0"), &state);
+        assert!(state.listening.lock().unwrap().is_empty());
+    }
+
+    fn command_complete(tag: &str) -> Vec<u8> {
+        frame(b'C', format!("{tag}\0").as_bytes())
+    }
+
+    #[test]
+    fn explicit_transaction_is_summarized() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        client(&frame(b'Q', b"BEGIN\0"), &state);
+        server(&command_complete("BEGIN"), &state);
+        server(&frame(b'Z', b"T"), &state);
+        client(&frame(b'Q', b"update t set x = 1\0"), &state);
+        server(&command_complete("UPDATE 3"), &state);
+        server(&frame(b'Z', b"T"), &state);
+        client(&frame(b'Q', b"COMMIT\0"), &state);
+        server(&command_complete("COMMIT"), &state);
+        let lines = server(&frame(b'Z', b"I"), &state);
+        assert!(
+            lines[1].starts_with("[test] Transaction committed (3 statements, 3 rows, "),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn aborted_transaction_reports_rollback_after_error() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        client(&frame(b'Q', b"BEGIN\0"), &state);
+        server(&command_complete("BEGIN"), &state);
+        server(&frame(b'Z', b"T"), &state);
+        client(&frame(b'Q', b"select 1/0\0"), &state);
+        server(
+            &encode_error_response("ERROR", "22012", "division by zero")[..],
+            &state,
+        );
+        server(&frame(b'Z', b"E"), &state);
+        client(&frame(b'Q', b"COMMIT\0"), &state);
+        server(&command_complete("ROLLBACK"), &state);
+        let lines = server(&frame(b'Z', b"I"), &state);
+        assert!(
+            lines[1]
+                .starts_with("[test] Transaction rolled back after error (2 statements, 0 rows, "),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn autocommit_statements_are_summarized_above_threshold() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        client(&frame(b'Q', b"select 1\0"), &state);
+        server(&command_complete("SELECT 1"), &state);
+        assert_eq!(server(&frame(b'Z', b"I"), &state).len(), 1);
 
-        let format_str = match format_code {
-            0 => "text",
-            1 => "binary",
-            _ => "unknown",
+        let options = ProtocolOptions {
+            autocommit_threshold: Duration::ZERO,
+            ..ProtocolOptions::default()
         };
+        let state = ClientState::new(options, false);
+        client(&frame(b'Q', b"insert into t values (1), (2)\0"), &state);
+        server(&command_complete("INSERT 0 2"), &state);
+        let lines = server(&frame(b'Z', b"I"), &state);
+        assert!(
+            lines[1].starts_with("[test] Implicit transaction committed (1 statement, 2 rows, "),
+            "{lines:?}"
+        );
+
+        client(&frame(b'Q', b"select 1/0\0"), &state);
+        server(
+            &encode_error_response("ERROR", "22012", "division by zero")[..],
+            &state,
+        );
+        let lines = server(&frame(b'Z', b"I"), &state);
+        assert!(
+            lines[1].starts_with("[test] Implicit transaction failed (0 statements, 0 rows, "),
+            "{lines:?}"
+        );
+    }
 
-        let type_name = get_pg_type_name(type_oid);
-        let name_str = String::from_utf8_lossy(&field_name).to_string();
+    #[test]
+    fn errors_are_logged_at_their_severity_with_sqlstate() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let mut body = b"SERROR\0VERROR\0C22012\0Mdivision by zero\0".to_vec();
+        body.extend_from_slice(b"Fint.c\0L842\0\0");
+        let lines = capture_logs_with_level(|| {
+            parse_message(
+                &frame(b'E', &body),
+                MessageDirection::ServerToClient,
+                "test",
+                None,
+                &state,
+                false,
+            );
+        });
+        assert_eq!(
+            lines,
+            vec![
+                "WARN [test] ← ErrorResponse: ERROR sqlstate=22012: division by zero",
+                "WARN [test]    File: int.c, Line: 842",
+            ]
+        );
 
-        let description = format!(
-            "name='{}', type={} (OID={}), size={}, typemod={}, format={}",
-            name_str, type_name, type_oid, type_size, type_mod, format_str
+        let fatal = encode_error_response("FATAL", "57P01", "terminating connection");
+        let lines = capture_logs_with_level(|| {
+            parse_message(
+                &fatal,
+                MessageDirection::ServerToClient,
+                "test",
+                None,
+                &state,
+                false,
+            );
+        });
+        assert_eq!(
+            lines,
+            vec!["ERROR [test] ← ErrorResponse: FATAL sqlstate=57P01: terminating connection"]
         );
 
-        fields.push(RowDescriptionField {
-            field_info: FieldInfo {
-                name: name_str,
-                type_name: type_name.to_string(),
-            },
-            description,
+        let notice = frame(b'N', b"SWARNUNG\0VWARNING\0C01000\0Mcareful\0\0");
+        let lines = capture_logs_with_level(|| {
+            parse_message(
+                &notice,
+                MessageDirection::ServerToClient,
+                "test",
+                None,
+                &state,
+                false,
+            );
         });
+        assert_eq!(
+            lines,
+            vec!["WARN [test] ← NoticeResponse: WARNING sqlstate=01000: careful"]
+        );
     }
 
-    if fields.is_empty() {
-        None
-    } else {
-        Some(fields)
+    #[test]
+    fn protocol_violations_are_logged_when_validating() {
+        let options = ProtocolOptions {
+            validate_protocol: true,
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        server(&frame(b'Z', b"I"), &state);
+        client(&frame(b'Q', b"select 1\0"), &state);
+        let lines = server(&frame(b'D', &data_row(&[b"1"])), &state);
+        assert_eq!(
+            lines.last().unwrap(),
+            "[test] Protocol violation: DataRow in state 'simple query, 2 response(s) outstanding', \
+             expected RowDescription, CommandComplete or EmptyQueryResponse"
+        );
     }
-}
 
-fn parse_data_row(data: &[u8]) -> Option<Vec<String>> {
-    if data.len() < 2 {
-        return None;
+    fn startup(parameters: &[(&str, &str)]) -> Vec<u8> {
+        encode_startup_message(
+            &parameters
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        )
     }
 
-    let field_count = u16::from_be_bytes([data[0], data[1]]) as usize;
-    let mut values = Vec::new();
-    let mut i = 2;
+    #[test]
+    fn startup_message_database_and_user_are_rewritten() {
+        let message = startup(&[
+            ("user", "alice"),
+            ("database", "prod"),
+            ("application_name", "psql"),
+        ]);
+        let (rewritten, rewrites) =
+            rewrite_startup_message(&message, Some("staging"), Some("bob")).unwrap();
+        assert_eq!(
+            rewritten,
+            startup(&[
+                ("user", "bob"),
+                ("database", "staging"),
+                ("application_name", "psql")
+            ])
+        );
+        assert_eq!(
+            u32::from_be_bytes(rewritten[..4].try_into().unwrap()) as usize,
+            rewritten.len()
+        );
+        assert_eq!(
+            rewrites[0],
+            StartupRewrite {
+                parameter: "database",
+                original: Some("prod".to_string()),
+                rewritten: "staging".to_string(),
+            }
+        );
+        assert_eq!(rewrites[1].original.as_deref(), Some("alice"));
 
-    for _ in 0..field_count {
-        if i + 4 > data.len() {
-            break;
-        }
+        // Without a database parameter the original user's database is kept
+        let message = startup(&[("user", "alice")]);
+        let (rewritten, _) = rewrite_startup_message(&message, None, Some("bob")).unwrap();
+        assert_eq!(
+            rewritten,
+            startup(&[("user", "bob"), ("database", "alice")])
+        );
 
-        // Field length (4 bytes, -1 = NULL)
-        let length = i32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
+        // CancelRequest and incomplete messages are left alone
+        let cancel = [0, 0, 0, 16, 4, 210, 22, 46, 0, 0, 0, 1, 0, 0, 0, 2];
+        assert!(rewrite_startup_message(&cancel, Some("x"), None).is_none());
+        assert!(rewrite_startup_message(&message[..10], Some("x"), None).is_none());
+        assert!(rewrite_startup_message(&message, None, None).is_none());
+    }
 
-        if length == -1 {
-            values.push("NULL".to_string());
-        } else if length >= 0 {
-            let length = length as usize;
-            if i + length > data.len() {
-                break;
-            }
+    #[test]
+    fn startup_allowlists_refuse_other_users_and_databases() {
+        let allowed = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let message = startup(&[("user", "alice"), ("database", "prod")]);
+        assert_eq!(startup_allowlist_violation(&message, &[], &[]), None);
+        assert_eq!(
+            startup_allowlist_violation(
+                &message,
+                &allowed(&["prod", "staging"]),
+                &allowed(&["alice"])
+            ),
+            None
+        );
+        assert_eq!(
+            startup_allowlist_violation(&message, &allowed(&["staging"]), &[]).as_deref(),
+            Some("database \"prod\" is not allowed by the proxy")
+        );
+        assert_eq!(
+            startup_allowlist_violation(&message, &[], &allowed(&["bob"])).as_deref(),
+            Some("user \"alice\" is not allowed by the proxy")
+        );
 
-            let value_bytes = &data[i..i + length];
-            i += length;
+        // Without a database parameter the user's name is checked as the database
+        let message = startup(&[("user", "alice")]);
+        assert_eq!(
+            startup_allowlist_violation(&message, &allowed(&["alice"]), &[]),
+            None
+        );
+        assert!(startup_allowlist_violation(&message, &allowed(&["prod"]), &[]).is_some());
+        assert!(startup_allowlist_violation(&message[..10], &[], &allowed(&["alice"])).is_some());
+    }
 
-            // Try to display as UTF-8 string, otherwise show hex
-            match std::str::from_utf8(value_bytes) {
-                Ok(s) => {
-                    // Truncate long values
-                    if s.len() > 100 {
-                        values.push(format!("'{}...' ({} bytes)", &s[..100], s.len()));
-                    } else {
-                        values.push(format!("'{}'", s));
-                    }
-                }
-                Err(_) => {
-                    // Binary data, show hex
-                    let hex: String = value_bytes
-                        .iter()
-                        .take(32) // Show first 32 bytes max
-                        .map(|b| format!("{:02x}", b))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    if value_bytes.len() > 32 {
-                        values.push(format!(
-                            "<binary: {} ...> ({} bytes)",
-                            hex,
-                            value_bytes.len()
-                        ));
-                    } else {
-                        values.push(format!("<binary: {}>", hex));
-                    }
+    #[test]
+    fn text_copy_rows_span_copy_data_messages() {
+        let options = ProtocolOptions {
+            copy_preview_lines: 2,
+            ..ProtocolOptions::default()
+        };
+        let state = ClientState::new(options, false);
+        server(&frame(b'G', &[0, 0, 2, 0, 0, 0, 0]), &state);
+
+        assert_eq!(
+            client(&frame(b'd', b"1\tone\n2\tt"), &state),
+            vec![
+                "[test] → CopyData (9 bytes, 1 row)",
+                "[test]    Row 1: '1\\tone'"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'd', b"wo\n3\tthree\n4\tfour\n5"), &state),
+            vec![
+                "[test] → CopyData (19 bytes, 3 rows)",
+                "[test]    Row 2: '2\\ttwo'",
+                "[test]    Row 3: '3\\tthree'"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'd', b"\tfive"), &state),
+            vec!["[test] → CopyData (5 bytes, 0 rows)"]
+        );
+        assert_eq!(
+            client(&frame(b'c', b""), &state),
+            vec!["[test] → CopyDone (5 rows, 33 B copied)"]
+        );
+    }
+
+    /// A binary COPY of (1, 'one') and (NULL, 'two') with its trailer
+    fn binary_copy_stream() -> Vec<u8> {
+        let mut stream = PGCOPY_SIGNATURE.to_vec();
+        stream.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        stream.extend_from_slice(&[0, 2, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 3]);
+        stream.extend_from_slice(b"one");
+        stream.extend_from_slice(&[0, 2, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 3]);
+        stream.extend_from_slice(b"two");
+        stream.extend_from_slice(&[0xff, 0xff]);
+        stream
+    }
+
+    #[test]
+    fn binary_copy_tuples_are_decoded_across_copy_data_messages() {
+        let stream = binary_copy_stream();
+        let expected = vec![
+            CopyTuple {
+                fields: 2,
+                bytes: 17,
+            },
+            CopyTuple {
+                fields: 2,
+                bytes: 13,
+            },
+        ];
+        for split in 0..=stream.len() {
+            for second in split..=stream.len() {
+                let mut decoder = BinaryCopyDecoder::default();
+                let mut tuples = Vec::new();
+                for chunk in [&stream[..split], &stream[split..second], &stream[second..]] {
+                    let (decoded, error) = decoder.feed(chunk);
+                    assert_eq!(error, None, "split at {split} and {second}");
+                    tuples.extend(decoded);
                 }
+                assert_eq!(tuples, expected, "split at {split} and {second}");
+                assert_eq!(decoder.stage, BinaryCopyStage::Trailer);
             }
         }
-    }
 
-    if values.is_empty() {
-        None
-    } else {
-        Some(values)
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let mut response = vec![1, 0, 2];
+        response.extend_from_slice(&[0, 1, 0, 1]);
+        server(&frame(b'G', &response), &state);
+        // Split inside the first field length and inside the second tuple's data
+        assert_eq!(
+            client(&frame(b'd', &stream[..23]), &state),
+            vec!["[test] → CopyData (23 bytes, binary, 0 tuples)"]
+        );
+        assert_eq!(
+            client(&frame(b'd', &stream[23..47]), &state),
+            vec![
+                "[test] → CopyData (24 bytes, binary, 1 tuple)",
+                "[test]    Tuple 1: 2 fields, 17 bytes"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'd', &stream[47..]), &state),
+            vec![
+                "[test] → CopyData (4 bytes, binary, 1 tuple)",
+                "[test]    Tuple 2: 2 fields, 13 bytes",
+                "[test]    Trailer (2 tuples in total)"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'c', b""), &state),
+            vec!["[test] → CopyDone (2 tuples, 51 B copied)"]
+        );
     }
-}
 
-fn get_pg_type_name(oid: u32) -> &'static str {
-    match oid {
-        16 => "bool",
-        17 => "bytea",
-        18 => "char",
-        19 => "name",
-        20 => "int8",
-        21 => "int2",
-        23 => "int4",
-        25 => "text",
-        26 => "oid",
-        114 => "json",
-        142 => "xml",
-        700 => "float4",
-        701 => "float8",
-        1000 => "bool[]",
-        1001 => "bytea[]",
-        1002 => "char[]",
-        1003 => "name[]",
-        1005 => "int2[]",
-        1007 => "int4[]",
-        1009 => "text[]",
-        1014 => "char[]",
-        1015 => "varchar[]",
-        1016 => "int8[]",
-        1021 => "float4[]",
-        1022 => "float8[]",
-        1042 => "bpchar",
-        1043 => "varchar",
-        1082 => "date",
-        1083 => "time",
-        1114 => "timestamp",
-        1184 => "timestamptz",
-        1186 => "interval",
-        1266 => "timetz",
-        1560 => "bit",
-        1562 => "varbit",
-        1700 => "numeric",
-        2950 => "uuid",
-        3802 => "jsonb",
-        _ => "unknown",
+    #[test]
+    fn corrupt_binary_copy_is_reported_once() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        server(&frame(b'G', &[1, 0, 0]), &state);
+        assert_eq!(
+            client(&frame(b'd', b"1\tone\n2\ttwo\n"), &state),
+            vec![
+                "[test] → CopyData (12 bytes, binary, 0 tuples)",
+                "[test] Malformed binary COPY data (missing PGCOPY signature), \
+                 no longer decoding this COPY"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'd', &binary_copy_stream()), &state),
+            vec!["[test] → CopyData (51 bytes, binary)"]
+        );
+        assert_eq!(
+            client(&frame(b'c', b""), &state),
+            vec!["[test] → CopyDone (63 B copied)"]
+        );
     }
-}
 
-fn parse_bind_message(data: &[u8]) -> Option<String> {
-    let mut i = 0;
+    #[test]
+    fn binary_copy_header_flags_follow_the_copy_file_format() {
+        let with_flags = |flags: u32| {
+            let mut stream = binary_copy_stream();
+            stream[11..15].copy_from_slice(&flags.to_be_bytes());
+            BinaryCopyDecoder::default().feed(&stream)
+        };
 
-    let portal_name = read_cstring(data, &mut i)?;
-    let stmt_name = read_cstring(data, &mut i)?;
+        // Unknown critical bits (17-31) stop decoding
+        for flags in [0x0002_0000, 0x8000_0000] {
+            let (tuples, error) = with_flags(flags);
+            assert!(tuples.is_empty());
+            assert_eq!(error, Some(format!("unknown header flags 0x{:08x}", flags)));
+        }
 
-    if i + 2 > data.len() {
-        return None;
+        // Unknown backwards-compatible bits (0-15) are ignored
+        let (tuples, error) = with_flags(0x0000_8001);
+        assert_eq!((tuples.len(), error), (2, None));
+
+        // Bit 16 is known: each tuple then starts with an OID
+        let mut header = PGCOPY_SIGNATURE.to_vec();
+        header.extend_from_slice(&PGCOPY_WITH_OIDS.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes());
+        let mut decoder = BinaryCopyDecoder::default();
+        assert_eq!(decoder.feed(&header).1, None);
+        assert!(decoder.with_oids);
     }
 
-    // Parameter format codes
-    let param_format_count = u16::from_be_bytes([data[i], data[i + 1]]);
-    i += 2;
-    let mut param_formats = Vec::new();
-    for _ in 0..param_format_count {
-        if i + 2 > data.len() {
-            return None;
-        }
-        param_formats.push(u16::from_be_bytes([data[i], data[i + 1]]));
-        i += 2;
+    fn xlog_data(start: u64, wal_end: u64, sent: i64, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![b'w'];
+        data.extend_from_slice(&start.to_be_bytes());
+        data.extend_from_slice(&wal_end.to_be_bytes());
+        data.extend_from_slice(&sent.to_be_bytes());
+        data.extend_from_slice(payload);
+        data
     }
 
-    if i + 2 > data.len() {
-        return None;
+    #[test]
+    fn xlog_data_is_decoded_in_copy_both() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        server(&frame(b'W', &[0, 0, 0]), &state);
+
+        let data = xlog_data(0x1_0000_0028, 0x1_0000_0100, 1_000_000, b"BEGIN 735");
+        assert_eq!(
+            server(&frame(b'd', &data), &state),
+            vec![
+                "[test] ← XLogData (start 1/00000028, server WAL end 1/00000100, \
+                 sent 2000-01-01 00:00:01+00, 9 bytes)",
+                "[test]    Payload: 'BEGIN 735'"
+            ]
+        );
+        let data = xlog_data(0x16B_3748, 0x16B_3748, 0, &[0xd1, 0x00, 0x07]);
+        assert_eq!(
+            server(&frame(b'd', &data), &state),
+            vec![
+                "[test] ← XLogData (start 0/016B3748, server WAL end 0/016B3748, \
+                 sent 2000-01-01 00:00:00+00, 3 bytes)",
+                "[test]    Payload: <binary: d1 00 07>"
+            ]
+        );
+        assert_eq!(
+            client(&frame(b'c', b""), &state),
+            vec!["[test] → CopyDone (2 replication messages, 62 B copied)"]
+        );
     }
 
-    // Parameter count
-    let param_count = u16::from_be_bytes([data[i], data[i + 1]]);
-    i += 2;
+    #[test]
+    fn keepalives_and_standby_status_updates_are_decoded() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        server(&frame(b'W', &[0, 0, 0]), &state);
 
-    // Skip parameter values
-    for _ in 0..param_count {
-        if i + 4 > data.len() {
-            return None;
-        }
-        let value_len = i32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
+        let mut keepalive = vec![b'k'];
+        keepalive.extend_from_slice(&0x16B_3748u64.to_be_bytes());
+        keepalive.extend_from_slice(&0i64.to_be_bytes());
+        keepalive.push(1);
+        assert_eq!(
+            server(&frame(b'd', &keepalive), &state),
+            vec![
+                "[test] ← Primary keepalive (server WAL end 0/016B3748, \
+                 sent 2000-01-01 00:00:00+00, reply requested)"
+            ]
+        );
 
-        if value_len < 0 {
-            continue;
+        let mut status = vec![b'r'];
+        for lsn in [0x16B_3748u64, 0x16B_3700, 0] {
+            status.extend_from_slice(&lsn.to_be_bytes());
         }
+        status.extend_from_slice(&60_000_000i64.to_be_bytes());
+        status.push(0);
+        assert_eq!(
+            client(&frame(b'd', &status), &state),
+            vec![
+                "[test] → Standby status update (write 0/016B3748, flush 0/016B3700, \
+                 apply 0/00000000, sent 2000-01-01 00:01:00+00)"
+            ]
+        );
 
-        let value_len = value_len as usize;
-        if i + value_len > data.len() {
-            return None;
-        }
-        i += value_len;
+        // Anything else is still logged as COPY data
+        assert_eq!(
+            client(&frame(b'd', b"r\n"), &state),
+            vec!["[test] → CopyData (2 bytes, 1 row)", "[test]    Row 1: 'r'"]
+        );
     }
 
-    if i + 2 > data.len() {
-        return None;
+    #[test]
+    fn replication_startup_parameter_enables_decoding() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        state.record_startup(&encode_startup_message(&[
+            ("user".to_string(), "replicator".to_string()),
+            ("replication".to_string(), "database".to_string()),
+        ]));
+        server(&frame(b'H', &[0, 0, 0]), &state);
+        let data = xlog_data(0x28, 0x28, 0, b"");
+        assert_eq!(
+            server(&frame(b'd', &data), &state),
+            vec![
+                "[test] ← XLogData (start 0/00000028, server WAL end 0/00000028, \
+                 sent 2000-01-01 00:00:00+00, 0 bytes)"
+            ]
+        );
     }
 
-    // Result format codes
-    let result_format_count = u16::from_be_bytes([data[i], data[i + 1]]);
-    i += 2;
-    let mut result_formats = Vec::new();
-    for _ in 0..result_format_count {
-        if i + 2 > data.len() {
-            return None;
-        }
-        result_formats.push(u16::from_be_bytes([data[i], data[i + 1]]));
-        i += 2;
-    }
+    #[test]
+    fn copy_responses_set_the_copy_data_format() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        assert_eq!(
+            server(&frame(b'H', &[0, 0, 2, 0, 0, 0, 0]), &state),
+            vec!["[test] ← CopyOutResponse (text, 2 columns, formats=[text x2])"]
+        );
+        assert_eq!(
+            server(&frame(b'd', b"1\tone\n"), &state),
+            vec![
+                "[test] ← CopyData (6 bytes, 1 row)",
+                "[test]    Row 1: '1\\tone'"
+            ]
+        );
+        server(&frame(b'c', b""), &state);
 
-    let portal_str = format_identifier(&portal_name);
-    let stmt_str = format_identifier(&stmt_name);
-    let param_formats_desc =
-        describe_format_codes("ParamFormats", param_format_count, &param_formats);
-    let result_formats_desc =
-        describe_format_codes("ResultFormats", result_format_count, &result_formats);
+        let mut binary = vec![1, 0, 5];
+        for _ in 0..5 {
+            binary.extend_from_slice(&[0, 1]);
+        }
+        assert_eq!(
+            server(&frame(b'G', &binary), &state)[0],
+            "[test] ← CopyInResponse (binary, 5 columns, formats=[binary x5])"
+        );
+        assert_eq!(
+            client(&frame(b'd', b"PGCOPY\n\xff\r\n\0"), &state),
+            vec!["[test] → CopyData (11 bytes, binary, 0 tuples)"]
+        );
 
-    Some(format!(
-        "Portal='{}', Statement='{}', Parameters={}, {}, {}",
-        portal_str, stmt_str, param_count, param_formats_desc, result_formats_desc
-    ))
-}
+        assert_eq!(
+            client(&frame(b'c', b""), &state),
+            vec!["[test] → CopyDone (0 tuples, 11 B copied)"]
+        );
 
-fn read_cstring(data: &[u8], index: &mut usize) -> Option<Vec<u8>> {
-    if *index >= data.len() {
-        return None;
+        assert_eq!(
+            summarize_format_codes(&[0, 0, 1, 0]),
+            "text x2, binary, text"
+        );
+        assert!(decode_copy_response(&[0, 0, 2, 0, 0]).is_none());
     }
 
-    let start = *index;
-    while *index < data.len() && data[*index] != 0 {
-        *index += 1;
-    }
+    #[test]
+    fn function_call_and_response_are_decoded() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        // lowrite(fd 0, 6 bytes of data), binary arguments and result
+        let mut body = 955u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0, 1, 0, 1, 0, 2]);
+        body.extend_from_slice(&4i32.to_be_bytes());
+        body.extend_from_slice(&0i32.to_be_bytes());
+        body.extend_from_slice(&6i32.to_be_bytes());
+        body.extend_from_slice(b"\x00\x01abcd");
+        body.extend_from_slice(&[0, 1]);
+        assert_eq!(
+            client(&frame(b'F', &body), &state),
+            vec![
+                "[test] → FunctionCall: lowrite (OID 955), 2 args (4 B, 6 B), result format binary",
+                "[test]    $1=0, $2=<binary: 00 01 61 62 63 64>",
+            ]
+        );
 
-    if *index >= data.len() {
-        return None;
+        let mut result = 4i32.to_be_bytes().to_vec();
+        result.extend_from_slice(&6i32.to_be_bytes());
+        assert_eq!(
+            server(&frame(b'V', &result), &state),
+            vec!["[test] ← FunctionCallResponse (lowrite): 4 B, 6"]
+        );
     }
 
-    let value = data[start..*index].to_vec();
-    *index += 1; // Skip null terminator
-    Some(value)
-}
-
-fn format_identifier(bytes: &[u8]) -> String {
-    let name = String::from_utf8_lossy(bytes).to_string();
-    if name.is_empty() {
-        "(unnamed)".to_string()
-    } else {
-        name
+    #[test]
+    fn function_call_with_null_result() {
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let mut body = 12345u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0, 0, 0, 1]);
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            client(&frame(b'F', &body), &state),
+            vec![
+                "[test] → FunctionCall: function (OID 12345), 1 args (NULL), result format text",
+                "[test]    $1=NULL",
+            ]
+        );
+        assert_eq!(
+            server(&frame(b'V', &(-1i32).to_be_bytes()), &state),
+            vec!["[test] ← FunctionCallResponse (OID 12345): NULL"]
+        );
     }
-}
 
-fn describe_format_codes(label: &str, count: u16, codes: &[u16]) -> String {
-    match count {
-        0 => format!("{label}=text (all)"),
-        1 => {
-            let code = codes.get(0).copied().unwrap_or(0);
-            format!("{label}={} (all)", format_format(code))
-        }
-        _ => {
-            let formats = codes
-                .iter()
-                .map(|code| format_format(*code))
-                .collect::<Vec<_>>()
-                .join(", ");
-            format!("{label}=[{}]", formats)
-        }
+    #[test]
+    fn encoded_error_response_round_trips() {
+        let message = encode_error_response("FATAL", "08P01", "SSL required");
+        assert_eq!(message[0], b'E');
+        let length = u32::from_be_bytes(message[1..5].try_into().unwrap()) as usize;
+        assert_eq!(length, message.len() - 1);
+        assert_eq!(
+            parse_error_response(&message[5..]).unwrap().to_string(),
+            "Severity: FATAL, Severity: FATAL, Code: 08P01, Message: SSL required"
+        );
     }
-}
 
-fn format_format(code: u16) -> &'static str {
-    match code {
-        0 => "text",
-        1 => "binary",
-        _ => "unknown",
-    }
-}
+    #[test]
+    fn cleartext_password_request_warns_without_tls() {
+        let request = frame(b'R', &3u32.to_be_bytes());
 
-fn parse_parameter_description(data: &[u8]) -> Option<Vec<String>> {
-    if data.len() < 2 {
-        return None;
+        let plain = ClientState::new(ProtocolOptions::default(), false);
+        let lines = server(&request, &plain);
+        assert_eq!(
+            lines,
+            vec![
+                "[test] ← Authentication: AuthenticationCleartextPassword",
+                "[test] Cleartext password sent over unencrypted connection",
+            ]
+        );
+
+        let encrypted = ClientState::new(ProtocolOptions::default(), true);
+        assert_eq!(server(&request, &encrypted).len(), 1);
     }
 
-    let param_count = u16::from_be_bytes([data[0], data[1]]) as usize;
-    let mut params = Vec::new();
-    let mut i = 2;
+    #[test]
+    fn md5_password_request_warns_without_tls() {
+        let mut body = 5u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&[1, 2, 3, 4]);
+        let state = ClientState::new(ProtocolOptions::default(), false);
+        let lines = server(&frame(b'R', &body), &state);
+        assert_eq!(
+            lines[1],
+            "[test] MD5 password hash sent over unencrypted connection"
+        );
+    }
 
-    for _ in 0..param_count {
-        if i + 4 > data.len() {
-            break;
+    #[test]
+    fn statement_tracker_is_bounded() {
+        let mut tracker = StatementTracker::default();
+        for i in 0..MAX_TRACKED_STATEMENTS + 10 {
+            tracker.record_parse(&format!("s{i}"), "select 1");
         }
-
-        // Parameter type OID (4 bytes)
-        let type_oid = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-        i += 4;
-
-        let type_name = get_pg_type_name(type_oid);
-        params.push(format!("type={} (OID={})", type_name, type_oid));
+        assert_eq!(tracker.statements.len(), MAX_TRACKED_STATEMENTS);
+        assert!(tracker.sql_for("s0").is_none());
+        assert!(tracker
+            .sql_for(&format!("s{}", MAX_TRACKED_STATEMENTS + 9))
+            .is_some());
     }
 
-    if params.is_empty() {
-        None
-    } else {
-        Some(params)
+    #[test]
+    fn long_queries_are_truncated_in_the_log() {
+        assert_eq!(truncate_query("select 1", 8), "select 1");
+        assert_eq!(
+            truncate_query("select 'héllo'", 9),
+            "select 'h... [truncated, 14 chars total]"
+        );
+        assert_eq!(truncate_query("select 'héllo'", 0), "select 'héllo'");
+        let body = b"s1\0select 12345\0\0\0";
+        assert_eq!(
+            parse_details(body, 8).unwrap(),
+            "Statement: 's1', Query: 'select 1... [truncated, 12 chars total]'"
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn simple_query_timing_measures_once() {
-        let timing = ConnectionTiming::new();
-        timing.mark_simple_query();
-        assert!(timing.finish_simple_query().is_some());
-        assert!(timing.finish_simple_query().is_none());
+    fn sql_preview_collapses_and_truncates() {
+        assert_eq!(sql_preview("select\n  1", 60), "select 1");
+        assert_eq!(sql_preview("select 123456", 8), "select 1...");
+    }
+
+    fn formatted_row(data: &[u8], max_display_bytes: usize, columns: &[(u32, u16)]) -> Vec<String> {
+        let values = decode_data_row(data).expect("row decoded");
+        format_data_row(&values, max_display_bytes, 100, columns)
     }
 
     #[test]
-    fn format_duration_outputs_seconds() {
-        let dur = Duration::from_millis(1500);
-        assert_eq!(format_duration(dur), "1.500s");
+    fn typed_messages_are_decoded() {
+        assert_eq!(
+            parse_frontend_message(b'Q', b"select 1\0"),
+            Some(FrontendMessage::Query {
+                text: "select 1".to_string()
+            })
+        );
+        assert_eq!(
+            parse_frontend_message(b'E', b"p1\0\0\0\0\x0a"),
+            Some(FrontendMessage::Execute {
+                portal: "p1".to_string(),
+                max_rows: 10
+            })
+        );
+        assert_eq!(
+            parse_frontend_message(b'D', b"Sstmt\0"),
+            Some(FrontendMessage::Describe {
+                target: b'S',
+                name: "stmt".to_string()
+            })
+        );
+        assert_eq!(
+            parse_frontend_message(b'd', b"1\tone\n"),
+            Some(FrontendMessage::CopyData(b"1\tone\n"))
+        );
+        assert_eq!(
+            parse_frontend_message(b'H', b""),
+            Some(FrontendMessage::Flush)
+        );
+        // Truncated bodies and unknown types are not decoded
+        assert_eq!(parse_frontend_message(b'D', b""), None);
+        assert_eq!(parse_frontend_message(b'C', b"S_p3"), None);
+        assert_eq!(parse_frontend_message(b'?', b""), None);
+
+        assert_eq!(
+            parse_backend_message(b'Z', b"T"),
+            Some(BackendMessage::ReadyForQuery { status: b'T' })
+        );
+        assert_eq!(
+            parse_backend_message(b'C', b"INSERT 0 3\0"),
+            Some(BackendMessage::CommandComplete {
+                tag: "INSERT 0 3".to_string()
+            })
+        );
+        assert_eq!(
+            parse_backend_message(b'D', b"\0\x02\0\0\0\x01x\xff\xff\xff\xff"),
+            Some(BackendMessage::DataRow {
+                values: vec![Some(&b"x"[..]), None]
+            })
+        );
+        let mut row_description = b"\0\x01id\0".to_vec();
+        row_description.extend_from_slice(&16396u32.to_be_bytes());
+        row_description.extend_from_slice(&1i16.to_be_bytes());
+        row_description.extend_from_slice(&23u32.to_be_bytes());
+        row_description.extend_from_slice(&4i16.to_be_bytes());
+        row_description.extend_from_slice(&(-1i32).to_be_bytes());
+        row_description.extend_from_slice(&0u16.to_be_bytes());
+        let Some(BackendMessage::RowDescription { fields }) =
+            parse_backend_message(b'T', &row_description)
+        else {
+            panic!("not decoded as a RowDescription");
+        };
+        assert_eq!(
+            fields[0].describe(),
+            "name='id', type=int4 (OID=23), size=4, typemod=-1, format=text"
+        );
+        assert_eq!((fields[0].table_oid, fields[0].column_id), (16396, 1));
+        assert_eq!(
+            parse_backend_message(b't', b"\0\x01\0\0\0\x17"),
+            Some(BackendMessage::ParameterDescription {
+                param_oids: vec![23]
+            })
+        );
+        let Some(BackendMessage::ErrorResponse(fields)) =
+            parse_backend_message(b'E', b"SERROR\0C42703\0Mno such column\0\0")
+        else {
+            panic!("not decoded as an ErrorResponse");
+        };
+        assert_eq!(fields.get('C'), Some("42703"));
+        // A DataRow promising more columns than it holds
+        assert_eq!(parse_backend_message(b'D', b"\0\x02\xff\xff\xff\xff"), None);
+        assert_eq!(parse_backend_message(b'T', &row_description[..10]), None);
+        assert_eq!(parse_backend_message(b'Z', b""), None);
     }
 
     #[test]
-    fn bind_message_reports_all_binary_result_format() {
+    fn data_row_binary_values_respect_display_limit() {
         let data = vec![
-            0, // portal ""
-            b'_', b'p', b'1', 0, // statement "_p1"
-            0, 0, // param format count = 0
-            0, 0, // param count = 0
-            0, 1, // result format count = 1
-            0, 1, // binary for all
+            0, 1, // one field
+            0, 0, 0, 4, // length 4
+            0xde, 0xad, 0xbe, 0xef,
         ];
 
-        let summary = parse_bind_message(&data).expect("bind parsed");
-        assert!(
-            summary.contains("ResultFormats=binary (all)"),
-            "summary missing binary all: {summary}"
+        let values = formatted_row(&data, 2, &[]);
+        assert_eq!(values, vec!["<binary: de ad ...> (4 bytes)".to_string()]);
+
+        let values = formatted_row(&data, 32, &[]);
+        assert_eq!(values, vec!["<binary: de ad be ef>".to_string()]);
+    }
+
+    #[test]
+    fn data_row_text_values_are_cut_on_a_character_boundary() {
+        let text = "éa".repeat(60);
+        let data = data_row(&[text.as_bytes()]);
+        let values = decode_data_row(&data).expect("row decoded");
+
+        // Byte 100 of the value falls inside an "é"
+        assert_eq!(
+            format_data_row(&values, 32, 67, &[]),
+            vec![format!("'{}é...' (180 bytes)", "éa".repeat(33))]
         );
-        assert!(
-            summary.contains("ParamFormats=text (all)"),
-            "summary missing default param format: {summary}"
+        assert_eq!(
+            format_data_row(&values, 32, 120, &[]),
+            vec![format!("'{}'", text)]
         );
     }
 
+    /// A binary array value: dimensions as (size, lower bound), then each
+    /// element (None = NULL)
+    fn binary_array(element_oid: u32, dims: &[(i32, i32)], elements: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(dims.len() as i32).to_be_bytes());
+        data.extend_from_slice(&(elements.iter().any(Option::is_none) as i32).to_be_bytes());
+        data.extend_from_slice(&element_oid.to_be_bytes());
+        for (size, lower) in dims {
+            data.extend_from_slice(&size.to_be_bytes());
+            data.extend_from_slice(&lower.to_be_bytes());
+        }
+        for element in elements {
+            match element {
+                Some(bytes) => {
+                    data.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+                    data.extend_from_slice(bytes);
+                }
+                None => data.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        data
+    }
+
     #[test]
-    fn bind_message_reports_per_column_formats() {
-        let data = vec![
-            0, // portal ""
-            b'_', b'p', b'1', 0, // statement "_p1"
-            0, 1, // param format count = 1
-            0, 1, // binary params
-            0, 0, // param count = 0
-            0, 2, // result format count = 2
-            0, 0, // column 1 text
-            0, 1, // column 2 binary
-        ];
+    fn binary_arrays_are_shown_in_text_form() {
+        let one = 1i32.to_be_bytes();
+        let two = 2i32.to_be_bytes();
+        let three = 3i32.to_be_bytes();
+        let four = 4i32.to_be_bytes();
 
-        let summary = parse_bind_message(&data).expect("bind parsed");
-        assert!(
-            summary.contains("ParamFormats=binary (all)"),
-            "summary missing binary params: {summary}"
+        let array = binary_array(23, &[(3, 1)], &[Some(&one), None, Some(&three)]);
+        assert_eq!(decode_binary_array(&array).as_deref(), Some("{1,NULL,3}"));
+
+        let array = binary_array(
+            23,
+            &[(2, 1), (2, 1)],
+            &[Some(&one), Some(&two), Some(&three), Some(&four)],
         );
-        assert!(
-            summary.contains("ResultFormats=[text, binary]"),
-            "summary missing per-column formats: {summary}"
+        assert_eq!(
+            decode_binary_array(&array).as_deref(),
+            Some("{{1,2},{3,4}}")
+        );
+
+        let array = binary_array(23, &[(2, 0)], &[Some(&one), Some(&two)]);
+        assert_eq!(decode_binary_array(&array).as_deref(), Some("[0:1]={1,2}"));
+
+        let array = binary_array(
+            25,
+            &[(4, 1)],
+            &[Some(b"plain"), Some(b"a b"), Some(b"say \"hi\""), Some(b"")],
+        );
+        assert_eq!(
+            decode_binary_array(&array).as_deref(),
+            Some(r#"{plain,"a b","say \"hi\"",""}"#)
+        );
+
+        let array = binary_array(16, &[(2, 1)], &[Some(&[1]), Some(&[0])]);
+        assert_eq!(decode_binary_array(&array).as_deref(), Some("{t,f}"));
+
+        assert_eq!(
+            decode_binary_array(&binary_array(23, &[], &[])).as_deref(),
+            Some("{}")
         );
+
+        // A truncated element is not decoded
+        let mut array = binary_array(23, &[(1, 1)], &[Some(&one)]);
+        array.pop();
+        assert_eq!(decode_binary_array(&array), None);
+    }
+
+    #[test]
+    fn data_row_binary_arrays_use_the_row_description() {
+        let array = binary_array(23, &[(2, 1)], &[Some(&7i32.to_be_bytes()), None]);
+        let mut data = vec![0, 1];
+        data.extend_from_slice(&(array.len() as i32).to_be_bytes());
+        data.extend_from_slice(&array);
+
+        let values = formatted_row(&data, 32, &[(1007, 1)]);
+        assert_eq!(values, vec!["'{7,NULL}'".to_string()]);
+
+        // Text format columns are left alone
+        let values = formatted_row(&data, 4, &[(1007, 0)]);
+        assert!(values[0].starts_with("<binary:"), "{:?}", values);
+    }
+
+    #[test]
+    fn type_names_come_from_the_catalog() {
+        let expected = [
+            (16, "bool"),
+            (17, "bytea"),
+            (18, "char"),
+            (19, "name"),
+            (20, "int8"),
+            (21, "int2"),
+            (22, "int2vector"),
+            (23, "int4"),
+            (24, "regproc"),
+            (25, "text"),
+            (26, "oid"),
+            (27, "tid"),
+            (28, "xid"),
+            (29, "cid"),
+            (30, "oidvector"),
+            (114, "json"),
+            (142, "xml"),
+            (194, "pg_node_tree"),
+            (600, "point"),
+            (601, "lseg"),
+            (602, "path"),
+            (603, "box"),
+            (604, "polygon"),
+            (628, "line"),
+            (650, "cidr"),
+            (700, "float4"),
+            (701, "float8"),
+            (718, "circle"),
+            (774, "macaddr8"),
+            (790, "money"),
+            (829, "macaddr"),
+            (869, "inet"),
+            (1000, "bool[]"),
+            (1001, "bytea[]"),
+            (1005, "int2[]"),
+            (1007, "int4[]"),
+            (1009, "text[]"),
+            (1014, "bpchar[]"),
+            (1015, "varchar[]"),
+            (1016, "int8[]"),
+            (1033, "aclitem"),
+            (1042, "bpchar"),
+            (1043, "varchar"),
+            (1082, "date"),
+            (1083, "time"),
+            (1114, "timestamp"),
+            (1184, "timestamptz"),
+            (1186, "interval"),
+            (1266, "timetz"),
+            (1560, "bit"),
+            (1562, "varbit"),
+            (1700, "numeric"),
+            (1790, "refcursor"),
+            (2205, "regclass"),
+            (2206, "regtype"),
+            (2249, "record"),
+            (2278, "void"),
+            (2950, "uuid"),
+            (2951, "uuid[]"),
+            (3220, "pg_lsn"),
+            (3614, "tsvector"),
+            (3615, "tsquery"),
+            (3802, "jsonb"),
+            (3807, "jsonb[]"),
+            (3904, "int4range"),
+            (3926, "int8range"),
+            (4072, "jsonpath"),
+            (4451, "int4multirange"),
+            (5038, "pg_snapshot"),
+        ];
+        assert!(expected.len() >= 50);
+        for (oid, name) in expected {
+            assert_eq!(get_pg_type_name(oid), name, "OID {}", oid);
+        }
+        assert_eq!(get_pg_type_name(0), "unknown");
+        assert_eq!(get_pg_type_name(16384), "unknown");
     }
 }
@@ -0,0 +1,337 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::step::split_messages;
+
+/// First bytes of a recording, followed by the format version
+pub const MAGIC: &[u8; 8] = b"PGWIREC\n";
+/// Version of the record layout below
+pub const VERSION: u16 = 1;
+/// Connection ID, direction and timestamp ahead of the frame
+const RECORD_HEADER_LEN: usize = 8 + 1 + 8;
+
+/// One message of a recording.
+///
+/// A recording is the magic and a big-endian u16 version, then records of a
+/// big-endian u32 length (of everything after it), u64 connection ID, u8
+/// direction (0 = from the client, 1 = from the server), u64 timestamp in
+/// microseconds since the Unix epoch, and the frame bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Connections are numbered from 1 in the order they arrive
+    pub connection: u64,
+    pub from_client: bool,
+    pub timestamp_micros: u64,
+    /// A whole message, a startup or SSL/GSS request packet, or the
+    /// one-byte answer to such a request
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((RECORD_HEADER_LEN + self.frame.len()) as u32).to_be_bytes());
+        out.extend_from_slice(&self.connection.to_be_bytes());
+        out.push(if self.from_client { 0 } else { 1 });
+        out.extend_from_slice(&self.timestamp_micros.to_be_bytes());
+        out.extend_from_slice(&self.frame);
+    }
+}
+
+/// Reads the records of a recording in order
+pub struct RecordReader<R> {
+    reader: R,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// Check the magic and version at the start of `reader`
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; MAGIC.len() + 2];
+        reader
+            .read_exact(&mut header)
+            .context("Recording is shorter than its header")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("Not a recording (bad magic)");
+        }
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        if version != VERSION {
+            bail!("Unsupported recording version {}", version);
+        }
+        Ok(Self { reader })
+    }
+
+    /// The next record, or None at the end of the recording
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut length = [0u8; 4];
+        let mut filled = 0;
+        while filled < length.len() {
+            match self.reader.read(&mut length[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => bail!("Recording ends inside a record length"),
+                n => filled += n,
+            }
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length < RECORD_HEADER_LEN {
+            bail!("Invalid record length {}", length);
+        }
+        let mut body = vec![0u8; length];
+        self.reader
+            .read_exact(&mut body)
+            .context("Recording ends inside a record")?;
+        let direction = body[8];
+        if direction > 1 {
+            bail!("Invalid record direction {}", direction);
+        }
+        Ok(Some(Record {
+            connection: u64::from_be_bytes(body[..8].try_into().unwrap()),
+            from_client: direction == 0,
+            timestamp_micros: u64::from_be_bytes(body[9..17].try_into().unwrap()),
+            frame: body.split_off(RECORD_HEADER_LEN),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+enum Command {
+    Write(Record),
+    Finish(oneshot::Sender<()>),
+}
+
+/// Shared by every connection: the queue to the writer task and the last
+/// connection number handed out
+#[derive(Debug)]
+struct Recorder {
+    sender: UnboundedSender<Command>,
+    connections: AtomicU64,
+}
+
+/// A handle on the recording file. The proxy-wide handle numbers each new
+/// connection with [`Recording::connection`]; the forwarding path only
+/// queues records, and a writer task writes and flushes them.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    recorder: Arc<Recorder>,
+    connection: u64,
+}
+
+impl Recording {
+    /// Open `path` for appending and start the writer task. A new file gets
+    /// the header; an existing recording is checked and its connection
+    /// numbers are continued.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut connections = 0;
+        let existing = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        if existing > 0 {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open recording {}", path.display()))?;
+            let mut records = 0;
+            for record in RecordReader::new(BufReader::new(file))? {
+                let record = record
+                    .with_context(|| format!("{} is not a complete recording", path.display()))?;
+                connections = connections.max(record.connection);
+                records += 1;
+            }
+            info!(
+                "Appending to recording {} ({} records from {} connections)",
+                path.display(),
+                records,
+                connections
+            );
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        if existing == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_be_bytes())?;
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_records(
+            BufWriter::new(tokio::fs::File::from_std(file)),
+            receiver,
+        ));
+        Ok(Self {
+            recorder: Arc::new(Recorder {
+                sender,
+                connections: AtomicU64::new(connections),
+            }),
+            connection: 0,
+        })
+    }
+
+    /// The handle of a new connection, numbered after every earlier one
+    pub fn connection(&self) -> Self {
+        Self {
+            recorder: self.recorder.clone(),
+            connection: self.recorder.connections.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+
+    /// Queue each whole message of a framed buffer
+    pub fn record(&self, from_client: bool, data: &[u8]) {
+        for message in split_messages(data) {
+            self.record_packet(from_client, message);
+        }
+    }
+
+    /// Queue bytes without a message type: a startup packet or the answer
+    /// to an SSL or GSS encryption request
+    pub fn record_packet(&self, from_client: bool, frame: &[u8]) {
+        let timestamp_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        // Only fails once the writer has finished at shutdown
+        let _ = self.recorder.sender.send(Command::Write(Record {
+            connection: self.connection,
+            from_client,
+            timestamp_micros,
+            frame: frame.to_vec(),
+        }));
+    }
+
+    /// Write out everything queued so far and stop the writer
+    pub async fn finish(&self) {
+        let (done, finished) = oneshot::channel();
+        if self.recorder.sender.send(Command::Finish(done)).is_ok() {
+            let _ = finished.await;
+        }
+    }
+}
+
+/// Write records as they arrive, flushing whenever the queue runs empty so
+/// the file is current even if the proxy is killed
+async fn write_records(
+    mut out: BufWriter<tokio::fs::File>,
+    mut commands: UnboundedReceiver<Command>,
+) {
+    let mut buf = Vec::new();
+    while let Some(command) = commands.recv().await {
+        let mut next = Some(command);
+        let mut finished = None;
+        while let Some(command) = next {
+            match command {
+                Command::Write(record) => {
+                    buf.clear();
+                    record.encode(&mut buf);
+                    if let Err(e) = out.write_all(&buf).await {
+                        warn!("Failed to write recording: {}", e);
+                    }
+                }
+                Command::Finish(done) => {
+                    finished = Some(done);
+                    break;
+                }
+            }
+            next = commands.try_recv().ok();
+        }
+        if let Err(e) = out.flush().await {
+            warn!("Failed to flush recording: {}", e);
+        }
+        if let Some(done) = finished {
+            let _ = done.send(());
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = b"Q\0\0\0\x0dSELECT 1\0";
+    const READY: &[u8] = b"Z\0\0\0\x05I";
+
+    #[tokio::test]
+    async fn multi_connection_recordings_read_back() {
+        let path = std::env::temp_dir().join(format!("record-test-{}.pgrec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recording = Recording::open(&path).unwrap();
+        let first = recording.connection();
+        let second = recording.connection();
+        first.record_packet(true, b"\0\0\0\x08\x04\xd2\x16\x2f");
+        first.record_packet(false, b"N");
+        second.record(true, QUERY);
+        // One buffer holding two messages is split into two records
+        first.record(false, &[READY, READY].concat());
+        recording.finish().await;
+
+        let file = File::open(&path).unwrap();
+        let records: Vec<Record> = RecordReader::new(file)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let summary: Vec<(u64, bool, &[u8])> = records
+            .iter()
+            .map(|record| (record.connection, record.from_client, &record.frame[..]))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, true, &b"\0\0\0\x08\x04\xd2\x16\x2f"[..]),
+                (1, false, b"N"),
+                (2, true, QUERY),
+                (1, false, READY),
+                (1, false, READY),
+            ]
+        );
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp_micros <= pair[1].timestamp_micros));
+
+        // Reopening appends and continues the connection numbers
+        let recording = Recording::open(&path).unwrap();
+        recording.connection().record(true, QUERY);
+        recording.finish().await;
+        let records: Vec<Record> = RecordReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[5].connection, 3);
+    }
+
+    #[test]
+    fn damaged_recordings_are_rejected() {
+        assert!(RecordReader::new(&b"PGWIREC\n\0\x02"[..]).is_err());
+        assert!(RecordReader::new(&b"not a recording"[..]).is_err());
+
+        let record = Record {
+            connection: 7,
+            from_client: false,
+            timestamp_micros: 1_700_000_000_000_000,
+            frame: READY.to_vec(),
+        };
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        record.encode(&mut bytes);
+        let mut reader = RecordReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.next_record().unwrap(), Some(record));
+        assert_eq!(reader.next_record().unwrap(), None);
+
+        bytes.pop();
+        let mut reader = RecordReader::new(&bytes[..]).unwrap();
+        assert!(reader.next_record().is_err());
+    }
+}
@@ -0,0 +1,637 @@
+use crate::fault::message_name;
+use crate::protocol::{
+    decode_startup_parameters, describe_error_response, encode_startup_message, MessageFramer,
+};
+use crate::record::{Record, RecordReader};
+use crate::step::split_messages;
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use postgres_protocol::authentication::sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256};
+use postgres_protocol::message::frontend;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep_until, timeout, Instant};
+
+/// SSLRequest, GSSENCRequest and CancelRequest codes, sent in place of a
+/// protocol version
+const SSL_REQUEST_CODE: u32 = 80877103;
+const GSSENC_REQUEST_CODE: u32 = 80877104;
+const CANCEL_REQUEST_CODE: u32 = 80877102;
+
+/// Server messages whose presence depends on the server rather than on
+/// what the client sent; they are not compared
+const ASYNCHRONOUS_MESSAGES: &[u8] = b"SNA";
+
+/// Replay a --record recording against a server
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// Recording written by --record
+    #[arg(long, value_name = "PATH")]
+    pub file: PathBuf,
+
+    /// PostgreSQL host to replay against
+    #[arg(long, default_value = "localhost")]
+    pub upstream_host: String,
+
+    /// PostgreSQL port to replay against
+    #[arg(long, default_value = "5432")]
+    pub upstream_port: u16,
+
+    /// Log in as this user instead of the recorded one
+    #[arg(long)]
+    pub user: Option<String>,
+
+    /// Password for cleartext, md5 or SCRAM-SHA-256 authentication
+    #[arg(long)]
+    pub password: Option<String>,
+
+    /// Connect to this database instead of the recorded one
+    #[arg(long)]
+    pub database: Option<String>,
+
+    /// Keep the recorded gaps between client messages, and start sessions at
+    /// their recorded offsets and run them side by side; without it sessions
+    /// run one after another as fast as the server answers
+    #[arg(long)]
+    pub preserve_timing: bool,
+
+    /// Seconds to wait for each server message before giving up on a session
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub timeout: u64,
+}
+
+/// The records of one recorded connection
+#[derive(Debug)]
+pub struct Session {
+    pub connection: u64,
+    pub records: Vec<Record>,
+}
+
+/// Group records by connection, in the order the connections started
+pub fn group_sessions(records: Vec<Record>) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    for record in records {
+        match sessions
+            .iter_mut()
+            .find(|session| session.connection == record.connection)
+        {
+            Some(session) => session.records.push(record),
+            None => sessions.push(Session {
+                connection: record.connection,
+                records: vec![record],
+            }),
+        }
+    }
+    sessions
+}
+
+/// A server message that differs from the recorded one
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// Server messages are counted from 1 after the startup, skipping
+    /// ParameterStatus, NoticeResponse and NotificationResponse
+    pub position: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// What replaying one session found
+#[derive(Debug, Default)]
+pub struct SessionReport {
+    pub connection: u64,
+    /// Client messages sent after the startup
+    pub sent: usize,
+    /// Server messages compared
+    pub compared: usize,
+    pub divergences: Vec<Divergence>,
+    /// Why the replay stopped early, or why the session was not replayed
+    pub error: Option<String>,
+    pub skipped: bool,
+}
+
+impl SessionReport {
+    pub fn matched(&self) -> bool {
+        self.divergences.is_empty() && self.error.is_none()
+    }
+
+    fn print(&self) {
+        if self.skipped {
+            println!(
+                "connection {}: skipped ({})",
+                self.connection,
+                self.error.as_deref().unwrap_or_default()
+            );
+            return;
+        }
+        println!(
+            "connection {}: sent {} client messages, compared {} server messages, {} divergences",
+            self.connection,
+            self.sent,
+            self.compared,
+            self.divergences.len()
+        );
+        for divergence in &self.divergences {
+            println!(
+                "  server message {}: expected {}, got {}",
+                divergence.position, divergence.expected, divergence.actual
+            );
+        }
+        if let Some(error) = &self.error {
+            println!("  stopped: {}", error);
+        }
+    }
+}
+
+/// Replay every session of the recording and print what diverged. Returns
+/// whether every session matched.
+pub async fn run(args: ReplayArgs) -> Result<bool> {
+    let file = File::open(&args.file)
+        .with_context(|| format!("Failed to open recording {}", args.file.display()))?;
+    let records = RecordReader::new(BufReader::new(file))?.collect::<Result<Vec<_>>>()?;
+    let sessions = group_sessions(records);
+    println!(
+        "Replaying {} sessions from {} against {}:{}",
+        sessions.len(),
+        args.file.display(),
+        args.upstream_host,
+        args.upstream_port
+    );
+
+    let reports = if args.preserve_timing {
+        let start = Instant::now();
+        let first = sessions
+            .first()
+            .map_or(0, |session| session.records[0].timestamp_micros);
+        let tasks: Vec<_> = sessions
+            .into_iter()
+            .map(|session| {
+                let args = args.clone();
+                let offset = session.records[0].timestamp_micros - first;
+                tokio::spawn(async move {
+                    sleep_until(start + Duration::from_micros(offset)).await;
+                    replay_session(&session, &args).await
+                })
+            })
+            .collect();
+        let mut reports = Vec::new();
+        for task in tasks {
+            reports.push(task.await?);
+        }
+        reports
+    } else {
+        let mut reports = Vec::new();
+        for session in &sessions {
+            reports.push(replay_session(session, &args).await);
+        }
+        reports
+    };
+
+    for report in &reports {
+        report.print();
+    }
+    let replayed = reports.iter().filter(|report| !report.skipped).count();
+    let matched = reports
+        .iter()
+        .filter(|report| !report.skipped && report.matched())
+        .count();
+    println!(
+        "Replayed {} sessions: {} matched, {} diverged",
+        replayed,
+        matched,
+        replayed - matched
+    );
+    Ok(matched == replayed)
+}
+
+/// Replay one session, reporting rather than returning any failure
+pub async fn replay_session(session: &Session, args: &ReplayArgs) -> SessionReport {
+    let mut report = SessionReport {
+        connection: session.connection,
+        ..SessionReport::default()
+    };
+    if let Err(e) = replay_records(session, args, &mut report).await {
+        report.error = Some(format!("{:#}", e));
+    }
+    report
+}
+
+async fn replay_records(
+    session: &Session,
+    args: &ReplayArgs,
+    report: &mut SessionReport,
+) -> Result<()> {
+    let mut records = session.records.iter();
+
+    // SSL and GSS encryption requests and their one-byte answers are not
+    // replayed: the replay always talks plain TCP
+    let startup = loop {
+        let Some(record) = records.next() else {
+            report.skipped = true;
+            bail!("no startup message recorded");
+        };
+        if !record.from_client {
+            continue;
+        }
+        match startup_code(&record.frame) {
+            Some(SSL_REQUEST_CODE | GSSENC_REQUEST_CODE) => continue,
+            Some(CANCEL_REQUEST_CODE) => {
+                report.skipped = true;
+                bail!("CancelRequest");
+            }
+            _ => break record,
+        }
+    };
+    let Some(mut parameters) = decode_startup_parameters(&startup.frame) else {
+        report.skipped = true;
+        bail!("startup message is not protocol 3.0");
+    };
+    for (name, value) in [("user", &args.user), ("database", &args.database)] {
+        if let Some(value) = value {
+            parameters.retain(|(parameter, _)| parameter != name);
+            parameters.push((name.to_string(), value.clone()));
+        }
+    }
+    let user = parameters
+        .iter()
+        .find(|(name, _)| name == "user")
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default();
+
+    let mut server = ServerConnection::connect(args).await?;
+    server
+        .stream
+        .write_all(&encode_startup_message(&parameters))
+        .await?;
+    server
+        .authenticate(&user, args.password.as_deref())
+        .await
+        .context("startup failed")?;
+
+    // The recorded startup ends at its first ReadyForQuery; password
+    // messages sent before it belong to the recorded authentication
+    for record in records.by_ref() {
+        if !record.from_client && record.frame.first() == Some(&b'Z') {
+            break;
+        }
+    }
+
+    let started = Instant::now();
+    let mut skip_expected = false;
+    let mut drain_actual = false;
+    for record in records {
+        let tag = record.frame.first().copied().unwrap_or_default();
+        if record.from_client {
+            if args.preserve_timing {
+                let offset = record
+                    .timestamp_micros
+                    .saturating_sub(startup.timestamp_micros);
+                sleep_until(started + Duration::from_micros(offset)).await;
+            }
+            server.stream.write_all(&record.frame).await?;
+            report.sent += 1;
+            if tag == b'X' {
+                break;
+            }
+            continue;
+        }
+        if ASYNCHRONOUS_MESSAGES.contains(&tag) {
+            continue;
+        }
+        // After a divergence both sides skip to the end of the exchange,
+        // their next ReadyForQuery, and compare again from there
+        if skip_expected {
+            skip_expected = tag != b'Z';
+            continue;
+        }
+        if drain_actual {
+            while server.next_message().await?.first() != Some(&b'Z') {}
+            drain_actual = false;
+        }
+        let actual = server.next_message().await.with_context(|| {
+            format!(
+                "no answer for server message {} ({} expected)",
+                report.compared + 1,
+                describe_message(&record.frame)
+            )
+        })?;
+        report.compared += 1;
+        if !same_message(&record.frame, &actual) {
+            report.divergences.push(Divergence {
+                position: report.compared,
+                expected: describe_message(&record.frame),
+                actual: describe_message(&actual),
+            });
+            skip_expected = tag != b'Z';
+            drain_actual = actual.first() != Some(&b'Z');
+        }
+    }
+    Ok(())
+}
+
+/// The request code of an 8-byte SSLRequest, GSSENCRequest or the 16-byte
+/// CancelRequest
+fn startup_code(frame: &[u8]) -> Option<u32> {
+    if frame.len() != 8 && frame.len() != 16 {
+        return None;
+    }
+    Some(u32::from_be_bytes(frame[4..8].try_into().ok()?))
+}
+
+/// Messages match when their types do, and CommandComplete tags as well
+fn same_message(expected: &[u8], actual: &[u8]) -> bool {
+    match (expected.first(), actual.first()) {
+        (Some(b'C'), Some(b'C')) => expected == actual,
+        (expected, actual) => expected == actual,
+    }
+}
+
+/// `CommandComplete "INSERT 0 1"`, `ErrorResponse (ERROR sqlstate=...)` or
+/// the message name
+fn describe_message(frame: &[u8]) -> String {
+    let tag = frame.first().copied().unwrap_or_default();
+    let body = frame.get(5..).unwrap_or_default();
+    let name = message_name(false, tag);
+    match tag {
+        b'C' => format!(
+            "{} \"{}\"",
+            name,
+            String::from_utf8_lossy(body.strip_suffix(b"\0").unwrap_or(body))
+        ),
+        b'E' => format!("{} ({})", name, describe_error_response(body)),
+        _ => name.to_string(),
+    }
+}
+
+struct ServerConnection {
+    stream: TcpStream,
+    framer: MessageFramer,
+    pending: VecDeque<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl ServerConnection {
+    async fn connect(args: &ReplayArgs) -> Result<Self> {
+        let stream = TcpStream::connect((args.upstream_host.as_str(), args.upstream_port))
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to connect to {}:{}",
+                    args.upstream_host, args.upstream_port
+                )
+            })?;
+        Ok(Self {
+            stream,
+            framer: MessageFramer::default(),
+            pending: VecDeque::new(),
+            timeout: Duration::from_secs(args.timeout),
+        })
+    }
+
+    /// The next whole message from the server
+    async fn next_message(&mut self) -> Result<Vec<u8>> {
+        let mut buf = [0u8; 8192];
+        while self.pending.is_empty() {
+            let n = timeout(self.timeout, self.stream.read(&mut buf))
+                .await
+                .context("timed out waiting for the server")??;
+            if n == 0 {
+                bail!("server closed the connection");
+            }
+            let messages = self.framer.push(&buf[..n]);
+            self.pending
+                .extend(split_messages(&messages).into_iter().map(<[u8]>::to_vec));
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    /// Answer the server's authentication requests up to ReadyForQuery
+    async fn authenticate(&mut self, user: &str, password: Option<&str>) -> Result<()> {
+        let mut scram = None;
+        loop {
+            let message = self.next_message().await?;
+            let body = &message[5..];
+            let mut reply = BytesMut::new();
+            match message[0] {
+                b'R' => {
+                    let method = i32::from_be_bytes(body[..4].try_into()?);
+                    let data = &body[4..];
+                    let password =
+                        || password.context("server asked for a password; pass --password");
+                    match method {
+                        0 => continue,
+                        3 => frontend::password_message(password()?.as_bytes(), &mut reply)?,
+                        5 => {
+                            let response = md5_password_response(user, password()?, data);
+                            frontend::password_message(response.as_bytes(), &mut reply)?;
+                        }
+                        10 => {
+                            let mechanisms: Vec<&[u8]> = data
+                                .split(|&b| b == 0)
+                                .filter(|name| !name.is_empty())
+                                .collect();
+                            if !mechanisms.contains(&SCRAM_SHA_256.as_bytes()) {
+                                bail!("server offers no SASL mechanism the replay supports");
+                            }
+                            let client = ScramSha256::new(
+                                password()?.as_bytes(),
+                                ChannelBinding::unsupported(),
+                            );
+                            frontend::sasl_initial_response(
+                                SCRAM_SHA_256,
+                                client.message(),
+                                &mut reply,
+                            )?;
+                            scram = Some(client);
+                        }
+                        11 => {
+                            let client = scram.as_mut().context("unexpected SASLContinue")?;
+                            client.update(data)?;
+                            frontend::sasl_response(client.message(), &mut reply)?;
+                        }
+                        12 => {
+                            let client = scram.as_mut().context("unexpected SASLFinal")?;
+                            client.finish(data)?;
+                            continue;
+                        }
+                        method => bail!("unsupported authentication method {}", method),
+                    }
+                    self.stream.write_all(&reply).await?;
+                }
+                b'E' => bail!("{}", describe_error_response(body)),
+                b'Z' => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn md5_password_response(user: &str, password: &str, salt: &[u8]) -> String {
+    let inner = format!("{:x}", md5::compute(format!("{}{}", password, user)));
+    let mut outer = inner.into_bytes();
+    outer.extend_from_slice(salt);
+    format!("md5{:x}", md5::compute(outer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        encode_backend_message, encode_command_complete, encode_ready_for_query,
+    };
+    use tokio::net::TcpListener;
+
+    fn record(from_client: bool, frame: Vec<u8>) -> Record {
+        Record {
+            connection: 1,
+            from_client,
+            timestamp_micros: 0,
+            frame,
+        }
+    }
+
+    fn query(sql: &str) -> Vec<u8> {
+        encode_backend_message(b'Q', format!("{}\0", sql).as_bytes())
+    }
+
+    fn startup() -> Vec<u8> {
+        encode_startup_message(&[("user".to_string(), "app".to_string())])
+    }
+
+    /// A recording of: SSL declined, startup, `INSERT` then `SELECT 1`
+    fn recorded_session(insert_tag: &str) -> Session {
+        let ready = encode_ready_for_query(b'I');
+        Session {
+            connection: 1,
+            records: vec![
+                record(true, vec![0, 0, 0, 8, 4, 210, 22, 47]),
+                record(false, b"N".to_vec()),
+                record(true, startup()),
+                record(false, encode_backend_message(b'R', &5i32.to_be_bytes())),
+                record(true, encode_backend_message(b'p', b"md5secret\0")),
+                record(false, encode_backend_message(b'R', &0i32.to_be_bytes())),
+                record(false, encode_backend_message(b'S', b"TimeZone\0UTC\0")),
+                record(false, ready.clone()),
+                record(true, query("INSERT INTO t VALUES (1)")),
+                record(false, encode_command_complete(insert_tag)),
+                record(false, ready.clone()),
+                record(true, query("SELECT 1")),
+                record(false, encode_backend_message(b'T', b"\0\0")),
+                record(false, encode_backend_message(b'D', b"\0\0")),
+                record(false, encode_command_complete("SELECT 1")),
+                record(false, ready),
+                record(true, encode_backend_message(b'X', b"")),
+            ],
+        }
+    }
+
+    /// Trusts the client, then answers INSERT with an error and every
+    /// other query with one row
+    async fn fake_server(listener: TcpListener) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = socket.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &startup()[..]);
+        let mut reply = encode_backend_message(b'R', &0i32.to_be_bytes());
+        reply.extend(encode_backend_message(b'S', b"TimeZone\0Europe/Paris\0"));
+        reply.extend(encode_ready_for_query(b'I'));
+        socket.write_all(&reply).await.unwrap();
+        let mut framer = MessageFramer::default();
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            let messages = framer.push(&buf[..n]);
+            for message in split_messages(&messages) {
+                let reply = match message[0] {
+                    b'X' => return,
+                    _ if message.windows(6).any(|w| w == b"INSERT") => [
+                        crate::protocol::encode_error_response(
+                            "ERROR",
+                            "42P01",
+                            "relation \"t\" does not exist",
+                        ),
+                        encode_ready_for_query(b'I'),
+                    ]
+                    .concat(),
+                    _ => [
+                        encode_backend_message(b'T', b"\0\0"),
+                        encode_backend_message(b'D', b"\0\0"),
+                        encode_command_complete("SELECT 1"),
+                        encode_ready_for_query(b'I'),
+                    ]
+                    .concat(),
+                };
+                socket.write_all(&reply).await.unwrap();
+            }
+        }
+    }
+
+    async fn replay_against_fake(session: Session) -> SessionReport {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let args = ReplayArgs {
+            file: PathBuf::new(),
+            upstream_host: "127.0.0.1".to_string(),
+            upstream_port: listener.local_addr().unwrap().port(),
+            user: None,
+            password: None,
+            database: None,
+            preserve_timing: false,
+            timeout: 5,
+        };
+        let server = tokio::spawn(fake_server(listener));
+        let report = replay_session(&session, &args).await;
+        server.await.unwrap();
+        report
+    }
+
+    #[tokio::test]
+    async fn divergent_replies_are_reported_and_later_ones_compared() {
+        let report = replay_against_fake(recorded_session("INSERT 0 1")).await;
+        assert_eq!(report.error, None);
+        // INSERT, SELECT 1 and Terminate
+        assert_eq!(report.sent, 3);
+        assert_eq!(
+            report.divergences,
+            vec![Divergence {
+                position: 1,
+                expected: "CommandComplete \"INSERT 0 1\"".to_string(),
+                actual: "ErrorResponse (ERROR sqlstate=42P01: relation \"t\" does not exist)"
+                    .to_string(),
+            }]
+        );
+        // After the skip to ReadyForQuery, the SELECT's four messages match
+        assert_eq!(report.compared, 5);
+    }
+
+    #[test]
+    fn sessions_are_grouped_in_start_order() {
+        let mut records = recorded_session("INSERT 0 1").records;
+        records[3].connection = 2;
+        records[0].connection = 3;
+        let sessions = group_sessions(records);
+        let connections: Vec<u64> = sessions.iter().map(|session| session.connection).collect();
+        assert_eq!(connections, vec![3, 1, 2]);
+        assert_eq!(sessions[1].records.len(), 15);
+    }
+
+    #[test]
+    fn messages_compare_by_type_and_command_tag() {
+        let insert = encode_command_complete("INSERT 0 1");
+        assert!(same_message(&insert, &insert));
+        assert!(!same_message(
+            &insert,
+            &encode_command_complete("INSERT 0 2")
+        ));
+        assert!(same_message(
+            &encode_backend_message(b'D', b"\0\x01\0\0\0\x011"),
+            &encode_backend_message(b'D', b"\0\x01\0\0\0\x012")
+        ));
+        assert_eq!(
+            md5_password_response("user", "password", &[0x12, 0x34, 0x56, 0x78]),
+            "md5d6f407104ca5ba8553d598fed7df90e0"
+        );
+    }
+}
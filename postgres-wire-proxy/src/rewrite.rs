@@ -0,0 +1,180 @@
+use regex_automata::meta::Regex;
+
+/// Largest message body the server accepts (PQ_LARGE_MESSAGE_LIMIT)
+pub const MAX_MESSAGE_LENGTH: usize = 0x3fff_fffe;
+
+/// One --rewrite rule: every match of the regex in the SQL of a Query or
+/// Parse is replaced, with `$1`/`${name}` expanding capture groups
+#[derive(Clone, Debug)]
+pub struct QueryRewrite {
+    pattern: String,
+    regex: Regex,
+    replacement: String,
+}
+
+impl std::fmt::Display for QueryRewrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{}/ => '{}'", self.pattern, self.replacement)
+    }
+}
+
+impl QueryRewrite {
+    /// `sql` with every match replaced, or None when the regex does not match
+    fn apply(&self, sql: &str) -> Option<String> {
+        let mut rewritten = String::with_capacity(sql.len());
+        let mut copied = 0;
+        let mut matched = false;
+        for captures in self.regex.captures_iter(sql) {
+            let found = captures.get_match()?;
+            rewritten.push_str(&sql[copied..found.start()]);
+            captures.interpolate_string_into(sql, &self.replacement, &mut rewritten);
+            copied = found.end();
+            matched = true;
+        }
+        if !matched {
+            return None;
+        }
+        rewritten.push_str(&sql[copied..]);
+        Some(rewritten)
+    }
+}
+
+/// Parse one `pattern=>replacement` argument of --rewrite. The split is at
+/// the first `=>`, so the replacement may contain one (e.g. named function
+/// arguments) but the pattern may not.
+pub fn parse_query_rewrite(value: &str) -> Result<QueryRewrite, String> {
+    let (pattern, replacement) = value
+        .split_once("=>")
+        .ok_or_else(|| "expected PATTERN=>REPLACEMENT".to_string())?;
+    let regex = Regex::new(pattern).map_err(|e| format!("invalid regex: {}", e))?;
+    Ok(QueryRewrite {
+        pattern: pattern.to_string(),
+        regex,
+        replacement: replacement.to_string(),
+    })
+}
+
+/// A Query or Parse message re-encoded around its rewritten SQL
+#[derive(Debug, PartialEq, Eq)]
+pub struct RewrittenMessage {
+    pub original: String,
+    pub rewritten: String,
+    /// The whole message: type byte, new length and body
+    pub message: Vec<u8>,
+}
+
+impl RewrittenMessage {
+    /// Value of the length field, which counts itself but not the type byte
+    pub fn length(&self) -> usize {
+        self.message.len() - 1
+    }
+}
+
+/// Apply the rules in order to the SQL of a Query or Parse message body.
+/// Returns None for any other message, SQL that is not UTF-8, or SQL that
+/// no rule changes. Everything after the SQL (Parse's parameter types) is
+/// copied unchanged.
+pub fn rewrite_message(
+    msg_type: u8,
+    body: &[u8],
+    rules: &[QueryRewrite],
+) -> Option<RewrittenMessage> {
+    let sql_start = match msg_type {
+        b'Q' => 0,
+        b'P' => body.iter().position(|&b| b == 0)? + 1,
+        _ => return None,
+    };
+    let sql_len = body[sql_start..].iter().position(|&b| b == 0)?;
+    let original = std::str::from_utf8(&body[sql_start..sql_start + sql_len]).ok()?;
+
+    let mut rewritten: Option<String> = None;
+    for rule in rules {
+        if let Some(sql) = rule.apply(rewritten.as_deref().unwrap_or(original)) {
+            rewritten = Some(sql);
+        }
+    }
+    let rewritten = rewritten.filter(|sql| sql != original)?;
+
+    let tail = &body[sql_start + sql_len..];
+    let length = 4 + sql_start + rewritten.len() + tail.len();
+    let mut message = Vec::with_capacity(length + 1);
+    message.push(msg_type);
+    message.extend_from_slice(&(length as u32).to_be_bytes());
+    message.extend_from_slice(&body[..sql_start]);
+    message.extend_from_slice(rewritten.as_bytes());
+    message.extend_from_slice(tail);
+    Some(RewrittenMessage {
+        original: original.to_string(),
+        rewritten,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(values: &[&str]) -> Vec<QueryRewrite> {
+        values
+            .iter()
+            .map(|value| parse_query_rewrite(value).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn query_is_reframed_with_the_new_length() {
+        let rewritten =
+            rewrite_message(b'Q', b"SELECT 1\0", &rules(&["^=>/* trace 42 */ "])).unwrap();
+        assert_eq!(rewritten.original, "SELECT 1");
+        assert_eq!(rewritten.rewritten, "/* trace 42 */ SELECT 1");
+        assert_eq!(
+            rewritten.message,
+            b"Q\0\0\0\x1c/* trace 42 */ SELECT 1\0".to_vec()
+        );
+        assert_eq!(rewritten.length(), 28);
+    }
+
+    #[test]
+    fn parse_keeps_its_name_and_parameter_types() {
+        // Statement "s1", one parameter of type int4 (oid 23)
+        let body = b"s1\0SELECT * FROM orders WHERE id = $1\0\0\x01\0\0\0\x17";
+        let rewritten =
+            rewrite_message(b'P', body, &rules(&[r"\borders\b=>orders_shadow"])).unwrap();
+        assert_eq!(
+            rewritten.message,
+            b"P\0\0\0\x37s1\0SELECT * FROM orders_shadow WHERE id = $1\0\0\x01\0\0\0\x17".to_vec()
+        );
+    }
+
+    #[test]
+    fn rules_apply_in_order_with_capture_groups() {
+        let rewritten = rewrite_message(
+            b'Q',
+            b"select a from t1; select b from t2\0",
+            &rules(&[r"from (t\d)=>from shadow_$1", "shadow_t2=>t2"]),
+        )
+        .unwrap();
+        assert_eq!(
+            rewritten.rewritten,
+            "select a from shadow_t1; select b from t2"
+        );
+    }
+
+    #[test]
+    fn other_messages_and_unmatched_sql_are_left_alone() {
+        let rules = rules(&["orders=>orders_shadow"]);
+        assert!(rewrite_message(b'Q', b"SELECT 1\0", &rules).is_none());
+        // Bind parameter values are never rewritten
+        assert!(rewrite_message(b'B', b"\0s1\0\0\0\0\x01\0\0\0\x06orders\0\0", &rules).is_none());
+        assert!(rewrite_message(b'Q', b"orders", &rules).is_none());
+        assert!(rewrite_message(b'Q', b"SELECT '\xff' FROM orders\0", &rules).is_none());
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        assert!(parse_query_rewrite("orders").is_err());
+        assert!(parse_query_rewrite("(=>x").is_err());
+        let rule = parse_query_rewrite("f\\(=>f(a => ").unwrap();
+        assert_eq!(rule.to_string(), "/f\\(/ => 'f(a => '");
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::io;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+/// TCP socket options applied to both client and upstream connections
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// Log the values the OS actually applied to each socket
+    pub report: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            report: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Apply the options to an already connected (accepted) socket
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        self.apply(&SockRef::from(stream))
+    }
+
+    /// Connect to `host:port`, applying the options before the handshake so
+    /// buffer sizes take part in TCP window negotiation
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in lookup_host((host, port))
+            .await
+            .context("Failed to resolve upstream address")?
+        {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            self.apply(&socket)?;
+            socket.set_nonblocking(true)?;
+            let socket = TcpSocket::from_std_stream(socket.into());
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => Err(e).context("Failed to connect to upstream"),
+            None => anyhow::bail!("Upstream host {} did not resolve", host),
+        }
+    }
+
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe the options currently in effect on a socket; these can differ
+/// from the requested values (Linux doubles buffer sizes, for example)
+pub fn describe_socket(stream: &TcpStream) -> io::Result<String> {
+    let socket = SockRef::from(stream);
+    Ok(format!(
+        "nodelay={} sndbuf={} rcvbuf={}",
+        socket.nodelay()?,
+        socket.send_buffer_size()?,
+        socket.recv_buffer_size()?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn options_are_applied_to_both_ends() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let options = SocketOptions {
+            nodelay: false,
+            recv_buffer_size: Some(64 * 1024),
+            ..SocketOptions::default()
+        };
+
+        let upstream = options.connect("127.0.0.1", port).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        options.apply_to_stream(&accepted).unwrap();
+
+        for stream in [&upstream, &accepted] {
+            let socket = SockRef::from(stream);
+            assert!(!socket.nodelay().unwrap());
+            assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        }
+        assert!(describe_socket(&upstream)
+            .unwrap()
+            .starts_with("nodelay=false sndbuf="));
+    }
+}
@@ -0,0 +1,238 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
+
+use crate::fault::message_name;
+use crate::protocol::log_hex_dump;
+
+/// What the operator typed on stdin for the held message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepCommand {
+    /// Forward the held message and hold the next one
+    Step,
+    /// Forward everything from now on; a later `s` resumes stepping
+    Continue,
+    /// Show the held message's bytes and keep holding it
+    Dump,
+    /// Close the session's client and server connections
+    Kill,
+}
+
+/// One line of stdin: Enter or `s` steps, `c` continues, `d` dumps and
+/// `k` kills
+pub fn parse_step_command(line: &str) -> Option<StepCommand> {
+    match line.trim() {
+        "" | "s" => Some(StepCommand::Step),
+        "c" => Some(StepCommand::Continue),
+        "d" => Some(StepCommand::Dump),
+        "k" => Some(StepCommand::Kill),
+        _ => None,
+    }
+}
+
+/// Hands the operator's commands to the one session being stepped. Sessions
+/// are numbered in the order they start proxying, from 1.
+#[derive(Debug)]
+pub struct Stepper {
+    /// Only this session is stepped (--step-session); without it the first
+    /// session to start while none is stepped is
+    session: Option<u64>,
+    sessions: AtomicU64,
+    stepped: Mutex<Option<u64>>,
+    /// Locked by the direction holding a message, so the client and server
+    /// sides of the stepped session prompt one at a time
+    commands: tokio::sync::Mutex<UnboundedReceiver<StepCommand>>,
+}
+
+impl Stepper {
+    /// The stepper and the sender the stdin task (or a test) feeds
+    pub fn new(session: Option<u64>) -> (Arc<Self>, UnboundedSender<StepCommand>) {
+        let (sender, receiver) = unbounded_channel();
+        let stepper = Arc::new(Self {
+            session,
+            sessions: AtomicU64::new(0),
+            stepped: Mutex::new(None),
+            commands: tokio::sync::Mutex::new(receiver),
+        });
+        (stepper, sender)
+    }
+
+    /// Number a new session and return its gate if it is the one to step
+    pub fn attach(self: &Arc<Self>, client_addr: &str) -> Option<Arc<StepGate>> {
+        let session = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut stepped = self.stepped.lock().unwrap();
+        let claim = match self.session {
+            Some(wanted) => wanted == session,
+            None => stepped.is_none(),
+        };
+        if !claim {
+            info!(
+                "[{}] Session {} is not stepped{}",
+                client_addr,
+                session,
+                stepped.map_or(String::new(), |current| format!(
+                    " (stepping session {})",
+                    current
+                ))
+            );
+            return None;
+        }
+        *stepped = Some(session);
+        warn!(
+            "[{}] Stepping session {}: each message is held until a command is entered on stdin \
+             (Enter/s = forward, c = continue, d = dump, k = kill)",
+            client_addr, session
+        );
+        Some(Arc::new(StepGate {
+            stepper: self.clone(),
+            session,
+            stepping: AtomicBool::new(true),
+        }))
+    }
+}
+
+/// The stepped session's hold on its messages; dropping it lets another
+/// session be stepped
+#[derive(Debug)]
+pub struct StepGate {
+    stepper: Arc<Stepper>,
+    session: u64,
+    stepping: AtomicBool,
+}
+
+impl Drop for StepGate {
+    fn drop(&mut self) {
+        let mut stepped = self.stepper.stepped.lock().unwrap();
+        if *stepped == Some(self.session) {
+            *stepped = None;
+        }
+    }
+}
+
+impl StepGate {
+    /// Wait for the operator to release one whole message. Returns false
+    /// when the session is to be killed instead.
+    pub async fn hold(&self, client_addr: &str, from_client: bool, message: &[u8]) -> bool {
+        let mut commands = self.stepper.commands.lock().await;
+        if !self.stepping.load(Ordering::Relaxed) {
+            match commands.try_recv() {
+                Ok(StepCommand::Step) => self.stepping.store(true, Ordering::Relaxed),
+                Ok(StepCommand::Kill) => return false,
+                _ => return true,
+            }
+        }
+        let (arrow, name) = match message.first() {
+            Some(&msg_type) => (
+                if from_client { "→" } else { "←" },
+                message_name(from_client, msg_type),
+            ),
+            None => return true,
+        };
+        loop {
+            info!(
+                "[{}] Step: holding {} {} ({} bytes)",
+                client_addr,
+                arrow,
+                name,
+                message.len()
+            );
+            match commands.recv().await {
+                Some(StepCommand::Step) => return true,
+                Some(StepCommand::Dump) => log_hex_dump(message, client_addr),
+                Some(StepCommand::Continue) => {
+                    info!(
+                        "[{}] Step: continuing session {} without stepping",
+                        client_addr, self.session
+                    );
+                    self.stepping.store(false, Ordering::Relaxed);
+                    return true;
+                }
+                Some(StepCommand::Kill) => {
+                    warn!("[{}] Step: killing session {}", client_addr, self.session);
+                    return false;
+                }
+                // stdin is gone, so nobody can step any more
+                None => {
+                    self.stepping.store(false, Ordering::Relaxed);
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+/// The whole messages of a framed buffer, in order
+pub fn split_messages(data: &[u8]) -> Vec<&[u8]> {
+    let mut messages = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 5 {
+        let length = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        let end = (length + 1).min(rest.len());
+        messages.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        messages.push(rest);
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = b"Q\0\0\0\x0dSELECT 1\0";
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse_step_command("\n"), Some(StepCommand::Step));
+        assert_eq!(parse_step_command(" s "), Some(StepCommand::Step));
+        assert_eq!(parse_step_command("c"), Some(StepCommand::Continue));
+        assert_eq!(parse_step_command("d"), Some(StepCommand::Dump));
+        assert_eq!(parse_step_command("k"), Some(StepCommand::Kill));
+        assert_eq!(parse_step_command("x"), None);
+    }
+
+    #[test]
+    fn only_one_session_is_stepped_at_a_time() {
+        let (stepper, _commands) = Stepper::new(None);
+        let first = stepper.attach("a").unwrap();
+        assert!(stepper.attach("b").is_none());
+        drop(first);
+        assert_eq!(stepper.attach("c").unwrap().session, 3);
+
+        let (stepper, _commands) = Stepper::new(Some(2));
+        assert!(stepper.attach("a").is_none());
+        assert!(stepper.attach("b").is_some());
+        assert!(stepper.attach("c").is_none());
+    }
+
+    #[tokio::test]
+    async fn held_messages_follow_the_operator() {
+        let (stepper, commands) = Stepper::new(None);
+        let gate = stepper.attach("test").unwrap();
+
+        // A dump keeps the message held until the step
+        commands.send(StepCommand::Dump).unwrap();
+        commands.send(StepCommand::Step).unwrap();
+        assert!(gate.hold("test", true, QUERY).await);
+        assert!(stepper.commands.lock().await.is_empty());
+
+        // After continue nothing waits for a command, until `s` again
+        commands.send(StepCommand::Continue).unwrap();
+        assert!(gate.hold("test", true, QUERY).await);
+        assert!(gate.hold("test", false, b"Z\0\0\0\x05I").await);
+        commands.send(StepCommand::Step).unwrap();
+        commands.send(StepCommand::Kill).unwrap();
+        assert!(!gate.hold("test", true, QUERY).await);
+    }
+
+    #[test]
+    fn framed_buffers_are_split_into_messages() {
+        let sync = b"S\0\0\0\x04";
+        let data = [QUERY, sync].concat();
+        assert_eq!(split_messages(&data), vec![QUERY, &sync[..]]);
+        assert!(split_messages(b"").is_empty());
+    }
+}
@@ -1,4 +1,5 @@
-use std::io::{self, IsTerminal};
+use owo_colors::{AnsiColors, OwoColorize};
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 /// Represents field metadata from RowDescription
@@ -8,29 +9,123 @@ pub struct FieldInfo {
     pub type_name: String,
 }
 
+/// Width of a streamed column that has no --column-width of its own
+pub const DEFAULT_COL_WIDTH: usize = 15;
+
+/// Display widths for table columns: per field name from --column-width,
+/// and --default-column-width for every other column
+#[derive(Clone, Debug)]
+pub struct ColumnWidthOverride {
+    widths: HashMap<String, usize>,
+    default_width: usize,
+}
+
+impl Default for ColumnWidthOverride {
+    fn default() -> Self {
+        Self {
+            widths: HashMap::new(),
+            default_width: DEFAULT_COL_WIDTH,
+        }
+    }
+}
+
+impl ColumnWidthOverride {
+    pub fn new(widths: impl IntoIterator<Item = (String, usize)>, default_width: usize) -> Self {
+        Self {
+            widths: widths.into_iter().collect(),
+            default_width,
+        }
+    }
+
+    /// Width set for a column by name, if any
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.widths.get(name).copied()
+    }
+
+    pub fn default_width(&self) -> usize {
+        self.default_width
+    }
+}
+
+impl std::fmt::Display for ColumnWidthOverride {
+    /// `default:N` followed by each named column, e.g. `default:15,id:36`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "default:{}", self.default_width)?;
+        let mut names: Vec<_> = self.widths.iter().collect();
+        names.sort();
+        for (name, width) in names {
+            write!(f, ",{}:{}", name, width)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse one `<column>=<width>` argument of --column-width; the split is at
+/// the last `=`, so column names may contain one
+pub fn parse_column_width(value: &str) -> Result<(String, usize), String> {
+    let (name, width) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <column>=<width>, got '{}'", value))?;
+    if name.is_empty() {
+        return Err(format!("missing column name in '{}'", value));
+    }
+    let width = width
+        .parse::<usize>()
+        .ok()
+        .filter(|width| *width > 0)
+        .ok_or_else(|| format!("invalid column width '{}'", width))?;
+    Ok((name.to_string(), width))
+}
+
+/// Widest column of a buffered table, so one long value cannot stretch the
+/// table past the screen
+const MAX_BUFFERED_COL_WIDTH: usize = 40;
+
 /// Table formatting state for a single result set
 pub struct TableFormatter {
     fields: Vec<FieldInfo>,
     column_widths: Vec<usize>,
+    /// Widths set by --column-width, kept when buffering sizes the others
+    pinned_widths: Vec<Option<usize>>,
     header_printed: bool,
-    terminal_width: Option<usize>,
+    /// Color data cells by their column type
+    color: bool,
+    /// Rows held back to size the columns to their content, while buffering
+    buffer: Option<Vec<Vec<String>>>,
+    /// Past this many buffered rows the table is printed and the rest streamed
+    buffer_max_rows: usize,
 }
 
 impl TableFormatter {
-    pub fn new(fields: Vec<FieldInfo>) -> Self {
-        // Use fixed column width of 15 characters for simplicity and alignment
-        const FIXED_COL_WIDTH: usize = 15;
-
-        let column_widths = vec![FIXED_COL_WIDTH; fields.len()];
+    pub fn new(fields: Vec<FieldInfo>, color: bool, widths: &ColumnWidthOverride) -> Self {
+        // A column named by --column-width gets its own width, the rest a
+        // fixed one so rows line up as they stream
+        let pinned_widths: Vec<Option<usize>> =
+            fields.iter().map(|field| widths.get(&field.name)).collect();
+        let column_widths = pinned_widths
+            .iter()
+            .map(|width| width.unwrap_or(widths.default_width()))
+            .collect();
 
         Self {
             fields,
             column_widths,
+            pinned_widths,
             header_printed: false,
-            terminal_width: None, // Not using dynamic width anymore
+            color,
+            buffer: None,
+            buffer_max_rows: 0,
         }
     }
 
+    /// Hold up to `max_rows` rows and size each column to the widest of its
+    /// name and values before printing them
+    pub fn buffered(mut self, max_rows: usize) -> Self {
+        self.buffer = Some(Vec::new());
+        self.buffer_max_rows = max_rows;
+        self
+    }
+
     /// Print the table header with column names
     pub fn print_header(&mut self, client_addr: &str) {
         if self.header_printed {
@@ -38,8 +133,12 @@ impl TableFormatter {
         }
 
         let parts = self.format_row(
-            &self.fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
-            &self.column_widths
+            &self
+                .fields
+                .iter()
+                .map(|f| f.name.as_str())
+                .collect::<Vec<_>>(),
+            &self.column_widths,
         );
 
         // Print header
@@ -52,24 +151,81 @@ impl TableFormatter {
 
     /// Print a data row
     pub fn print_row(&mut self, values: &[String], client_addr: &str) {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.push(values.to_vec());
+            if buffer.len() <= self.buffer_max_rows {
+                return;
+            }
+            tracing::warn!(
+                "[{}] Table buffer exceeded {} rows, streaming the rest of the result set with the current column widths",
+                client_addr,
+                self.buffer_max_rows
+            );
+            self.flush_buffer(client_addr);
+            return;
+        }
+
         // Ensure header is printed first
         if !self.header_printed {
             self.print_header(client_addr);
         }
 
-        // Use fixed column widths - no dynamic adjustment
-        let value_refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
-        let parts = self.format_row(&value_refs, &self.column_widths);
-        tracing::info!("[{}] │{}│", client_addr, parts.data);
+        tracing::info!("[{}] │{}│", client_addr, self.format_data_row(values));
+    }
+
+    /// Format the cells of a data row, colored when enabled
+    fn format_data_row(&self, values: &[String]) -> String {
+        if !self.color {
+            // Use fixed column widths - no dynamic adjustment
+            let value_refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+            return self.format_row(&value_refs, &self.column_widths).data;
+        }
+
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let width = self.column_widths.get(i).copied().unwrap_or(10);
+                let text = truncate(value, width);
+                let type_name = self.fields.get(i).map_or("", |f| f.type_name.as_str());
+                let cell = match cell_color(type_name, value) {
+                    Some(color) => text.color(color).to_string(),
+                    None => text,
+                };
+                pad(&cell, width)
+            })
+            .collect::<Vec<_>>()
+            .join("│")
+    }
+
+    /// Size the columns to the buffered rows, print them and stream from
+    /// then on
+    pub fn flush_buffer(&mut self, client_addr: &str) {
+        let Some(rows) = self.buffer.take() else {
+            return;
+        };
+        if rows.is_empty() {
+            return;
+        }
+        self.column_widths = content_widths(&self.fields, &rows)
+            .into_iter()
+            .zip(&self.pinned_widths)
+            .map(|(content, pinned)| pinned.unwrap_or(content))
+            .collect();
+        for row in &rows {
+            self.print_row(row, client_addr);
+        }
     }
 
-    /// Print the table footer
-    pub fn print_footer(&self, client_addr: &str) {
+    /// Print the table footer, and first any rows still buffered
+    pub fn print_footer(&mut self, client_addr: &str) {
+        self.flush_buffer(client_addr);
         if !self.header_printed {
             return;
         }
 
-        let separator = self.column_widths
+        let separator = self
+            .column_widths
             .iter()
             .map(|w| "─".repeat(*w))
             .collect::<Vec<_>>()
@@ -104,54 +260,118 @@ struct FormattedParts {
     separator: String,
 }
 
-/// Detect terminal width, returning None if not detectable
-fn detect_terminal_width() -> Option<usize> {
-    // Try to get terminal size using termsize crate or environment variable
-    // For now, we'll use a simple approach with COLUMNS env var or default
-    if let Ok(cols) = std::env::var("COLUMNS") {
-        cols.parse().ok()
-    } else {
-        // Default to 120 if we can't detect
-        Some(120)
+/// Width of each column: its name or its widest value, whichever is wider
+fn content_widths(fields: &[FieldInfo], rows: &[Vec<String>]) -> Vec<usize> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            rows.iter()
+                .filter_map(|row| row.get(i))
+                .map(|value| unicode_display_width(value))
+                .chain([unicode_display_width(&field.name)])
+                .max()
+                .unwrap_or(1)
+                .clamp(1, MAX_BUFFERED_COL_WIDTH)
+        })
+        .collect()
+}
+
+/// Pick the cell color for a value from its column type
+fn cell_color(type_name: &str, value: &str) -> Option<AnsiColors> {
+    if value == "NULL" {
+        return Some(AnsiColors::BrightBlack);
+    }
+    match type_name {
+        "int2" | "int4" | "int8" | "oid" | "float4" | "float8" | "numeric" => {
+            Some(AnsiColors::Yellow)
+        }
+        "bool" => Some(AnsiColors::Magenta),
+        "text" | "varchar" | "bpchar" | "char" | "name" | "json" | "jsonb" | "xml" => {
+            Some(AnsiColors::Green)
+        }
+        _ => None,
     }
 }
 
-/// Calculate the display width of a string (handling Unicode)
+/// Remove ANSI escape sequences, leaving the visible text
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequence: ESC [ parameters final-byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Calculate the display width of a string (handling Unicode), ignoring
+/// ANSI color codes
 fn unicode_display_width(s: &str) -> usize {
     // For simplicity, use char count. In production, you'd use unicode-width crate
-    s.chars().count()
+    if s.contains('\u{1b}') {
+        strip_ansi(s).chars().count()
+    } else {
+        s.chars().count()
+    }
 }
 
 /// Pad or truncate a string to fit the desired width
 fn pad_or_truncate(s: &str, width: usize) -> String {
-    let chars: Vec<char> = s.chars().collect();
-    let char_count = chars.len();
+    pad(&truncate(s, width), width)
+}
 
-    if char_count <= width {
-        // Pad with spaces to reach the exact width
-        let padding = " ".repeat(width - char_count);
-        format!("{}{}", s, padding)
+/// Truncate a plain string to the desired width, adding an ellipsis
+fn truncate(s: &str, width: usize) -> String {
+    if unicode_display_width(s) <= width {
+        return s.to_string();
+    }
+    if width >= 3 {
+        let truncated: String = s.chars().take(width - 3).collect();
+        format!("{}...", truncated)
     } else {
-        // Truncate and add ellipsis
-        if width >= 3 {
-            let truncated: String = chars.iter().take(width - 3).collect();
-            format!("{}...", truncated)
-        } else {
-            chars.iter().take(width).collect()
-        }
+        s.chars().take(width).collect()
     }
 }
 
+/// Pad with spaces to reach the exact visible width
+fn pad(s: &str, width: usize) -> String {
+    let padding = " ".repeat(width.saturating_sub(unicode_display_width(s)));
+    format!("{}{}", s, padding)
+}
+
 /// Per-client state for table formatting
 pub struct TableState {
     table_mode: bool,
+    color: bool,
+    /// Buffer up to this many rows of each result set (--table-buffer-max-rows)
+    buffer_max_rows: Option<usize>,
+    widths: ColumnWidthOverride,
     current_formatter: Mutex<Option<TableFormatter>>,
 }
 
 impl TableState {
-    pub fn new(table_mode: bool) -> Self {
+    pub fn new(
+        table_mode: bool,
+        color: bool,
+        buffer_max_rows: Option<usize>,
+        widths: ColumnWidthOverride,
+    ) -> Self {
         Self {
             table_mode,
+            color,
+            buffer_max_rows,
+            widths,
             current_formatter: Mutex::new(None),
         }
     }
@@ -160,10 +380,19 @@ impl TableState {
         self.table_mode
     }
 
-    pub fn set_row_description(&self, fields: Vec<FieldInfo>) {
+    pub fn set_row_description(&self, fields: Vec<FieldInfo>, client_addr: &str) {
         if self.table_mode {
             let mut formatter = self.current_formatter.lock().unwrap();
-            *formatter = Some(TableFormatter::new(fields));
+            // A result set that ended without CommandComplete still gets
+            // its buffered rows and footer printed
+            if let Some(previous) = formatter.as_mut() {
+                previous.print_footer(client_addr);
+            }
+            let table = TableFormatter::new(fields, self.color, &self.widths);
+            *formatter = Some(match self.buffer_max_rows {
+                Some(max_rows) => table.buffered(max_rows),
+                None => table,
+            });
         }
     }
 
@@ -178,13 +407,21 @@ impl TableState {
         }
     }
 
+    /// Print the rows buffered so far and stream the rest of the result
+    /// set, which continues after a PortalSuspended
+    pub fn flush_rows(&self, client_addr: &str) {
+        if let Some(f) = self.current_formatter.lock().unwrap().as_mut() {
+            f.flush_buffer(client_addr);
+        }
+    }
+
     pub fn finish_result_set(&self, client_addr: &str) {
         if !self.table_mode {
             return;
         }
 
         let mut formatter = self.current_formatter.lock().unwrap();
-        if let Some(ref f) = *formatter {
+        if let Some(ref mut f) = *formatter {
             f.print_footer(client_addr);
         }
         *formatter = None;
@@ -231,7 +468,7 @@ mod tests {
             },
         ];
 
-        let formatter = TableFormatter::new(fields.clone());
+        let formatter = TableFormatter::new(fields.clone(), false, &ColumnWidthOverride::default());
         assert_eq!(formatter.fields.len(), 2);
         assert_eq!(formatter.column_widths[0], 15); // fixed width
         assert_eq!(formatter.column_widths[1], 15); // fixed width
@@ -244,7 +481,7 @@ mod tests {
             type_name: "text".to_string(),
         }];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
         assert_eq!(formatter.column_widths[0], 15); // Fixed width
 
         // Add rows - width should remain fixed
@@ -257,7 +494,7 @@ mod tests {
 
     #[test]
     fn table_state_only_formats_when_enabled() {
-        let state = TableState::new(false);
+        let state = TableState::new(false, false, None, ColumnWidthOverride::default());
         assert!(!state.is_table_mode());
 
         // Should not panic even when called without setup
@@ -267,7 +504,7 @@ mod tests {
 
     #[test]
     fn table_state_formats_when_enabled() {
-        let state = TableState::new(true);
+        let state = TableState::new(true, false, None, ColumnWidthOverride::default());
         assert!(state.is_table_mode());
 
         let fields = vec![FieldInfo {
@@ -275,7 +512,7 @@ mod tests {
             type_name: "int4".to_string(),
         }];
 
-        state.set_row_description(fields);
+        state.set_row_description(fields, "test");
         state.print_data_row(&["123".to_string()], "test");
         state.finish_result_set("test");
     }
@@ -293,7 +530,7 @@ mod tests {
             },
         ];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
         formatter.print_row(&["1".to_string(), "NULL".to_string()], "test");
         formatter.print_row(&["2".to_string(), "Alice".to_string()], "test");
         formatter.print_footer("test");
@@ -312,7 +549,7 @@ mod tests {
             },
         ];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
         assert_eq!(formatter.column_widths[1], 15); // fixed width
 
         formatter.print_row(&["a".to_string(), "b".to_string()], "test");
@@ -333,7 +570,7 @@ mod tests {
             type_name: "text".to_string(),
         }];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
         formatter.print_row(&["".to_string()], "test");
         formatter.print_row(&["value".to_string()], "test");
         formatter.print_footer("test");
@@ -360,7 +597,7 @@ mod tests {
             },
         ];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
         formatter.print_row(
             &[
                 "1".to_string(),
@@ -382,6 +619,44 @@ mod tests {
         formatter.print_footer("test");
     }
 
+    #[test]
+    fn colored_cells_keep_column_alignment() {
+        let fields = vec![
+            FieldInfo {
+                name: "id".to_string(),
+                type_name: "int4".to_string(),
+            },
+            FieldInfo {
+                name: "name".to_string(),
+                type_name: "text".to_string(),
+            },
+            FieldInfo {
+                name: "ok".to_string(),
+                type_name: "bool".to_string(),
+            },
+        ];
+        let formatter = TableFormatter::new(fields, true, &ColumnWidthOverride::default());
+        let row = formatter.format_data_row(&[
+            "42".to_string(),
+            "NULL".to_string(),
+            "a value that is far too long".to_string(),
+        ]);
+
+        assert!(row.contains(&"42".color(AnsiColors::Yellow).to_string()));
+        assert!(row.contains(&"NULL".color(AnsiColors::BrightBlack).to_string()));
+        assert!(row.contains(&"a value that...".color(AnsiColors::Magenta).to_string()));
+        assert_eq!(
+            strip_ansi(&row),
+            format!("{:15}│{:15}│{:15}", "42", "NULL", "a value that...")
+        );
+    }
+
+    #[test]
+    fn strip_ansi_removes_color_codes() {
+        assert_eq!(strip_ansi("\u{1b}[33m42\u{1b}[39m  "), "42  ");
+        assert_eq!(unicode_display_width("\u{1b}[33m42\u{1b}[39m"), 2);
+    }
+
     #[test]
     fn pad_or_truncate_handles_very_short_width() {
         // Width 3 results in "..." (ellipsis)
@@ -406,7 +681,7 @@ mod tests {
             },
         ];
 
-        let mut formatter = TableFormatter::new(fields);
+        let mut formatter = TableFormatter::new(fields, false, &ColumnWidthOverride::default());
 
         // First row with short values
         formatter.print_row(&["1".to_string(), "a".to_string()], "test");
@@ -421,4 +696,78 @@ mod tests {
         assert_eq!(formatter.column_widths[0], 15);
         assert_eq!(formatter.column_widths[1], 15);
     }
+
+    fn num_and_text() -> Vec<FieldInfo> {
+        ["num", "text"]
+            .iter()
+            .map(|name| FieldInfo {
+                name: name.to_string(),
+                type_name: "text".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn buffered_tables_size_columns_to_their_content() {
+        let mut formatter =
+            TableFormatter::new(num_and_text(), false, &ColumnWidthOverride::default())
+                .buffered(10);
+        formatter.print_row(&["1".to_string(), "a".to_string()], "test");
+        formatter.print_row(&["12345".to_string(), "longer text".to_string()], "test");
+        assert!(!formatter.header_printed);
+
+        formatter.print_footer("test");
+        assert!(formatter.header_printed);
+        assert_eq!(formatter.column_widths, vec![5, 11]);
+
+        // Names count too, and very long values are capped
+        let rows = vec![vec!["1".to_string(), "x".repeat(100)]];
+        assert_eq!(
+            content_widths(&num_and_text(), &rows),
+            vec![3, MAX_BUFFERED_COL_WIDTH]
+        );
+    }
+
+    #[test]
+    fn buffered_tables_stream_once_the_buffer_is_full() {
+        let mut formatter =
+            TableFormatter::new(num_and_text(), false, &ColumnWidthOverride::default()).buffered(2);
+        formatter.print_row(&["1".to_string(), "a".to_string()], "test");
+        formatter.print_row(&["22".to_string(), "b".to_string()], "test");
+        assert!(formatter.buffer.is_some());
+
+        formatter.print_row(&["333".to_string(), "c".to_string()], "test");
+        assert!(formatter.buffer.is_none());
+        assert!(formatter.header_printed);
+        let widths = formatter.column_widths.clone();
+        assert_eq!(widths, vec![3, 4]);
+
+        // Later rows keep the widths of the buffered ones
+        formatter.print_row(&["4444444".to_string(), "d".to_string()], "test");
+        assert_eq!(formatter.column_widths, widths);
+    }
+
+    #[test]
+    fn column_widths_are_parsed() {
+        assert_eq!(parse_column_width("id=36"), Ok(("id".to_string(), 36)));
+        assert_eq!(parse_column_width("a=b=4"), Ok(("a=b".to_string(), 4)));
+        assert!(parse_column_width("id").is_err());
+        assert!(parse_column_width("=4").is_err());
+        assert!(parse_column_width("id=0").is_err());
+        assert!(parse_column_width("id=wide").is_err());
+    }
+
+    #[test]
+    fn named_columns_get_their_own_width() {
+        let widths = ColumnWidthOverride::new([("text".to_string(), 36)], 6);
+        let formatter = TableFormatter::new(num_and_text(), false, &widths);
+        assert_eq!(formatter.column_widths, vec![6, 36]);
+        assert_eq!(widths.to_string(), "default:6,text:36");
+
+        // Buffering sizes only the columns without a width of their own
+        let mut formatter = TableFormatter::new(num_and_text(), false, &widths).buffered(10);
+        formatter.print_row(&["12345".to_string(), "a".to_string()], "test");
+        formatter.print_footer("test");
+        assert_eq!(formatter.column_widths, vec![5, 36]);
+    }
 }
@@ -0,0 +1,317 @@
+use rustls::server::ClientHello;
+use rustls::{ProtocolVersion, ServerConnection};
+use std::fmt;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// What a client and the proxy agreed on in a TLS handshake, e.g.
+/// `version=TLSv1.3 cipher=TLS13_AES_256_GCM_SHA384 sni=db.example.com alpn=postgresql`
+pub fn describe_session(connection: &ServerConnection) -> String {
+    let version = match connection.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+        Some(other) => format!("{:?}", other),
+        None => "unknown".to_string(),
+    };
+    let cipher = connection
+        .negotiated_cipher_suite()
+        .map_or("unknown".to_string(), |suite| {
+            format!("{:?}", suite.suite())
+        });
+    let sni = connection.server_name().unwrap_or("none");
+    let alpn = connection
+        .alpn_protocol()
+        .map_or("none".to_string(), |alpn| {
+            String::from_utf8_lossy(alpn).into_owned()
+        });
+    format!(
+        "version={} cipher={} sni={} alpn={}",
+        version, cipher, sni, alpn
+    )
+}
+
+/// What a client offers in its ClientHello, e.g.
+/// `sni=db.example.com alpn=postgresql ciphers=TLS13_AES_128_GCM_SHA256,...`
+pub fn describe_client_hello(hello: &ClientHello<'_>) -> String {
+    let alpn = hello.alpn().map_or("none".to_string(), |protocols| {
+        protocols
+            .map(|alpn| String::from_utf8_lossy(alpn).into_owned())
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    let ciphers = hello
+        .cipher_suites()
+        .iter()
+        .map(|suite| format!("{:?}", suite))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "sni={} alpn={} ciphers={}",
+        hello.server_name().unwrap_or("none"),
+        alpn,
+        ciphers
+    )
+}
+
+/// Subject, issuer and validity period of an X.509 certificate
+#[derive(Debug, PartialEq)]
+pub struct CertificateSummary {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+}
+
+impl CertificateSummary {
+    /// Read the fields of a DER certificate, or None when it cannot be parsed
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (SEQUENCE, certificate, _) = read_tlv(der)? else {
+            return None;
+        };
+        let (SEQUENCE, tbs, _) = read_tlv(certificate)? else {
+            return None;
+        };
+        let mut rest = tbs;
+        // Optional explicit version, then serial number and signature algorithm
+        let (tag, _, after) = read_tlv(rest)?;
+        if tag == EXPLICIT_VERSION {
+            rest = after;
+        }
+        let (_, _, rest) = read_tlv(rest)?;
+        let (_, _, rest) = read_tlv(rest)?;
+        let (SEQUENCE, issuer, rest) = read_tlv(rest)? else {
+            return None;
+        };
+        let (SEQUENCE, validity, rest) = read_tlv(rest)? else {
+            return None;
+        };
+        let (SEQUENCE, subject, _) = read_tlv(rest)? else {
+            return None;
+        };
+        let (tag, not_before, validity) = read_tlv(validity)?;
+        let not_before = parse_time(tag, not_before)?;
+        let (tag, not_after, _) = read_tlv(validity)?;
+        let not_after = parse_time(tag, not_after)?;
+        Some(Self {
+            subject: format_name(subject)?,
+            issuer: format_name(issuer)?,
+            not_before,
+            not_after,
+        })
+    }
+
+    pub fn expired(&self, now: OffsetDateTime) -> bool {
+        now > self.not_after
+    }
+}
+
+impl fmt::Display for CertificateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "subject=\"{}\" issuer=\"{}\" not_before={} not_after={}",
+            self.subject,
+            self.issuer,
+            format_time(self.not_before),
+            format_time(self.not_after)
+        )
+    }
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const BMP_STRING: u8 = 0x1e;
+const EXPLICIT_VERSION: u8 = 0xa0;
+
+/// Split one DER element off `input`: its tag, its contents and what follows
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let (length, after) = input.split_at(octets);
+        input = after;
+        length
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | byte as usize)
+    };
+    if input.len() < length {
+        return None;
+    }
+    let (contents, rest) = input.split_at(length);
+    Some((tag, contents, rest))
+}
+
+/// Render a Name as `C=NL, O=Example Ltd, CN=db.example.com`, in the order
+/// the certificate lists it
+fn format_name(mut name: &[u8]) -> Option<String> {
+    let mut parts = Vec::new();
+    while !name.is_empty() {
+        let (SET, mut set, rest) = read_tlv(name)? else {
+            return None;
+        };
+        name = rest;
+        while !set.is_empty() {
+            let (SEQUENCE, attribute, rest) = read_tlv(set)? else {
+                return None;
+            };
+            set = rest;
+            let (OBJECT_IDENTIFIER, oid, attribute) = read_tlv(attribute)? else {
+                return None;
+            };
+            let (tag, value, _) = read_tlv(attribute)?;
+            parts.push(format!(
+                "{}={}",
+                attribute_name(oid),
+                decode_string(tag, value)
+            ));
+        }
+    }
+    Some(parts.join(", "))
+}
+
+/// Short name of a well-known attribute type, or its dotted OID
+fn attribute_name(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".to_string(),
+        [0x55, 0x04, 0x05] => "serialNumber".to_string(),
+        [0x55, 0x04, 0x06] => "C".to_string(),
+        [0x55, 0x04, 0x07] => "L".to_string(),
+        [0x55, 0x04, 0x08] => "ST".to_string(),
+        [0x55, 0x04, 0x0a] => "O".to_string(),
+        [0x55, 0x04, 0x0b] => "OU".to_string(),
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress".to_string(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC".to_string(),
+        _ => format_oid(oid),
+    }
+}
+
+fn format_oid(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value: u64 = 0;
+    for &byte in oid {
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.push(first);
+                arcs.push(value - first * 40);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter()
+        .map(|arc| arc.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Decode an attribute value; BMPString is UTF-16, the other string types
+/// are read as UTF-8
+fn decode_string(tag: u8, value: &[u8]) -> String {
+    if tag == BMP_STRING {
+        let units: Vec<u16> = value
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        return String::from_utf16_lossy(&units);
+    }
+    String::from_utf8_lossy(value).into_owned()
+}
+
+/// Parse a UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`)
+fn parse_time(tag: u8, value: &[u8]) -> Option<OffsetDateTime> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        // RFC 5280: two-digit years from 50 are in the 1900s
+        UTC_TIME if text.len() == 12 => {
+            let year: i32 = text[..2].parse().ok()?;
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &text[2..],
+            )
+        }
+        GENERALIZED_TIME if text.len() == 14 => (text[..4].parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| rest.get(i..i + 2)?.parse::<u8>().ok();
+    let date = Date::from_calendar_date(year, Month::try_from(field(0)?).ok()?, field(2)?).ok()?;
+    let time = Time::from_hms(field(4)?, field(6)?, field(8)?).ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+pub fn format_time(datetime: OffsetDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        datetime.year(),
+        u8::from(datetime.month()),
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed, valid from 2025-01-01 until 2051-01-01 12:00 (a
+    /// GeneralizedTime, as dates from 2050 on must be)
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBzzCCAXWgAwIBAgIUYiX2PWFIhqaJNbAJr0J4N61uWaMwCgYIKoZIzj0EAwIw
+PDELMAkGA1UEBhMCTkwxFDASBgNVBAoMC0V4YW1wbGUgTHRkMRcwFQYDVQQDDA5k
+Yi5leGFtcGxlLmNvbTAgFw0yNTAxMDEwMDAwMDBaGA8yMDUxMDEwMTEyMDAwMFow
+PDELMAkGA1UEBhMCTkwxFDASBgNVBAoMC0V4YW1wbGUgTHRkMRcwFQYDVQQDDA5k
+Yi5leGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABJCz6aSxKvj+
+vr5tvhOjdcLUQ8DTnewRJHgbic9VgPpips5u+2FC/tGq0uogKps6o8InzJ3rTi40
+bW0g8cJGV+KjUzBRMB0GA1UdDgQWBBRhUXXGxYWh3Xeu9ahnw5oEn9L8WTAfBgNV
+HSMEGDAWgBRhUXXGxYWh3Xeu9ahnw5oEn9L8WTAPBgNVHRMBAf8EBTADAQH/MAoG
+CCqGSM49BAMCA0gAMEUCIAXqXc3fTpyXVna/yBNriSndnC86djav2o+2E9rwIR7E
+AiEA1AkCegBIO4pSrcsSgZPPK3zmAakm8Du8iCeK2umVCgk=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn certificates_are_summarized() {
+        let der = rustls_pemfile::certs(&mut CERTIFICATE.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let summary = CertificateSummary::parse(&der).unwrap();
+        assert_eq!(
+            summary.to_string(),
+            "subject=\"C=NL, O=Example Ltd, CN=db.example.com\" \
+             issuer=\"C=NL, O=Example Ltd, CN=db.example.com\" \
+             not_before=2025-01-01T00:00:00Z not_after=2051-01-01T12:00:00Z"
+        );
+        assert!(!summary.expired(summary.not_before));
+        assert!(summary.expired(summary.not_after + time::Duration::SECOND));
+
+        assert_eq!(CertificateSummary::parse(&der[..100]), None);
+        assert_eq!(CertificateSummary::parse(b""), None);
+    }
+
+    #[test]
+    fn unknown_attributes_show_their_oid() {
+        assert_eq!(attribute_name(&[0x55, 0x04, 0x0a]), "O");
+        assert_eq!(
+            attribute_name(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37]),
+            "1.3.6.1.4.1.311"
+        );
+        assert_eq!(
+            parse_time(UTC_TIME, b"991231235959Z").map(format_time),
+            Some("1999-12-31T23:59:59Z".to_string())
+        );
+        assert_eq!(parse_time(UTC_TIME, b"991331235959Z"), None);
+    }
+}
@@ -0,0 +1,523 @@
+use crate::cancel::{decode_backend_key, BackendKey};
+use crate::protocol::{
+    decode_parse_message, decode_startup_parameters, describe_error_response,
+    encode_backend_message, encode_query, encode_ready_for_query, encode_startup_message,
+    MessageFramer,
+};
+use crate::socket_options::SocketOptions;
+use crate::step::split_messages;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+/// How --pool-size shares upstream connections
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum PoolMode {
+    /// Pre-open connections; each client session keeps its own until it ends
+    #[default]
+    Session,
+    /// Lend authenticated server sessions to clients one transaction at a time
+    Transaction,
+}
+
+/// The database and user a server session was started for. Backends are
+/// only lent to clients that asked for the same pair.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PoolKey {
+    pub database: String,
+    pub user: String,
+}
+
+impl PoolKey {
+    /// The database and user of a client's startup message; the database
+    /// defaults to the user name, as in PostgreSQL
+    pub fn from_startup(message: &[u8]) -> Option<Self> {
+        let parameters = decode_startup_parameters(message)?;
+        let value = |name: &str| {
+            parameters
+                .iter()
+                .find(|(parameter, _)| parameter == name)
+                .map(|(_, value)| value.clone())
+        };
+        let user = value("user")?;
+        Some(Self {
+            database: value("database").unwrap_or_else(|| user.clone()),
+            user,
+        })
+    }
+}
+
+/// An authenticated server session
+#[derive(Debug)]
+pub struct Backend {
+    /// Numbered from 1 in the order the pool opened them
+    pub id: u64,
+    pub process_id: i32,
+    /// BackendKeyData of the server session, which cancels its queries
+    pub key: Option<BackendKey>,
+    pub stream: TcpStream,
+}
+
+#[derive(Debug, Default)]
+struct Backends {
+    idle: Vec<Backend>,
+    /// Idle and lent out together
+    open: usize,
+    /// ParameterStatus values reported when the first backend started
+    parameters: Vec<(String, String)>,
+    released: Arc<Notify>,
+}
+
+/// Server sessions shared between clients one transaction at a time, the
+/// way PgBouncer's transaction pooling works.
+///
+/// The proxy starts each server session itself with the database and user
+/// of the client that needed it, and keeps at most `size` of them per
+/// database and user. Only servers that trust the proxy are supported: no
+/// password is ever sent upstream.
+#[derive(Debug)]
+pub struct TransactionPool {
+    host: String,
+    port: u16,
+    socket_options: SocketOptions,
+    size: usize,
+    backends: Mutex<HashMap<PoolKey, Backends>>,
+    opened: AtomicU64,
+}
+
+impl TransactionPool {
+    pub fn new(host: String, port: u16, socket_options: SocketOptions, size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            host,
+            port,
+            socket_options,
+            size,
+            backends: Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+        })
+    }
+
+    /// Take an idle backend for `key`, start a new one while fewer than
+    /// `size` are open, or wait for one to be released
+    pub async fn acquire(&self, key: &PoolKey) -> Result<Backend> {
+        loop {
+            let released = {
+                let mut backends = self.backends.lock().unwrap();
+                let backends = backends.entry(key.clone()).or_default();
+                if let Some(backend) = backends.idle.pop() {
+                    return Ok(backend);
+                }
+                if backends.open < self.size {
+                    backends.open += 1;
+                    None
+                } else {
+                    Some(backends.released.clone())
+                }
+            };
+            match released {
+                Some(released) => released.notified().await,
+                None => {
+                    let opened = self.open(key).await;
+                    if opened.is_err() {
+                        self.discard(key);
+                    }
+                    return opened;
+                }
+            }
+        }
+    }
+
+    /// Put a backend that is idle outside any transaction back in the pool
+    pub fn release(&self, key: &PoolKey, backend: Backend) {
+        let mut backends = self.backends.lock().unwrap();
+        let backends = backends.entry(key.clone()).or_default();
+        backends.idle.push(backend);
+        backends.released.notify_one();
+    }
+
+    /// Account for a backend that was closed instead of released
+    pub fn discard(&self, key: &PoolKey) {
+        let mut backends = self.backends.lock().unwrap();
+        let backends = backends.entry(key.clone()).or_default();
+        backends.open = backends.open.saturating_sub(1);
+        backends.released.notify_one();
+    }
+
+    /// Idle and open backends for `key`
+    pub fn counts(&self, key: &PoolKey) -> (usize, usize) {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or((0, 0), |backends| (backends.idle.len(), backends.open))
+    }
+
+    /// The server parameters clients of `key` are greeted with
+    pub fn parameters(&self, key: &PoolKey) -> Vec<(String, String)> {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|backends| backends.parameters.clone())
+            .unwrap_or_default()
+    }
+
+    /// Connect and run the startup of a new backend
+    async fn open(&self, key: &PoolKey) -> Result<Backend> {
+        let mut stream = self.socket_options.connect(&self.host, self.port).await?;
+        stream
+            .write_all(&encode_startup_message(&[
+                ("user".to_string(), key.user.clone()),
+                ("database".to_string(), key.database.clone()),
+            ]))
+            .await?;
+
+        let mut framer = MessageFramer::default();
+        let mut buf = vec![0u8; 8192];
+        let mut parameters = Vec::new();
+        let mut process_id = 0;
+        let mut backend_key = None;
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                bail!("Server closed the pooled connection during startup");
+            }
+            for message in split_messages(&framer.push(&buf[..n])) {
+                let body = &message[5..];
+                match message[0] {
+                    b'R' => {
+                        let method = body
+                            .get(..4)
+                            .map_or(-1, |code| i32::from_be_bytes(code.try_into().unwrap()));
+                        if method != 0 {
+                            bail!(
+                                "Server asked for authentication (method {}); transaction pooling needs a server that trusts the proxy",
+                                method
+                            );
+                        }
+                    }
+                    b'S' => {
+                        let mut fields = body.split(|&b| b == 0);
+                        if let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+                            parameters.push((
+                                String::from_utf8_lossy(name).into_owned(),
+                                String::from_utf8_lossy(value).into_owned(),
+                            ));
+                        }
+                    }
+                    b'K' => {
+                        if let Some(pid) = body.get(..4) {
+                            process_id = i32::from_be_bytes(pid.try_into().unwrap());
+                        }
+                        backend_key = decode_backend_key(body);
+                    }
+                    b'E' => bail!(
+                        "Server refused the pooled connection: {}",
+                        describe_error_response(body)
+                    ),
+                    b'Z' => {
+                        let mut backends = self.backends.lock().unwrap();
+                        let backends = backends.entry(key.clone()).or_default();
+                        if backends.parameters.is_empty() {
+                            backends.parameters = parameters;
+                        }
+                        return Ok(Backend {
+                            id: self.opened.fetch_add(1, Ordering::Relaxed) + 1,
+                            process_id,
+                            key: backend_key,
+                            stream,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Run DISCARD ALL so the next client of the backend starts from a clean
+/// session: no prepared statements, settings, listeners or temp tables
+pub async fn reset_backend(backend: &mut Backend) -> Result<()> {
+    backend
+        .stream
+        .write_all(&encode_query("DISCARD ALL"))
+        .await?;
+    let mut framer = MessageFramer::default();
+    let mut buf = vec![0u8; 1024];
+    let mut error = None;
+    loop {
+        let n = backend.stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Server closed the connection during DISCARD ALL");
+        }
+        for message in split_messages(&framer.push(&buf[..n])) {
+            match message[0] {
+                b'E' => error = Some(describe_error_response(&message[5..])),
+                b'Z' => {
+                    return match error {
+                        Some(error) => bail!("DISCARD ALL failed: {}", error),
+                        None => Ok(()),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What a client sees in place of the server's startup reply: AuthenticationOk,
+/// the pool's ParameterStatus values, a BackendKeyData of the proxy's own and
+/// ReadyForQuery
+pub fn encode_greeting(parameters: &[(String, String)], process_id: i32, secret: i32) -> Vec<u8> {
+    let mut greeting = encode_backend_message(b'R', &0i32.to_be_bytes());
+    for (name, value) in parameters {
+        let mut body = name.as_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+        greeting.extend_from_slice(&encode_backend_message(b'S', &body));
+    }
+    let mut key = process_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&secret.to_be_bytes());
+    greeting.extend_from_slice(&encode_backend_message(b'K', &key));
+    greeting.extend_from_slice(&encode_ready_for_query(b'I'));
+    greeting
+}
+
+/// Follows the messages of one client's borrowed backend to tell when it
+/// can be given back: every Query, FunctionCall and Sync sent has had its
+/// ReadyForQuery, no extended-protocol messages wait for a Sync, and the
+/// backend is outside a transaction.
+#[derive(Debug, Default)]
+pub struct TransactionTracker {
+    /// ReadyForQuery messages still to come
+    pending: usize,
+    /// Extended-protocol messages were sent since the last Sync
+    unsynced: bool,
+    /// The session gained state the next client must not inherit
+    dirty: bool,
+}
+
+impl TransactionTracker {
+    /// Note a message sent to the backend. Returns the name of a named
+    /// prepared statement it creates, which only lives as long as the
+    /// client keeps this backend.
+    pub fn client_message(&mut self, message: &[u8]) -> Option<String> {
+        let body = message.get(5..).unwrap_or_default();
+        match message.first() {
+            Some(b'Q') => {
+                self.pending += 1;
+                let sql = String::from_utf8_lossy(body);
+                if creates_session_state(&sql) {
+                    self.dirty = true;
+                }
+            }
+            Some(b'F') => self.pending += 1,
+            Some(b'S') => {
+                self.pending += 1;
+                self.unsynced = false;
+            }
+            // COPY data travels inside a Query or an Execute already counted
+            Some(b'd' | b'c' | b'f') => {}
+            Some(b'P') => {
+                self.unsynced = true;
+                let (name, sql) = decode_parse_message(body);
+                if creates_session_state(&sql) {
+                    self.dirty = true;
+                }
+                if !name.is_empty() {
+                    self.dirty = true;
+                    return Some(name);
+                }
+            }
+            _ => self.unsynced = true,
+        }
+        None
+    }
+
+    /// Note messages from the backend; true when the backend can be given
+    /// back after they are forwarded
+    pub fn server_messages(&mut self, messages: &[u8]) -> bool {
+        let mut releasable = false;
+        for message in split_messages(messages) {
+            if message.first() == Some(&b'Z') {
+                self.pending = self.pending.saturating_sub(1);
+                releasable = self.pending == 0 && !self.unsynced && message.get(5) == Some(&b'I');
+            }
+        }
+        releasable
+    }
+
+    /// Whether the backend needs a reset before its next client, clearing
+    /// the flag for the next transaction
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+/// Statements whose effect outlives the transaction they run in
+fn creates_session_state(sql: &str) -> bool {
+    let mut words = sql.split_whitespace().map(str::to_ascii_uppercase);
+    match words.next().as_deref() {
+        Some("PREPARE" | "LISTEN" | "DECLARE") => true,
+        Some("SET") => !matches!(words.next().as_deref(), Some("LOCAL" | "TRANSACTION")),
+        Some("CREATE") => matches!(words.next().as_deref(), Some("TEMP" | "TEMPORARY")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+        encode_backend_message(tag, body)
+    }
+
+    fn query(sql: &str) -> Vec<u8> {
+        message(b'Q', format!("{}\0", sql).as_bytes())
+    }
+
+    fn parse(name: &str, sql: &str) -> Vec<u8> {
+        message(b'P', format!("{}\0{}\0\0\0", name, sql).as_bytes())
+    }
+
+    #[test]
+    fn backends_are_released_when_idle_after_the_last_reply() {
+        let mut tracker = TransactionTracker::default();
+        // Autocommit query
+        tracker.client_message(&query("SELECT 1"));
+        assert!(!tracker.server_messages(&message(b'C', b"SELECT 1\0")));
+        assert!(tracker.server_messages(&encode_ready_for_query(b'I')));
+
+        // Explicit transaction: held until COMMIT leaves it idle
+        tracker.client_message(&query("BEGIN"));
+        assert!(!tracker.server_messages(&encode_ready_for_query(b'T')));
+        tracker.client_message(&query("SELECT 1/0"));
+        assert!(!tracker.server_messages(&encode_ready_for_query(b'E')));
+        tracker.client_message(&query("ROLLBACK"));
+        assert!(tracker.server_messages(&encode_ready_for_query(b'I')));
+
+        // Pipelined queries wait for the last ReadyForQuery
+        tracker.client_message(&query("SELECT 1"));
+        tracker.client_message(&query("SELECT 2"));
+        let both = [encode_ready_for_query(b'I'), encode_ready_for_query(b'I')];
+        assert!(!tracker.server_messages(&both[0]));
+        assert!(tracker.server_messages(&both[1]));
+
+        // Extended protocol waits for the Sync
+        tracker.client_message(&parse("", "SELECT 1"));
+        tracker.client_message(&message(b'B', b"\0\0\0\0\0\0\0"));
+        tracker.client_message(&message(b'E', b"\0\0\0\0\0"));
+        tracker.client_message(&message(b'S', b""));
+        tracker.client_message(&parse("", "SELECT 2"));
+        assert!(!tracker.server_messages(&encode_ready_for_query(b'I')));
+        tracker.client_message(&message(b'S', b""));
+        assert!(tracker.server_messages(&encode_ready_for_query(b'I')));
+        assert!(!tracker.take_dirty());
+    }
+
+    #[test]
+    fn session_state_marks_the_backend_for_reset() {
+        let mut tracker = TransactionTracker::default();
+        assert_eq!(
+            tracker.client_message(&parse("s1", "SELECT $1")),
+            Some("s1".to_string())
+        );
+        assert!(tracker.take_dirty());
+        assert!(!tracker.take_dirty());
+
+        for sql in [
+            "set search_path = app",
+            "PREPARE q AS SELECT 1",
+            "LISTEN jobs",
+        ] {
+            tracker.client_message(&query(sql));
+            assert!(tracker.take_dirty(), "{}", sql);
+        }
+        for sql in ["SET LOCAL statement_timeout = 0", "SELECT 'SET x'"] {
+            tracker.client_message(&query(sql));
+            assert!(!tracker.take_dirty(), "{}", sql);
+        }
+    }
+
+    /// A server that trusts every client and answers each Query with
+    /// ReadyForQuery
+    async fn fake_server(listener: TcpListener) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(PoolKey::from_startup(&buf[..n]).is_some());
+                let mut reply = message(b'R', &0i32.to_be_bytes());
+                reply.extend_from_slice(&message(b'S', b"server_version\x0016.4\0"));
+                reply.extend_from_slice(&message(b'K', &[0, 0, 0, 42, 0, 0, 0, 7]));
+                reply.extend_from_slice(&encode_ready_for_query(b'I'));
+                socket.write_all(&reply).await.unwrap();
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    let mut reply = message(b'C', b"DISCARD ALL\0");
+                    reply.extend_from_slice(&encode_ready_for_query(b'I'));
+                    socket.write_all(&reply).await.unwrap();
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_lends_backends_per_database_and_user() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(fake_server(listener));
+        let pool = TransactionPool::new("127.0.0.1".into(), port, SocketOptions::default(), 1);
+        let key = PoolKey::from_startup(&encode_startup_message(&[(
+            "user".to_string(),
+            "app".to_string(),
+        )]))
+        .unwrap();
+        assert_eq!(key.database, "app");
+
+        let mut first = pool.acquire(&key).await.unwrap();
+        assert_eq!((first.id, first.process_id), (1, 42));
+        assert_eq!(
+            pool.parameters(&key),
+            vec![("server_version".to_string(), "16.4".to_string())]
+        );
+
+        // The only backend is lent out, so the next client waits for it
+        let pool_clone = pool.clone();
+        let key_clone = key.clone();
+        let waiting = tokio::spawn(async move { pool_clone.acquire(&key_clone).await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        reset_backend(&mut first).await.unwrap();
+        pool.release(&key, first);
+        assert_eq!(waiting.await.unwrap().unwrap().id, 1);
+        assert_eq!(pool.counts(&key), (0, 1));
+
+        // Another user gets a backend of its own
+        let other = PoolKey {
+            database: "app".to_string(),
+            user: "report".to_string(),
+        };
+        assert_eq!(pool.acquire(&other).await.unwrap().id, 2);
+        pool.discard(&other);
+        assert_eq!(pool.counts(&other), (0, 0));
+    }
+
+    #[test]
+    fn greeting_ends_ready_for_query() {
+        let greeting = encode_greeting(&[("TimeZone".to_string(), "UTC".to_string())], 9, 1);
+        let tags: Vec<u8> = split_messages(&greeting).iter().map(|m| m[0]).collect();
+        assert_eq!(tags, b"RSKZ");
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+
+/// A response the server owes the client for a message it already sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Expected {
+    ParseComplete,
+    BindComplete,
+    CloseComplete,
+    ParameterDescription,
+    RowDescriptionOrNoData,
+    /// DataRows, then CommandComplete, EmptyQueryResponse or PortalSuspended
+    ExecuteResult,
+    /// Any number of result sets, each RowDescription → DataRow* → CommandComplete
+    SimpleQueryResult {
+        described: bool,
+    },
+    FunctionCallResponse,
+    ReadyForQuery,
+}
+
+impl Expected {
+    fn describe(&self) -> &'static str {
+        match self {
+            Expected::ParseComplete => "ParseComplete",
+            Expected::BindComplete => "BindComplete",
+            Expected::CloseComplete => "CloseComplete",
+            Expected::ParameterDescription => "ParameterDescription",
+            Expected::RowDescriptionOrNoData => "RowDescription or NoData",
+            Expected::ExecuteResult => "DataRow, CommandComplete or PortalSuspended",
+            Expected::SimpleQueryResult { described: false } => {
+                "RowDescription, CommandComplete or EmptyQueryResponse"
+            }
+            Expected::SimpleQueryResult { described: true } => "DataRow or CommandComplete",
+            Expected::FunctionCallResponse => "FunctionCallResponse",
+            Expected::ReadyForQuery => "ReadyForQuery",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// Authentication and parameter exchange, until the first ReadyForQuery
+    Startup,
+    /// Nothing outstanding
+    Idle,
+    SimpleQuery,
+    ExtendedQuery,
+    /// An error was reported; the server skips everything up to the next Sync
+    ErrorRecovery,
+}
+
+/// A message that did not fit the conversation so far
+#[derive(Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub message: &'static str,
+    pub state: String,
+    pub expected: String,
+}
+
+/// Follows both directions of a connection and checks every server
+/// response against what the client's messages asked for
+pub struct ProtocolStateMachine {
+    phase: Phase,
+    pending: VecDeque<Expected>,
+}
+
+impl Default for ProtocolStateMachine {
+    fn default() -> Self {
+        Self {
+            phase: Phase::Startup,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl ProtocolStateMachine {
+    pub fn client_message(&mut self, msg_type: char, data: &[u8]) {
+        if self.phase == Phase::Startup {
+            return;
+        }
+        let expected: &[Expected] = match msg_type {
+            'Q' => &[
+                Expected::SimpleQueryResult { described: false },
+                Expected::ReadyForQuery,
+            ],
+            'F' => &[Expected::FunctionCallResponse, Expected::ReadyForQuery],
+            'P' => &[Expected::ParseComplete],
+            'B' => &[Expected::BindComplete],
+            'D' if data.first() == Some(&b'S') => &[
+                Expected::ParameterDescription,
+                Expected::RowDescriptionOrNoData,
+            ],
+            'D' => &[Expected::RowDescriptionOrNoData],
+            'E' => &[Expected::ExecuteResult],
+            'C' => &[Expected::CloseComplete],
+            'S' => &[Expected::ReadyForQuery],
+            _ => &[],
+        };
+        if expected.is_empty() {
+            return;
+        }
+        self.pending.extend(expected);
+        if self.phase == Phase::Idle {
+            self.phase = if msg_type == 'Q' {
+                Phase::SimpleQuery
+            } else {
+                Phase::ExtendedQuery
+            };
+        }
+    }
+
+    /// Check a server message, returning the violation it represents
+    pub fn server_message(&mut self, msg_type: char) -> Option<Violation> {
+        match (self.phase, msg_type) {
+            (Phase::Startup, 'Z') => {
+                self.phase = Phase::Idle;
+                return None;
+            }
+            (Phase::Startup, _) => return None,
+            // Asynchronous messages can arrive at any time
+            (_, 'N' | 'A' | 'S') => return None,
+            // COPY sub-protocol; not followed in detail
+            (_, 'G' | 'H' | 'W' | 'd' | 'c') => return None,
+            (_, 'E') => {
+                self.error();
+                return None;
+            }
+            _ => {}
+        }
+
+        let front = self.pending.front().copied();
+        let accepted = match (front, msg_type) {
+            (Some(Expected::ParseComplete), '1')
+            | (Some(Expected::BindComplete), '2')
+            | (Some(Expected::CloseComplete), '3')
+            | (Some(Expected::ParameterDescription), 't')
+            | (Some(Expected::FunctionCallResponse), 'V')
+            | (Some(Expected::RowDescriptionOrNoData), 'T' | 'n')
+            | (Some(Expected::ExecuteResult), 'C' | 'I' | 's') => {
+                self.pending.pop_front();
+                true
+            }
+            (Some(Expected::ExecuteResult), 'D')
+            | (Some(Expected::SimpleQueryResult { described: true }), 'D') => true,
+            (Some(Expected::SimpleQueryResult { .. }), 'T') => {
+                self.pending[0] = Expected::SimpleQueryResult { described: true };
+                true
+            }
+            (Some(Expected::SimpleQueryResult { .. }), 'C' | 'I') => {
+                self.pending[0] = Expected::SimpleQueryResult { described: false };
+                true
+            }
+            (Some(Expected::SimpleQueryResult { .. }), 'Z') => {
+                self.pending.pop_front();
+                self.pending.pop_front();
+                true
+            }
+            (Some(Expected::ReadyForQuery), 'Z') => {
+                self.pending.pop_front();
+                true
+            }
+            _ => false,
+        };
+
+        if accepted {
+            if msg_type == 'Z' {
+                self.phase = match self.pending.front() {
+                    None => Phase::Idle,
+                    Some(Expected::SimpleQueryResult { .. }) => Phase::SimpleQuery,
+                    Some(_) => Phase::ExtendedQuery,
+                };
+            }
+            return None;
+        }
+
+        let violation = Violation {
+            message: message_name(msg_type),
+            state: self.state(),
+            expected: front.map_or("nothing", |e| e.describe()).to_string(),
+        };
+        self.resync(msg_type);
+        Some(violation)
+    }
+
+    fn error(&mut self) {
+        // Everything owed before the next ReadyForQuery is skipped
+        while let Some(front) = self.pending.front() {
+            if *front == Expected::ReadyForQuery {
+                break;
+            }
+            self.pending.pop_front();
+        }
+        self.phase = Phase::ErrorRecovery;
+    }
+
+    /// After a violation, skip ahead to the expectation the message
+    /// satisfies, if any, so one mistake is reported once
+    fn resync(&mut self, msg_type: char) {
+        let target = match msg_type {
+            '1' => Expected::ParseComplete,
+            '2' => Expected::BindComplete,
+            '3' => Expected::CloseComplete,
+            't' => Expected::ParameterDescription,
+            'V' => Expected::FunctionCallResponse,
+            'Z' => Expected::ReadyForQuery,
+            _ => return,
+        };
+        if let Some(idx) = self.pending.iter().position(|e| *e == target) {
+            self.pending.drain(..=idx);
+        }
+        if msg_type == 'Z' && self.pending.is_empty() {
+            self.phase = Phase::Idle;
+        }
+    }
+
+    fn state(&self) -> String {
+        let phase = match self.phase {
+            Phase::Startup => "startup",
+            Phase::Idle => "idle",
+            Phase::SimpleQuery => "simple query",
+            Phase::ExtendedQuery => "extended query",
+            Phase::ErrorRecovery => "error recovery",
+        };
+        format!("{}, {} response(s) outstanding", phase, self.pending.len())
+    }
+}
+
+fn message_name(msg_type: char) -> &'static str {
+    match msg_type {
+        '1' => "ParseComplete",
+        '2' => "BindComplete",
+        '3' => "CloseComplete",
+        't' => "ParameterDescription",
+        'T' => "RowDescription",
+        'n' => "NoData",
+        'D' => "DataRow",
+        'C' => "CommandComplete",
+        'I' => "EmptyQueryResponse",
+        's' => "PortalSuspended",
+        'V' => "FunctionCallResponse",
+        'Z' => "ReadyForQuery",
+        _ => "unexpected message",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready() -> ProtocolStateMachine {
+        let mut machine = ProtocolStateMachine::default();
+        assert_eq!(machine.server_message('R'), None);
+        assert_eq!(machine.server_message('Z'), None);
+        machine
+    }
+
+    #[test]
+    fn well_formed_exchanges_pass() {
+        let mut machine = ready();
+        for msg in ['P', 'B', 'D', 'E', 'S'] {
+            machine.client_message(msg, b"P");
+        }
+        for msg in ['1', '2', 'T', 'D', 'D', 'C', 'Z'] {
+            assert_eq!(machine.server_message(msg), None, "{msg}");
+        }
+
+        machine.client_message('F', b"");
+        for msg in ['V', 'Z'] {
+            assert_eq!(machine.server_message(msg), None, "{msg}");
+        }
+
+        machine.client_message('Q', b"select 1; select 2\0");
+        for msg in ['T', 'D', 'C', 'T', 'D', 'C', 'Z'] {
+            assert_eq!(machine.server_message(msg), None, "{msg}");
+        }
+        assert_eq!(machine.phase, Phase::Idle);
+    }
+
+    #[test]
+    fn errors_skip_to_ready_for_query() {
+        let mut machine = ready();
+        for msg in ['P', 'B', 'E', 'S'] {
+            machine.client_message(msg, b"");
+        }
+        assert_eq!(machine.server_message('E'), None);
+        assert_eq!(machine.server_message('Z'), None);
+        assert!(machine.pending.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_responses_are_reported() {
+        let mut machine = ready();
+        machine.client_message('Q', b"select 1\0");
+        assert_eq!(
+            machine.server_message('D'),
+            Some(Violation {
+                message: "DataRow",
+                state: "simple query, 2 response(s) outstanding".to_string(),
+                expected: "RowDescription, CommandComplete or EmptyQueryResponse".to_string(),
+            })
+        );
+
+        let mut machine = ready();
+        for msg in ['P', 'B', 'E', 'S'] {
+            machine.client_message(msg, b"");
+        }
+        let violation = machine.server_message('2').unwrap();
+        assert_eq!(violation.message, "BindComplete");
+        assert_eq!(violation.expected, "ParseComplete");
+        // Resynchronized on the BindComplete
+        assert_eq!(machine.server_message('C'), None);
+        assert_eq!(machine.server_message('Z'), None);
+    }
+}